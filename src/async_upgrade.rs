@@ -1,10 +1,24 @@
 #[cfg(feature = "tls")]
 use crate::SslMode;
-use crate::upgrade_macros::{do_await, run_upgrade_flow};
-use crate::{PostgresUpgraderOptions, UpgraderError};
+use crate::outcome::FlowError;
+use crate::shared_macros::{report_progress, trace_event};
+use crate::upgrade_macros::{
+    describe_db_error, do_await, execute_upgrader_sql, execution_error,
+    prepare_parallel_cold_start, retry_connect, run_apply_hooks, run_baseline_flow,
+    run_downgrade_flow, run_parallel_step, run_plan_flow, run_repair_flow, run_status_flow,
+    run_upgrade_flow, upgrader_error,
+};
+use crate::{
+    MigrationSet, MigrationStatus, Migrations, PostgresUpgraderOptions, UpgradeOutcome,
+    UpgradePlan, UpgradeReport, UpgraderError,
+};
+use std::future::Future;
+use tokio_postgres::{CancelToken, Client, NoTls};
 
 /// Asynchronously applies schema upgrades from the specified folder to the database.
 ///
+/// Returns an [`UpgradeReport`] of the upgraders applied.
+///
 /// # Errors
 ///
 /// Returns `UpgraderError` if:
@@ -17,61 +31,1103 @@ pub async fn upgrade_async(
     upgraders_folder: impl AsRef<std::path::Path>,
     connection_string: &str,
     options: &PostgresUpgraderOptions,
+) -> Result<UpgradeReport, UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?
+        .apply_async(connection_string)
+        .await
+}
+
+/// Asynchronously applies schema upgrades embedded in the executable, e.g. with
+/// [`embed_migrations!`](crate::embed_migrations), to the database.
+///
+/// Returns an [`UpgradeReport`] of the upgraders applied.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - The embedded upgrader files are invalid.
+/// - An integrity violation is detected.
+/// - Execution of a migration step fails.
+#[cfg(feature = "tokio-postgres")]
+pub async fn upgrade_async_embedded(
+    migrations: &Migrations,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<UpgradeReport, UpgraderError> {
+    MigrationSet::from_migrations(migrations, options)?
+        .apply_async(connection_string)
+        .await
+}
+
+/// Asynchronously applies schema upgrades from the specified folder over an existing client,
+/// e.g. one taken from a pool, instead of opening a connection.
+///
+/// The TLS options are not used, and
+/// [`parallel_cold_start`](crate::PostgresUpgraderOptionsBuilder::parallel_cold_start) is
+/// ignored since it needs connections of its own. The client is left outside any transaction.
+///
+/// Returns an [`UpgradeReport`] of the upgraders applied.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Upgrader files cannot be loaded or are invalid.
+/// - An integrity violation is detected.
+/// - Execution of a migration step fails.
+#[cfg(feature = "tokio-postgres")]
+pub async fn upgrade_with_client_async(
+    client: &mut Client,
+    upgraders_folder: impl AsRef<std::path::Path>,
+    options: &PostgresUpgraderOptions,
+) -> Result<UpgradeReport, UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?
+        .apply_with_client_async(client)
+        .await
+}
+
+/// Asynchronously applies schema upgrades from the specified folder over a connection checked
+/// out of a pool, such as a `bb8::PooledConnection` or a `deadpool_postgres::Object` (passed as
+/// `&mut *object`).
+///
+/// Like [`upgrade_with_client_async`], the upgrade only borrows the connection: it neither
+/// spawns nor awaits the connection task, which stays with the pool. The caller must hold the
+/// checkout until the returned future completes, and can return it to the pool afterwards,
+/// outside any transaction.
+///
+/// Returns an [`UpgradeReport`] of the upgraders applied.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Upgrader files cannot be loaded or are invalid.
+/// - An integrity violation is detected.
+/// - Execution of a migration step fails.
+#[cfg(feature = "tokio-postgres")]
+pub async fn upgrade_async_pooled<C>(
+    connection: &mut C,
+    upgraders_folder: impl AsRef<std::path::Path>,
+    options: &PostgresUpgraderOptions,
+) -> Result<UpgradeReport, UpgraderError>
+where
+    C: std::ops::DerefMut<Target = Client>,
+{
+    upgrade_with_client_async(connection, upgraders_folder, options).await
+}
+
+/// Asynchronously reports the upgraders that [`upgrade_async`] would apply, as
+/// `(file_id, upgrader_id, description)` in order, without changing the database.
+///
+/// Runs the upgrade with [`dry_run`](crate::PostgresUpgraderOptionsBuilder::dry_run) set:
+/// unlike [`MigrationSet::plan_async`], it waits for the upgrade lock, so the result
+/// is not affected by an upgrade that is running at the same time.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid.
+/// - An integrity violation is detected.
+#[cfg(feature = "tokio-postgres")]
+pub async fn dry_run_async(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<Vec<(i32, i32, String)>, UpgraderError> {
+    let mut options = options.clone();
+    options.dry_run = true;
+    let set = MigrationSet::load(upgraders_folder, &options)?;
+    Ok(apply(connection_string, &set, None).await?.pending)
+}
+
+/// Like [`upgrade_async`], but reports what happened as an [`UpgradeOutcome`] instead of an
+/// error, so that callers can tell an integrity violation, e.g. to show the drift, from other
+/// failures without inspecting error variants.
+#[cfg(feature = "tokio-postgres")]
+pub async fn try_upgrade_async(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> UpgradeOutcome {
+    match MigrationSet::load(upgraders_folder, options) {
+        Ok(set) => UpgradeOutcome::from_result(apply(connection_string, &set, None).await),
+        Err(e) => UpgradeOutcome::Failed(e),
+    }
+}
+
+/// Asynchronously applies only the upgraders of file `file_id` from the specified folder, e.g.
+/// to iterate on one migration during development.
+///
+/// Every upgrader is still loaded and the recorded history checked against them, and each
+/// file before `file_id` must already be fully applied: the file cannot be applied ahead of
+/// them. Later files stay pending.
+///
+/// Returns an [`UpgradeReport`] of the upgraders applied.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid, or none has id `file_id`.
+/// - An upgrader of an earlier file is not applied yet; nothing is applied in that case.
+/// - An integrity violation is detected.
+/// - Execution of a migration step fails.
+#[cfg(feature = "tokio-postgres")]
+pub async fn apply_file_async(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+    file_id: i32,
+) -> Result<UpgradeReport, UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?
+        .apply_file_async(connection_string, file_id)
+        .await
+}
+
+/// Asynchronously rolls the database back to `target`, a `(file_id, upgrader_id)` pair, using the
+/// `--- <id> down:` blocks of the upgraders in the specified folder.
+///
+/// Every applied upgrader after `target` is undone, latest first, each in its own transaction
+/// that runs its down block and deletes its row from the upgraders table. `target` itself
+/// stays applied.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid, or `target` is not one of them.
+/// - An integrity violation is detected.
+/// - An upgrader to undo has no down block; nothing is undone in that case.
+/// - Execution of a down block fails.
+#[cfg(feature = "tokio-postgres")]
+pub async fn downgrade_async(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+    target: (i32, i32),
 ) -> Result<(), UpgraderError> {
-    use tokio_postgres::NoTls;
+    MigrationSet::load(upgraders_folder, options)?
+        .downgrade_async(connection_string, target)
+        .await
+}
 
-    #[cfg(feature = "tls")]
-    use crate::tls::create_tls_config;
+/// Like [`upgrade_async`], but stops early when `shutdown` resolves.
+///
+/// On shutdown the statements currently running on the server are cancelled and the open
+/// transactions are rolled back. Upgraders committed before that point remain applied; the
+/// most recent of them is reported in [`UpgraderError::Cancelled`].
+///
+/// Returns an [`UpgradeReport`] of the upgraders applied.
+///
+/// # Errors
+///
+/// Returns `UpgraderError::Cancelled` if `shutdown` resolved before the upgrade finished,
+/// and the same errors as [`upgrade_async`] otherwise. If the upgraders table cannot be read
+/// back after the shutdown, that error is returned instead, since the most recent committed
+/// upgrader is then unknown.
+#[cfg(feature = "tokio-postgres")]
+pub async fn upgrade_async_with_shutdown(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+    shutdown: impl Future<Output = ()>,
+) -> Result<UpgradeReport, UpgraderError> {
+    let set = MigrationSet::load(upgraders_folder, options)?;
+    let cancel_tokens = CancelTokens::default();
 
-    #[cfg(feature = "tls")]
-    let mut client = match options.ssl_mode {
-        SslMode::Disable => {
-            let (client, connection) = tokio_postgres::connect(connection_string, NoTls)
-                .await
-                .map_err(|e| UpgraderError::ConnectionError(e.to_string()))?;
+    {
+        let upgrade = apply(connection_string, &set, Some(&cancel_tokens));
+        tokio::pin!(upgrade);
 
-            tokio::spawn(async move {
-                if let Err(_e) = connection.await {
-                    // Connection error will be detected by the client on next query
-                }
-            });
-            client
+        tokio::select! {
+            result = &mut upgrade => return result.map_err(Into::into),
+            _ = shutdown => {}
         }
-        SslMode::Require => {
-            let tls = create_tls_config()?;
-            let (client, connection) = tokio_postgres::connect(connection_string, tls)
-                .await
-                .map_err(|e| UpgraderError::ConnectionError(e.to_string()))?;
 
-            tokio::spawn(async move {
-                if let Err(_e) = connection.await {
-                    // Connection error will be detected by the client on next query
-                }
-            });
-            client
+        // Interrupt whatever the server is executing; dropping `upgrade` at the end of this
+        // block then rolls back its transactions. This is best effort: if a cancel request
+        // fails, the server finishes the statement before processing the rollback.
+        let cancel_tokens = std::mem::take(&mut *cancel_tokens.lock().unwrap());
+        for cancel_token in &cancel_tokens {
+            let _ = cancel_query(cancel_token, connection_string, options).await;
         }
+    }
+
+    use crate::db_tracker::async_tracker::{Executor, load_applied_upgraders, relation_exists};
+    let mut client = connect(connection_string, options).await?;
+    let table = crate::db_tracker::table_name(options);
+    let applied = if relation_exists(Executor::from(&mut client), &table).await? {
+        load_applied_upgraders(Executor::from(&mut client), options).await?
+    } else {
+        Vec::new()
     };
 
-    #[cfg(not(feature = "tls"))]
-    let mut client = {
-        let (client, connection) = tokio_postgres::connect(connection_string, NoTls)
-            .await
-            .map_err(|e| UpgraderError::ConnectionError(e.to_string()))?;
+    Err(UpgraderError::Cancelled {
+        last_committed: crate::db_tracker::last_applied(&applied)
+            .map(|u| (u.file_id, u.upgrader_id)),
+    })
+}
 
-        tokio::spawn(async move {
-            if let Err(_e) = connection.await {
-                // Log error
-            }
-        });
-        client
+/// Asynchronously records every upgrader from the specified folder up to and including `up_to`, a
+/// `(file_id, upgrader_id)` pair, as applied without running it, for adopting a database whose
+/// schema was created by other means.
+///
+/// The upgraders are recorded in one transaction, under the same lock as an upgrade, with
+/// their SQL and description from the files. A later upgrade then applies only the upgraders
+/// after `up_to`.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid, or `up_to` is not one of them.
+/// - Any upgrader up to `up_to` is already recorded; nothing is recorded in that case.
+/// - The recorded history would no longer pass the integrity check.
+#[cfg(feature = "tokio-postgres")]
+pub async fn baseline_async(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+    up_to: (i32, i32),
+) -> Result<(), UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?
+        .baseline_async(connection_string, up_to)
+        .await
+}
+
+/// Asynchronously rewrites the description and SQL recorded for applied upgraders to match the
+/// specified folder, for recovering from upgraders that were reworded after being applied.
+/// Returns the number of rows updated.
+///
+/// Only rows whose `(file_id, upgrader_id)` still line up with the files are rewritten, in one
+/// transaction under the same lock as an upgrade. Upgraders with a checksum get the checksum
+/// of the file SQL.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid.
+/// - The recorded history diverges from the files in order or identity, e.g. an upgrader is
+///   missing from the files or there is a gap; nothing is rewritten in that case.
+#[cfg(feature = "tokio-postgres")]
+pub async fn repair_async(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<usize, UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?
+        .repair_async(connection_string)
+        .await
+}
+
+/// Asynchronously reports which upgraders from the specified folder are applied to the database and
+/// which are pending, without changing the database or locking the upgraders table.
+///
+/// A mismatch between the database and the files is reported in
+/// [`MigrationStatus::drift`] rather than as an error.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid.
+#[cfg(feature = "tokio-postgres")]
+pub async fn status_async(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<MigrationStatus, UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?
+        .status_async(connection_string)
+        .await
+}
+
+/// Asynchronously checks whether every upgrader from the specified folder is applied to the
+/// database, e.g. for a readiness probe.
+///
+/// Like [`status_async`], it reads the upgraders table in a read-only transaction without waiting
+/// for the upgrade lock, so it is cheap enough to run on every probe. Upgraders recorded in the
+/// database but newer than the files are accepted, as an upgrade accepts them.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid.
+/// - The recorded upgraders do not match the files, with the `IntegrityError` an upgrade
+///   would fail with.
+#[cfg(feature = "tokio-postgres")]
+pub async fn is_up_to_date_async(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<bool, UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?
+        .is_up_to_date_async(connection_string)
+        .await
+}
+
+/// Asynchronously checks that the upgraders recorded in the database match those in the
+/// specified folder, without applying anything, e.g. in CI against a copy of production.
+///
+/// Pending upgraders are not a mismatch. The check runs even with
+/// [`verify_integrity(false)`](crate::PostgresUpgraderOptionsBuilder::verify_integrity), and
+/// reads the upgraders table like [`status_async`], without waiting for the upgrade lock.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid.
+/// - The recorded upgraders do not match the files, with the `IntegrityError` an upgrade
+///   would fail with.
+#[cfg(feature = "tokio-postgres")]
+pub async fn check_integrity_async(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<(), UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?
+        .check_integrity_async(connection_string)
+        .await
+}
+#[cfg(feature = "tokio-postgres")]
+impl MigrationSet {
+    /// Asynchronously applies the upgraders in this set to the database.
+    ///
+    /// Returns an [`UpgradeReport`] of the upgraders applied.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - Connection to the database fails.
+    /// - An integrity violation is detected.
+    /// - Execution of a migration step fails.
+    pub async fn apply_async(
+        &self,
+        connection_string: &str,
+    ) -> Result<UpgradeReport, UpgraderError> {
+        Ok(apply(connection_string, self, None).await?)
+    }
+
+    /// Asynchronously applies the upgraders in this set over an existing client, see
+    /// [`upgrade_with_client_async`](crate::upgrade_with_client_async).
+    ///
+    /// Returns an [`UpgradeReport`] of the upgraders applied.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - An integrity violation is detected.
+    /// - Execution of a migration step fails.
+    pub async fn apply_with_client_async(
+        &self,
+        client: &mut Client,
+    ) -> Result<UpgradeReport, UpgraderError> {
+        Ok(run_upgrade(client, self).await?)
+    }
+
+    /// Asynchronously applies only the upgraders of file `file_id` in this set, see
+    /// [`apply_file_async`](crate::apply_file_async).
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - Connection to the database fails.
+    /// - No upgrader in this set has file id `file_id`.
+    /// - An upgrader of an earlier file is not applied yet.
+    /// - An integrity violation is detected.
+    /// - Execution of a migration step fails.
+    pub async fn apply_file_async(
+        &self,
+        connection_string: &str,
+        file_id: i32,
+    ) -> Result<UpgradeReport, UpgraderError> {
+        Ok(apply(connection_string, &self.limited_to_file(file_id)?, None).await?)
+    }
+
+    /// Asynchronously rolls the database back to `target` using the down blocks of the
+    /// upgraders in this set, see [`downgrade_async`](crate::downgrade_async).
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - Connection to the database fails.
+    /// - `target` is not one of the upgraders in this set.
+    /// - An integrity violation is detected.
+    /// - An upgrader to undo has no down block.
+    /// - Execution of a down block fails.
+    pub async fn downgrade_async(
+        &self,
+        connection_string: &str,
+        target: (i32, i32),
+    ) -> Result<(), UpgraderError> {
+        let mut client = connect(connection_string, &self.options).await?;
+        run_downgrade(&mut client, self, target).await
+    }
+
+    /// Asynchronously records the upgraders in this set up to and including `up_to` as applied
+    /// without running them, see [`baseline_async`](crate::baseline_async).
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - Connection to the database fails.
+    /// - `up_to` is not one of the upgraders in this set.
+    /// - Any upgrader up to `up_to` is already recorded.
+    /// - The recorded history would no longer pass the integrity check.
+    pub async fn baseline_async(
+        &self,
+        connection_string: &str,
+        up_to: (i32, i32),
+    ) -> Result<(), UpgraderError> {
+        let mut client = connect(connection_string, &self.options).await?;
+        run_baseline(&mut client, self, up_to).await
+    }
+
+    /// Asynchronously rewrites the recorded upgraders to match this set, see
+    /// [`repair_async`](crate::repair_async).
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - Connection to the database fails.
+    /// - The recorded history diverges from this set in order or identity.
+    pub async fn repair_async(&self, connection_string: &str) -> Result<usize, UpgraderError> {
+        let mut client = connect(connection_string, &self.options).await?;
+        run_repair(&mut client, self).await
+    }
+
+    /// Asynchronously works out what [`apply_async`](Self::apply_async) would do, without
+    /// changing the database or locking the upgraders table.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - Connection to the database fails.
+    /// - An integrity violation is detected.
+    pub async fn plan_async(&self, connection_string: &str) -> Result<UpgradePlan, UpgraderError> {
+        let mut client = connect(connection_string, &self.options).await?;
+        run_plan(&mut client, self).await
+    }
+
+    /// Asynchronously reports which upgraders in this set are applied to the database and which
+    /// are pending, without changing the database or locking the upgraders table.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if connection to the database fails.
+    pub async fn status_async(
+        &self,
+        connection_string: &str,
+    ) -> Result<MigrationStatus, UpgraderError> {
+        let mut client = connect(connection_string, &self.options).await?;
+        run_status(&mut client, self).await
+    }
+
+    /// Asynchronously checks whether every upgrader in this set is applied to the database,
+    /// without changing the database or waiting for the upgrade lock.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if connection to the database fails, or the `IntegrityError`
+    /// an upgrade would fail with if the recorded upgraders do not match the set.
+    pub async fn is_up_to_date_async(
+        &self,
+        connection_string: &str,
+    ) -> Result<bool, UpgraderError> {
+        self.status_async(connection_string)
+            .await?
+            .check_up_to_date()
+    }
+
+    /// Asynchronously checks that the upgraders recorded in the database match this set,
+    /// without changing the database or waiting for the upgrade lock.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if connection to the database fails, or the `IntegrityError`
+    /// an upgrade would fail with if the recorded upgraders do not match the set.
+    pub async fn check_integrity_async(
+        &self,
+        connection_string: &str,
+    ) -> Result<(), UpgraderError> {
+        self.status_async(connection_string)
+            .await?
+            .check_integrity()
+    }
+}
+
+/// Asynchronously creates the upgraders table, and the schema if
+/// [`create_schema`](crate::PostgresUpgraderOptionsBuilder::create_schema) is set, without
+/// loading or applying any upgraders.
+///
+/// Meant for provisioning steps that run the privileged DDL ahead of time, so that the upgrade
+/// itself can run as a less privileged role. Creating an existing table or schema is a no-op.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - The options are invalid, e.g. a schema name is not a valid identifier.
+/// - Connection to the database fails.
+/// - The database is a read replica.
+/// - Creating the schema or the upgraders table fails.
+#[cfg(feature = "tokio-postgres")]
+pub async fn init_only_async(
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<(), UpgraderError> {
+    use crate::db_tracker::async_tracker::{
+        Executor, create_schema_if_needed, ensure_not_in_recovery, init_upgraders_table,
+    };
+    options.validate()?;
+    let mut client = connect(connection_string, options).await?;
+    ensure_not_in_recovery(Executor::from(&mut client)).await?;
+    if options.create_schema {
+        create_schema_if_needed(Executor::from(&mut client), options).await?;
+    }
+    init_upgraders_table(&mut client, options).await
+}
+
+/// Asynchronously reads the highest sequence number recorded in the upgraders table.
+///
+/// Every applied upgrader is given the next number in a single global sequence starting at 1,
+/// which makes "is the database at least at version N" checks simple. Returns `None` if no
+/// upgrader has been applied yet.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if connecting to or querying the database fails.
+#[cfg(feature = "tokio-postgres")]
+pub async fn current_sequence_async(
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<Option<i64>, UpgraderError> {
+    use crate::db_tracker::async_tracker::{Executor, current_sequence};
+    let mut client = connect(connection_string, options).await?;
+    current_sequence(Executor::from(&mut client), options).await
+}
+
+/// Asynchronously reports which host applied the most recent upgrader.
+///
+/// Useful when a deploy goes wrong and several instances may have run the upgrade. Upgraders
+/// recorded before hosts were tracked are reported as `"unknown"`. Returns `None` if no
+/// upgrader has been applied yet.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if connecting to or querying the database fails.
+#[cfg(feature = "tokio-postgres")]
+pub async fn last_applier_async(
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<Option<String>, UpgraderError> {
+    use crate::db_tracker::async_tracker::{Executor, load_applied_upgraders, relation_exists};
+    let mut client = connect(connection_string, options).await?;
+    let table = crate::db_tracker::table_name(options);
+    if !relation_exists(Executor::from(&mut client), &table).await? {
+        return Ok(None);
+    }
+    let applied = load_applied_upgraders(Executor::from(&mut client), options).await?;
+    Ok(crate::db_tracker::last_applier(&applied))
+}
+
+/// Asynchronously writes the upgraders recorded in the database as upgrader files.
+///
+/// Meant for adopting this library on a database whose history was recorded by another
+/// tool: every file `NNN_<description>.sql` gets the recorded SQL under reconstructed
+/// `--- N: <description>` headers, so loading the folder and upgrading the database finds
+/// everything applied. `out_dir` is created if needed and must be empty. Returns the paths
+/// of the written files, in id order.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connecting to or querying the database fails.
+/// - The upgraders table does not exist, or an upgrader cannot be written so that it loads
+///   back identically, e.g. because of a gap in the ids.
+/// - `out_dir` is not empty or the files cannot be written.
+#[cfg(feature = "tokio-postgres")]
+pub async fn export_as_files_async(
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+    out_dir: impl AsRef<std::path::Path>,
+) -> Result<Vec<std::path::PathBuf>, UpgraderError> {
+    use crate::db_tracker::async_tracker::{
+        Executor, load_applied_upgraders_with_text, relation_exists,
     };
+    let mut client = connect(connection_string, options).await?;
+    let table = crate::db_tracker::table_name(options);
+    if !relation_exists(Executor::from(&mut client), &table).await? {
+        return Err(UpgraderError::ConfigurationError(format!(
+            "Upgraders table {} does not exist.",
+            table
+        )));
+    }
+    let applied = load_applied_upgraders_with_text(Executor::from(&mut client), options).await?;
+    crate::export::write_files(out_dir.as_ref(), &applied)
+}
+
+/// A check run by [`verify_from_scratch_async`] against the scratch schema, whose name it is
+/// given. An `Err` fails the verification with that message.
+pub type SchemaCheck<'a> = dyn Fn(&str) -> Result<(), String> + Sync + 'a;
+
+/// Asynchronously checks that the upgraders apply cleanly to an empty schema, for use in CI.
+///
+/// A uniquely named scratch schema is created and put first on the connection's
+/// `search_path`, and every upgrader is applied into it with `{{SCHEMA}}` substituted by its
+/// name; the schema and `create_schema` in `options` are replaced and `role_per_schema` is
+/// ignored. If all upgraders apply, `check` is called with the schema name, e.g. to compare a
+/// `pg_dump --schema` of it against the previous release. The scratch schema is dropped
+/// afterwards, whether verification succeeded or not, including when the returned future is
+/// dropped before it completes.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid.
+/// - Execution of a migration step fails.
+/// - `check` fails, as `UpgraderError::VerificationError`.
+/// - The scratch schema cannot be dropped.
+#[cfg(feature = "tokio-postgres")]
+pub async fn verify_from_scratch_async(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+    check: Option<&SchemaCheck<'_>>,
+) -> Result<(), UpgraderError> {
+    let schema = crate::db_tracker::scratch_schema_name();
+    let mut scratch_options = options.clone();
+    scratch_options.schema = Some(schema.clone());
+    scratch_options.tracking_schema = None;
+    scratch_options.create_schema = true;
+    scratch_options.role_per_schema = false;
+
+    let set = MigrationSet::load(upgraders_folder, &scratch_options)?;
+    let client = connect(connection_string, &scratch_options).await?;
+    let mut scratch = ScratchSchema {
+        cancel_token: client.cancel_token(),
+        client: Some(client),
+        schema: scratch_options.identifier(&schema),
+        connection_string: connection_string.to_string(),
+        options: scratch_options,
+    };
+
+    let result = async {
+        let client = scratch
+            .client
+            .as_mut()
+            .expect("client is present until dropped");
+        client
+            .batch_execute(&format!("SET search_path TO {};", scratch.schema))
+            .await
+            .map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to set search_path: {}", e))
+            })?;
+        run_upgrade(client, &set).await?;
+        if let Some(check) = check {
+            check(&schema).map_err(UpgraderError::VerificationError)?;
+        }
+        Ok(())
+    }
+    .await;
+
+    let cleanup = scratch.drop_schema().await;
+    result.and(cleanup)
+}
+
+/// The scratch schema of [`verify_from_scratch_async`], dropped along with this value.
+struct ScratchSchema {
+    client: Option<Client>,
+    cancel_token: CancelToken,
+    /// The schema name, ready for use in SQL.
+    schema: String,
+    /// The connection string, to send the cancel request with the same TLS choice.
+    connection_string: String,
+    options: PostgresUpgraderOptions,
+}
+
+impl ScratchSchema {
+    async fn drop_schema(mut self) -> Result<(), UpgraderError> {
+        let client = self.client.take().expect("client is present until dropped");
+        drop_scratch_schema(&client, &self.schema).await
+    }
+}
+
+impl Drop for ScratchSchema {
+    fn drop(&mut self) {
+        // Only reached without `drop_schema` when the verification future was dropped midway.
+        // Destructors cannot await, so the cleanup runs on the runtime in the background,
+        // after interrupting the upgrader that may still be running on the connection.
+        if let Some(client) = self.client.take()
+            && let Ok(runtime) = tokio::runtime::Handle::try_current()
+        {
+            let cancel_token = self.cancel_token.clone();
+            let schema = std::mem::take(&mut self.schema);
+            let connection_string = std::mem::take(&mut self.connection_string);
+            let options = self.options.clone();
+            runtime.spawn(async move {
+                let _ = cancel_query(&cancel_token, &connection_string, &options).await;
+                let _ = drop_scratch_schema(&client, &schema).await;
+            });
+        }
+    }
+}
+
+async fn drop_scratch_schema(client: &Client, schema: &str) -> Result<(), UpgraderError> {
+    client
+        .batch_execute(&format!("DROP SCHEMA IF EXISTS {} CASCADE;", schema))
+        .await
+        .map_err(|e| UpgraderError::ExecutionError(format!("Failed to drop scratch schema: {}", e)))
+}
+
+/// Cancel tokens of the connections opened by an upgrade, for
+/// [`upgrade_async_with_shutdown`] to interrupt them.
+type CancelTokens = std::sync::Mutex<Vec<CancelToken>>;
 
+fn register_cancel_token(cancel_tokens: Option<&CancelTokens>, client: &Client) {
+    if let Some(cancel_tokens) = cancel_tokens {
+        cancel_tokens.lock().unwrap().push(client.cancel_token());
+    }
+}
+
+/// Aborts the parallel cold start workers if it is dropped before they finish, e.g. on
+/// shutdown; aborting a finished worker does nothing.
+struct AbortOnDrop(Vec<tokio::task::AbortHandle>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for worker in &self.0 {
+            worker.abort();
+        }
+    }
+}
+
+async fn apply(
+    connection_string: &str,
+    set: &MigrationSet,
+    cancel_tokens: Option<&CancelTokens>,
+) -> Result<UpgradeReport, FlowError> {
+    let mut applied = Vec::new();
+    if set.options.parallel_files() > 1
+        && !set.options.single_transaction
+        && !set.options.dry_run
+        && set.options.target.is_none()
+        && set.has_independent_files()
+    {
+        applied = run_parallel_cold_start(connection_string, set, cancel_tokens).await?;
+    }
+    let mut client = connect(connection_string, &set.options).await?;
+    register_cancel_token(cancel_tokens, &client);
+    let mut report = run_upgrade(&mut client, set).await?;
+    // The upgrade only analyzes after applying upgraders itself.
+    if set.options.run_analyze_after && report.applied.is_empty() && !applied.is_empty() {
+        use crate::db_tracker::async_tracker::{Executor, analyze};
+        analyze(Executor::from(&mut client), &set.options).await?;
+    }
+    report.already_applied = report.already_applied.saturating_sub(applied.len());
+    applied.append(&mut report.applied);
+    report.applied = applied;
+    Ok(report)
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "upgrade", skip_all, fields(schema = set.options.schema.as_deref()))
+)]
+async fn run_upgrade(client: &mut Client, set: &MigrationSet) -> Result<UpgradeReport, FlowError> {
+    let options = &set.options;
     run_upgrade_flow!(
         client,
         options,
-        upgraders_folder,
+        &set.upgraders,
+        crate::db_tracker::async_tracker,
+        do_await
+    )
+}
+
+async fn run_baseline(
+    client: &mut Client,
+    set: &MigrationSet,
+    up_to: (i32, i32),
+) -> Result<(), UpgraderError> {
+    let options = &set.options;
+    run_baseline_flow!(
+        client,
+        options,
+        &set.upgraders,
+        up_to,
+        crate::db_tracker::async_tracker,
+        do_await
+    )
+}
+
+async fn run_repair(client: &mut Client, set: &MigrationSet) -> Result<usize, UpgraderError> {
+    let options = &set.options;
+    run_repair_flow!(
+        client,
+        options,
+        &set.upgraders,
+        crate::db_tracker::async_tracker,
+        do_await
+    )
+}
+
+async fn run_downgrade(
+    client: &mut Client,
+    set: &MigrationSet,
+    target: (i32, i32),
+) -> Result<(), UpgraderError> {
+    let options = &set.options;
+    run_downgrade_flow!(
+        client,
+        options,
+        &set.upgraders,
+        target,
+        crate::db_tracker::async_tracker,
+        do_await
+    )
+}
+
+/// Applies the upgraders of a fresh database concurrently, see
+/// [`parallel_cold_start`](crate::PostgresUpgraderOptionsBuilder::parallel_cold_start).
+///
+/// Returns the upgraders it applied, as in [`UpgradeReport::applied`]. That is not all of
+/// them if the database is not fresh or an upgrader fails; the sequential upgrade that
+/// follows picks up from there.
+async fn run_parallel_cold_start(
+    connection_string: &str,
+    set: &MigrationSet,
+    cancel_tokens: Option<&CancelTokens>,
+) -> Result<Vec<(i32, i32, String)>, UpgraderError> {
+    let options = &set.options;
+    let mut client = connect(connection_string, options).await?;
+    register_cancel_token(cancel_tokens, &client);
+    let mut applied = Vec::new();
+    if !is_cold_start(&mut client, set).await? {
+        return Ok(applied);
+    }
+
+    for chunk in set.files().chunks(options.parallel_files()) {
+        let mut clients = Vec::with_capacity(chunk.len());
+        for _ in chunk {
+            let client = connect(connection_string, options).await?;
+            register_cancel_token(cancel_tokens, &client);
+            clients.push(client);
+        }
+
+        let mut steps = Vec::with_capacity(chunk.len());
+        let mut previous: Option<tokio::sync::oneshot::Receiver<()>> = None;
+        for (file, client) in chunk.iter().zip(clients) {
+            let mut turns = Vec::with_capacity(file.len());
+            for _ in file.iter() {
+                let (done, next) = tokio::sync::oneshot::channel();
+                turns.push((previous.replace(next), done));
+            }
+            let file = file.to_vec();
+            let options = options.clone();
+            steps.push(tokio::spawn(async move {
+                apply_parallel_file(client, &options, &file, turns).await
+            }));
+        }
+
+        let _workers = AbortOnDrop(steps.iter().map(|step| step.abort_handle()).collect());
+        let applied_before = applied.len();
+        let mut all_applied = true;
+        for (file, step) in chunk.iter().zip(steps) {
+            // A panicking worker is not a failed step to fall back from.
+            let count = match step.await {
+                Ok(count) => count,
+                Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                Err(_) => 0,
+            };
+            applied.extend(
+                file[..count]
+                    .iter()
+                    .map(|u| (u.file_id, u.upgrader_id, u.description.clone())),
+            );
+            all_applied &= count == file.len();
+        }
+        // A cold start has every upgrader pending.
+        if applied.len() > applied_before {
+            report_progress!(options, applied.len(), set.upgraders.len());
+        }
+        if !all_applied {
+            return Ok(applied);
+        }
+    }
+    Ok(applied)
+}
+
+/// Applies the upgraders of one file in order, each after the turn of the one before it in
+/// id order, and returns how many were applied. It stops at the first failure, so that
+/// turns of the later upgraders fail and they are rolled back as well.
+async fn apply_parallel_file(
+    mut client: Client,
+    options: &PostgresUpgraderOptions,
+    file: &[crate::schema_loader::SchemaUpgrader],
+    turns: Vec<(
+        Option<tokio::sync::oneshot::Receiver<()>>,
+        tokio::sync::oneshot::Sender<()>,
+    )>,
+) -> usize {
+    let mut count = 0;
+    for (upgrader, (turn, done)) in file.iter().zip(turns) {
+        if let Err(_e) = apply_parallel_step(&mut client, options, upgrader, turn).await {
+            trace_event!(
+                warn,
+                file_id = upgrader.file_id,
+                upgrader_id = upgrader.upgrader_id,
+                error = %_e,
+                "Parallel cold start step failed; continuing sequentially"
+            );
+            break;
+        }
+        let _ = done.send(());
+        count += 1;
+    }
+    count
+}
+
+async fn is_cold_start(client: &mut Client, set: &MigrationSet) -> Result<bool, UpgraderError> {
+    prepare_parallel_cold_start!(client, set, crate::db_tracker::async_tracker, do_await)
+}
+
+async fn apply_parallel_step(
+    client: &mut Client,
+    options: &PostgresUpgraderOptions,
+    upgrader: &crate::schema_loader::SchemaUpgrader,
+    turn: Option<tokio::sync::oneshot::Receiver<()>>,
+) -> Result<(), UpgraderError> {
+    run_parallel_step!(
+        client,
+        options,
+        upgrader,
         crate::db_tracker::async_tracker,
         do_await,
-        &
+        match turn {
+            Some(previous_done) => previous_done.await.is_ok(),
+            None => true,
+        }
     )
 }
+
+async fn run_plan(client: &mut Client, set: &MigrationSet) -> Result<UpgradePlan, UpgraderError> {
+    run_plan_flow!(client, set, crate::db_tracker::async_tracker, do_await)
+}
+
+async fn run_status(
+    client: &mut Client,
+    set: &MigrationSet,
+) -> Result<MigrationStatus, UpgraderError> {
+    run_status_flow!(client, set, crate::db_tracker::async_tracker, do_await)
+}
+
+async fn connect(
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<Client, UpgraderError> {
+    retry_connect!(
+        try_connect(connection_string, options),
+        options,
+        tokio::time::sleep,
+        do_await
+    )
+}
+
+/// Makes one connection attempt. The outer error is a configuration error, the inner one a
+/// failure to connect.
+async fn try_connect(
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<Result<Client, tokio_postgres::Error>, UpgraderError> {
+    #[cfg(feature = "tls")]
+    let result = match crate::tls::ssl_mode_for(connection_string, options) {
+        SslMode::Disable => connect_with(connection_string, NoTls, options).await,
+        SslMode::Prefer => {
+            let tls = crate::tls::create_tls_config(options)?;
+            match connect_with(connection_string, tls, options).await {
+                // Errors reported by the server, e.g. a wrong password, would not be fixed by
+                // dropping TLS.
+                Err(e) if e.as_db_error().is_none() => {
+                    connect_with(connection_string, NoTls, options).await
+                }
+                result => result,
+            }
+        }
+        SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => {
+            let tls = crate::tls::create_tls_config(options)?;
+            connect_with(connection_string, tls, options).await
+        }
+    };
+
+    #[cfg(not(feature = "tls"))]
+    let result = connect_with(connection_string, NoTls, options).await;
+
+    Ok(result)
+}
+
+/// Connects and drives the connection in the background.
+async fn connect_with<T>(
+    connection_string: &str,
+    tls: T,
+    options: &PostgresUpgraderOptions,
+) -> Result<Client, tokio_postgres::Error>
+where
+    T: tokio_postgres::tls::MakeTlsConnect<tokio_postgres::Socket>,
+    T::Stream: Send + 'static,
+{
+    let (client, connection) = tokio_postgres::connect(connection_string, tls).await?;
+
+    #[cfg(feature = "tracing")]
+    let schema = options.schema.clone();
+    #[cfg(not(feature = "tracing"))]
+    let _ = options;
+    tokio::spawn(async move {
+        if let Err(_e) = connection.await {
+            // The client also sees the error on its next query
+            trace_event!(
+                error,
+                schema = schema.as_deref(),
+                error = %_e,
+                "Database connection failed"
+            );
+        }
+    });
+    Ok(client)
+}
+
+async fn cancel_query(
+    cancel_token: &CancelToken,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<(), UpgraderError> {
+    #[cfg(feature = "tls")]
+    let result = match crate::tls::ssl_mode_for(connection_string, options) {
+        SslMode::Disable => cancel_token.cancel_query(NoTls).await,
+        SslMode::Prefer => {
+            match cancel_token
+                .cancel_query(crate::tls::create_tls_config(options)?)
+                .await
+            {
+                Err(e) if e.as_db_error().is_none() => cancel_token.cancel_query(NoTls).await,
+                result => result,
+            }
+        }
+        SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => {
+            cancel_token
+                .cancel_query(crate::tls::create_tls_config(options)?)
+                .await
+        }
+    };
+
+    #[cfg(not(feature = "tls"))]
+    let result = {
+        let _ = (connection_string, options);
+        cancel_token.cancel_query(NoTls).await
+    };
+
+    result.map_err(|e| UpgraderError::ConnectionError(format!("Failed to cancel query: {}", e)))
+}
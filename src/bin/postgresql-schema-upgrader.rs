@@ -1,21 +1,107 @@
 use anyhow::{Context, Result};
-use clap::{Args, Parser, Subcommand};
-use postgresql_schema_upgrader::{upgrade_async, PostgresUpgraderOptions, SslMode};
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use chrono::{DateTime, Utc};
+use postgresql_schema_upgrader::{
+    new_upgrader, status_async, upgrade_async_with_shutdown, AppliedUpgrader, IntegrityReport, MaintenanceWindow, MigrationSet,
+    MigrationStatus, MigrationInfo, PlanStep, PostgresUpgraderOptions, PostgresUpgraderOptionsBuilder,
+    UpgraderError, validate_folder,
+};
+#[cfg(feature = "tls")]
+use postgresql_schema_upgrader::SslMode;
+use postgresql_schema_upgrader::__private::is_unix_socket;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// Exit code of `status` when upgraders are pending, distinct from every failure.
+const EXIT_PENDING: i32 = 6;
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Exit codes:\n  0    success\n  1    other error\n  2    integrity violation\n  3    failing or timed out migration\n  4    connection error\n  5    invalid options or upgrader files\n  6    status: upgraders are pending\n  130  cancelled"
+)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// The JSON object printed with `--format json`.
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum JsonOutput {
+    Ok {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        applied: Option<Vec<JsonUpgrader>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pending: Option<Vec<JsonUpgrader>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        server_version: Option<String>,
+    },
+    Error {
+        kind: &'static str,
+        message: String,
+    },
+}
+
+#[derive(Serialize)]
+struct JsonUpgrader {
+    file_id: i32,
+    upgrader_id: i32,
+    description: String,
+}
+
+impl From<&MigrationInfo> for JsonUpgrader {
+    fn from(u: &MigrationInfo) -> Self {
+        JsonUpgrader { file_id: u.file_id, upgrader_id: u.upgrader_id, description: u.description.clone() }
+    }
+}
+
+impl From<&(i32, i32, String)> for JsonUpgrader {
+    fn from((file_id, upgrader_id, description): &(i32, i32, String)) -> Self {
+        JsonUpgrader { file_id: *file_id, upgrader_id: *upgrader_id, description: description.clone() }
+    }
+}
+
+impl JsonOutput {
+    fn error(e: &anyhow::Error) -> Self {
+        JsonOutput::Error { kind: error_kind(e), message: format!("{:#}", e) }
+    }
+}
+
+impl std::fmt::Display for JsonOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Upgrade the database schema
-    Upgrade(UpgradeArgs),
+    Upgrade(Box<UpgradeArgs>),
     /// Check the connection to the database
     CheckConnection(CheckConnectionArgs),
+    /// Validate the upgrade scripts without connecting to the database, listing each upgrader
+    /// with the SHA-256 of its SQL
+    Validate(ValidateArgs),
+    /// List the applied and pending upgraders; exits with 6 if any are pending
+    Status(StatusArgs),
+    /// Roll the database back by running the down blocks of the latest upgraders
+    Rollback(RollbackArgs),
+    /// Create an upgrader file with the next file id, or append an upgrader to the last file
+    New(NewArgs),
 }
 
 #[derive(Args)]
@@ -23,21 +109,185 @@ struct UpgradeArgs {
     #[command(flatten)]
     connection: ConnectionArgs,
 
-    /// Path to the directory containing upgrade scripts
-    #[arg(long, default_value = ".")]
-    path: PathBuf,
+    #[command(flatten)]
+    upgraders: UpgradersArgs,
 
-    /// Target schema (optional)
-    #[arg(long)]
-    schema: Option<String>,
+    /// Format of what is printed on stdout, including failures
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
 
     /// Create schema if it does not exist
     #[arg(long, default_value_t = false)]
     create_schema: bool,
 
+    /// Create the database if it does not exist, connecting to the postgres database to do so
+    #[arg(long, default_value_t = false)]
+    create_database: bool,
+
+    /// Print the operations the upgrade would perform, in order, without executing them
+    #[arg(long, default_value_t = false)]
+    print_plan: bool,
+
+    /// Check integrity and report what would be applied, without changing the database
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// With --dry-run, list the applied and pending upgraders as the status command does
+    #[arg(long, default_value_t = false, requires = "dry_run")]
+    verbose: bool,
+
+    /// Time zone to show applied_on in with --verbose, e.g. Europe/Berlin (stored times stay UTC)
+    #[cfg(feature = "timezone")]
+    #[arg(long, value_name = "TZ", default_value = "UTC", value_parser = parse_timezone)]
+    timezone: chrono_tz::Tz,
+
+    /// Apply upgraders missing from the database even if later ones are already applied
+    #[arg(long, default_value_t = false)]
+    allow_out_of_order: bool,
+
+    /// Record a SHA-256 checksum of each applied upgrader instead of its SQL
+    #[arg(long, default_value_t = false)]
+    store_checksums: bool,
+
+    /// Check the applied upgraders against checksums computed by the database, without reading their SQL
+    #[arg(long, default_value_t = false)]
+    compare_checksums: bool,
+
+    /// Apply all pending upgraders in one transaction, so that a failure leaves nothing applied
+    #[arg(long, default_value_t = false)]
+    single_transaction: bool,
+
+    /// Execute upgraders one statement at a time, so that a failure names the failing statement
+    #[arg(long, default_value_t = false)]
+    debug_statements: bool,
+
+    /// Run ANALYZE on the schema's tables (or the whole database) after applying upgraders
+    #[arg(long, default_value_t = false)]
+    analyze: bool,
+
+    /// Apply pending upgraders without checking the recorded ones against the files
+    #[arg(long, default_value_t = false)]
+    skip_integrity_check: bool,
+
+    /// Retry connecting this many times while the server is not accepting connections yet
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    connect_retries: u32,
+
+    /// Seconds to wait between connection attempts with --connect-retries
+    #[arg(long, value_name = "SECONDS", default_value_t = 1)]
+    connect_retry_delay: u64,
+
+    /// Stop after this upgrader, leaving later ones pending (e.g. 3:0)
+    #[arg(long, value_name = "FILE:UPGRADER", value_parser = parse_target)]
+    target: Option<(i32, i32)>,
+
+    /// Skip the content check of a range of squashed upgraders (e.g. 0:0-2:3); repeatable
+    #[arg(long, value_name = "FROM-TO", value_parser = parse_frozen)]
+    frozen: Vec<RangeInclusive<(i32, i32)>>,
+
+    /// Who applies the upgrade (e.g. a user or service account), recorded with each upgrader
+    #[arg(long)]
+    applied_by: Option<String>,
+
+    /// Role to hand the upgraders table over to when the upgrade creates it
+    #[arg(long, value_name = "ROLE")]
+    tracking_table_owner: Option<String>,
+
+    /// Identifies this deploy (e.g. a release tag); a key that was already processed is skipped
+    #[arg(long)]
+    deploy_key: Option<String>,
+
+    /// Only apply destructive or locking upgraders within this daily window, in UTC (e.g. 22:00-02:00)
+    #[arg(long, value_name = "HH:MM-HH:MM")]
+    maintenance_window: Option<MaintenanceWindow>,
+
+    /// Apply destructive or locking upgraders even outside --maintenance-window
+    #[arg(long, default_value_t = false, requires = "maintenance_window")]
+    force: bool,
+
+    /// Also write the report printed on stdout to this file, creating parent directories
+    #[arg(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+/// Where the upgraders are loaded from and tracked, shared by the subcommands that load them
+/// and read the upgraders table.
+#[derive(Args)]
+struct UpgradersArgs {
+    /// TOML file with upgrade settings; flags given here take precedence over it
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Path to the directory containing upgrade scripts [default: .]
+    #[arg(long)]
+    path: Option<PathBuf>,
+
+    /// Target schema (optional)
+    #[arg(long)]
+    schema: Option<String>,
+
     /// Enable TLS (SSL)
     #[arg(long, default_value_t = false)]
     tls: bool,
+
+    /// Also load upgrader files from subdirectories of the upgraders folder
+    #[arg(long, default_value_t = false)]
+    recursive: bool,
+
+    /// Comma-separated file extensions loaded as upgrader files [default: sql,ddl]
+    #[arg(long, value_delimiter = ',')]
+    extensions: Option<Vec<String>>,
+
+    /// Advisory lock id serializing upgrades; all instances of the same app must use the same id
+    #[arg(long, value_name = "I64", allow_negative_numbers = true)]
+    lock_id: Option<i64>,
+}
+
+impl UpgradersArgs {
+    /// Reads `--config` and returns the upgraders folder, the file's settings with `tls` set
+    /// if either the file or `--tls` asks for it, and the options these flags and the file
+    /// set. The settings only `upgrade` uses are left for it to apply.
+    fn resolve(self) -> Result<(PathBuf, ConfigFile, PostgresUpgraderOptionsBuilder)> {
+        let mut config = self.config.as_deref().map(read_config).transpose()?.unwrap_or_default();
+        let path = self.path.or(config.path.take()).unwrap_or_else(|| PathBuf::from("."));
+        config.tls |= self.tls;
+
+        let mut options_builder = PostgresUpgraderOptions::builder().recursive(self.recursive);
+
+        if let Some(extensions) = self.extensions {
+            options_builder = options_builder.extensions(extensions);
+        }
+
+        if let Some(schema) = self.schema.or(config.schema.take()) {
+            options_builder = options_builder.schema(schema);
+        }
+
+        if let Some(table) = config.upgraders_table.take() {
+            options_builder = options_builder.upgraders_table(table);
+        }
+
+        if let Some(seconds) = config.lock_timeout {
+            options_builder = options_builder.lock_timeout(std::time::Duration::from_secs(seconds));
+        }
+
+        if let Some(seconds) = config.statement_timeout {
+            options_builder = options_builder.statement_timeout(std::time::Duration::from_secs(seconds));
+        }
+
+        if let Some(seconds) = config.lock_wait_timeout {
+            options_builder = options_builder.lock_wait_timeout(std::time::Duration::from_secs(seconds));
+        }
+
+        for (key, value) in std::mem::take(&mut config.variables) {
+            options_builder = options_builder.variable(key, value);
+        }
+
+        if let Some(lock_id) = self.lock_id {
+            options_builder = options_builder.advisory_lock_id(lock_id);
+        }
+
+        Ok((path, config, options_builder))
+    }
 }
 
 #[derive(Args)]
@@ -45,9 +295,84 @@ struct CheckConnectionArgs {
     #[command(flatten)]
     connection: ConnectionArgs,
 
+    /// Format of what is printed on stdout, including failures
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
     /// Enable TLS (SSL)
     #[arg(long, default_value_t = false)]
     tls: bool,
+
+    /// Seconds to wait for the connection before giving up
+    #[arg(long, value_name = "SECS", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: u64,
+}
+
+#[derive(Args)]
+struct ValidateArgs {
+    /// Path to the directory containing upgrade scripts
+    #[arg(long, default_value = ".")]
+    path: PathBuf,
+
+    /// Warn about upgraders with identical SQL content (likely copy-paste mistakes)
+    #[arg(long, default_value_t = false)]
+    detect_duplicates: bool,
+}
+
+#[derive(Args)]
+struct NewArgs {
+    /// Path to the directory containing upgrade scripts
+    #[arg(long, default_value = ".")]
+    path: PathBuf,
+
+    /// Description of the new upgrader, also used for the file name
+    #[arg(long)]
+    description: String,
+
+    /// Add the upgrader to the last file instead of creating a new one
+    #[arg(long, default_value_t = false)]
+    append: bool,
+}
+
+#[derive(Args)]
+struct RollbackArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    #[command(flatten)]
+    upgraders: UpgradersArgs,
+
+    /// Undo every upgrader after this one, which stays applied (e.g. 2:0)
+    #[arg(
+        long,
+        value_name = "FILE:UPGRADER",
+        value_parser = parse_target,
+        required_unless_present = "steps",
+        conflicts_with = "steps"
+    )]
+    to: Option<(i32, i32)>,
+
+    /// Undo this many of the most recently applied upgraders instead of using --to
+    #[arg(long, value_name = "N")]
+    steps: Option<usize>,
+}
+
+#[derive(Args)]
+struct StatusArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    #[command(flatten)]
+    upgraders: UpgradersArgs,
+
+    /// Only print the number of pending upgraders
+    #[arg(long, default_value_t = false)]
+    pending_only: bool,
+
+    /// Time zone to show applied_on in, e.g. Europe/Berlin (stored times stay UTC)
+    #[cfg(feature = "timezone")]
+    #[arg(long, value_name = "TZ", default_value = "UTC", value_parser = parse_timezone)]
+    timezone: chrono_tz::Tz,
 }
 
 #[derive(Args)]
@@ -56,70 +381,582 @@ struct ConnectionArgs {
     #[arg(
         long,
         env = "DATABASE_URL",
-        conflicts_with_all = ["host", "port", "user", "password", "database"]
+        conflicts_with_all = ["host", "port", "user", "password", "password_file", "database"]
     )]
     connection_string: Option<String>,
 
     #[arg(long, required_unless_present = "connection_string")]
+    #[cfg_attr(feature = "unix-socket", arg(required_unless_present = "socket_dir"))]
     host: Option<String>,
 
+    /// Directory of the server's Unix domain socket (e.g. /var/run/postgresql), instead of --host
+    #[cfg(feature = "unix-socket")]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["host", "connection_string"])]
+    socket_dir: Option<PathBuf>,
+
     #[arg(long, default_value = "5432")]
     port: u16,
 
     #[arg(long, required_unless_present = "connection_string")]
     user: Option<String>,
 
-    #[arg(long, env = "PGPASSWORD")]
+    /// Password [default: $PGPASSWORD]
+    #[arg(long)]
     password: Option<String>,
 
+    /// File whose first line is the password, which keeps it out of process listings
+    #[arg(long, value_name = "PATH", conflicts_with = "password")]
+    password_file: Option<PathBuf>,
+
     #[arg(long, required_unless_present = "connection_string")]
     database: Option<String>,
 }
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
+async fn main() {
     dotenvy::dotenv().ok();
     let cli = Cli::parse();
 
+    let format = match &cli.command {
+        Commands::Upgrade(args) => args.format,
+        Commands::CheckConnection(args) => args.format,
+        _ => OutputFormat::Text,
+    };
+    let json = format == OutputFormat::Json;
+    if let Err(e) = run(cli).await {
+        if json {
+            println!("{}", JsonOutput::error(&e));
+        } else {
+            print_error(&e);
+        }
+        std::process::exit(exit_code(&e));
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
     match cli.command {
         Commands::Upgrade(args) => {
             let connection_string = build_connection_string(&args.connection)?;
+            let (path, config, options_builder) = args.upgraders.resolve()?;
+
+            let mut options_builder = options_builder
+                .create_schema(args.create_schema || config.create_schema)
+                .allow_out_of_order(args.allow_out_of_order)
+                .store_checksums(args.store_checksums)
+                .compare_checksums(args.compare_checksums)
+                .single_transaction(args.single_transaction)
+                .debug_statements(args.debug_statements)
+                .run_analyze_after(args.analyze)
+                .verify_integrity(!args.skip_integrity_check)
+                .connect_retries(args.connect_retries)
+                .connect_retry_delay(std::time::Duration::from_secs(args.connect_retry_delay))
+                .ignore_maintenance_window(args.force);
             
-            let mut options_builder = PostgresUpgraderOptions::builder()
-                .create_schema(args.create_schema);
-            
-            if let Some(schema) = args.schema {
-                options_builder = options_builder.schema(schema);
+            if let Some(target) = args.target {
+                options_builder = options_builder.target(target);
             }
 
-            if args.tls {
-                #[cfg(feature = "tls")]
-                {
-                    options_builder = options_builder.ssl_mode(SslMode::Require);
-                }
-                #[cfg(not(feature = "tls"))]
-                {
-                    return Err(anyhow::anyhow!("TLS requested but 'tls' feature is not enabled"));
+            for range in args.frozen {
+                options_builder = options_builder.frozen_upgraders(range);
+            }
+
+            if let Some(applied_by) = args.applied_by {
+                options_builder = options_builder.applied_by(applied_by);
+            }
+
+            if let Some(owner) = args.tracking_table_owner {
+                options_builder = options_builder.tracking_table_owner(owner);
+            }
+
+            if let Some(window) = args.maintenance_window {
+                options_builder = options_builder.maintenance_window(window);
+            }
+
+            if let Some(deploy_key) = &args.deploy_key {
+                options_builder = options_builder.deploy_key(deploy_key.clone());
+            }
+
+            let tls = tls_for(&connection_string, config.tls);
+            let options = with_tls(options_builder, tls)?.build();
+            // Opened before connecting so an unwritable path fails before anything is applied.
+            let mut output = args.output.as_deref().map(open_output).transpose()?;
+
+            if args.create_database && !args.print_plan && !args.dry_run {
+                let created = create_database_if_missing(&connection_string, tls).await?;
+                if let Some(database) = created.filter(|_| args.format == OutputFormat::Text) {
+                    println!("Created database {}.", database);
                 }
-            } else {
-                 #[cfg(feature = "tls")]
-                {
-                    options_builder = options_builder.ssl_mode(SslMode::Disable);
+            }
+
+            if args.format == OutputFormat::Json {
+                let result = upgrade_json(&path, args.print_plan, args.dry_run, &connection_string, &options).await;
+                let text = match &result {
+                    Ok(json) => json.to_string(),
+                    Err(e) => JsonOutput::error(e).to_string(),
+                };
+                write_output(&mut output, &format!("{}\n", text))?;
+                println!("{}", result?);
+                return Ok(());
+            }
+
+            if args.print_plan {
+                let set = MigrationSet::load(&path, &options)?;
+                let plan = set.plan_async(&connection_string).await?;
+                report(&mut output, &format!("Upgrade plan:\n{}", plan))?;
+                return Ok(());
+            }
+
+            if args.dry_run {
+                let set = MigrationSet::load(&path, &options)?;
+                let plan = set.plan_async(&connection_string).await?;
+                let mut text = String::new();
+                if args.verbose {
+                    // Listed exactly as by `status`; the plan above already verified integrity.
+                    let status = set.status_async(&connection_string).await?;
+                    #[cfg(feature = "timezone")]
+                    text.push_str(&format_status(&status, |at| format_applied_on(at, args.timezone)));
+                    #[cfg(not(feature = "timezone"))]
+                    text.push_str(&format_status(&status, |at| at.format("%Y-%m-%d %H:%M:%S UTC").to_string()));
                 }
+                text.push_str(&format!(
+                    "Dry run: {} upgraders applied, {} pending.\n",
+                    plan.applied.len(),
+                    plan.pending_upgraders()
+                ));
+                report(&mut output, &text)?;
+                return Ok(());
             }
 
-            let options = options_builder.build();
+            if args.deploy_key.is_some() {
+                let set = MigrationSet::load(&path, &options)?;
+                if set.plan_async(&connection_string).await?.is_empty() {
+                    report(&mut output, "deploy key already processed\n")?;
+                    return Ok(());
+                }
+            }
 
             println!("Starting schema upgrade...");
-            upgrade_async(args.path, &connection_string, &options).await?;
-            println!("Schema upgrade completed successfully.");
+            match upgrade_async_with_shutdown(
+                &path,
+                &connection_string,
+                &options,
+                shutdown_signal(),
+            )
+            .await
+            {
+                Ok(upgrade) => report(
+                    &mut output,
+                    &format!(
+                        "Schema upgrade completed successfully: {} upgraders applied, {} already applied.\n",
+                        upgrade.applied.len(),
+                        upgrade.already_applied
+                    ),
+                )?,
+                Err(UpgraderError::Cancelled { last_committed }) => {
+                    let message = match last_committed {
+                        Some((file_id, upgrader_id)) => format!(
+                            "cancelled; last committed migration was {}:{}\n",
+                            file_id, upgrader_id
+                        ),
+                        None => "cancelled; no migration has been committed\n".to_string(),
+                    };
+                    eprint!("{}", message);
+                    write_output(&mut output, &message)?;
+                    std::process::exit(130);
+                }
+                Err(e) => {
+                    write_output(&mut output, &format!("Schema upgrade failed: {}\n", e))?;
+                    if let UpgraderError::IntegrityError(_) = e {
+                        // The error only carries the message; status locates the violation.
+                        let status = MigrationSet::load(&path, &options)?.status_async(&connection_string).await;
+                        if let Some(drift) = status.ok().and_then(|status| status.drift) {
+                            return Err(anyhow::Error::new(e).context(Divergence(drift)));
+                        }
+                    }
+                    return Err(e.into());
+                }
+            }
         }
         Commands::CheckConnection(args) => {
             let connection_string = build_connection_string(&args.connection)?;
-            check_connection(&connection_string, args.tls).await?;
+            let tls = tls_for(&connection_string, args.tls);
+            let server_version = check_connection(&connection_string, tls, args.timeout, args.format).await?;
+            if args.format == OutputFormat::Json {
+                println!("{}", JsonOutput::Ok { applied: None, pending: None, server_version: Some(server_version) });
+            }
+        }
+        Commands::Status(args) => {
+            let connection_string = build_connection_string(&args.connection)?;
+
+            let (path, config, options_builder) = args.upgraders.resolve()?;
+            let options = with_tls(options_builder, tls_for(&connection_string, config.tls))?.build();
+
+            let status = status_async(&path, &connection_string, &options).await?;
+            if args.pending_only {
+                println!("{}", status.pending.len());
+            } else {
+                #[cfg(feature = "timezone")]
+                let text = format_status(&status, |at| format_applied_on(at, args.timezone));
+                #[cfg(not(feature = "timezone"))]
+                let text = format_status(&status, |at| at.format("%Y-%m-%d %H:%M:%S UTC").to_string());
+                print!("{}", text);
+            }
+
+            if let Some(drift) = &status.drift {
+                eprintln!("Integrity violation: {}", drift.message);
+                std::process::exit(2);
+            }
+            if !status.pending.is_empty() {
+                std::process::exit(EXIT_PENDING);
+            }
+        }
+        Commands::Rollback(args) => {
+            let connection_string = build_connection_string(&args.connection)?;
+
+            let (path, config, options_builder) = args.upgraders.resolve()?;
+            let options = with_tls(options_builder, tls_for(&connection_string, config.tls))?.build();
+
+            let set = MigrationSet::load(&path, &options)?;
+            let before = set.status_async(&connection_string).await?;
+            let target = match args.to {
+                Some(target) => target,
+                None => steps_target(&before.applied, args.steps.unwrap_or(0))?,
+            };
+
+            // Fails without undoing anything if an upgrader to undo has no down block.
+            set.downgrade_async(&connection_string, target).await?;
+
+            let after = set.status_async(&connection_string).await?;
+            let reverted: Vec<_> = before
+                .applied
+                .iter()
+                .rev()
+                .filter(|u| !after.applied.iter().any(|a| (a.file_id, a.upgrader_id) == (u.file_id, u.upgrader_id)))
+                .collect();
+            for u in &reverted {
+                println!("Reverted {}:{} {}", u.file_id, u.upgrader_id, u.description);
+            }
+            println!("Rolled back to {}:{}; {} upgraders reverted.", target.0, target.1, reverted.len());
+        }
+        Commands::New(args) => {
+            let (path, (file_id, upgrader_id)) = new_upgrader(&args.path, &args.description, args.append)?;
+            println!("Wrote upgrader {}:{} to {}.", file_id, upgrader_id, path.display());
+        }
+        Commands::Validate(args) => {
+            let migrations = validate_folder(&args.path)?;
+            for m in &migrations {
+                println!("{}:{} {} {}", m.file_id, m.upgrader_id, m.checksum(), m.description);
+            }
+            println!("Found {} valid upgraders in {}.", migrations.len(), args.path.display());
+
+            if args.detect_duplicates {
+                let set = MigrationSet::load(&args.path, &PostgresUpgraderOptions::default())?;
+                for duplicate in set.duplicate_upgraders() {
+                    eprintln!(
+                        "warning: upgraders {}:{} and {}:{} have identical SQL: {}",
+                        duplicate.first.0,
+                        duplicate.first.1,
+                        duplicate.second.0,
+                        duplicate.second.1,
+                        duplicate.snippet
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `upgrade` for `--format json`. The upgraders applied by this run are those pending
+/// before it and no longer pending after it.
+async fn upgrade_json(
+    path: &Path,
+    print_plan: bool,
+    dry_run: bool,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<JsonOutput> {
+    if print_plan {
+        return Err(UpgraderError::ConfigurationError("--print-plan has no JSON output; use --dry-run".to_string()).into());
+    }
+
+    let set = MigrationSet::load(path, options)?;
+    if dry_run {
+        let plan = set.plan_async(connection_string).await?;
+        let pending = plan
+            .steps
+            .iter()
+            .filter_map(|step| match step {
+                PlanStep::ApplyUpgrader { file_id, upgrader_id, description, .. } => Some(JsonUpgrader {
+                    file_id: *file_id,
+                    upgrader_id: *upgrader_id,
+                    description: description.clone(),
+                }),
+                _ => None,
+            })
+            .collect();
+        return Ok(JsonOutput::Ok { applied: Some(Vec::new()), pending: Some(pending), server_version: None });
+    }
+
+    let upgrade = upgrade_async_with_shutdown(path, connection_string, options, shutdown_signal()).await?;
+    // With --target, the upgraders after it are still pending.
+    let after = set.status_async(connection_string).await?;
+    Ok(JsonOutput::Ok {
+        applied: Some(upgrade.applied.iter().map(JsonUpgrader::from).collect()),
+        pending: Some(after.pending.iter().map(JsonUpgrader::from).collect()),
+        server_version: None,
+    })
+}
+
+/// Returns a short, stable name for the kind of `e`, for `--format json`.
+fn error_kind(e: &anyhow::Error) -> &'static str {
+    match e.downcast_ref::<UpgraderError>() {
+        Some(UpgraderError::ConnectionError(_)) => "connection",
+        Some(UpgraderError::ExecutionError(_)) => "execution",
+        Some(UpgraderError::ExecutionFailed { .. }) => "execution_failed",
+        Some(UpgraderError::ConfigurationError(_)) => "configuration",
+        Some(UpgraderError::LoaderError(_)) => "loader",
+        Some(UpgraderError::IntegrityError(_)) => "integrity",
+        Some(UpgraderError::VerificationError(_)) => "verification",
+        Some(UpgraderError::MaintenanceWindowError(_)) => "maintenance_window",
+        Some(UpgraderError::TimeoutError(_)) => "timeout",
+        Some(UpgraderError::LockTimeout(_)) => "lock_timeout",
+        Some(UpgraderError::Cancelled { .. }) => "cancelled",
+        None if e.downcast_ref::<tokio_postgres::Error>().is_some() => "connection",
+        None => "other",
+    }
+}
+
+/// Returns the exit code for `e`, so that scripts can tell e.g. drift from a failing migration.
+fn exit_code(e: &anyhow::Error) -> i32 {
+    match error_kind(e) {
+        "integrity" => 2,
+        "execution" | "execution_failed" | "timeout" | "lock_timeout" => 3,
+        "connection" => 4,
+        "configuration" | "loader" => 5,
+        "cancelled" => 130,
+        _ => 1,
+    }
+}
+
+/// The integrity violation behind an `IntegrityError` of `upgrade`, as found by `status`.
+#[derive(Debug)]
+struct Divergence(IntegrityReport);
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self.0.kind {
+            "text_changed" => "SQL content changed",
+            "description_changed" => "description changed",
+            "missing_from_files" => "upgrader is missing from the files",
+            "gap" => "upgrader is missing from the database, but later ones are applied",
+            "out_of_order" => "upgrader was applied before the one preceding it",
+            _ => &self.0.message,
+        };
+        write!(f, "Migration files diverge from database at {}:{}: {}", self.0.ids.0, self.0.ids.1, reason)
+    }
+}
+
+/// Returns the message printed for `e` and, where there is a usual fix, a hint.
+fn describe_error(e: &anyhow::Error) -> (String, Option<&'static str>) {
+    if let Some(divergence) = e.downcast_ref::<Divergence>() {
+        let hint = match divergence.0.kind {
+            "text_changed" | "description_changed" => {
+                Some("restore the upgrader as it was applied; put further changes in a new upgrader")
+            }
+            "missing_from_files" => Some("the database was upgraded by newer files; deploy those, or restore the deleted upgrader"),
+            "gap" => Some("pass --allow-out-of-order to apply the missing upgrader anyway"),
+            "out_of_order" => Some("this history was written with --allow-out-of-order; pass it again"),
+            _ => None,
+        };
+        return (divergence.to_string(), hint);
+    }
+    match e.downcast_ref::<UpgraderError>() {
+        Some(UpgraderError::ExecutionFailed { file_id, upgrader_id, statement: Some(statement), source }) => (
+            format!("Statement {} of migration {}:{} failed: {}", statement, file_id, upgrader_id, source),
+            None,
+        ),
+        Some(UpgraderError::ExecutionFailed { file_id, upgrader_id, statement: None, source }) => {
+            (format!("Migration {}:{} failed: {}", file_id, upgrader_id, source), None)
+        }
+        _ => (format!("{:#}", e), None),
+    }
+}
+
+/// Prints `e` on stderr; with the `color` feature in colour, unless `NO_COLOR` is set or stderr
+/// is not a terminal.
+fn print_error(e: &anyhow::Error) {
+    let (message, hint) = describe_error(e);
+    #[cfg(feature = "color")]
+    {
+        let error = anstyle::AnsiColor::Red.on_default().bold();
+        let note = anstyle::AnsiColor::Cyan.on_default().bold();
+        anstream::eprintln!("{error}error:{error:#} {}", message);
+        if let Some(hint) = hint {
+            anstream::eprintln!("{note}hint:{note:#} {}", hint);
+        }
+    }
+    #[cfg(not(feature = "color"))]
+    {
+        eprintln!("error: {}", message);
+        if let Some(hint) = hint {
+            eprintln!("hint: {}", hint);
+        }
+    }
+}
+
+/// Returns whether to use TLS as requested with `--tls`: not over a Unix domain socket, which it
+/// does not apply to.
+fn tls_for(connection_string: &str, tls: bool) -> bool {
+    if tls && is_unix_socket(connection_string) {
+        eprintln!("Warning: --tls does not apply to a Unix domain socket; connecting without TLS.");
+        return false;
+    }
+    tls
+}
+
+fn with_tls(options_builder: PostgresUpgraderOptionsBuilder, tls: bool) -> Result<PostgresUpgraderOptionsBuilder> {
+    if tls {
+        #[cfg(feature = "tls")]
+        {
+            Ok(options_builder.ssl_mode(SslMode::Require))
+        }
+        #[cfg(not(feature = "tls"))]
+        {
+            Err(anyhow::anyhow!("TLS requested but 'tls' feature is not enabled"))
+        }
+    } else {
+        #[cfg(feature = "tls")]
+        {
+            Ok(options_builder.ssl_mode(SslMode::Disable))
+        }
+        #[cfg(not(feature = "tls"))]
+        {
+            Ok(options_builder)
+        }
+    }
+}
+
+/// Renders the applied upgraders as a table, followed by the pending ones.
+fn format_status(status: &MigrationStatus, applied_on: impl Fn(DateTime<Utc>) -> String) -> String {
+    let rows: Vec<_> = status
+        .applied
+        .iter()
+        .map(|u| (u.file_id.to_string(), u.upgrader_id.to_string(), u.description.as_str(), applied_on(u.applied_on)))
+        .collect();
+    let file_width = rows.iter().map(|r| r.0.len()).chain(["FILE".len()]).max().unwrap_or(0);
+    let upgrader_width = rows.iter().map(|r| r.1.len()).chain(["UPGRADER".len()]).max().unwrap_or(0);
+    let description_width = rows.iter().map(|r| r.2.chars().count()).chain(["DESCRIPTION".len()]).max().unwrap_or(0);
+
+    let mut text = format!("Applied upgraders ({}):\n", rows.len());
+    if !rows.is_empty() {
+        text.push_str(&format!(
+            "{:<file_width$}  {:<upgrader_width$}  {:<description_width$}  APPLIED ON\n",
+            "FILE", "UPGRADER", "DESCRIPTION"
+        ));
+        for (file_id, upgrader_id, description, applied_on) in &rows {
+            text.push_str(&format!(
+                "{:<file_width$}  {:<upgrader_width$}  {:<description_width$}  {}\n",
+                file_id, upgrader_id, description, applied_on
+            ));
+        }
+    }
+
+    text.push_str(&format!("Pending upgraders ({}):\n", status.pending.len()));
+    for u in &status.pending {
+        text.push_str(&format!("  {}:{} {}\n", u.file_id, u.upgrader_id, u.description));
+    }
+    if !status.ahead.is_empty() {
+        text.push_str(&format!(
+            "The database has {} upgraders newer than these files.\n",
+            status.ahead.len()
+        ));
+    }
+    text
+}
+
+/// Returns the upgrader to roll back to so that the latest `steps` of `applied`, in id order,
+/// are undone.
+fn steps_target(applied: &[AppliedUpgrader], steps: usize) -> Result<(i32, i32)> {
+    if steps >= applied.len() {
+        anyhow::bail!(
+            "cannot roll back {} of {} applied upgraders; the first upgrader always stays applied",
+            steps,
+            applied.len()
+        );
+    }
+    let target = &applied[applied.len() - 1 - steps];
+    Ok((target.file_id, target.upgrader_id))
+}
+
+fn parse_target(value: &str) -> std::result::Result<(i32, i32), String> {
+    let invalid = || format!("invalid target '{}' (expected FILE:UPGRADER such as 3:0)", value);
+    let (file_id, upgrader_id) = value.split_once(':').ok_or_else(invalid)?;
+    Ok((
+        file_id.trim().parse().map_err(|_| invalid())?,
+        upgrader_id.trim().parse().map_err(|_| invalid())?,
+    ))
+}
+
+/// Parses an inclusive range of upgraders such as `0:0-2:3`; a single upgrader stands for itself.
+fn parse_frozen(value: &str) -> std::result::Result<RangeInclusive<(i32, i32)>, String> {
+    let (from, to) = value.split_once('-').unwrap_or((value, value));
+    let invalid = |_| format!("invalid range '{}' (expected FILE:UPGRADER-FILE:UPGRADER such as 0:0-2:3)", value);
+    Ok(parse_target(from).map_err(invalid)?..=parse_target(to).map_err(invalid)?)
+}
+
+#[cfg(feature = "timezone")]
+fn parse_timezone(name: &str) -> std::result::Result<chrono_tz::Tz, String> {
+    name.parse::<chrono_tz::Tz>()
+        .map_err(|_| format!("unknown time zone '{}' (expected an IANA name such as Europe/Berlin)", name))
+}
+
+/// Shows `at` in `timezone`; only the display changes, the stored value is UTC.
+#[cfg(feature = "timezone")]
+fn format_applied_on(at: DateTime<Utc>, timezone: chrono_tz::Tz) -> String {
+    at.with_timezone(&timezone).format("%Y-%m-%d %H:%M:%S %Z").to_string()
+}
+
+/// Resolves on the first Ctrl-C so the running upgrade can be cancelled and rolled back.
+/// A second Ctrl-C exits immediately.
+async fn shutdown_signal() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        // No signal handler could be installed, so never request a shutdown.
+        std::future::pending::<()>().await;
+    }
+    eprintln!("Interrupted; cancelling the running migration (press Ctrl-C again to force exit)...");
+
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Forced exit.");
+            std::process::exit(130);
         }
+    });
+}
+
+/// Creates (or truncates) the `--output` file, creating missing parent directories.
+fn open_output(path: &Path) -> Result<(PathBuf, File)> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Cannot create directory for --output {}", path.display()))?;
     }
+    let file = File::create(path)
+        .with_context(|| format!("Cannot write --output file {}", path.display()))?;
+    Ok((path.to_path_buf(), file))
+}
+
+/// Prints `text` on stdout and writes it to the `--output` file, if any.
+fn report(output: &mut Option<(PathBuf, File)>, text: &str) -> Result<()> {
+    print!("{}", text);
+    write_output(output, text)
+}
 
+fn write_output(output: &mut Option<(PathBuf, File)>, text: &str) -> Result<()> {
+    if let Some((path, file)) = output {
+        file.write_all(text.as_bytes())
+            .with_context(|| format!("Failed to write --output file {}", path.display()))?;
+    }
     Ok(())
 }
 
@@ -128,61 +965,547 @@ fn build_connection_string(args: &ConnectionArgs) -> Result<String> {
         return Ok(s.clone());
     }
 
+    #[cfg(feature = "unix-socket")]
+    let host = match &args.socket_dir {
+        Some(dir) => dir.to_str().context("socket directory must be valid UTF-8")?,
+        None => args.host.as_ref().context("host required")?,
+    };
+    #[cfg(not(feature = "unix-socket"))]
     let host = args.host.as_ref().context("host required")?;
     let user = args.user.as_ref().context("user required")?;
     let dbname = args.database.as_ref().context("database required")?;
     let port = args.port;
-    let password = args.password.as_deref().unwrap_or("");
+    let password = match &args.password_file {
+        Some(path) => read_password_file(path)?,
+        // Read here rather than through clap, so that an exported PGPASSWORD does not
+        // conflict with --password-file or --connection-string.
+        None => args
+            .password
+            .clone()
+            .or_else(|| std::env::var("PGPASSWORD").ok())
+            .unwrap_or_default(),
+    };
 
     Ok(format!(
         "host='{}' port={} user='{}' password='{}' dbname='{}'",
         escape(host),
         port,
         escape(user),
-        escape(password),
+        escape(&password),
         escape(dbname)
     ))
 }
 
+/// Settings read from `--config`. Flags take precedence: a value given on the command
+/// line replaces the file's, and a switch such as `--tls` cannot be turned off by the file.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    /// Relative to the directory of the config file.
+    path: Option<PathBuf>,
+    schema: Option<String>,
+    #[serde(default)]
+    create_schema: bool,
+    #[serde(default)]
+    tls: bool,
+    upgraders_table: Option<String>,
+    /// In seconds, like the timeouts below.
+    lock_timeout: Option<u64>,
+    statement_timeout: Option<u64>,
+    lock_wait_timeout: Option<u64>,
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+}
+
+/// Reads the TOML config file at `path`; unknown keys are rejected so that typos do not go
+/// unnoticed.
+fn read_config(path: &Path) -> Result<ConfigFile> {
+    let invalid = |message: String| UpgraderError::ConfigurationError(format!("--config {}: {}", path.display(), message));
+    let content = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    let mut config: ConfigFile = toml::from_str(&content).map_err(|e| invalid(e.message().to_string()))?;
+    if let Some(folder) = config.path.take() {
+        config.path = Some(path.parent().unwrap_or(Path::new("")).join(folder));
+    }
+    Ok(config)
+}
+
+/// Returns the first line of the file at `path`, without its line ending.
+fn read_password_file(path: &Path) -> Result<String> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read --password-file {}", path.display()))?;
+    Ok(content.lines().next().unwrap_or_default().to_string())
+}
+
 fn escape(s: &str) -> String {
     s.replace('\\', "\\\\").replace('\'', "\\'")
 }
 
-async fn check_connection(conn_string: &str, tls: bool) -> Result<()> {
-    println!("Checking connection...");
+/// Returns the name of the database `connection_string` connects to, which defaults to the
+/// user name, and the config connecting to the `postgres` maintenance database instead.
+fn maintenance_config(connection_string: &str) -> Result<(String, tokio_postgres::Config)> {
+    let mut config: tokio_postgres::Config = connection_string.parse().context("Invalid connection string")?;
+    let database = config
+        .get_dbname()
+        .or(config.get_user())
+        .context("The connection string names no database")?
+        .to_string();
+    config.dbname("postgres");
+    Ok((database, config))
+}
 
+/// Creates the database of `connection_string` if it does not exist yet, and returns its name
+/// if it was created.
+async fn create_database_if_missing(connection_string: &str, tls: bool) -> Result<Option<String>> {
+    let (database, config) = maintenance_config(connection_string)?;
+    let client = connect(&config, tls).await.context("Failed to connect to the postgres database")?;
+
+    let row = client
+        .query_one("SELECT EXISTS (SELECT 1 FROM pg_database WHERE datname = $1)", &[&database])
+        .await
+        .context("Failed to look up the database")?;
+    if row.get::<_, bool>(0) {
+        return Ok(None);
+    }
+    // PostgreSQL has no CREATE DATABASE IF NOT EXISTS, so a concurrent creation shows up as
+    // an error, which means the database is there.
+    let create_sql = format!("CREATE DATABASE \"{}\"", database.replace('"', "\"\""));
+    match client.batch_execute(&create_sql).await {
+        Ok(()) => Ok(Some(database)),
+        Err(e)
+            if e.code().is_some_and(|code| {
+                *code == tokio_postgres::error::SqlState::DUPLICATE_DATABASE
+                    || *code == tokio_postgres::error::SqlState::UNIQUE_VIOLATION
+            }) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to create database {}", database)),
+    }
+}
+
+/// Connects with `config`, spawning the connection task.
+async fn connect(config: &tokio_postgres::Config, tls: bool) -> Result<tokio_postgres::Client> {
     if tls {
         #[cfg(feature = "tls")]
         {
             use rustls::ClientConfig;
             let root_store = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-            let config = ClientConfig::builder()
+            let tls_config = ClientConfig::builder()
                 .with_root_certificates(root_store)
                 .with_no_client_auth();
-            let tls_connector = tokio_postgres_rustls::MakeRustlsConnect::new(config);
+            let tls_connector = tokio_postgres_rustls::MakeRustlsConnect::new(tls_config);
 
-            let (client, connection) = tokio_postgres::connect(conn_string, tls_connector).await.context("Failed to connect with TLS")?;
+            let (client, connection) = config.connect(tls_connector).await.context("Failed to connect with TLS")?;
             tokio::spawn(async move {
                 if let Err(e) = connection.await {
                     eprintln!("connection error: {}", e);
                 }
             });
-            client.simple_query("SELECT 1").await.context("Failed to execute query")?;
+            Ok(client)
         }
         #[cfg(not(feature = "tls"))]
         {
-            return Err(anyhow::anyhow!("TLS requested but 'tls' feature is not enabled"));
+            Err(anyhow::anyhow!("TLS requested but 'tls' feature is not enabled"))
         }
     } else {
-        let (client, connection) = tokio_postgres::connect(conn_string, tokio_postgres::NoTls).await.context("Failed to connect")?;
+        let (client, connection) = config.connect(tokio_postgres::NoTls).await.context("Failed to connect")?;
         tokio::spawn(async move {
             if let Err(e) = connection.await {
                 eprintln!("connection error: {}", e);
             }
         });
-        client.simple_query("SELECT 1").await.context("Failed to execute query")?;
+        Ok(client)
+    }
+}
+
+/// Connects within `timeout` seconds and returns the server version.
+async fn check_connection(conn_string: &str, tls: bool, timeout: u64, format: OutputFormat) -> Result<String> {
+    if format == OutputFormat::Text {
+        println!("Checking connection...");
     }
 
-    println!("Connection successful!");
+    let config: tokio_postgres::Config = conn_string.parse().context("Invalid connection string")?;
+    // Bounds the queries as well, since a server may accept the connection and then stall.
+    let check = async {
+        let client = connect(&config, tls).await?;
+        let row = client.query_one("SELECT version()", &[]).await.context("Failed to execute query")?;
+        let server_version: String = row.get(0);
+        warn_if_in_recovery(&client).await?;
+        Ok::<_, anyhow::Error>(server_version)
+    };
+    let server_version = tokio::time::timeout(std::time::Duration::from_secs(timeout), check)
+        .await
+        .map_err(|_| UpgraderError::ConnectionError(format!("connection timed out after {}s", timeout)))??;
+
+    if format == OutputFormat::Text {
+        println!("Connection successful!");
+        println!("Server version: {}", server_version);
+    }
+    Ok(server_version)
+}
+
+async fn warn_if_in_recovery(client: &tokio_postgres::Client) -> Result<()> {
+    let row = client.query_one("SELECT pg_is_in_recovery()", &[]).await.context("Failed to check recovery status")?;
+    if row.get::<_, bool>(0) {
+        eprintln!("Warning: the server is a read replica / in recovery; upgrades must target the primary.");
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_json_output() {
+        let ok = JsonOutput::Ok {
+            applied: Some(vec![JsonUpgrader { file_id: 0, upgrader_id: 1, description: "Add email".to_string() }]),
+            pending: Some(Vec::new()),
+            server_version: None,
+        };
+        assert_eq!(
+            ok.to_string(),
+            r#"{"status":"ok","applied":[{"file_id":0,"upgrader_id":1,"description":"Add email"}],"pending":[]}"#
+        );
+        assert_eq!(
+            JsonOutput::Ok { applied: None, pending: None, server_version: None }.to_string(),
+            r#"{"status":"ok"}"#
+        );
+        let connected = JsonOutput::Ok { applied: None, pending: None, server_version: Some("PostgreSQL 16.4".to_string()) };
+        assert_eq!(connected.to_string(), r#"{"status":"ok","server_version":"PostgreSQL 16.4"}"#);
+
+        let error = JsonOutput::error(&UpgraderError::IntegrityError("text changed".to_string()).into());
+        assert_eq!(
+            error.to_string(),
+            r#"{"status":"error","kind":"integrity","message":"Integrity error: text changed"}"#
+        );
+        let other = JsonOutput::error(&anyhow::anyhow!("host required"));
+        assert_eq!(other.to_string(), r#"{"status":"error","kind":"other","message":"host required"}"#);
+    }
+
+    #[test]
+    fn test_format_flag() {
+        let upgrade = ["postgresql-schema-upgrader", "upgrade", "--connection-string", "host=db", "--format", "json"];
+        let Commands::Upgrade(args) = Cli::try_parse_from(upgrade).unwrap().command else { panic!("expected upgrade") };
+        assert!(args.format == OutputFormat::Json);
+
+        // Only upgrade and check-connection print JSON, so the others reject the flag.
+        let status = ["postgresql-schema-upgrader", "status", "--connection-string", "host=db", "--format", "json"];
+        let kind = Cli::try_parse_from(status).err().map(|e| e.kind());
+        assert_eq!(kind, Some(clap::error::ErrorKind::UnknownArgument));
+    }
+
+    #[test]
+    fn test_describe_error() {
+        let failed: anyhow::Error = UpgraderError::ExecutionFailed {
+            file_id: 1,
+            upgrader_id: 0,
+            statement: None,
+            source: "ERROR: relation \"users\" does not exist".to_string(),
+        }
+        .into();
+        assert_eq!(describe_error(&failed).0, "Migration 1:0 failed: ERROR: relation \"users\" does not exist");
+        assert_eq!(exit_code(&failed), 3);
+        let statement_failed: anyhow::Error = UpgraderError::ExecutionFailed {
+            file_id: 1,
+            upgrader_id: 0,
+            statement: Some(3),
+            source: "ERROR: division by zero".to_string(),
+        }
+        .into();
+        assert_eq!(describe_error(&statement_failed).0, "Statement 3 of migration 1:0 failed: ERROR: division by zero");
+
+        let report = IntegrityReport {
+            kind: "text_changed",
+            ids: (0, 1),
+            message: "Upgrader 0:1. SQL content has changed.".to_string(),
+        };
+        let diverged = anyhow::Error::new(UpgraderError::IntegrityError(report.message.clone())).context(Divergence(report));
+        let (message, hint) = describe_error(&diverged);
+        assert_eq!(message, "Migration files diverge from database at 0:1: SQL content changed");
+        assert!(hint.is_some());
+        assert_eq!(exit_code(&diverged), 2);
+        assert!(JsonOutput::error(&diverged).to_string().contains(r#""kind":"integrity""#));
+
+        let other = anyhow::anyhow!("host required");
+        assert_eq!(describe_error(&other), ("host required".to_string(), None));
+        assert_eq!(exit_code(&other), 1);
+        assert_eq!(exit_code(&UpgraderError::Cancelled { last_committed: None }.into()), 130);
+    }
+
+    #[test]
+    fn test_steps_target() {
+        let applied: Vec<_> = [(0, 0), (0, 1), (1, 0)]
+            .into_iter()
+            .map(|(file_id, upgrader_id)| AppliedUpgrader {
+                file_id,
+                upgrader_id,
+                description: format!("Step {}:{}", file_id, upgrader_id),
+                text: "SELECT 1;".to_string(),
+                applied_on: Utc::now(),
+                sequence: None,
+                applied_host: None,
+                applied_by: None,
+                last_verified_on: None,
+                checksum: None,
+            })
+            .collect();
+        assert_eq!(steps_target(&applied, 0).unwrap(), (1, 0));
+        assert_eq!(steps_target(&applied, 2).unwrap(), (0, 0));
+        assert!(steps_target(&applied, 3).is_err());
+        assert!(steps_target(&[], 1).is_err());
+    }
+
+    #[test]
+    fn test_maintenance_config() {
+        let (database, config) = maintenance_config("host=localhost user=app dbname='my db'").unwrap();
+        assert_eq!(database, "my db");
+        assert_eq!(config.get_dbname(), Some("postgres"));
+        assert_eq!(config.get_user(), Some("app"));
+
+        let (database, config) = maintenance_config("postgres://app@localhost:5433").unwrap();
+        assert_eq!(database, "app");
+        assert_eq!(config.get_dbname(), Some("postgres"));
+        assert_eq!(config.get_ports(), [5433]);
+    }
+
+    #[test]
+    fn test_read_password_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("password");
+        fs::write(&path, "s3cr'et\r\nignored\n").unwrap();
+        assert_eq!(read_password_file(&path).unwrap(), "s3cr'et");
+
+        fs::write(&path, "").unwrap();
+        assert_eq!(read_password_file(&path).unwrap(), "");
+
+        let err = read_password_file(&dir.path().join("missing")).unwrap_err();
+        assert!(err.to_string().contains("Cannot read --password-file"));
+    }
+
+    #[test]
+    fn test_read_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("upgrader.toml");
+        fs::write(
+            &path,
+            r#"
+path = "upgraders"
+schema = "app"
+create_schema = true
+upgraders_table = "schema_upgraders"
+lock_timeout = 5
+
+[variables]
+TABLESPACE = "fast_ssd"
+"#,
+        )
+        .unwrap();
+        let config = read_config(&path).unwrap();
+        assert_eq!(
+            config,
+            ConfigFile {
+                path: Some(dir.path().join("upgraders")),
+                schema: Some("app".to_string()),
+                create_schema: true,
+                upgraders_table: Some("schema_upgraders".to_string()),
+                lock_timeout: Some(5),
+                variables: BTreeMap::from([("TABLESPACE".to_string(), "fast_ssd".to_string())]),
+                ..ConfigFile::default()
+            }
+        );
+
+        // An absolute path is kept as is.
+        fs::write(&path, "path = \"/srv/upgraders\"\n").unwrap();
+        assert_eq!(read_config(&path).unwrap().path, Some(PathBuf::from("/srv/upgraders")));
+
+        fs::write(&path, "shema = \"app\"\n").unwrap();
+        let err = read_config(&path).unwrap_err();
+        assert!(err.to_string().contains("unknown field `shema`"), "{}", err);
+        assert_eq!(exit_code(&err), 5);
+
+        let err = read_config(&dir.path().join("missing.toml")).unwrap_err();
+        assert!(err.to_string().contains("--config"), "{}", err);
+    }
+
+    #[test]
+    fn test_upgraders_args_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("upgrader.toml");
+        fs::write(&config_path, "path = \"upgraders\"\ntls = true\nupgraders_table = \"schema_upgraders\"\n").unwrap();
+        let config_arg = config_path.to_str().unwrap();
+
+        // status and rollback read the same upgraders and table as upgrade.
+        for command in [
+            vec!["status"],
+            vec!["rollback", "--steps", "1"],
+            vec!["upgrade"],
+        ] {
+            let args = ["postgresql-schema-upgrader"]
+                .into_iter()
+                .chain(command)
+                .chain(["--connection-string", "host=db", "--config", config_arg, "--recursive", "--extensions", "sql,pgsql"]);
+            let upgraders = match Cli::try_parse_from(args).unwrap().command {
+                Commands::Status(args) => args.upgraders,
+                Commands::Rollback(args) => args.upgraders,
+                Commands::Upgrade(args) => args.upgraders,
+                _ => panic!("unexpected subcommand"),
+            };
+            assert!(upgraders.recursive);
+            assert_eq!(upgraders.extensions.as_deref(), Some(&["sql".to_string(), "pgsql".to_string()][..]));
+            let (path, config, _) = upgraders.resolve().unwrap();
+            assert_eq!(path, dir.path().join("upgraders"));
+            assert!(config.tls);
+            // Applied to the options by resolve.
+            assert_eq!(config.upgraders_table, None);
+        }
+
+        let status = ["postgresql-schema-upgrader", "status", "--connection-string", "host=db", "--config", config_arg, "--path", "other"];
+        let Commands::Status(args) = Cli::try_parse_from(status).unwrap().command else { panic!("expected status") };
+        assert_eq!(args.upgraders.resolve().unwrap().0, PathBuf::from("other"));
+    }
+
+    #[tokio::test]
+    async fn test_check_connection_timeout() {
+        // The listener completes the TCP handshake but never answers the startup message.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let conn_string = format!("host=127.0.0.1 port={} user=postgres", port);
+
+        let e = check_connection(&conn_string, false, 1, OutputFormat::Json).await.unwrap_err();
+        assert_eq!(e.to_string(), "Connection error: connection timed out after 1s");
+        assert_eq!(exit_code(&e), 4);
+
+        let zero = ["postgresql-schema-upgrader", "check-connection", "--host", "db", "--user", "app", "--database", "app", "--timeout", "0"];
+        let kind = Cli::try_parse_from(zero).err().map(|e| e.kind());
+        assert_eq!(kind, Some(clap::error::ErrorKind::ValueValidation));
+    }
+
+    #[cfg(feature = "unix-socket")]
+    #[test]
+    fn test_socket_dir() {
+        let cli = Cli::try_parse_from([
+            "postgresql-schema-upgrader",
+            "check-connection",
+            "--socket-dir",
+            "/var/run/postgresql",
+            "--user",
+            "app",
+            "--password",
+            "pw",
+            "--database",
+            "app",
+            "--tls",
+        ])
+        .unwrap();
+        let Commands::CheckConnection(args) = cli.command else { panic!("expected check-connection") };
+        let connection_string = build_connection_string(&args.connection).unwrap();
+        assert_eq!(connection_string, "host='/var/run/postgresql' port=5432 user='app' password='pw' dbname='app'");
+        #[cfg(unix)]
+        assert!(!tls_for(&connection_string, args.tls));
+        assert!(tls_for("host=db.internal user=app", true));
+
+        let both = ["postgresql-schema-upgrader", "check-connection", "--socket-dir", "/tmp", "--host", "db"];
+        assert!(Cli::try_parse_from(both).is_err());
+        let with_url = ["postgresql-schema-upgrader", "check-connection", "--socket-dir", "/tmp", "--connection-string", "host=db"];
+        assert!(Cli::try_parse_from(with_url).is_err());
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(parse_target("3:0"), Ok((3, 0)));
+        assert_eq!(parse_target("12:4"), Ok((12, 4)));
+        assert!(parse_target("3").is_err());
+        assert!(parse_target("3:x").is_err());
+    }
+
+    #[test]
+    fn test_parse_frozen() {
+        assert_eq!(parse_frozen("0:0-2:3"), Ok((0, 0)..=(2, 3)));
+        assert_eq!(parse_frozen("1:4"), Ok((1, 4)..=(1, 4)));
+        assert!(parse_frozen("0:0-2").is_err());
+        assert!(parse_frozen("0-2").is_err());
+    }
+
+    #[test]
+    fn test_format_status() {
+        let status = MigrationStatus {
+            applied: vec![AppliedUpgrader {
+                file_id: 0,
+                upgrader_id: 0,
+                description: "Create users".to_string(),
+                text: "CREATE TABLE users (id INT);".to_string(),
+                applied_on: Utc.with_ymd_and_hms(2024, 7, 1, 12, 30, 0).unwrap(),
+                sequence: Some(1),
+                applied_host: None,
+                applied_by: None,
+                last_verified_on: None,
+                checksum: None,
+            }],
+            pending: vec![MigrationInfo {
+                file_id: 1,
+                upgrader_id: 0,
+                description: "Create orders".to_string(),
+                text: "CREATE TABLE orders (id INT);".to_string(),
+            }],
+            drift: None,
+            ahead: Vec::new(),
+        };
+        let text = format_status(&status, |at| at.format("%Y-%m-%d %H:%M").to_string());
+        assert_eq!(
+            text,
+            "Applied upgraders (1):\n\
+             FILE  UPGRADER  DESCRIPTION   APPLIED ON\n\
+             0     0         Create users  2024-07-01 12:30\n\
+             Pending upgraders (1):\n  \
+             1:0 Create orders\n"
+        );
+
+        let status = MigrationStatus { ahead: vec![(2, 0), (2, 1)], ..status };
+        let text = format_status(&status, |at| at.to_string());
+        assert!(text.ends_with("The database has 2 upgraders newer than these files.\n"));
+    }
+
+    #[cfg(feature = "timezone")]
+    #[test]
+    fn test_applied_on_timezone() {
+        let at = Utc.with_ymd_and_hms(2024, 7, 1, 12, 30, 0).unwrap();
+        assert_eq!(format_applied_on(at, parse_timezone("UTC").unwrap()), "2024-07-01 12:30:00 UTC");
+        assert_eq!(
+            format_applied_on(at, parse_timezone("Europe/Berlin").unwrap()),
+            "2024-07-01 14:30:00 CEST"
+        );
+        assert_eq!(
+            format_applied_on(at, parse_timezone("America/New_York").unwrap()),
+            "2024-07-01 08:30:00 EDT"
+        );
+        assert!(parse_timezone("Mars/Olympus").unwrap_err().contains("unknown time zone"));
+    }
+
+    #[test]
+    fn test_output_creates_parent_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reports/nested/upgrade.txt");
+        let mut output = Some(open_output(&path).unwrap());
+        write_output(&mut output, "Dry run: 2 upgraders applied, 1 pending.\n").unwrap();
+        drop(output);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "Dry run: 2 upgraders applied, 1 pending.\n"
+        );
+    }
+
+    #[test]
+    fn test_output_fails_for_unwritable_path() {
+        let dir = tempfile::tempdir().unwrap();
+        // A directory cannot be opened as the report file.
+        let err = open_output(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("Cannot write --output file"));
+
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        let err = open_output(&file.join("upgrade.txt")).unwrap_err();
+        assert!(err.to_string().contains("Cannot create directory for --output"));
+    }
+}
@@ -1,10 +1,22 @@
 #[cfg(feature = "tls")]
 use crate::SslMode;
-use crate::upgrade_macros::{do_sync, run_upgrade_flow};
-use crate::{PostgresUpgraderOptions, UpgraderError};
+use crate::outcome::FlowError;
+use crate::shared_macros::{report_progress, trace_event};
+use crate::upgrade_macros::{
+    describe_db_error, do_sync, execute_upgrader_sql, execution_error, prepare_parallel_cold_start,
+    retry_connect, run_apply_hooks, run_baseline_flow, run_downgrade_flow, run_parallel_step,
+    run_plan_flow, run_repair_flow, run_status_flow, run_upgrade_flow, upgrader_error,
+};
+use crate::{
+    MigrationSet, MigrationStatus, Migrations, PostgresUpgraderOptions, UpgradeOutcome,
+    UpgradePlan, UpgradeReport, UpgraderError,
+};
+use postgres::{Client, NoTls};
 
 /// Synchronously applies schema upgrades from the specified folder to the database.
 ///
+/// Returns an [`UpgradeReport`] of the upgraders applied.
+///
 /// # Errors
 ///
 /// Returns `UpgraderError` if:
@@ -17,33 +29,783 @@ pub fn upgrade_blocking(
     upgraders_folder: impl AsRef<std::path::Path>,
     connection_string: &str,
     options: &PostgresUpgraderOptions,
+) -> Result<UpgradeReport, UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?.apply_blocking(connection_string)
+}
+
+/// Synchronously applies schema upgrades embedded in the executable, e.g. with
+/// [`embed_migrations!`](crate::embed_migrations), to the database.
+///
+/// Returns an [`UpgradeReport`] of the upgraders applied.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - The embedded upgrader files are invalid.
+/// - An integrity violation is detected.
+/// - Execution of a migration step fails.
+#[cfg(feature = "postgres")]
+pub fn upgrade_blocking_embedded(
+    migrations: &Migrations,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<UpgradeReport, UpgraderError> {
+    MigrationSet::from_migrations(migrations, options)?.apply_blocking(connection_string)
+}
+
+/// Synchronously applies schema upgrades from the specified folder over an existing client,
+/// e.g. one taken from a pool, instead of opening a connection.
+///
+/// The TLS options are not used, and
+/// [`parallel_cold_start`](crate::PostgresUpgraderOptionsBuilder::parallel_cold_start) is
+/// ignored since it needs connections of its own. The client is left outside any transaction.
+///
+/// Returns an [`UpgradeReport`] of the upgraders applied.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Upgrader files cannot be loaded or are invalid.
+/// - An integrity violation is detected.
+/// - Execution of a migration step fails.
+#[cfg(feature = "postgres")]
+pub fn upgrade_with_client_blocking(
+    client: &mut Client,
+    upgraders_folder: impl AsRef<std::path::Path>,
+    options: &PostgresUpgraderOptions,
+) -> Result<UpgradeReport, UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?.apply_with_client_blocking(client)
+}
+
+/// Synchronously reports the upgraders that [`upgrade_blocking`] would apply, as
+/// `(file_id, upgrader_id, description)` in order, without changing the database.
+///
+/// Runs the upgrade with [`dry_run`](crate::PostgresUpgraderOptionsBuilder::dry_run) set:
+/// unlike [`MigrationSet::plan_blocking`], it waits for the upgrade lock, so the
+/// result is not affected by an upgrade that is running at the same time.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid.
+/// - An integrity violation is detected.
+#[cfg(feature = "postgres")]
+pub fn dry_run_blocking(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<Vec<(i32, i32, String)>, UpgraderError> {
+    let mut options = options.clone();
+    options.dry_run = true;
+    let set = MigrationSet::load(upgraders_folder, &options)?;
+    Ok(apply(connection_string, &set)?.pending)
+}
+
+/// Like [`upgrade_blocking`], but reports what happened as an [`UpgradeOutcome`] instead of
+/// an error, so that callers can tell an integrity violation, e.g. to show the drift, from
+/// other failures without inspecting error variants.
+#[cfg(feature = "postgres")]
+pub fn try_upgrade_blocking(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> UpgradeOutcome {
+    match MigrationSet::load(upgraders_folder, options) {
+        Ok(set) => UpgradeOutcome::from_result(apply(connection_string, &set)),
+        Err(e) => UpgradeOutcome::Failed(e),
+    }
+}
+
+/// Synchronously applies only the upgraders of file `file_id` from the specified folder, e.g.
+/// to iterate on one migration during development.
+///
+/// Every upgrader is still loaded and the recorded history checked against them, and each
+/// file before `file_id` must already be fully applied: the file cannot be applied ahead of
+/// them. Later files stay pending.
+///
+/// Returns an [`UpgradeReport`] of the upgraders applied.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid, or none has id `file_id`.
+/// - An upgrader of an earlier file is not applied yet; nothing is applied in that case.
+/// - An integrity violation is detected.
+/// - Execution of a migration step fails.
+#[cfg(feature = "postgres")]
+pub fn apply_file_blocking(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+    file_id: i32,
+) -> Result<UpgradeReport, UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?.apply_file_blocking(connection_string, file_id)
+}
+
+/// Synchronously rolls the database back to `target`, a `(file_id, upgrader_id)` pair, using the
+/// `--- <id> down:` blocks of the upgraders in the specified folder.
+///
+/// Every applied upgrader after `target` is undone, latest first, each in its own transaction
+/// that runs its down block and deletes its row from the upgraders table. `target` itself
+/// stays applied.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid, or `target` is not one of them.
+/// - An integrity violation is detected.
+/// - An upgrader to undo has no down block; nothing is undone in that case.
+/// - Execution of a down block fails.
+#[cfg(feature = "postgres")]
+pub fn downgrade_blocking(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+    target: (i32, i32),
 ) -> Result<(), UpgraderError> {
-    use postgres::{Client, NoTls};
+    MigrationSet::load(upgraders_folder, options)?.downgrade_blocking(connection_string, target)
+}
 
-    #[cfg(feature = "tls")]
-    use crate::tls::create_tls_config;
+/// Synchronously records every upgrader from the specified folder up to and including `up_to`, a
+/// `(file_id, upgrader_id)` pair, as applied without running it, for adopting a database whose
+/// schema was created by other means.
+///
+/// The upgraders are recorded in one transaction, under the same lock as an upgrade, with
+/// their SQL and description from the files. A later upgrade then applies only the upgraders
+/// after `up_to`.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid, or `up_to` is not one of them.
+/// - Any upgrader up to `up_to` is already recorded; nothing is recorded in that case.
+/// - The recorded history would no longer pass the integrity check.
+#[cfg(feature = "postgres")]
+pub fn baseline_blocking(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+    up_to: (i32, i32),
+) -> Result<(), UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?.baseline_blocking(connection_string, up_to)
+}
 
-    #[cfg(feature = "tls")]
-    let mut client = match options.ssl_mode {
-        SslMode::Disable => Client::connect(connection_string, NoTls)
-            .map_err(|e| UpgraderError::ConnectionError(e.to_string()))?,
-        SslMode::Require => {
-            let tls = create_tls_config()?;
-            Client::connect(connection_string, tls)
-                .map_err(|e| UpgraderError::ConnectionError(e.to_string()))?
-        }
+/// Synchronously rewrites the description and SQL recorded for applied upgraders to match the
+/// specified folder, for recovering from upgraders that were reworded after being applied.
+/// Returns the number of rows updated.
+///
+/// Only rows whose `(file_id, upgrader_id)` still line up with the files are rewritten, in one
+/// transaction under the same lock as an upgrade. Upgraders with a checksum get the checksum
+/// of the file SQL.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid.
+/// - The recorded history diverges from the files in order or identity, e.g. an upgrader is
+///   missing from the files or there is a gap; nothing is rewritten in that case.
+#[cfg(feature = "postgres")]
+pub fn repair_blocking(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<usize, UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?.repair_blocking(connection_string)
+}
+
+/// Synchronously reports which upgraders from the specified folder are applied to the database and
+/// which are pending, without changing the database or locking the upgraders table.
+///
+/// A mismatch between the database and the files is reported in
+/// [`MigrationStatus::drift`] rather than as an error.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid.
+#[cfg(feature = "postgres")]
+pub fn status_blocking(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<MigrationStatus, UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?.status_blocking(connection_string)
+}
+
+/// Synchronously checks whether every upgrader from the specified folder is applied to the
+/// database, e.g. for a readiness probe.
+///
+/// Like [`status_blocking`], it reads the upgraders table in a read-only transaction without waiting
+/// for the upgrade lock, so it is cheap enough to run on every probe. Upgraders recorded in the
+/// database but newer than the files are accepted, as an upgrade accepts them.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid.
+/// - The recorded upgraders do not match the files, with the `IntegrityError` an upgrade
+///   would fail with.
+#[cfg(feature = "postgres")]
+pub fn is_up_to_date_blocking(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<bool, UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?.is_up_to_date_blocking(connection_string)
+}
+
+/// Synchronously checks that the upgraders recorded in the database match those in the
+/// specified folder, without applying anything, e.g. in CI against a copy of production.
+///
+/// Pending upgraders are not a mismatch. The check runs even with
+/// [`verify_integrity(false)`](crate::PostgresUpgraderOptionsBuilder::verify_integrity), and
+/// reads the upgraders table like [`status_blocking`], without waiting for the upgrade lock.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connection to the database fails.
+/// - Upgrader files cannot be loaded or are invalid.
+/// - The recorded upgraders do not match the files, with the `IntegrityError` an upgrade
+///   would fail with.
+#[cfg(feature = "postgres")]
+pub fn check_integrity_blocking(
+    upgraders_folder: impl AsRef<std::path::Path>,
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<(), UpgraderError> {
+    MigrationSet::load(upgraders_folder, options)?.check_integrity_blocking(connection_string)
+}
+
+#[cfg(feature = "postgres")]
+impl MigrationSet {
+    /// Synchronously applies the upgraders in this set to the database.
+    ///
+    /// Returns an [`UpgradeReport`] of the upgraders applied.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - Connection to the database fails.
+    /// - An integrity violation is detected.
+    /// - Execution of a migration step fails.
+    pub fn apply_blocking(&self, connection_string: &str) -> Result<UpgradeReport, UpgraderError> {
+        Ok(apply(connection_string, self)?)
+    }
+
+    /// Synchronously applies the upgraders in this set over an existing client, see
+    /// [`upgrade_with_client_blocking`](crate::upgrade_with_client_blocking).
+    ///
+    /// Returns an [`UpgradeReport`] of the upgraders applied.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - An integrity violation is detected.
+    /// - Execution of a migration step fails.
+    pub fn apply_with_client_blocking(
+        &self,
+        client: &mut Client,
+    ) -> Result<UpgradeReport, UpgraderError> {
+        check_blocking_hooks(&self.options)?;
+        Ok(run_upgrade(client, self)?)
+    }
+
+    /// Synchronously applies only the upgraders of file `file_id` in this set, see
+    /// [`apply_file_blocking`](crate::apply_file_blocking).
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - Connection to the database fails.
+    /// - No upgrader in this set has file id `file_id`.
+    /// - An upgrader of an earlier file is not applied yet.
+    /// - An integrity violation is detected.
+    /// - Execution of a migration step fails.
+    pub fn apply_file_blocking(
+        &self,
+        connection_string: &str,
+        file_id: i32,
+    ) -> Result<UpgradeReport, UpgraderError> {
+        Ok(apply(connection_string, &self.limited_to_file(file_id)?)?)
+    }
+
+    /// Synchronously rolls the database back to `target` using the down blocks of the
+    /// upgraders in this set, see [`downgrade_blocking`](crate::downgrade_blocking).
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - Connection to the database fails.
+    /// - `target` is not one of the upgraders in this set.
+    /// - An integrity violation is detected.
+    /// - An upgrader to undo has no down block.
+    /// - Execution of a down block fails.
+    pub fn downgrade_blocking(
+        &self,
+        connection_string: &str,
+        target: (i32, i32),
+    ) -> Result<(), UpgraderError> {
+        let mut client = connect(connection_string, &self.options)?;
+        run_downgrade(&mut client, self, target)
+    }
+
+    /// Synchronously records the upgraders in this set up to and including `up_to` as applied
+    /// without running them, see [`baseline_blocking`](crate::baseline_blocking).
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - Connection to the database fails.
+    /// - `up_to` is not one of the upgraders in this set.
+    /// - Any upgrader up to `up_to` is already recorded.
+    /// - The recorded history would no longer pass the integrity check.
+    pub fn baseline_blocking(
+        &self,
+        connection_string: &str,
+        up_to: (i32, i32),
+    ) -> Result<(), UpgraderError> {
+        let mut client = connect(connection_string, &self.options)?;
+        run_baseline(&mut client, self, up_to)
+    }
+
+    /// Synchronously rewrites the recorded upgraders to match this set, see
+    /// [`repair_blocking`](crate::repair_blocking).
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - Connection to the database fails.
+    /// - The recorded history diverges from this set in order or identity.
+    pub fn repair_blocking(&self, connection_string: &str) -> Result<usize, UpgraderError> {
+        let mut client = connect(connection_string, &self.options)?;
+        run_repair(&mut client, self)
+    }
+
+    /// Synchronously works out what [`apply_blocking`](Self::apply_blocking) would do, without
+    /// changing the database or locking the upgraders table.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - Connection to the database fails.
+    /// - An integrity violation is detected.
+    pub fn plan_blocking(&self, connection_string: &str) -> Result<UpgradePlan, UpgraderError> {
+        let mut client = connect(connection_string, &self.options)?;
+        run_plan(&mut client, self)
+    }
+
+    /// Synchronously reports which upgraders in this set are applied to the database and which
+    /// are pending, without changing the database or locking the upgraders table.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if connection to the database fails.
+    pub fn status_blocking(
+        &self,
+        connection_string: &str,
+    ) -> Result<MigrationStatus, UpgraderError> {
+        let mut client = connect(connection_string, &self.options)?;
+        run_status(&mut client, self)
+    }
+
+    /// Synchronously checks whether every upgrader in this set is applied to the database,
+    /// without changing the database or waiting for the upgrade lock.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if connection to the database fails, or the `IntegrityError`
+    /// an upgrade would fail with if the recorded upgraders do not match the set.
+    pub fn is_up_to_date_blocking(&self, connection_string: &str) -> Result<bool, UpgraderError> {
+        self.status_blocking(connection_string)?.check_up_to_date()
+    }
+
+    /// Synchronously checks that the upgraders recorded in the database match this set,
+    /// without changing the database or waiting for the upgrade lock.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if connection to the database fails, or the `IntegrityError`
+    /// an upgrade would fail with if the recorded upgraders do not match the set.
+    pub fn check_integrity_blocking(&self, connection_string: &str) -> Result<(), UpgraderError> {
+        self.status_blocking(connection_string)?.check_integrity()
+    }
+}
+
+/// Synchronously creates the upgraders table, and the schema if
+/// [`create_schema`](crate::PostgresUpgraderOptionsBuilder::create_schema) is set, without
+/// loading or applying any upgraders.
+///
+/// Meant for provisioning steps that run the privileged DDL ahead of time, so that the upgrade
+/// itself can run as a less privileged role. Creating an existing table or schema is a no-op.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - The options are invalid, e.g. a schema name is not a valid identifier.
+/// - Connection to the database fails.
+/// - The database is a read replica.
+/// - Creating the schema or the upgraders table fails.
+#[cfg(feature = "postgres")]
+pub fn init_only_blocking(
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<(), UpgraderError> {
+    use crate::db_tracker::blocking::{
+        Executor, create_schema_if_needed, ensure_not_in_recovery, init_upgraders_table,
     };
+    options.validate()?;
+    let mut client = connect(connection_string, options)?;
+    ensure_not_in_recovery(Executor::from(&mut client))?;
+    if options.create_schema {
+        create_schema_if_needed(Executor::from(&mut client), options)?;
+    }
+    init_upgraders_table(&mut client, options)
+}
 
-    #[cfg(not(feature = "tls"))]
-    let mut client = Client::connect(connection_string, NoTls)
-        .map_err(|e| UpgraderError::ConnectionError(e.to_string()))?;
+/// Synchronously reads the highest sequence number recorded in the upgraders table.
+///
+/// Every applied upgrader is given the next number in a single global sequence starting at 1,
+/// which makes "is the database at least at version N" checks simple. Returns `None` if no
+/// upgrader has been applied yet.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if connecting to or querying the database fails.
+#[cfg(feature = "postgres")]
+pub fn current_sequence_blocking(
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<Option<i64>, UpgraderError> {
+    use crate::db_tracker::blocking::{Executor, current_sequence};
+    let mut client = connect(connection_string, options)?;
+    current_sequence(Executor::from(&mut client), options)
+}
+
+/// Synchronously reports which host applied the most recent upgrader.
+///
+/// Useful when a deploy goes wrong and several instances may have run the upgrade. Upgraders
+/// recorded before hosts were tracked are reported as `"unknown"`. Returns `None` if no
+/// upgrader has been applied yet.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if connecting to or querying the database fails.
+#[cfg(feature = "postgres")]
+pub fn last_applier_blocking(
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<Option<String>, UpgraderError> {
+    use crate::db_tracker::blocking::{Executor, load_applied_upgraders, relation_exists};
+    let mut client = connect(connection_string, options)?;
+    let table = crate::db_tracker::table_name(options);
+    if !relation_exists(Executor::from(&mut client), &table)? {
+        return Ok(None);
+    }
+    let applied = load_applied_upgraders(Executor::from(&mut client), options)?;
+    Ok(crate::db_tracker::last_applier(&applied))
+}
+
+/// Synchronously writes the upgraders recorded in the database as upgrader files.
+///
+/// Meant for adopting this library on a database whose history was recorded by another
+/// tool: every file `NNN_<description>.sql` gets the recorded SQL under reconstructed
+/// `--- N: <description>` headers, so loading the folder and upgrading the database finds
+/// everything applied. `out_dir` is created if needed and must be empty. Returns the paths
+/// of the written files, in id order.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - Connecting to or querying the database fails.
+/// - The upgraders table does not exist, or an upgrader cannot be written so that it loads
+///   back identically, e.g. because of a gap in the ids.
+/// - `out_dir` is not empty or the files cannot be written.
+#[cfg(feature = "postgres")]
+pub fn export_as_files_blocking(
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+    out_dir: impl AsRef<std::path::Path>,
+) -> Result<Vec<std::path::PathBuf>, UpgraderError> {
+    use crate::db_tracker::blocking::{
+        Executor, load_applied_upgraders_with_text, relation_exists,
+    };
+    let mut client = connect(connection_string, options)?;
+    let table = crate::db_tracker::table_name(options);
+    if !relation_exists(Executor::from(&mut client), &table)? {
+        return Err(UpgraderError::ConfigurationError(format!(
+            "Upgraders table {} does not exist.",
+            table
+        )));
+    }
+    let applied = load_applied_upgraders_with_text(Executor::from(&mut client), options)?;
+    crate::export::write_files(out_dir.as_ref(), &applied)
+}
+
+fn apply(connection_string: &str, set: &MigrationSet) -> Result<UpgradeReport, FlowError> {
+    check_blocking_hooks(&set.options)?;
+    let mut applied = Vec::new();
+    if set.options.parallel_files() > 1
+        && !set.options.single_transaction
+        && !set.options.dry_run
+        && set.options.target.is_none()
+        && set.has_independent_files()
+    {
+        applied = run_parallel_cold_start(connection_string, set)?;
+    }
+    let mut client = connect(connection_string, &set.options)?;
+    let mut report = run_upgrade(&mut client, set)?;
+    // The upgrade only analyzes after applying upgraders itself.
+    if set.options.run_analyze_after && report.applied.is_empty() && !applied.is_empty() {
+        use crate::db_tracker::blocking::{Executor, analyze};
+        analyze(Executor::from(&mut client), &set.options)?;
+    }
+    report.already_applied = report.already_applied.saturating_sub(applied.len());
+    applied.append(&mut report.applied);
+    report.applied = applied;
+    Ok(report)
+}
+
+fn check_blocking_hooks(options: &PostgresUpgraderOptions) -> Result<(), UpgraderError> {
+    if options.on_before_apply_async.is_some() || options.on_after_apply_async.is_some() {
+        return Err(UpgraderError::ConfigurationError(
+            "on_before_apply_async and on_after_apply_async need an async upgrade; use \
+             on_before_apply and on_after_apply with the blocking API."
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
 
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "upgrade", skip_all, fields(schema = set.options.schema.as_deref()))
+)]
+fn run_upgrade(client: &mut Client, set: &MigrationSet) -> Result<UpgradeReport, FlowError> {
+    let options = &set.options;
     run_upgrade_flow!(
         client,
         options,
-        upgraders_folder,
+        &set.upgraders,
+        crate::db_tracker::blocking,
+        do_sync
+    )
+}
+
+fn run_baseline(
+    client: &mut Client,
+    set: &MigrationSet,
+    up_to: (i32, i32),
+) -> Result<(), UpgraderError> {
+    let options = &set.options;
+    run_baseline_flow!(
+        client,
+        options,
+        &set.upgraders,
+        up_to,
+        crate::db_tracker::blocking,
+        do_sync
+    )
+}
+
+fn run_repair(client: &mut Client, set: &MigrationSet) -> Result<usize, UpgraderError> {
+    let options = &set.options;
+    run_repair_flow!(
+        client,
+        options,
+        &set.upgraders,
+        crate::db_tracker::blocking,
+        do_sync
+    )
+}
+
+fn run_downgrade(
+    client: &mut Client,
+    set: &MigrationSet,
+    target: (i32, i32),
+) -> Result<(), UpgraderError> {
+    let options = &set.options;
+    run_downgrade_flow!(
+        client,
+        options,
+        &set.upgraders,
+        target,
+        crate::db_tracker::blocking,
+        do_sync
+    )
+}
+
+/// Applies the upgraders of a fresh database concurrently, see
+/// [`parallel_cold_start`](crate::PostgresUpgraderOptionsBuilder::parallel_cold_start).
+///
+/// Returns the upgraders it applied, as in [`UpgradeReport::applied`]. That is not all of
+/// them if the database is not fresh or an upgrader fails; the sequential upgrade that
+/// follows picks up from there.
+fn run_parallel_cold_start(
+    connection_string: &str,
+    set: &MigrationSet,
+) -> Result<Vec<(i32, i32, String)>, UpgraderError> {
+    let options = &set.options;
+    let mut client = connect(connection_string, options)?;
+    let mut applied = Vec::new();
+    if !is_cold_start(&mut client, set)? {
+        return Ok(applied);
+    }
+
+    for chunk in set.files().chunks(options.parallel_files()) {
+        let clients = chunk
+            .iter()
+            .map(|_| connect(connection_string, options))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let applied_before = applied.len();
+        let all_applied = std::thread::scope(|scope| {
+            let mut steps = Vec::with_capacity(chunk.len());
+            let mut previous: Option<std::sync::mpsc::Receiver<()>> = None;
+            for (file, client) in chunk.iter().zip(clients) {
+                let mut turns = Vec::with_capacity(file.len());
+                for _ in file.iter() {
+                    let (done, next) = std::sync::mpsc::channel();
+                    turns.push((previous.replace(next), done));
+                }
+                steps.push(scope.spawn(move || apply_parallel_file(client, options, file, turns)));
+            }
+            let mut all_applied = true;
+            for (file, step) in chunk.iter().zip(steps) {
+                // A panicking worker is not a failed step to fall back from.
+                let count = step
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+                applied.extend(
+                    file[..count]
+                        .iter()
+                        .map(|u| (u.file_id, u.upgrader_id, u.description.clone())),
+                );
+                all_applied &= count == file.len();
+            }
+            all_applied
+        });
+        // A cold start has every upgrader pending.
+        if applied.len() > applied_before {
+            report_progress!(options, applied.len(), set.upgraders.len());
+        }
+        if !all_applied {
+            return Ok(applied);
+        }
+    }
+    Ok(applied)
+}
+
+/// Applies the upgraders of one file in order, each after the turn of the one before it in
+/// id order, and returns how many were applied. It stops at the first failure, so that
+/// turns of the later upgraders fail and they are rolled back as well.
+fn apply_parallel_file(
+    mut client: Client,
+    options: &PostgresUpgraderOptions,
+    file: &[crate::schema_loader::SchemaUpgrader],
+    turns: Vec<(
+        Option<std::sync::mpsc::Receiver<()>>,
+        std::sync::mpsc::Sender<()>,
+    )>,
+) -> usize {
+    let mut count = 0;
+    for (upgrader, (turn, done)) in file.iter().zip(turns) {
+        if let Err(_e) = apply_parallel_step(&mut client, options, upgrader, turn) {
+            trace_event!(
+                warn,
+                file_id = upgrader.file_id,
+                upgrader_id = upgrader.upgrader_id,
+                error = %_e,
+                "Parallel cold start step failed; continuing sequentially"
+            );
+            break;
+        }
+        let _ = done.send(());
+        count += 1;
+    }
+    count
+}
+
+fn is_cold_start(client: &mut Client, set: &MigrationSet) -> Result<bool, UpgraderError> {
+    prepare_parallel_cold_start!(client, set, crate::db_tracker::blocking, do_sync)
+}
+
+fn apply_parallel_step(
+    client: &mut Client,
+    options: &PostgresUpgraderOptions,
+    upgrader: &crate::schema_loader::SchemaUpgrader,
+    turn: Option<std::sync::mpsc::Receiver<()>>,
+) -> Result<(), UpgraderError> {
+    run_parallel_step!(
+        client,
+        options,
+        upgrader,
         crate::db_tracker::blocking,
         do_sync,
-        &mut
+        match turn {
+            Some(previous_done) => previous_done.recv().is_ok(),
+            None => true,
+        }
     )
 }
+
+fn run_plan(client: &mut Client, set: &MigrationSet) -> Result<UpgradePlan, UpgraderError> {
+    run_plan_flow!(client, set, crate::db_tracker::blocking, do_sync)
+}
+
+fn run_status(client: &mut Client, set: &MigrationSet) -> Result<MigrationStatus, UpgraderError> {
+    run_status_flow!(client, set, crate::db_tracker::blocking, do_sync)
+}
+
+fn connect(
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<Client, UpgraderError> {
+    retry_connect!(
+        try_connect(connection_string, options),
+        options,
+        std::thread::sleep,
+        do_sync
+    )
+}
+
+/// Makes one connection attempt. The outer error is a configuration error, the inner one a
+/// failure to connect.
+fn try_connect(
+    connection_string: &str,
+    options: &PostgresUpgraderOptions,
+) -> Result<Result<Client, postgres::Error>, UpgraderError> {
+    #[cfg(feature = "tls")]
+    let result = match crate::tls::ssl_mode_for(connection_string, options) {
+        SslMode::Disable => Client::connect(connection_string, NoTls),
+        SslMode::Prefer => {
+            let tls = crate::tls::create_tls_config(options)?;
+            match Client::connect(connection_string, tls) {
+                // Errors reported by the server, e.g. a wrong password, would not be fixed by
+                // dropping TLS.
+                Err(e) if e.as_db_error().is_none() => Client::connect(connection_string, NoTls),
+                result => result,
+            }
+        }
+        SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => {
+            let tls = crate::tls::create_tls_config(options)?;
+            Client::connect(connection_string, tls)
+        }
+    };
+
+    #[cfg(not(feature = "tls"))]
+    let result = {
+        let _ = options;
+        Client::connect(connection_string, NoTls)
+    };
+
+    Ok(result)
+}
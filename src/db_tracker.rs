@@ -1,110 +1,759 @@
-use crate::UpgraderError;
+use crate::integrity::IntegrityViolation;
 use crate::schema_loader::SchemaUpgrader;
+use crate::shared_macros::trace_event;
+#[cfg(any(feature = "postgres", feature = "tokio-postgres"))]
 use crate::upgrade_macros::{
-    do_await, do_sync, impl_create_schema_if_needed, impl_init_upgraders_table,
-    impl_load_applied_upgraders, impl_lock_upgraders_table, impl_record_upgrader,
+    describe_db_error, execution_error, impl_acquire_xact_lock, impl_analyze,
+    impl_apply_without_transaction, impl_create_schema_if_needed, impl_current_sequence,
+    impl_delete_upgrader, impl_deploy_key_recorded, impl_ensure_not_in_recovery,
+    impl_init_upgraders_table, impl_load_applied_upgraders, impl_lock_upgraders_table,
+    impl_record_deploy_key, impl_record_integrity_failure, impl_record_upgrader,
+    impl_record_verification, impl_relation_exists, impl_repair_upgrader,
+    impl_run_without_transaction, impl_schema_exists, run_apply_hooks, upgrader_error,
 };
+use crate::{PostgresUpgraderOptions, UpgraderError};
 use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+#[cfg(feature = "tokio-postgres")]
+use std::sync::atomic::{AtomicU64, Ordering};
 
-#[derive(Debug, Clone)]
-pub(crate) struct AppliedUpgrader {
-    pub(crate) file_id: i32,
-    pub(crate) upgrader_id: i32,
-    pub(crate) description: String,
-    pub(crate) text: String,
-    pub(crate) applied_on: DateTime<Utc>,
+/// An upgrader as recorded in the upgraders table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AppliedUpgrader {
+    pub file_id: i32,
+    pub upgrader_id: i32,
+    pub description: String,
+    /// The SQL that was applied, before schema substitution. Empty for upgraders recorded
+    /// with `store_checksums`, which keep only [`checksum`](Self::checksum).
+    pub text: String,
+    pub applied_on: DateTime<Utc>,
+    /// Global order in which upgraders were applied, starting at 1. `None` only when read
+    /// from a table that has not yet been updated to include the column.
+    pub sequence: Option<i64>,
+    /// Host that applied the upgrader. `None` for rows recorded before the column existed.
+    pub applied_host: Option<String>,
+    /// Who applied the upgrader, as configured with
+    /// [`applied_by`](crate::PostgresUpgraderOptionsBuilder::applied_by). `None` if unset.
+    pub applied_by: Option<String>,
+    /// When an upgrade last confirmed that the upgrader still matches its file. Only
+    /// maintained with `track_verification`; `None` if it was never recorded.
+    pub last_verified_on: Option<DateTime<Utc>>,
+    /// SHA-256 of the trimmed SQL, in lowercase hex. Only recorded with `store_checksums`.
+    pub checksum: Option<String>,
 }
 
 pub(crate) const ADVISORY_LOCK_ID: i64 = 42_00_42_00; // Arbitrary constant for serialization of CREATE TABLE
 
-pub(crate) fn table_name(schema: Option<&str>) -> String {
-    match schema {
-        Some(s) => format!("\"{}\".\"$upgraders$\"", s),
-        None => "\"$upgraders$\"".to_string(),
+pub(crate) const DEFAULT_UPGRADERS_TABLE: &str = "$upgraders$";
+pub(crate) const AUDIT_TABLE: &str = "$upgrader_audit$";
+pub(crate) const DEPLOYS_TABLE: &str = "$upgrader_deploys$";
+pub(crate) const IN_PROGRESS_TABLE: &str = "$upgrader_in_progress$";
+
+/// SQLSTATEs of a statement creating or adding something that already exists.
+const DUPLICATE_OBJECT_CODES: &[&str] = &["42P06", "42P07", "42701", "42710", "42723"];
+
+/// Columns added to the upgraders table after its first release, with their types. They are
+/// added to existing tables during initialization.
+pub(crate) const ADDED_COLUMNS: &[(&str, &str)] = &[
+    ("sequence", "BIGINT"),
+    ("applied_host", "TEXT"),
+    ("last_verified_on", "TIMESTAMPTZ"),
+    ("applied_by", "TEXT"),
+];
+
+/// How often an upgrade waiting to apply a `(no-transaction)` upgrader retries the lock.
+pub(crate) const NO_TRANSACTION_POLL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_millis(200);
+
+/// How often an upgrade with `lock_wait_timeout` retries the advisory lock.
+pub(crate) const LOCK_WAIT_POLL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_millis(100);
+
+/// Column holding the SQL checksum, added to the upgraders table with `store_checksums`.
+pub(crate) const CHECKSUM_COLUMN: (&str, &str) = ("checksum", "CHAR(64)");
+
+/// Length of the `description` column, which the loader checks descriptions against.
+pub(crate) const MAX_DESCRIPTION_CHARS: usize = 500;
+
+/// Reported in place of the host for upgraders recorded before hosts were tracked.
+pub(crate) const UNKNOWN_HOST: &str = "unknown";
+
+pub(crate) fn table_name(options: &PostgresUpgraderOptions) -> String {
+    // Like the other tracking tables, the default name is owned by the library and always
+    // quoted, even with `unquoted_identifiers`.
+    let table = match &options.upgraders_table {
+        Some(table) => options.identifier(table),
+        None => crate::identifier::quote(DEFAULT_UPGRADERS_TABLE),
+    };
+    match options.tracking_schema() {
+        Some(s) => format!("{}.{}", options.identifier(s), table),
+        None => table,
+    }
+}
+
+/// The whitespace trimmed from SQL before its [`checksum`] is taken: the ASCII characters
+/// `str::trim` removes, which the database trims the same way in any server encoding.
+const ASCII_WHITESPACE: [char; 6] = ['\t', '\n', '\u{b}', '\u{c}', '\r', ' '];
+
+/// [`ASCII_WHITESPACE`] as an SQL string literal.
+const ASCII_WHITESPACE_LITERAL: &str = r"E'\t\n\013\f\r '";
+
+/// Lists the columns of the upgraders table, in order. Its parameter is the table name.
+pub(crate) const COLUMNS_SQL: &str = "SELECT attname::text FROM pg_attribute WHERE attrelid = to_regclass($1) AND attnum > 0 AND NOT attisdropped ORDER BY attnum";
+
+/// Returns the statement loading the applied upgraders of `table`, whose columns are
+/// `columns`, with `compare_checksums`: every column but `text`, and the [`checksum`] of the
+/// recorded SQL, computed by the database, for rows that do not have one.
+///
+/// The database trims the same [`ASCII_WHITESPACE`] as [`checksum`], so both hash the same
+/// SQL. Rows whose SQL still starts or ends with a non-ASCII character, which could be other
+/// whitespace the loader trims from files, are read with their SQL instead, so that they
+/// compare as without the option.
+pub(crate) fn checksum_select_sql(table: &str, columns: &[String]) -> String {
+    let has_non_ascii_edge = "ascii(trimmed) > 127 OR ascii(right(trimmed, 1)) > 127";
+    let text_checksum = format!(
+        "CASE WHEN {} THEN NULL ELSE encode(sha256(convert_to(trimmed, 'UTF8')), 'hex') END",
+        has_non_ascii_edge
+    );
+    let mut selected: Vec<String> = columns
+        .iter()
+        .filter(|column| !matches!(column.as_str(), "text" | "checksum"))
+        .map(|column| crate::identifier::quote(column))
+        .collect();
+    selected.push(format!(
+        "CASE WHEN {} THEN text END AS text",
+        has_non_ascii_edge
+    ));
+    if columns.iter().any(|column| column == "checksum") {
+        selected.push(format!("COALESCE(checksum, {}) AS checksum", text_checksum));
+    } else {
+        selected.push(format!("{} AS checksum", text_checksum));
+    }
+    format!(
+        "SELECT {} FROM {} CROSS JOIN LATERAL (SELECT btrim(text, {}) AS trimmed) AS trimmed_text ORDER BY file_id, upgrader_id;",
+        selected.join(", "),
+        table,
+        ASCII_WHITESPACE_LITERAL
+    )
+}
+
+/// Returns the statement creating the upgraders table `table` if it does not exist.
+pub(crate) fn create_table_sql(table: &str) -> String {
+    format!(
+        r#"
+        CREATE TABLE IF NOT EXISTS {} (
+            file_id INT,
+            upgrader_id INT,
+            description VARCHAR({}),
+            text TEXT,
+            applied_on TIMESTAMPTZ,
+            sequence BIGINT,
+            applied_host TEXT,
+            last_verified_on TIMESTAMPTZ,
+            applied_by TEXT,
+            PRIMARY KEY (file_id, upgrader_id)
+        );
+    "#,
+        table, MAX_DESCRIPTION_CHARS
+    )
+}
+
+/// Returns the statement numbering the upgraders of `table` recorded without a sequence,
+/// in id order after the highest one.
+pub(crate) fn backfill_sequence_sql(table: &str) -> String {
+    format!(
+        r#"
+        UPDATE {0} AS t SET sequence = b.sequence
+        FROM (
+            SELECT file_id, upgrader_id,
+                (SELECT COALESCE(MAX(sequence), 0) FROM {0})
+                    + row_number() OVER (ORDER BY file_id, upgrader_id) AS sequence
+            FROM {0}
+            WHERE sequence IS NULL
+        ) AS b
+        WHERE t.file_id = b.file_id AND t.upgrader_id = b.upgrader_id;
+    "#,
+        table
+    )
+}
+
+/// Returns the statement handing `table` over to the
+/// [`tracking_table_owner`](crate::PostgresUpgraderOptionsBuilder::tracking_table_owner), if
+/// one is set.
+pub(crate) fn change_owner_sql(table: &str, options: &PostgresUpgraderOptions) -> Option<String> {
+    let owner = options.tracking_table_owner.as_deref()?;
+    Some(format!(
+        "ALTER TABLE {} OWNER TO {};",
+        table,
+        options.identifier(owner)
+    ))
+}
+
+/// Returns the statement recording an applied upgrader. Its parameters are the file id,
+/// upgrader id, description, [`recorded_content`], host, `applied_by` and [`applied_on`],
+/// which is the database's `now()` when `NULL`.
+pub(crate) fn record_upgrader_sql(options: &PostgresUpgraderOptions) -> String {
+    // With checksums, the text column is left NULL.
+    let content_column = if options.store_checksums {
+        "checksum"
+    } else {
+        "text"
+    };
+    format!(
+        "INSERT INTO {0} (file_id, upgrader_id, description, {1}, applied_on, sequence, applied_host, applied_by) VALUES ($1, $2, $3, $4, COALESCE($7::TIMESTAMPTZ, now()), (SELECT COALESCE(MAX(sequence), 0) + 1 FROM {0}), $5, $6);",
+        table_name(options),
+        content_column
+    )
+}
+
+/// Returns the `applied_on` recorded for the upgrader being applied: the configured
+/// [`clock`](crate::PostgresUpgraderOptionsBuilder::clock)'s time, else `None`.
+pub(crate) fn applied_on(options: &PostgresUpgraderOptions) -> Option<DateTime<Utc>> {
+    options.clock.as_ref().map(|clock| clock())
+}
+
+/// Returns what is recorded of the SQL of `upgrader`: its checksum with `store_checksums`,
+/// else the SQL itself.
+pub(crate) fn recorded_content(
+    options: &PostgresUpgraderOptions,
+    upgrader: &SchemaUpgrader,
+) -> String {
+    if options.store_checksums {
+        checksum(&upgrader.text)
+    } else {
+        upgrader.text.clone()
     }
 }
 
+pub(crate) fn audit_table_name(options: &PostgresUpgraderOptions) -> String {
+    let table = crate::identifier::quote(AUDIT_TABLE);
+    match options.tracking_schema() {
+        Some(s) => format!("{}.{}", options.identifier(s), table),
+        None => table,
+    }
+}
+
+pub(crate) fn deploys_table_name(options: &PostgresUpgraderOptions) -> String {
+    let table = crate::identifier::quote(DEPLOYS_TABLE);
+    match options.tracking_schema() {
+        Some(s) => format!("{}.{}", options.identifier(s), table),
+        None => table,
+    }
+}
+
+/// Table marking the `(no-transaction)` upgraders whose SQL has started but which are not
+/// recorded yet, so that an upgrade interrupted in between is recognized when run again.
+pub(crate) fn in_progress_table_name(options: &PostgresUpgraderOptions) -> String {
+    let table = crate::identifier::quote(IN_PROGRESS_TABLE);
+    match options.tracking_schema() {
+        Some(s) => format!("{}.{}", options.identifier(s), table),
+        None => table,
+    }
+}
+
+/// Whether `code` is the SQLSTATE of creating an object that already exists, which is how
+/// running the SQL of an already applied upgrader again usually fails.
+pub(crate) fn is_duplicate_object(code: &str) -> bool {
+    DUPLICATE_OBJECT_CODES.contains(&code)
+}
+
+/// Session-level advisory lock held while a `(no-transaction)` upgrader runs, next to the
+/// transaction-level one taken during initialization.
+pub(crate) fn no_transaction_lock_id(options: &PostgresUpgraderOptions) -> i64 {
+    options.advisory_lock_id().wrapping_add(1)
+}
+
+/// Transaction-level advisory lock taken at the start of each upgrader transaction. It
+/// serializes upgrades without locking the upgraders table, which other sessions can keep
+/// reading and writing while a long upgrader runs. Separate from the initialization lock, so
+/// an upgrade that finds nothing pending never waits for one that is applying.
+pub(crate) fn upgrade_lock_id(options: &PostgresUpgraderOptions) -> i64 {
+    options.advisory_lock_id().wrapping_add(2)
+}
+
+/// Derives an advisory lock id from a schema name (64-bit FNV-1a), so that applications
+/// upgrading different schemas do not serialize against each other. Stable across releases
+/// and platforms, since every instance of an application must compute the same id.
+pub(crate) fn schema_lock_id(schema: &str) -> i64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+    let hash = schema.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    });
+    hash as i64
+}
+
+/// Returns the SHA-256 of the SQL without leading and trailing [`ASCII_WHITESPACE`], in
+/// lowercase hex, as stored with `store_checksums`.
+pub(crate) fn checksum(text: &str) -> String {
+    Sha256::digest(text.trim_matches(ASCII_WHITESPACE).as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Name of the machine running the upgrade, as recorded in the tracking tables.
+pub(crate) fn host_name() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// Returns a schema name that is unique to this call, for applying upgraders from scratch.
+#[cfg(feature = "tokio-postgres")]
+pub(crate) fn scratch_schema_name() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "upgrader_scratch_{}_{}_{}",
+        std::process::id(),
+        Utc::now().timestamp_micros(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Returns the most recently applied upgrader, which is not the last in id order when
+/// upgraders were applied out of order. Returns `None` if nothing has been applied.
+pub(crate) fn last_applied(applied: &[AppliedUpgrader]) -> Option<&AppliedUpgrader> {
+    // Tables not yet updated to have a sequence fall back to the application time.
+    applied.iter().max_by_key(|u| (u.sequence, u.applied_on))
+}
+
+/// Returns the host that applied the most recent upgrader, or [`UNKNOWN_HOST`] if it was
+/// recorded before hosts were tracked. Returns `None` if nothing has been applied.
+pub(crate) fn last_applier(applied: &[AppliedUpgrader]) -> Option<String> {
+    let last = last_applied(applied)?;
+    Some(
+        last.applied_host
+            .clone()
+            .unwrap_or_else(|| UNKNOWN_HOST.to_string()),
+    )
+}
+
 #[cfg(feature = "postgres")]
 pub(crate) mod blocking {
     use super::*;
-    use postgres::GenericClient;
+    use crate::upgrade_macros::do_sync;
+    use postgres::types::ToSql;
+    use postgres::{Client, Row, Transaction};
+
+    /// Runs the tracker queries on either a client or an open transaction.
+    pub(crate) enum Executor<'a, 't> {
+        Client(&'a mut Client),
+        Transaction(&'a mut Transaction<'t>),
+    }
+
+    impl<'a> From<&'a mut Client> for Executor<'a, 'static> {
+        fn from(client: &'a mut Client) -> Self {
+            Executor::Client(client)
+        }
+    }
+
+    impl<'a, 't> From<&'a mut Transaction<'t>> for Executor<'a, 't> {
+        fn from(transaction: &'a mut Transaction<'t>) -> Self {
+            Executor::Transaction(transaction)
+        }
+    }
+
+    impl Executor<'_, '_> {
+        pub(crate) fn execute(
+            &mut self,
+            query: &str,
+            params: &[&(dyn ToSql + Sync)],
+        ) -> Result<u64, postgres::Error> {
+            match self {
+                Executor::Client(client) => client.execute(query, params),
+                Executor::Transaction(transaction) => transaction.execute(query, params),
+            }
+        }
+
+        pub(crate) fn query(
+            &mut self,
+            query: &str,
+            params: &[&(dyn ToSql + Sync)],
+        ) -> Result<Vec<Row>, postgres::Error> {
+            match self {
+                Executor::Client(client) => client.query(query, params),
+                Executor::Transaction(transaction) => transaction.query(query, params),
+            }
+        }
+
+        pub(crate) fn query_one(
+            &mut self,
+            query: &str,
+            params: &[&(dyn ToSql + Sync)],
+        ) -> Result<Row, postgres::Error> {
+            match self {
+                Executor::Client(client) => client.query_one(query, params),
+                Executor::Transaction(transaction) => transaction.query_one(query, params),
+            }
+        }
+    }
 
     pub fn create_schema_if_needed(
-        client: &mut impl GenericClient,
-        schema: Option<&str>,
+        mut executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
     ) -> Result<(), UpgraderError> {
-        impl_create_schema_if_needed!(client, schema, do_sync)
+        impl_create_schema_if_needed!(executor, options, do_sync)
     }
 
     pub fn init_upgraders_table(
-        client: &mut postgres::Client,
-        schema: Option<&str>,
+        client: &mut Client,
+        options: &PostgresUpgraderOptions,
     ) -> Result<(), UpgraderError> {
-        impl_init_upgraders_table!(client, schema, do_sync)
+        impl_init_upgraders_table!(client, options, do_sync, std::thread::sleep)
     }
 
     pub fn lock_upgraders_table(
-        transaction: &mut postgres::Transaction,
-        schema: Option<&str>,
+        transaction: &mut Transaction,
+        options: &PostgresUpgraderOptions,
     ) -> Result<(), UpgraderError> {
-        impl_lock_upgraders_table!(transaction, schema, do_sync)
+        impl_lock_upgraders_table!(transaction, options, do_sync, std::thread::sleep)
     }
 
     pub fn load_applied_upgraders(
-        client: &mut impl GenericClient,
-        schema: Option<&str>,
+        mut executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+    ) -> Result<Vec<AppliedUpgrader>, UpgraderError> {
+        impl_load_applied_upgraders!(executor, options, do_sync, false)
+    }
+
+    /// Like `load_applied_upgraders`, but reads the recorded SQL even with
+    /// `compare_checksums`, for rewriting or exporting it.
+    pub fn load_applied_upgraders_with_text(
+        mut executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
     ) -> Result<Vec<AppliedUpgrader>, UpgraderError> {
-        impl_load_applied_upgraders!(client, schema, do_sync)
+        impl_load_applied_upgraders!(executor, options, do_sync, true)
     }
 
     pub fn record_upgrader(
-        client: &mut impl GenericClient,
-        schema: Option<&str>,
+        mut executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        upgrader: &SchemaUpgrader,
+    ) -> Result<(), UpgraderError> {
+        impl_record_upgrader!(executor, options, upgrader, do_sync)
+    }
+
+    pub fn delete_upgrader(
+        mut executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        upgrader: &SchemaUpgrader,
+    ) -> Result<(), UpgraderError> {
+        impl_delete_upgrader!(executor, options, upgrader, do_sync)
+    }
+
+    pub fn repair_upgrader(
+        mut executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        applied: &AppliedUpgrader,
+    ) -> Result<(), UpgraderError> {
+        impl_repair_upgrader!(executor, options, applied, do_sync)
+    }
+
+    pub fn record_integrity_failure(
+        mut executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        violation: &IntegrityViolation,
+    ) -> Result<(), UpgraderError> {
+        impl_record_integrity_failure!(executor, options, violation, do_sync)
+    }
+
+    pub fn record_deploy_key(
+        mut executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        deploy_key: &str,
+    ) -> Result<(), UpgraderError> {
+        impl_record_deploy_key!(executor, options, deploy_key, do_sync)
+    }
+
+    pub fn deploy_key_recorded(
+        mut executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        deploy_key: &str,
+    ) -> Result<bool, UpgraderError> {
+        impl_deploy_key_recorded!(executor, options, deploy_key, do_sync)
+    }
+
+    pub fn current_sequence(
+        mut executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+    ) -> Result<Option<i64>, UpgraderError> {
+        impl_current_sequence!(executor, options, do_sync)
+    }
+
+    pub fn ensure_not_in_recovery(mut executor: Executor<'_, '_>) -> Result<(), UpgraderError> {
+        impl_ensure_not_in_recovery!(executor, do_sync)
+    }
+
+    /// Applies a `(no-transaction)` upgrader directly on `client`, under a session-level
+    /// advisory lock. Returns `false` if another upgrade applied it first.
+    pub fn apply_without_transaction(
+        client: &mut Client,
+        options: &PostgresUpgraderOptions,
+        upgraders: &[SchemaUpgrader],
+        upgrader: &SchemaUpgrader,
+    ) -> Result<bool, UpgraderError> {
+        impl_apply_without_transaction!(
+            client,
+            options,
+            upgraders,
+            upgrader,
+            do_sync,
+            std::thread::sleep
+        )
+    }
+
+    fn run_without_transaction(
+        client: &mut Client,
+        options: &PostgresUpgraderOptions,
+        upgraders: &[SchemaUpgrader],
         upgrader: &SchemaUpgrader,
+    ) -> Result<bool, UpgraderError> {
+        impl_run_without_transaction!(client, options, upgraders, upgrader, do_sync)
+    }
+
+    pub fn record_verification(
+        mut executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        verified: &[&AppliedUpgrader],
+    ) -> Result<(), UpgraderError> {
+        impl_record_verification!(executor, options, verified, do_sync)
+    }
+
+    pub fn schema_exists(
+        mut executor: Executor<'_, '_>,
+        schema: &str,
+    ) -> Result<bool, UpgraderError> {
+        impl_schema_exists!(executor, schema, do_sync)
+    }
+
+    pub fn analyze(
+        mut executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
     ) -> Result<(), UpgraderError> {
-        impl_record_upgrader!(client, schema, upgrader, do_sync)
+        impl_analyze!(executor, options, do_sync)
+    }
+
+    pub fn relation_exists(
+        mut executor: Executor<'_, '_>,
+        relation: &str,
+    ) -> Result<bool, UpgraderError> {
+        impl_relation_exists!(executor, relation, do_sync)
     }
 }
 
 #[cfg(feature = "tokio-postgres")]
 pub(crate) mod async_tracker {
     use super::*;
-    use tokio_postgres::GenericClient;
+    use crate::upgrade_macros::do_await;
+    use tokio_postgres::types::ToSql;
+    use tokio_postgres::{Client, Row, Transaction};
+
+    /// Runs the tracker queries on either a client or an open transaction.
+    ///
+    /// Built from mutable references, like its blocking counterpart, so that the shared upgrade
+    /// flow can construct it the same way in both modes.
+    pub(crate) enum Executor<'a, 't> {
+        Client(&'a Client),
+        Transaction(&'a Transaction<'t>),
+    }
+
+    impl<'a> From<&'a mut Client> for Executor<'a, 'static> {
+        fn from(client: &'a mut Client) -> Self {
+            Executor::Client(client)
+        }
+    }
+
+    impl<'a, 't> From<&'a mut Transaction<'t>> for Executor<'a, 't> {
+        fn from(transaction: &'a mut Transaction<'t>) -> Self {
+            Executor::Transaction(transaction)
+        }
+    }
+
+    impl Executor<'_, '_> {
+        pub(crate) async fn execute(
+            &self,
+            query: &str,
+            params: &[&(dyn ToSql + Sync)],
+        ) -> Result<u64, tokio_postgres::Error> {
+            match self {
+                Executor::Client(client) => client.execute(query, params).await,
+                Executor::Transaction(transaction) => transaction.execute(query, params).await,
+            }
+        }
+
+        pub(crate) async fn query(
+            &self,
+            query: &str,
+            params: &[&(dyn ToSql + Sync)],
+        ) -> Result<Vec<Row>, tokio_postgres::Error> {
+            match self {
+                Executor::Client(client) => client.query(query, params).await,
+                Executor::Transaction(transaction) => transaction.query(query, params).await,
+            }
+        }
+
+        pub(crate) async fn query_one(
+            &self,
+            query: &str,
+            params: &[&(dyn ToSql + Sync)],
+        ) -> Result<Row, tokio_postgres::Error> {
+            match self {
+                Executor::Client(client) => client.query_one(query, params).await,
+                Executor::Transaction(transaction) => transaction.query_one(query, params).await,
+            }
+        }
+    }
 
     pub async fn create_schema_if_needed(
-        client: &impl GenericClient,
-        schema: Option<&str>,
+        executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
     ) -> Result<(), UpgraderError> {
-        impl_create_schema_if_needed!(client, schema, do_await)
+        impl_create_schema_if_needed!(executor, options, do_await)
     }
 
     pub async fn init_upgraders_table(
-        client: &mut tokio_postgres::Client,
-        schema: Option<&str>,
+        client: &mut Client,
+        options: &PostgresUpgraderOptions,
     ) -> Result<(), UpgraderError> {
-        impl_init_upgraders_table!(client, schema, do_await)
+        impl_init_upgraders_table!(client, options, do_await, tokio::time::sleep)
     }
 
     pub async fn lock_upgraders_table(
-        transaction: &tokio_postgres::Transaction<'_>,
-        schema: Option<&str>,
+        transaction: &Transaction<'_>,
+        options: &PostgresUpgraderOptions,
     ) -> Result<(), UpgraderError> {
-        impl_lock_upgraders_table!(transaction, schema, do_await)
+        impl_lock_upgraders_table!(transaction, options, do_await, tokio::time::sleep)
     }
 
     pub async fn load_applied_upgraders(
-        client: &impl GenericClient,
-        schema: Option<&str>,
+        executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+    ) -> Result<Vec<AppliedUpgrader>, UpgraderError> {
+        impl_load_applied_upgraders!(executor, options, do_await, false)
+    }
+
+    /// Like `load_applied_upgraders`, but reads the recorded SQL even with
+    /// `compare_checksums`, for rewriting or exporting it.
+    pub async fn load_applied_upgraders_with_text(
+        executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
     ) -> Result<Vec<AppliedUpgrader>, UpgraderError> {
-        impl_load_applied_upgraders!(client, schema, do_await)
+        impl_load_applied_upgraders!(executor, options, do_await, true)
     }
 
     pub async fn record_upgrader(
-        client: &impl GenericClient,
-        schema: Option<&str>,
+        executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        upgrader: &SchemaUpgrader,
+    ) -> Result<(), UpgraderError> {
+        impl_record_upgrader!(executor, options, upgrader, do_await)
+    }
+
+    pub async fn delete_upgrader(
+        executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        upgrader: &SchemaUpgrader,
+    ) -> Result<(), UpgraderError> {
+        impl_delete_upgrader!(executor, options, upgrader, do_await)
+    }
+
+    pub async fn repair_upgrader(
+        executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        applied: &AppliedUpgrader,
+    ) -> Result<(), UpgraderError> {
+        impl_repair_upgrader!(executor, options, applied, do_await)
+    }
+
+    pub async fn record_integrity_failure(
+        executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        violation: &IntegrityViolation,
+    ) -> Result<(), UpgraderError> {
+        impl_record_integrity_failure!(executor, options, violation, do_await)
+    }
+
+    pub async fn record_deploy_key(
+        executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        deploy_key: &str,
+    ) -> Result<(), UpgraderError> {
+        impl_record_deploy_key!(executor, options, deploy_key, do_await)
+    }
+
+    pub async fn deploy_key_recorded(
+        executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        deploy_key: &str,
+    ) -> Result<bool, UpgraderError> {
+        impl_deploy_key_recorded!(executor, options, deploy_key, do_await)
+    }
+
+    pub async fn current_sequence(
+        executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+    ) -> Result<Option<i64>, UpgraderError> {
+        impl_current_sequence!(executor, options, do_await)
+    }
+
+    pub async fn ensure_not_in_recovery(executor: Executor<'_, '_>) -> Result<(), UpgraderError> {
+        impl_ensure_not_in_recovery!(executor, do_await)
+    }
+
+    /// Applies a `(no-transaction)` upgrader directly on `client`, under a session-level
+    /// advisory lock. Returns `false` if another upgrade applied it first.
+    pub async fn apply_without_transaction(
+        client: &mut Client,
+        options: &PostgresUpgraderOptions,
+        upgraders: &[SchemaUpgrader],
+        upgrader: &SchemaUpgrader,
+    ) -> Result<bool, UpgraderError> {
+        impl_apply_without_transaction!(
+            client,
+            options,
+            upgraders,
+            upgrader,
+            do_await,
+            tokio::time::sleep
+        )
+    }
+
+    async fn run_without_transaction(
+        client: &mut Client,
+        options: &PostgresUpgraderOptions,
+        upgraders: &[SchemaUpgrader],
         upgrader: &SchemaUpgrader,
+    ) -> Result<bool, UpgraderError> {
+        impl_run_without_transaction!(client, options, upgraders, upgrader, do_await)
+    }
+
+    pub async fn record_verification(
+        executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
+        verified: &[&AppliedUpgrader],
+    ) -> Result<(), UpgraderError> {
+        impl_record_verification!(executor, options, verified, do_await)
+    }
+
+    pub async fn schema_exists(
+        executor: Executor<'_, '_>,
+        schema: &str,
+    ) -> Result<bool, UpgraderError> {
+        impl_schema_exists!(executor, schema, do_await)
+    }
+
+    pub async fn analyze(
+        executor: Executor<'_, '_>,
+        options: &PostgresUpgraderOptions,
     ) -> Result<(), UpgraderError> {
-        impl_record_upgrader!(client, schema, upgrader, do_await)
+        impl_analyze!(executor, options, do_await)
+    }
+
+    pub async fn relation_exists(
+        executor: Executor<'_, '_>,
+        relation: &str,
+    ) -> Result<bool, UpgraderError> {
+        impl_relation_exists!(executor, relation, do_await)
     }
 }
 
@@ -114,19 +763,217 @@ mod tests {
 
     #[test]
     fn test_table_name_without_schema() {
-        let name = table_name(None);
+        let options = PostgresUpgraderOptions::builder().build();
+        let name = table_name(&options);
         assert_eq!(name, "\"$upgraders$\"");
     }
 
     #[test]
     fn test_table_name_with_schema() {
-        let name = table_name(Some("my_schema"));
+        let options = PostgresUpgraderOptions::builder()
+            .schema("my_schema")
+            .build();
+        let name = table_name(&options);
         assert_eq!(name, "\"my_schema\".\"$upgraders$\"");
     }
 
+    #[test]
+    fn test_table_name_with_tracking_schema() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("app")
+            .tracking_schema("meta")
+            .build();
+        assert_eq!(table_name(&options), "\"meta\".\"$upgraders$\"");
+        assert_eq!(
+            deploys_table_name(&options),
+            "\"meta\".\"$upgrader_deploys$\""
+        );
+        assert_eq!(options.advisory_lock_id(), schema_lock_id("meta"));
+        assert_eq!(options.schemas_to_create(), vec!["app", "meta"]);
+
+        let options = PostgresUpgraderOptions::builder()
+            .schema("app")
+            .tracking_schema("app")
+            .build();
+        assert_eq!(options.schemas_to_create(), vec!["app"]);
+    }
+
     #[test]
     fn test_table_name_with_public_schema() {
-        let name = table_name(Some("public"));
+        let options = PostgresUpgraderOptions::builder().schema("public").build();
+        let name = table_name(&options);
         assert_eq!(name, "\"public\".\"$upgraders$\"");
     }
+
+    #[test]
+    fn test_table_name_custom_table() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("MySchema")
+            .upgraders_table("Upgraders")
+            .build();
+        let name = table_name(&options);
+        assert_eq!(name, "\"MySchema\".\"Upgraders\"");
+    }
+
+    #[test]
+    fn test_table_name_unquoted() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("my_schema")
+            .upgraders_table("schema_upgraders")
+            .unquoted_identifiers(true)
+            .build();
+        let name = table_name(&options);
+        assert_eq!(name, "my_schema.schema_upgraders");
+
+        // The default table keeps its quotes.
+        let options = PostgresUpgraderOptions::builder()
+            .schema("my_schema")
+            .unquoted_identifiers(true)
+            .build();
+        assert_eq!(table_name(&options), "my_schema.\"$upgraders$\"");
+    }
+
+    #[test]
+    fn test_deploys_table_name() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("my_schema")
+            .build();
+        assert_eq!(
+            deploys_table_name(&options),
+            "\"my_schema\".\"$upgrader_deploys$\""
+        );
+    }
+
+    #[test]
+    fn test_audit_table_name() {
+        let options = PostgresUpgraderOptions::builder().build();
+        assert_eq!(audit_table_name(&options), "\"$upgrader_audit$\"");
+
+        let options = PostgresUpgraderOptions::builder()
+            .schema("my_schema")
+            .build();
+        assert_eq!(
+            audit_table_name(&options),
+            "\"my_schema\".\"$upgrader_audit$\""
+        );
+    }
+
+    fn applied(sequence: Option<i64>, seconds: i64, host: Option<&str>) -> AppliedUpgrader {
+        AppliedUpgrader {
+            file_id: 0,
+            upgrader_id: 0,
+            description: String::new(),
+            text: String::new(),
+            applied_on: DateTime::from_timestamp(seconds, 0).unwrap(),
+            sequence,
+            applied_host: host.map(str::to_string),
+            applied_by: None,
+            last_verified_on: None,
+            checksum: None,
+        }
+    }
+
+    #[test]
+    fn test_last_applier() {
+        assert_eq!(last_applier(&[]), None);
+
+        let rows = [
+            applied(Some(1), 10, Some("pod-a")),
+            applied(Some(3), 20, Some("pod-c")),
+            applied(Some(2), 30, Some("pod-b")),
+        ];
+        assert_eq!(last_applier(&rows).as_deref(), Some("pod-c"));
+    }
+
+    #[test]
+    fn test_last_applied_out_of_order() {
+        assert!(last_applied(&[]).is_none());
+
+        // (0, 1) was applied after (1, 0), e.g. with `allow_out_of_order`.
+        let mut rows = [applied(Some(2), 20, None), applied(Some(1), 10, None)];
+        rows[0].upgrader_id = 1;
+        rows[1].file_id = 1;
+        let last = last_applied(&rows).unwrap();
+        assert_eq!((last.file_id, last.upgrader_id), (0, 1));
+    }
+
+    #[test]
+    fn test_last_applier_predating_hosts() {
+        let rows = [
+            applied(Some(1), 10, Some("pod-a")),
+            applied(Some(2), 20, None),
+        ];
+        assert_eq!(last_applier(&rows).as_deref(), Some(UNKNOWN_HOST));
+
+        // Without sequence numbers the most recently applied row wins.
+        let rows = [applied(None, 30, None), applied(None, 20, Some("pod-a"))];
+        assert_eq!(last_applier(&rows).as_deref(), Some(UNKNOWN_HOST));
+    }
+
+    #[test]
+    fn test_schema_lock_id() {
+        // Reference values of 64-bit FNV-1a; changing them would make upgrades of different
+        // versions of an application stop serializing.
+        assert_eq!(schema_lock_id(""), 0xcbf2_9ce4_8422_2325_u64 as i64);
+        assert_eq!(schema_lock_id("a"), 0xaf63_dc4c_8601_ec8c_u64 as i64);
+        assert_ne!(schema_lock_id("billing"), schema_lock_id("orders"));
+    }
+
+    #[test]
+    fn test_checksum_select_sql() {
+        let columns = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let sql = checksum_select_sql(
+            "\"$upgraders$\"",
+            &columns(&[
+                "file_id",
+                "upgrader_id",
+                "description",
+                "text",
+                "applied_on",
+            ]),
+        );
+        assert!(sql.starts_with(
+            "SELECT \"file_id\", \"upgrader_id\", \"description\", \"applied_on\", CASE WHEN"
+        ));
+        assert!(sql.contains("'hex') END AS checksum FROM \"$upgraders$\" CROSS JOIN LATERAL"));
+        assert!(!sql.contains("checksum,"), "{}", sql);
+
+        // Checksums recorded with store_checksums are kept.
+        let sql = checksum_select_sql("t", &columns(&["file_id", "text", "checksum"]));
+        assert!(sql.contains("COALESCE(checksum, CASE WHEN"), "{}", sql);
+    }
+
+    #[test]
+    fn test_ascii_whitespace_matches_trim() {
+        // \013 is the vertical tab.
+        let trimmed: String = (0..128u8)
+            .map(char::from)
+            .filter(|c| c.is_whitespace())
+            .collect();
+        assert_eq!(trimmed, String::from_iter(ASCII_WHITESPACE));
+        assert_eq!(ASCII_WHITESPACE_LITERAL, r"E'\t\n\013\f\r '");
+    }
+
+    #[test]
+    fn test_checksum() {
+        // Reference value of SHA-256; changing it would make every stored checksum mismatch.
+        assert_eq!(
+            checksum("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(checksum("  abc\n"), checksum("abc"));
+        assert_eq!(checksum("\u{b}abc\u{c}"), checksum("abc"));
+        // Like btrim in the database, other whitespace is kept.
+        assert_ne!(checksum("\u{a0}abc"), checksum("abc"));
+    }
+
+    #[cfg(feature = "tokio-postgres")]
+    #[test]
+    fn test_scratch_schema_name() {
+        let first = scratch_schema_name();
+        let second = scratch_schema_name();
+        assert_ne!(first, second);
+        assert!(first.starts_with("upgrader_scratch_"));
+        assert!(crate::identifier::is_simple_identifier(&first));
+    }
 }
@@ -0,0 +1,125 @@
+use crate::schema_loader::{
+    SchemaUpgrader, parse_file, upgrader_file_id, validate_file_ids, validate_sequence,
+};
+use crate::{PostgresUpgraderOptions, UpgraderError};
+use std::path::Path;
+
+/// Upgrader files compiled into the executable, for single-binary deployments that cannot
+/// ship an upgraders folder alongside it.
+///
+/// Files follow the same naming and format rules as those of an upgraders folder; only the
+/// file name part of each name is used to derive the file id. Build one with
+/// [`embed_migrations!`](crate::embed_migrations), or with [`file`](Self::file) from contents
+/// obtained otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct Migrations {
+    pub(crate) files: Vec<(String, String)>,
+}
+
+impl Migrations {
+    /// Creates an empty set of files.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the upgrader file `name`, e.g. `000_init.sql` or `upgraders/000_init.sql`, with
+    /// its `content`.
+    pub fn file(mut self, name: impl Into<String>, content: impl Into<String>) -> Self {
+        self.files.push((name.into(), content.into()));
+        self
+    }
+
+    /// Parses the files with one of the configured extensions into upgraders, with the same
+    /// validation as an upgraders folder.
+    pub(crate) fn load(
+        &self,
+        options: &PostgresUpgraderOptions,
+    ) -> Result<Vec<SchemaUpgrader>, UpgraderError> {
+        let extensions = options.extensions();
+        let mut files: Vec<(i32, &Path, &str)> = Vec::new();
+        for (name, content) in &self.files {
+            let path = Path::new(name);
+            if let Some(id) = upgrader_file_id(path, &extensions)? {
+                files.push((id, path, content));
+            }
+        }
+
+        files.sort_by_key(|k| k.0);
+
+        validate_file_ids(files.iter().map(|(id, path, _)| (*id, *path)))?;
+
+        let mut upgraders = Vec::new();
+        for (file_id, path, content) in files {
+            upgraders.extend(parse_file(
+                file_id,
+                path,
+                content,
+                options.allow_empty_upgraders,
+            )?);
+        }
+        validate_sequence(&upgraders, options.allow_empty_upgraders)?;
+        Ok(upgraders)
+    }
+}
+
+/// Embeds upgrader files into the executable at build time and returns them as
+/// [`Migrations`]. Paths are relative to the directory of the calling crate's `Cargo.toml`.
+#[macro_export]
+macro_rules! embed_migrations {
+    ($($path:literal),* $(,)?) => {
+        $crate::Migrations::new()
+            $(.file($path, include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/", $path))))*
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_embedded() {
+        let migrations = Migrations::new()
+            .file(
+                "upgraders/001_orders.sql",
+                "--- 0: Create orders\nCREATE TABLE orders (id INT);",
+            )
+            .file("README.md", "Not an upgrader")
+            .file(
+                "upgraders/000_init.sql",
+                "--- 0: Create users\nCREATE TABLE users (id INT);\n--- 0 down:\nDROP TABLE users;",
+            );
+
+        let upgraders = migrations
+            .load(&PostgresUpgraderOptions::default())
+            .unwrap();
+        let loaded: Vec<_> = upgraders
+            .iter()
+            .map(|u| (u.file_id, u.upgrader_id, u.description.as_str()))
+            .collect();
+        assert_eq!(
+            loaded,
+            vec![(0, 0, "Create users"), (1, 0, "Create orders")]
+        );
+        assert_eq!(upgraders[0].down_text.as_deref(), Some("DROP TABLE users;"));
+    }
+
+    #[test]
+    fn test_load_embedded_validates() {
+        let gap = Migrations::new()
+            .file("000_init.sql", "--- 0: Init\nSELECT 1;")
+            .file("002_more.sql", "--- 0: More\nSELECT 1;");
+        match gap.load(&PostgresUpgraderOptions::default()).unwrap_err() {
+            UpgraderError::LoaderError(e) => assert!(e.contains("Missing file ID 1")),
+            other => panic!("Expected LoaderError, got {:?}", other),
+        }
+
+        let bad_header = Migrations::new().file("000_init.sql", "--- x: Init\nSELECT 1;");
+        match bad_header
+            .load(&PostgresUpgraderOptions::default())
+            .unwrap_err()
+        {
+            UpgraderError::LoaderError(e) => assert!(e.contains("000_init.sql")),
+            other => panic!("Expected LoaderError, got {:?}", other),
+        }
+    }
+}
@@ -2,9 +2,35 @@
 pub enum UpgraderError {
     ConnectionError(String),
     ExecutionError(String),
+    /// The SQL of an upgrader failed while it was applied; unless it is a `(no-transaction)`
+    /// upgrader, its changes were rolled back. `source` is the database error, shortened to
+    /// `max_error_chars`. With `debug_statements`, `statement` is the 1-based position of the
+    /// failing statement within the upgrader.
+    ExecutionFailed {
+        file_id: i32,
+        upgrader_id: i32,
+        statement: Option<usize>,
+        source: String,
+    },
     ConfigurationError(String),
     LoaderError(String),
     IntegrityError(String),
+    /// Upgraders applied to a scratch schema did not pass the caller's check.
+    VerificationError(String),
+    /// An upgrader that takes heavy locks was due outside the configured maintenance window.
+    MaintenanceWindowError(String),
+    /// PostgreSQL cancelled a statement of the upgrade after the configured `lock_timeout` or
+    /// `statement_timeout`. The upgrader it belonged to was rolled back.
+    TimeoutError(String),
+    /// An advisory lock serializing upgrades was still held by another process when the
+    /// configured `lock_wait_timeout` elapsed. The upgrader waiting for it was not applied;
+    /// upgraders committed before it remain applied.
+    LockTimeout(String),
+    /// The upgrade was interrupted by a shutdown request. Upgraders committed before the
+    /// interruption remain applied; `last_committed` is the most recent of them, if any.
+    Cancelled {
+        last_committed: Option<(i32, i32)>,
+    },
 }
 
 impl std::fmt::Display for UpgraderError {
@@ -12,11 +38,139 @@ impl std::fmt::Display for UpgraderError {
         match self {
             UpgraderError::ConnectionError(msg) => write!(f, "Connection error: {}", msg),
             UpgraderError::ExecutionError(msg) => write!(f, "Execution error: {}", msg),
+            UpgraderError::ExecutionFailed {
+                file_id,
+                upgrader_id,
+                statement: Some(statement),
+                source,
+            } => write!(
+                f,
+                "Execution error: statement {} of upgrader {}:{} failed: {}",
+                statement, file_id, upgrader_id, source
+            ),
+            UpgraderError::ExecutionFailed {
+                file_id,
+                upgrader_id,
+                statement: None,
+                source,
+            } => write!(
+                f,
+                "Execution error: upgrader {}:{} failed: {}",
+                file_id, upgrader_id, source
+            ),
             UpgraderError::ConfigurationError(msg) => write!(f, "Configuration error: {}", msg),
             UpgraderError::LoaderError(msg) => write!(f, "Loader error: {}", msg),
             UpgraderError::IntegrityError(msg) => write!(f, "Integrity error: {}", msg),
+            UpgraderError::VerificationError(msg) => write!(f, "Verification error: {}", msg),
+            UpgraderError::MaintenanceWindowError(msg) => {
+                write!(f, "Maintenance window error: {}", msg)
+            }
+            UpgraderError::TimeoutError(msg) => write!(f, "Timeout error: {}", msg),
+            UpgraderError::LockTimeout(msg) => write!(f, "Lock timeout: {}", msg),
+            UpgraderError::Cancelled {
+                last_committed: Some((file_id, upgrader_id)),
+            } => write!(
+                f,
+                "Cancelled: last committed migration was {}:{}",
+                file_id, upgrader_id
+            ),
+            UpgraderError::Cancelled {
+                last_committed: None,
+            } => write!(f, "Cancelled: no migration has been committed"),
         }
     }
 }
 
 impl std::error::Error for UpgraderError {}
+
+/// SQLSTATE `cannot_connect_now`, sent while the server is starting up or shutting down.
+const CANNOT_CONNECT_NOW: &str = "57P03";
+
+/// Returns `true` if a failure to connect with SQLSTATE `code` (`None` if the server did not
+/// answer, e.g. because it is not listening yet) may pass when retried. Errors such as a wrong
+/// password would not.
+pub(crate) fn is_transient_connect_error(code: Option<&str>) -> bool {
+    code.is_none_or(|code| code == CANNOT_CONNECT_NOW)
+}
+
+/// Default for the `max_error_chars` option.
+pub(crate) const DEFAULT_MAX_ERROR_CHARS: usize = 4096;
+
+/// Appended to error messages shortened by [`truncate_error`].
+const TRUNCATED_SUFFIX: &str = "...(truncated)";
+
+/// Shortens a database error message to at most `max_chars` characters, keeping its beginning,
+/// and appends the SQLSTATE `code`, which is never cut off.
+pub(crate) fn truncate_error(message: &str, code: Option<&str>, max_chars: usize) -> String {
+    let mut truncated = match message.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}{}", &message[..end], TRUNCATED_SUFFIX),
+        None => message.to_string(),
+    };
+    if let Some(code) = code {
+        truncated.push_str(&format!(" (SQLSTATE {})", code));
+    }
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_transient_connect_error() {
+        assert!(is_transient_connect_error(None));
+        assert!(is_transient_connect_error(Some("57P03")));
+        // invalid_password and invalid_catalog_name
+        assert!(!is_transient_connect_error(Some("28P01")));
+        assert!(!is_transient_connect_error(Some("3D000")));
+    }
+
+    #[test]
+    fn test_truncate_error_short_message_unchanged() {
+        assert_eq!(truncate_error("db error", None, 100), "db error");
+        assert_eq!(
+            truncate_error("db error", Some("42P01"), 8),
+            "db error (SQLSTATE 42P01)"
+        );
+    }
+
+    #[test]
+    fn test_truncate_error_long_message() {
+        let message = format!("ERROR: duplicate key{}", "x".repeat(10_000));
+        let truncated = truncate_error(&message, Some("23505"), 20);
+        assert_eq!(
+            truncated,
+            "ERROR: duplicate key...(truncated) (SQLSTATE 23505)"
+        );
+    }
+
+    #[test]
+    fn test_truncate_error_multibyte() {
+        assert_eq!(truncate_error("ééé", None, 2), "éé...(truncated)");
+    }
+
+    #[test]
+    fn test_execution_failed_display() {
+        let error = UpgraderError::ExecutionFailed {
+            file_id: 2,
+            upgrader_id: 1,
+            statement: None,
+            source: "db error: ERROR: relation \"users\" does not exist".to_string(),
+        };
+        assert_eq!(
+            error.to_string(),
+            "Execution error: upgrader 2:1 failed: db error: ERROR: relation \"users\" does not exist"
+        );
+
+        let error = UpgraderError::ExecutionFailed {
+            file_id: 1,
+            upgrader_id: 0,
+            statement: Some(3),
+            source: "db error: ERROR: division by zero".to_string(),
+        };
+        assert_eq!(
+            error.to_string(),
+            "Execution error: statement 3 of upgrader 1:0 failed: db error: ERROR: division by zero"
+        );
+    }
+}
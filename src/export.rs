@@ -0,0 +1,288 @@
+use crate::db_tracker::AppliedUpgrader;
+use crate::schema_loader::load_upgraders;
+use crate::{PostgresUpgraderOptions, UpgraderError};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Maximum length of the description part of exported file names.
+const MAX_SLUG_LENGTH: usize = 50;
+
+/// Renders applied upgraders as upgrader files, returned as `(file name, content)` pairs.
+///
+/// Fails if the history cannot be written in a form the loader reads back identically: ids
+/// with gaps, SQL that is empty, contains a header line or carriage returns, or descriptions
+/// that span lines or have surrounding whitespace.
+pub(crate) fn render_files(
+    applied: &[AppliedUpgrader],
+) -> Result<Vec<(String, String)>, UpgraderError> {
+    let mut files: Vec<(String, String)> = Vec::new();
+    let mut expected = (0, 0);
+
+    for upgrader in applied {
+        let ids = (upgrader.file_id, upgrader.upgrader_id);
+        if ids == (expected.0 + 1, 0) && !files.is_empty() {
+            expected = ids;
+        }
+        if ids != expected {
+            let reason = if files.is_empty() {
+                "expected 0:0 instead".to_string()
+            } else {
+                format!(
+                    "expected {}:{} or {}:0 instead",
+                    expected.0,
+                    expected.1,
+                    expected.0 + 1
+                )
+            };
+            return Err(unexportable(upgrader, &reason));
+        }
+        expected.1 += 1;
+        check_exportable(upgrader)?;
+
+        if upgrader.upgrader_id == 0 {
+            let name = format!(
+                "{:03}_{}.sql",
+                upgrader.file_id,
+                slug(&upgrader.description)
+            );
+            files.push((name, String::new()));
+        }
+        let (_, content) = files.last_mut().expect("a file is started at upgrader 0");
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        content.push_str(&format!(
+            "--- {}: {}\n{}\n",
+            upgrader.upgrader_id,
+            upgrader.description,
+            upgrader.text.trim()
+        ));
+    }
+    Ok(files)
+}
+
+/// Writes the files for `applied` into `out_dir`, which must be empty or not exist yet, and
+/// checks that they load back as `applied`.
+pub(crate) fn write_files(
+    out_dir: &Path,
+    applied: &[AppliedUpgrader],
+) -> Result<Vec<PathBuf>, UpgraderError> {
+    let files = render_files(applied)?;
+
+    let io_error = |e: std::io::Error| {
+        UpgraderError::ExecutionError(format!("Failed to export to {:?}: {}", out_dir, e))
+    };
+    fs::create_dir_all(out_dir).map_err(io_error)?;
+    if fs::read_dir(out_dir).map_err(io_error)?.next().is_some() {
+        return Err(UpgraderError::ConfigurationError(format!(
+            "Export folder {:?} is not empty.",
+            out_dir
+        )));
+    }
+
+    let mut paths = Vec::with_capacity(files.len());
+    for (name, content) in files {
+        let path = out_dir.join(name);
+        fs::write(&path, content).map_err(io_error)?;
+        paths.push(path);
+    }
+
+    // Checked field by field rather than with the integrity check, which would also reject
+    // histories that were not applied in id order. Recorded upgraders without SQL are part of
+    // the history, so empty ones are loaded too.
+    let options = PostgresUpgraderOptions::builder()
+        .allow_empty_upgraders(true)
+        .build();
+    let loaded = load_upgraders(out_dir, &options)?;
+    let identical = loaded.len() == applied.len()
+        && loaded.iter().zip(applied).all(|(file_u, db_u)| {
+            (file_u.file_id, file_u.upgrader_id) == (db_u.file_id, db_u.upgrader_id)
+                && file_u.description == db_u.description
+                && file_u.text == db_u.text.trim()
+        });
+    if !identical {
+        return Err(UpgraderError::ExecutionError(format!(
+            "Files exported to {:?} do not load back identically.",
+            out_dir
+        )));
+    }
+    Ok(paths)
+}
+
+fn check_exportable(upgrader: &AppliedUpgrader) -> Result<(), UpgraderError> {
+    let description = &upgrader.description;
+    if description.contains(['\n', '\r']) || description.trim() != description {
+        return Err(unexportable(
+            upgrader,
+            "its description spans lines or has surrounding whitespace",
+        ));
+    }
+
+    if upgrader.checksum.is_some() && upgrader.text.is_empty() {
+        return Err(unexportable(
+            upgrader,
+            "only its checksum was recorded, with store_checksums",
+        ));
+    }
+    let text = upgrader.text.trim();
+    if text.is_empty() {
+        return Err(unexportable(upgrader, "its SQL is empty"));
+    }
+    if text.contains('\r') {
+        return Err(unexportable(upgrader, "its SQL contains carriage returns"));
+    }
+    if text.lines().any(|line| line.starts_with("--- ")) {
+        return Err(unexportable(
+            upgrader,
+            "its SQL contains a line that would be read as an upgrader header",
+        ));
+    }
+    Ok(())
+}
+
+fn unexportable(upgrader: &AppliedUpgrader, reason: &str) -> UpgraderError {
+    UpgraderError::ConfigurationError(format!(
+        "Upgrader {}:{} cannot be exported: {}.",
+        upgrader.file_id, upgrader.upgrader_id, reason
+    ))
+}
+
+/// Turns a description into the lowercase, underscore-separated part of a file name.
+fn slug(description: &str) -> String {
+    let mut slug = String::new();
+    for c in description.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('_') {
+            slug.push('_');
+        }
+        if slug.len() >= MAX_SLUG_LENGTH {
+            break;
+        }
+    }
+    let slug = slug.trim_end_matches('_');
+    if slug.is_empty() {
+        "upgraders".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use tempfile::tempdir;
+
+    fn applied(file_id: i32, upgrader_id: i32, description: &str, text: &str) -> AppliedUpgrader {
+        AppliedUpgrader {
+            file_id,
+            upgrader_id,
+            description: description.to_string(),
+            text: text.to_string(),
+            applied_on: Utc::now(),
+            sequence: None,
+            applied_host: None,
+            applied_by: None,
+            last_verified_on: None,
+            checksum: None,
+        }
+    }
+
+    fn history() -> Vec<AppliedUpgrader> {
+        vec![
+            applied(0, 0, "Create users", "CREATE TABLE users (id INT);"),
+            applied(
+                0,
+                1,
+                "Add email: unique",
+                "-- keep emails unique\nALTER TABLE users ADD COLUMN email TEXT UNIQUE;",
+            ),
+            applied(1, 0, "Orders & items!", "CREATE TABLE orders (id INT);"),
+        ]
+    }
+
+    #[test]
+    fn test_render_files() {
+        let files = render_files(&history()).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                (
+                    "000_create_users.sql".to_string(),
+                    "--- 0: Create users\nCREATE TABLE users (id INT);\n\n\
+                     --- 1: Add email: unique\n-- keep emails unique\n\
+                     ALTER TABLE users ADD COLUMN email TEXT UNIQUE;\n"
+                        .to_string()
+                ),
+                (
+                    "001_orders_items.sql".to_string(),
+                    "--- 0: Orders & items!\nCREATE TABLE orders (id INT);\n".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_files_round_trip() {
+        let dir = tempdir().unwrap();
+        let out_dir = dir.path().join("exported");
+        let paths = write_files(&out_dir, &history()).unwrap();
+        assert_eq!(paths.len(), 2);
+
+        let loaded = load_upgraders(&out_dir, &PostgresUpgraderOptions::default()).unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded[1].description, "Add email: unique");
+        assert_eq!(loaded[1].text, history()[1].text);
+
+        // Exporting again would mix two histories.
+        let result = write_files(&out_dir, &history());
+        assert!(matches!(result, Err(UpgraderError::ConfigurationError(_))));
+    }
+
+    #[test]
+    fn test_render_files_rejects_gaps() {
+        let mut history = history();
+        history.remove(1);
+        history[1].file_id = 2;
+        let err = render_files(&history).unwrap_err();
+        assert!(err.to_string().contains("expected 0:1 or 1:0"));
+
+        let err = render_files(&[applied(0, 1, "Late", "SELECT 1;")]).unwrap_err();
+        assert!(err.to_string().contains("expected 0:0"));
+    }
+
+    #[test]
+    fn test_render_files_rejects_unloadable_content() {
+        for upgrader in [
+            applied(0, 0, "Two\nlines", "SELECT 1;"),
+            applied(0, 0, " Padded ", "SELECT 1;"),
+            applied(0, 0, "Empty", "  \n"),
+            applied(0, 0, "Windows", "SELECT 1;\r\nSELECT 2;"),
+            applied(0, 0, "Header", "SELECT 1;\n--- 1: Not a header\nSELECT 2;"),
+        ] {
+            assert!(matches!(
+                render_files(&[upgrader]),
+                Err(UpgraderError::ConfigurationError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_render_files_rejects_checksum_only() {
+        let upgrader = AppliedUpgrader {
+            checksum: Some(crate::db_tracker::checksum("SELECT 1;")),
+            ..applied(0, 0, "Hashed", "")
+        };
+        let err = render_files(&[upgrader]).unwrap_err();
+        assert!(err.to_string().contains("only its checksum was recorded"));
+    }
+
+    #[test]
+    fn test_slug() {
+        assert_eq!(slug("Create Users"), "create_users");
+        assert_eq!(slug("  -- Add index (email) --"), "add_index_email");
+        assert_eq!(slug("!!!"), "upgraders");
+        assert_eq!(slug(&"a".repeat(80)).len(), MAX_SLUG_LENGTH);
+    }
+}
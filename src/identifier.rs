@@ -0,0 +1,178 @@
+/// Key words that PostgreSQL reserves and which therefore cannot be used as unquoted identifiers.
+const RESERVED_KEYWORDS: &[&str] = &[
+    "all",
+    "analyse",
+    "analyze",
+    "and",
+    "any",
+    "array",
+    "as",
+    "asc",
+    "asymmetric",
+    "authorization",
+    "binary",
+    "both",
+    "case",
+    "cast",
+    "check",
+    "collate",
+    "collation",
+    "column",
+    "concurrently",
+    "constraint",
+    "create",
+    "cross",
+    "current_catalog",
+    "current_date",
+    "current_role",
+    "current_schema",
+    "current_time",
+    "current_timestamp",
+    "current_user",
+    "default",
+    "deferrable",
+    "desc",
+    "distinct",
+    "do",
+    "else",
+    "end",
+    "except",
+    "false",
+    "fetch",
+    "for",
+    "foreign",
+    "freeze",
+    "from",
+    "full",
+    "grant",
+    "group",
+    "having",
+    "ilike",
+    "in",
+    "initially",
+    "inner",
+    "intersect",
+    "into",
+    "is",
+    "isnull",
+    "join",
+    "lateral",
+    "leading",
+    "left",
+    "like",
+    "limit",
+    "localtime",
+    "localtimestamp",
+    "natural",
+    "not",
+    "notnull",
+    "null",
+    "offset",
+    "on",
+    "only",
+    "or",
+    "order",
+    "outer",
+    "overlaps",
+    "placing",
+    "primary",
+    "references",
+    "returning",
+    "right",
+    "select",
+    "session_user",
+    "similar",
+    "some",
+    "symmetric",
+    "system_user",
+    "table",
+    "tablesample",
+    "then",
+    "to",
+    "trailing",
+    "true",
+    "union",
+    "unique",
+    "user",
+    "using",
+    "variadic",
+    "verbose",
+    "when",
+    "where",
+    "window",
+    "with",
+];
+
+/// Maximum identifier length before PostgreSQL truncates it (`NAMEDATALEN - 1`).
+const MAX_IDENTIFIER_LENGTH: usize = 63;
+
+/// Returns `true` if `name` refers to itself when written without quotes, i.e. it is a
+/// lowercase identifier that PostgreSQL's case folding leaves unchanged and is not reserved.
+pub(crate) fn is_simple_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    let starts_ok = matches!(chars.next(), Some(c) if c.is_ascii_lowercase() || c == '_');
+
+    starts_ok
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '$')
+        && name.len() <= MAX_IDENTIFIER_LENGTH
+        && !RESERVED_KEYWORDS.contains(&name)
+}
+
+/// Returns `true` if `name` can be used as a double-quoted identifier without being truncated
+/// or escaping its quotes.
+pub(crate) fn is_valid_identifier(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_IDENTIFIER_LENGTH && !name.contains(['"', '\0'])
+}
+
+/// Wraps an identifier in double quotes, doubling any it contains so that it cannot end the
+/// quoted identifier early, even where it was not checked with [`is_valid_identifier`].
+pub(crate) fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simple_identifiers() {
+        assert!(is_simple_identifier("upgraders"));
+        assert!(is_simple_identifier("_upgraders"));
+        assert!(is_simple_identifier("schema_upgraders_v2"));
+        assert!(is_simple_identifier("upgraders$"));
+    }
+
+    #[test]
+    fn test_identifiers_requiring_quotes() {
+        assert!(!is_simple_identifier(""));
+        assert!(!is_simple_identifier("$upgraders$"));
+        assert!(!is_simple_identifier("Upgraders"));
+        assert!(!is_simple_identifier("2upgraders"));
+        assert!(!is_simple_identifier("my-upgraders"));
+        assert!(!is_simple_identifier("my upgraders"));
+        assert!(!is_simple_identifier("order"));
+        assert!(!is_simple_identifier("user"));
+        assert!(!is_simple_identifier(&"a".repeat(64)));
+    }
+
+    #[test]
+    fn test_valid_identifiers() {
+        assert!(is_valid_identifier("tenant_a"));
+        assert!(is_valid_identifier("Tenant A"));
+        assert!(is_valid_identifier("$upgraders$"));
+        assert!(!is_valid_identifier(""));
+        assert!(!is_valid_identifier("tenant\"a"));
+        assert!(!is_valid_identifier("tenant\0a"));
+        assert!(!is_valid_identifier(&"a".repeat(64)));
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("$upgraders$"), "\"$upgraders$\"");
+        assert_eq!(quote("MySchema"), "\"MySchema\"");
+        assert_eq!(
+            quote("a\"; DROP TABLE t; --"),
+            "\"a\"\"; DROP TABLE t; --\""
+        );
+    }
+}
@@ -1,27 +1,174 @@
-use crate::UpgraderError;
 use crate::db_tracker::AppliedUpgrader;
 use crate::schema_loader::SchemaUpgrader;
+use crate::{PostgresUpgraderOptions, UpgraderError};
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+
+/// A mismatch between the migration files and the upgraders recorded in the database.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum IntegrityViolation {
+    /// An upgrader was applied before the upgrader preceding it.
+    OutOfOrder {
+        file_id: i32,
+        upgrader_id: i32,
+        applied_on: DateTime<Utc>,
+        previous_applied_on: DateTime<Utc>,
+    },
+    /// A file upgrader is missing in the database while a later one is present.
+    Gap {
+        missing: (i32, i32),
+        present: (i32, i32),
+    },
+    /// The database contains an upgrader that is not in the migration files.
+    MissingFromFiles { file_id: i32, upgrader_id: i32 },
+    /// The SQL of an applied upgrader differs from its file.
+    TextChanged { file_id: i32, upgrader_id: i32 },
+    /// The description of an applied upgrader differs from its file.
+    DescriptionChanged {
+        file_id: i32,
+        upgrader_id: i32,
+        file_description: String,
+        db_description: String,
+    },
+}
+
+impl IntegrityViolation {
+    /// A short, stable name for the kind of violation.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            IntegrityViolation::OutOfOrder { .. } => "out_of_order",
+            IntegrityViolation::Gap { .. } => "gap",
+            IntegrityViolation::MissingFromFiles { .. } => "missing_from_files",
+            IntegrityViolation::TextChanged { .. } => "text_changed",
+            IntegrityViolation::DescriptionChanged { .. } => "description_changed",
+        }
+    }
+
+    /// The `(file_id, upgrader_id)` of the offending upgrader.
+    pub(crate) fn ids(&self) -> (i32, i32) {
+        match self {
+            IntegrityViolation::OutOfOrder {
+                file_id,
+                upgrader_id,
+                ..
+            }
+            | IntegrityViolation::MissingFromFiles {
+                file_id,
+                upgrader_id,
+            }
+            | IntegrityViolation::TextChanged {
+                file_id,
+                upgrader_id,
+            }
+            | IntegrityViolation::DescriptionChanged {
+                file_id,
+                upgrader_id,
+                ..
+            } => (*file_id, *upgrader_id),
+            IntegrityViolation::Gap { missing, .. } => *missing,
+        }
+    }
+}
+
+impl std::fmt::Display for IntegrityViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntegrityViolation::OutOfOrder {
+                file_id,
+                upgrader_id,
+                applied_on,
+                previous_applied_on,
+            } => write!(
+                f,
+                "Upgrader {}:{} was applied at {}, which is before the previous upgrader ({})",
+                file_id, upgrader_id, applied_on, previous_applied_on
+            ),
+            IntegrityViolation::Gap { missing, present } => write!(
+                f,
+                "Gap detected in database migrations. File upgrader {}:{} is missing in database, but later upgrader {}:{} is present.",
+                missing.0, missing.1, present.0, present.1
+            ),
+            IntegrityViolation::MissingFromFiles {
+                file_id,
+                upgrader_id,
+            } => write!(
+                f,
+                "Database contains an upgrader {}:{} that is missing from the migration files.",
+                file_id, upgrader_id
+            ),
+            IntegrityViolation::TextChanged {
+                file_id,
+                upgrader_id,
+            } => write!(
+                f,
+                "Upgrader {}:{}. SQL content has changed.",
+                file_id, upgrader_id
+            ),
+            IntegrityViolation::DescriptionChanged {
+                file_id,
+                upgrader_id,
+                file_description,
+                db_description,
+            } => write!(
+                f,
+                "Upgrader {}:{}. Description has changed.\nFile: '{}'\nDB:   '{}'",
+                file_id, upgrader_id, file_description, db_description
+            ),
+        }
+    }
+}
+
+impl From<IntegrityViolation> for UpgraderError {
+    fn from(violation: IntegrityViolation) -> Self {
+        UpgraderError::IntegrityError(violation.to_string())
+    }
+}
 
 /// Verifies the integrity of the database schema by comparing file-based upgraders with applied ones.
 ///
 /// This function assumes that both `files_upgraders` and `db_upgraders` are sorted by `file_id`
 /// and `upgrader_id` in ascending order.
+///
+/// SQL content is compared with the `content_comparator` from `options` if one is set, and
+/// ignoring leading and trailing whitespace otherwise.
 pub fn verify_integrity(
     files_upgraders: &[SchemaUpgrader],
     db_upgraders: &[AppliedUpgrader],
+    options: &PostgresUpgraderOptions,
 ) -> Result<(), UpgraderError> {
-    // Verify chronological order of application
-    let mut prev_applied_on = None;
+    find_violation(files_upgraders, db_upgraders, options).map_err(UpgraderError::from)
+}
+
+/// Like [`verify_integrity`], but returns the violation in structured form.
+pub(crate) fn find_violation(
+    files_upgraders: &[SchemaUpgrader],
+    db_upgraders: &[AppliedUpgrader],
+    options: &PostgresUpgraderOptions,
+) -> Result<(), IntegrityViolation> {
+    if options.allow_out_of_order {
+        return find_violation_out_of_order(files_upgraders, db_upgraders, options);
+    }
+
+    // Verify chronological order of application. Within the skew tolerance an upgrader may
+    // lie before the latest one so far; measuring against the latest keeps small regressions
+    // from adding up.
+    let tolerance = chrono::TimeDelta::from_std(options.applied_on_skew_tolerance)
+        .unwrap_or(chrono::TimeDelta::MAX);
+    let mut latest_applied_on: Option<DateTime<Utc>> = None;
     for db_u in db_upgraders {
-        if let Some(prev) = prev_applied_on
-            && db_u.applied_on < prev
+        if let Some(latest) = latest_applied_on
+            && latest
+                .checked_sub_signed(tolerance)
+                .is_some_and(|bound| db_u.applied_on < bound)
         {
-            return Err(UpgraderError::IntegrityError(format!(
-                "Upgrader {}:{} was applied at {}, which is before the previous upgrader ({})",
-                db_u.file_id, db_u.upgrader_id, db_u.applied_on, prev
-            )));
+            return Err(IntegrityViolation::OutOfOrder {
+                file_id: db_u.file_id,
+                upgrader_id: db_u.upgrader_id,
+                applied_on: db_u.applied_on,
+                previous_applied_on: latest,
+            });
         }
-        prev_applied_on = Some(db_u.applied_on);
+        latest_applied_on = latest_applied_on.max(Some(db_u.applied_on));
     }
 
     let mut files_iter = files_upgraders.iter();
@@ -43,35 +190,23 @@ pub fn verify_integrity(
                     if file_tuple < db_tuple {
                         // File has an upgrader that is "before" the current DB upgrader.
                         // Since we traverse in order, this means the DB skipped this upgrader.
-                        return Err(UpgraderError::IntegrityError(format!(
-                            "Gap detected in database migrations. File upgrader {}:{} is missing in database, but later upgrader {}:{} is present.",
-                            file_u.file_id, file_u.upgrader_id, db_u.file_id, db_u.upgrader_id
-                        )));
+                        return Err(IntegrityViolation::Gap {
+                            missing: file_tuple,
+                            present: db_tuple,
+                        });
                     } else {
                         // File tuple > DB tuple.
                         // This means the DB has an upgrader that is "before" the current File upgrader,
                         // but we didn't see it in the Files list (otherwise we would have matched it previously).
-                        return Err(UpgraderError::IntegrityError(format!(
-                            "Database contains an upgrader {}:{} that is missing from the migration files.",
-                            db_u.file_id, db_u.upgrader_id
-                        )));
+                        return Err(IntegrityViolation::MissingFromFiles {
+                            file_id: db_u.file_id,
+                            upgrader_id: db_u.upgrader_id,
+                        });
                     }
                 }
 
                 // 2. Check Content
-                if file_u.text.trim() != db_u.text.trim() {
-                    return Err(UpgraderError::IntegrityError(format!(
-                        "Upgrader {}:{}. SQL content has changed.",
-                        file_u.file_id, file_u.upgrader_id
-                    )));
-                }
-
-                if file_u.description.trim() != db_u.description.trim() {
-                    return Err(UpgraderError::IntegrityError(format!(
-                        "Upgrader {}:{}. Description has changed.\nFile: '{}'\nDB:   '{}'",
-                        file_u.file_id, file_u.upgrader_id, file_u.description, db_u.description
-                    )));
-                }
+                check_content(file_u, db_u, options)?;
             }
             (Some(_), None) => {
                 // More files than DB. This is normal (pending migrations).
@@ -95,10 +230,159 @@ pub fn verify_integrity(
     }
 }
 
+/// Checks the history before an upgrade applies pending upgraders, unless the integrity check
+/// is disabled with `verify_integrity(false)`.
+pub(crate) fn check_before_apply(
+    files_upgraders: &[SchemaUpgrader],
+    db_upgraders: &[AppliedUpgrader],
+    options: &PostgresUpgraderOptions,
+) -> Result<(), IntegrityViolation> {
+    if options.skip_integrity_check {
+        return Ok(());
+    }
+    find_violation(files_upgraders, db_upgraders, options)
+}
+
+/// Integrity rules for `allow_out_of_order`: the database may skip upgraders and may have
+/// received them in any chronological order, but every recorded upgrader within the range
+/// covered by the files must still exist in the files and match them.
+fn find_violation_out_of_order(
+    files_upgraders: &[SchemaUpgrader],
+    db_upgraders: &[AppliedUpgrader],
+    options: &PostgresUpgraderOptions,
+) -> Result<(), IntegrityViolation> {
+    let last_file = files_upgraders.last().map(|u| (u.file_id, u.upgrader_id));
+
+    for db_u in db_upgraders {
+        let db_tuple = (db_u.file_id, db_u.upgrader_id);
+        match files_upgraders.binary_search_by_key(&db_tuple, |u| (u.file_id, u.upgrader_id)) {
+            Ok(index) => check_content(&files_upgraders[index], db_u, options)?,
+            // Past the last file: the codebase is older than the database, which is allowed
+            // just like in the ordered mode.
+            Err(_) if last_file.is_none_or(|last| db_tuple > last) => {}
+            Err(_) => {
+                return Err(IntegrityViolation::MissingFromFiles {
+                    file_id: db_u.file_id,
+                    upgrader_id: db_u.upgrader_id,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that an applied upgrader still has the SQL and description of its file, unless it
+/// is one of the `frozen_upgraders`.
+fn check_content(
+    file_u: &SchemaUpgrader,
+    db_u: &AppliedUpgrader,
+    options: &PostgresUpgraderOptions,
+) -> Result<(), IntegrityViolation> {
+    if options.is_frozen((file_u.file_id, file_u.upgrader_id)) {
+        return Ok(());
+    }
+
+    // Upgraders recorded before store_checksums was enabled have no checksum and keep being
+    // compared by text, which matches exactly when their checksums would.
+    let same_content = match (&db_u.checksum, &options.content_comparator) {
+        (Some(checksum), _) => crate::db_tracker::checksum(&file_u.text) == *checksum,
+        (None, Some(comparator)) => comparator(&file_u.text, &db_u.text),
+        (None, None) => file_u.text.trim() == db_u.text.trim(),
+    };
+    if !same_content {
+        return Err(IntegrityViolation::TextChanged {
+            file_id: file_u.file_id,
+            upgrader_id: file_u.upgrader_id,
+        });
+    }
+
+    if file_u.description.trim() != db_u.description.trim() {
+        return Err(IntegrityViolation::DescriptionChanged {
+            file_id: file_u.file_id,
+            upgrader_id: file_u.upgrader_id,
+            file_description: file_u.description.clone(),
+            db_description: db_u.description.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// Returns the recorded upgraders whose SQL or description differ from their files, rewritten
+/// to match them, for `repair_*`. Fails with the violation that would remain if the recorded
+/// history also diverges from the files in order or identity, which repairing cannot fix.
+pub(crate) fn repairable_upgraders(
+    files_upgraders: &[SchemaUpgrader],
+    db_upgraders: &[AppliedUpgrader],
+    options: &PostgresUpgraderOptions,
+) -> Result<Vec<AppliedUpgrader>, IntegrityViolation> {
+    let mut repaired = db_upgraders.to_vec();
+    let mut changed = Vec::new();
+    for (index, db_u) in repaired.iter_mut().enumerate() {
+        let db_tuple = (db_u.file_id, db_u.upgrader_id);
+        let Ok(file_index) =
+            files_upgraders.binary_search_by_key(&db_tuple, |u| (u.file_id, u.upgrader_id))
+        else {
+            continue;
+        };
+        let file_u = &files_upgraders[file_index];
+        if check_content(file_u, db_u, options).is_ok() {
+            continue;
+        }
+        db_u.description = file_u.description.clone();
+        match &mut db_u.checksum {
+            Some(checksum) => *checksum = crate::db_tracker::checksum(&file_u.text),
+            None => db_u.text = file_u.text.clone(),
+        }
+        changed.push(index);
+    }
+
+    find_violation(files_upgraders, &repaired, options)?;
+    Ok(changed
+        .into_iter()
+        .map(|index| repaired[index].clone())
+        .collect())
+}
+
+/// Returns the file upgraders that are not recorded in the database, in id order.
+///
+/// Once integrity has been verified this is the tail of the files after the applied ones,
+/// unless `allow_out_of_order` lets the database skip upgraders.
+pub(crate) fn pending_upgraders<'a>(
+    files_upgraders: &'a [SchemaUpgrader],
+    db_upgraders: &[AppliedUpgrader],
+) -> impl Iterator<Item = &'a SchemaUpgrader> {
+    let applied: HashSet<(i32, i32)> = db_upgraders
+        .iter()
+        .map(|u| (u.file_id, u.upgrader_id))
+        .collect();
+    files_upgraders
+        .iter()
+        .filter(move |u| !applied.contains(&(u.file_id, u.upgrader_id)))
+}
+
+/// Returns the recorded upgraders after the last file upgrader, which the integrity check
+/// accepts when the files are older than the database, in id order.
+pub(crate) fn db_ahead_upgraders(
+    files_upgraders: &[SchemaUpgrader],
+    db_upgraders: &[AppliedUpgrader],
+) -> Vec<(i32, i32)> {
+    let last_file = files_upgraders.last().map(|u| (u.file_id, u.upgrader_id));
+    db_upgraders
+        .iter()
+        .map(|u| (u.file_id, u.upgrader_id))
+        .filter(|ids| last_file.is_none_or(|last| *ids > last))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use chrono::Utc;
+    use std::sync::Arc;
+
+    fn default_options() -> PostgresUpgraderOptions {
+        PostgresUpgraderOptions::default()
+    }
 
     fn create_schema_upgrader(
         file_id: i32,
@@ -111,6 +395,8 @@ mod tests {
             upgrader_id,
             description: desc.to_string(),
             text: text.to_string(),
+            down_text: None,
+            transactional: true,
         }
     }
 
@@ -126,6 +412,11 @@ mod tests {
             description: desc.to_string(),
             text: text.to_string(),
             applied_on: Utc::now(),
+            sequence: None,
+            applied_host: None,
+            applied_by: None,
+            last_verified_on: None,
+            checksum: None,
         }
     }
 
@@ -140,7 +431,7 @@ mod tests {
             create_applied_upgrader(0, 0, "SQL1", "Desc1"),
             create_applied_upgrader(0, 1, "SQL2", "Desc2"),
         ];
-        assert!(verify_integrity(&files, &db).is_ok());
+        assert!(verify_integrity(&files, &db, &default_options()).is_ok());
     }
 
     #[test]
@@ -151,7 +442,7 @@ mod tests {
             create_schema_upgrader(1, 0, "SQL3", "Desc3"),
         ];
         let db = vec![create_applied_upgrader(0, 0, "SQL1", "Desc1")];
-        assert!(verify_integrity(&files, &db).is_ok());
+        assert!(verify_integrity(&files, &db, &default_options()).is_ok());
     }
 
     #[test]
@@ -162,7 +453,20 @@ mod tests {
             create_applied_upgrader(0, 0, "SQL1", "Desc1"),
             create_applied_upgrader(0, 1, "SQL2", "Desc2"),
         ];
-        assert!(verify_integrity(&files, &db).is_ok());
+        assert!(verify_integrity(&files, &db, &default_options()).is_ok());
+    }
+
+    #[test]
+    fn test_db_ahead_upgraders() {
+        let files = vec![create_schema_upgrader(0, 0, "SQL1", "Desc1")];
+        let db = vec![
+            create_applied_upgrader(0, 0, "SQL1", "Desc1"),
+            create_applied_upgrader(0, 1, "SQL2", "Desc2"),
+            create_applied_upgrader(1, 0, "SQL3", "Desc3"),
+        ];
+        assert_eq!(db_ahead_upgraders(&files, &db), vec![(0, 1), (1, 0)]);
+        assert!(db_ahead_upgraders(&files, &db[..1]).is_empty());
+        assert_eq!(db_ahead_upgraders(&[], &db).len(), 3);
     }
 
     #[test]
@@ -170,7 +474,7 @@ mod tests {
         let files = vec![create_schema_upgrader(0, 0, "SQL1", "New Desc")];
         let db = vec![create_applied_upgrader(0, 0, "SQL1", "Old Desc")];
 
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         match err {
             UpgraderError::IntegrityError(msg) => assert!(msg.contains("Description has changed")),
             _ => panic!("Unexpected error type"),
@@ -182,7 +486,7 @@ mod tests {
         let files = vec![create_schema_upgrader(0, 0, "New SQL", "Desc1")];
         let db = vec![create_applied_upgrader(0, 0, "Old SQL", "Desc1")];
 
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         match err {
             UpgraderError::IntegrityError(msg) => assert!(msg.contains("SQL content has changed")),
             _ => panic!("Unexpected error type"),
@@ -198,7 +502,7 @@ mod tests {
         let files = vec![create_schema_upgrader(0, 0, "SQL_A", "Desc_A")];
         let db = vec![create_applied_upgrader(0, 0, "SQL_B", "Desc_B")];
 
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         match err {
             UpgraderError::IntegrityError(msg) => assert!(
                 msg.contains("SQL content has changed") || msg.contains("Description has changed")
@@ -223,7 +527,7 @@ mod tests {
             create_applied_upgrader(0, 1, "SQL2", "Desc2"),
         ];
 
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         // It compares (1,0) from files with (0,1) from DB.
         // (1,0) > (0,1). So DB has an upgrader "before" the current file upgrader.
         match err {
@@ -242,7 +546,7 @@ mod tests {
         let files = vec![create_schema_upgrader(1, 0, "SQL", "Desc")];
         let db = vec![create_applied_upgrader(0, 0, "SQL", "Desc")];
 
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         // (1,0) > (0,0). DB has earlier upgrader.
         match err {
             UpgraderError::IntegrityError(msg) => {
@@ -260,7 +564,7 @@ mod tests {
         let files = vec![create_schema_upgrader(0, 1, "SQL", "Desc")];
         let db = vec![create_applied_upgrader(0, 0, "SQL", "Desc")];
 
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         // (0,1) > (0,0)
         match err {
             UpgraderError::IntegrityError(msg) => {
@@ -282,7 +586,7 @@ mod tests {
         let db = vec![create_applied_upgrader(0, 0, "SQL_Old", "Desc_Old")];
 
         // Mismatch at (0,0). Content differs.
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         match err {
             UpgraderError::IntegrityError(msg) => assert!(
                 msg.contains("SQL content has changed") || msg.contains("Description has changed")
@@ -308,7 +612,7 @@ mod tests {
         ];
 
         // At 0:1, content mismatch.
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         match err {
             UpgraderError::IntegrityError(msg) => assert!(
                 msg.contains("SQL content has changed") || msg.contains("Description has changed")
@@ -335,7 +639,7 @@ mod tests {
 
         // At 2nd step: File (0,1) vs DB (0,2).
         // (0,1) < (0,2). File is "earlier". Means DB skipped it.
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         match err {
             UpgraderError::IntegrityError(msg) => assert!(
                 msg.contains("Gap detected in database migrations. File upgrader 0:1 is missing")
@@ -353,7 +657,7 @@ mod tests {
             create_schema_upgrader(0, 1, "SQL2", "Desc2"),
         ];
         let db = vec![create_applied_upgrader(0, 0, "SQL1", "Desc1")];
-        assert!(verify_integrity(&files, &db).is_ok());
+        assert!(verify_integrity(&files, &db, &default_options()).is_ok());
     }
 
     #[test]
@@ -373,7 +677,7 @@ mod tests {
 
         // Compare File (0,1) vs DB (1,0).
         // (0,1) < (1,0). Gap detected.
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         match err {
             UpgraderError::IntegrityError(msg) => assert!(
                 msg.contains("Gap detected in database migrations. File upgrader 0:1 is missing")
@@ -391,7 +695,7 @@ mod tests {
             create_schema_upgrader(1, 0, "SQL2", "Desc2"),
         ];
         let db = vec![create_applied_upgrader(0, 0, "SQL1", "Desc1")];
-        assert!(verify_integrity(&files, &db).is_ok());
+        assert!(verify_integrity(&files, &db, &default_options()).is_ok());
     }
 
     /// User Story: Developer changed leading/trailing SQL whitespace in an already applied upgrader.
@@ -401,7 +705,7 @@ mod tests {
         let files = vec![create_schema_upgrader(0, 0, "  SQL  ", " Desc ")];
         let db = vec![create_applied_upgrader(0, 0, "SQL", "Desc")];
 
-        assert!(verify_integrity(&files, &db).is_ok());
+        assert!(verify_integrity(&files, &db, &default_options()).is_ok());
     }
 
     /// User Story: Developer changed INTERNAL SQL whitespace. This should still FAIL.
@@ -410,7 +714,7 @@ mod tests {
         let files = vec![create_schema_upgrader(0, 0, "SELECT  1", "Desc")];
         let db = vec![create_applied_upgrader(0, 0, "SELECT 1", "Desc")];
 
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         match err {
             UpgraderError::IntegrityError(msg) => assert!(msg.contains("SQL content has changed")),
             _ => panic!("Unexpected error type"),
@@ -422,7 +726,7 @@ mod tests {
         let files = vec![create_schema_upgrader(0, 0, "SELECT 1", "Desc")];
         let db = vec![create_applied_upgrader(0, 0, "select 1", "Desc")];
 
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         match err {
             UpgraderError::IntegrityError(msg) => assert!(msg.contains("SQL content has changed")),
             _ => panic!("Unexpected error type"),
@@ -445,7 +749,7 @@ mod tests {
             create_applied_upgrader(0, 3, "SQL3", "Desc3"),
         ];
 
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         match err {
             UpgraderError::IntegrityError(msg) => {
                 assert!(msg.contains("File upgrader 0:1 is missing"))
@@ -469,7 +773,7 @@ mod tests {
             create_applied_upgrader(0, 2, "SQL2", "Desc2"),
         ];
 
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         // File has (0,2). DB has (0,1).
         // (0,2) > (0,1). Means DB has something "earlier".
         match err {
@@ -492,7 +796,7 @@ mod tests {
             create_applied_upgrader(2, 0, "SQL2", "Desc2"),
         ];
 
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         match err {
             UpgraderError::IntegrityError(msg) => {
                 assert!(msg.contains("Database contains an upgrader 1:0 that is missing"))
@@ -521,6 +825,11 @@ mod tests {
                 description: "Desc".to_string(),
                 text: "SQL".to_string(),
                 applied_on: now,
+                sequence: None,
+                applied_host: None,
+                applied_by: None,
+                last_verified_on: None,
+                checksum: None,
             },
             AppliedUpgrader {
                 file_id: 0,
@@ -528,10 +837,15 @@ mod tests {
                 description: "Desc".to_string(),
                 text: "SQL".to_string(),
                 applied_on: earlier,
+                sequence: None,
+                applied_host: None,
+                applied_by: None,
+                last_verified_on: None,
+                checksum: None,
             },
         ];
 
-        let err = verify_integrity(&files, &db).unwrap_err();
+        let err = verify_integrity(&files, &db, &default_options()).unwrap_err();
         match err {
             UpgraderError::IntegrityError(msg) => {
                 assert!(msg.contains("Upgrader 0:1 was applied at"))
@@ -539,4 +853,424 @@ mod tests {
             _ => panic!("Unexpected error type"),
         }
     }
+
+    #[test]
+    fn test_applied_on_skew_tolerance() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "SQL", "Desc"),
+            create_schema_upgrader(0, 1, "SQL", "Desc"),
+            create_schema_upgrader(0, 2, "SQL", "Desc"),
+        ];
+        let now = Utc::now();
+        let mut db: Vec<_> = files
+            .iter()
+            .map(|f| create_applied_upgrader(f.file_id, f.upgrader_id, "SQL", "Desc"))
+            .collect();
+        db[0].applied_on = now;
+        db[1].applied_on = now - chrono::TimeDelta::milliseconds(5);
+        db[2].applied_on = now - chrono::TimeDelta::milliseconds(3);
+
+        // A few milliseconds out of order are only accepted with a tolerance.
+        assert!(verify_integrity(&files[..2], &db[..2], &default_options()).is_err());
+        let options = PostgresUpgraderOptions::builder()
+            .applied_on_skew_tolerance(std::time::Duration::from_secs(1))
+            .build();
+        assert!(verify_integrity(&files, &db, &options).is_ok());
+
+        // The regression is measured against the latest time so far, not the previous row.
+        db[2].applied_on = now - chrono::TimeDelta::milliseconds(1500);
+        db[1].applied_on = now - chrono::TimeDelta::milliseconds(800);
+        let err = verify_integrity(&files, &db, &options).unwrap_err();
+        assert!(
+            err.to_string().contains("Upgrader 0:2 was applied at"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_violation_kind_and_ids_gap() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "SQL1", "Desc1"),
+            create_schema_upgrader(0, 1, "SQL2", "Desc2"),
+            create_schema_upgrader(0, 2, "SQL3", "Desc3"),
+        ];
+        let db = vec![
+            create_applied_upgrader(0, 0, "SQL1", "Desc1"),
+            create_applied_upgrader(0, 2, "SQL3", "Desc3"),
+        ];
+
+        let violation = find_violation(&files, &db, &default_options()).unwrap_err();
+        assert_eq!(violation.kind(), "gap");
+        assert_eq!(violation.ids(), (0, 1));
+    }
+
+    #[test]
+    fn test_violation_kind_and_ids_content() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "SQL1", "Desc1"),
+            create_schema_upgrader(1, 0, "New SQL", "Desc2"),
+        ];
+        let db = vec![
+            create_applied_upgrader(0, 0, "SQL1", "Desc1"),
+            create_applied_upgrader(1, 0, "SQL2", "Desc2"),
+        ];
+        let violation = find_violation(&files, &db, &default_options()).unwrap_err();
+        assert_eq!(violation.kind(), "text_changed");
+        assert_eq!(violation.ids(), (1, 0));
+
+        let files = vec![create_schema_upgrader(0, 0, "SQL1", "New Desc")];
+        let violation = find_violation(&files, &db, &default_options()).unwrap_err();
+        assert_eq!(violation.kind(), "description_changed");
+        assert_eq!(violation.ids(), (0, 0));
+    }
+
+    #[test]
+    fn test_violation_converts_to_integrity_error() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "SQL1", "Desc1"),
+            create_schema_upgrader(1, 0, "SQL3", "Desc3"),
+        ];
+        let db = vec![
+            create_applied_upgrader(0, 0, "SQL1", "Desc1"),
+            create_applied_upgrader(0, 1, "SQL2", "Desc2"),
+        ];
+        let violation = find_violation(&files, &db, &default_options()).unwrap_err();
+        assert_eq!(violation.kind(), "missing_from_files");
+        assert_eq!(violation.ids(), (0, 1));
+
+        match UpgraderError::from(violation.clone()) {
+            UpgraderError::IntegrityError(msg) => assert_eq!(msg, violation.to_string()),
+            _ => panic!("Unexpected error type"),
+        }
+    }
+
+    fn without_leading_comments(sql: &str) -> &str {
+        let mut rest = sql.trim_start();
+        while rest.starts_with("--") {
+            rest = rest
+                .split_once('\n')
+                .map_or("", |(_, tail)| tail)
+                .trim_start();
+        }
+        rest.trim_end()
+    }
+
+    /// User Story: A team prepends a license header to old files and plugs in a comparator that ignores it.
+    #[test]
+    fn test_integrity_custom_comparator_ignores_leading_comments() {
+        let files = vec![create_schema_upgrader(
+            0,
+            0,
+            "-- Copyright (c) Example Corp.\n-- SPDX-License-Identifier: MIT\nCREATE TABLE users (id INT);",
+            "Desc1",
+        )];
+        let db = vec![create_applied_upgrader(
+            0,
+            0,
+            "CREATE TABLE users (id INT);",
+            "Desc1",
+        )];
+
+        // The built-in comparison sees the header as a change.
+        assert!(verify_integrity(&files, &db, &default_options()).is_err());
+
+        let options = PostgresUpgraderOptions::builder()
+            .content_comparator(Arc::new(|file: &str, db: &str| {
+                without_leading_comments(file) == without_leading_comments(db)
+            }))
+            .build();
+        assert!(verify_integrity(&files, &db, &options).is_ok());
+
+        // Real changes are still detected.
+        let files = vec![create_schema_upgrader(
+            0,
+            0,
+            "-- Copyright (c) Example Corp.\nCREATE TABLE users (id BIGINT);",
+            "Desc1",
+        )];
+        let err = verify_integrity(&files, &db, &options).unwrap_err();
+        assert!(err.to_string().contains("SQL content has changed"));
+    }
+
+    /// User Story: The comparator replaces the built-in trimming entirely.
+    #[test]
+    fn test_integrity_custom_comparator_replaces_trim() {
+        let files = vec![create_schema_upgrader(0, 0, "SQL1", "Desc1")];
+        let db = vec![create_applied_upgrader(0, 0, "  SQL1  ", "Desc1")];
+
+        let options = PostgresUpgraderOptions::builder()
+            .content_comparator(Arc::new(|file: &str, db: &str| file == db))
+            .build();
+        let violation = find_violation(&files, &db, &options).unwrap_err();
+        assert_eq!(violation.kind(), "text_changed");
+    }
+
+    fn checksum_only(file_id: i32, upgrader_id: i32, text: &str, desc: &str) -> AppliedUpgrader {
+        AppliedUpgrader {
+            checksum: Some(crate::db_tracker::checksum(text)),
+            ..create_applied_upgrader(file_id, upgrader_id, "", desc)
+        }
+    }
+
+    /// User Story: Checksums recorded with store_checksums are compared instead of the SQL,
+    /// next to older upgraders that were recorded with their SQL only.
+    #[test]
+    fn test_integrity_checksums() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "SQL0", "Desc0"),
+            create_schema_upgrader(0, 1, "SQL1", "Desc1"),
+        ];
+        let options = PostgresUpgraderOptions::builder()
+            .store_checksums(true)
+            .build();
+
+        let db = vec![
+            create_applied_upgrader(0, 0, "SQL0", "Desc0"),
+            checksum_only(0, 1, "  SQL1\n", "Desc1"),
+        ];
+        assert!(find_violation(&files, &db, &options).is_ok());
+
+        let db = vec![
+            create_applied_upgrader(0, 0, "SQL0", "Desc0"),
+            checksum_only(0, 1, "SQL1 changed", "Desc1"),
+        ];
+        let violation = find_violation(&files, &db, &options).unwrap_err();
+        assert_eq!(violation.ids(), (0, 1));
+        assert_eq!(violation.kind(), "text_changed");
+
+        // Recorded checksums keep being compared once the option is disabled again.
+        let db = vec![checksum_only(0, 0, "SQL0 changed", "Desc0")];
+        let violation = find_violation(&files, &db, &default_options()).unwrap_err();
+        assert_eq!(violation.kind(), "text_changed");
+    }
+
+    /// User Story: The first file's upgraders were squashed into a single baseline upgrader,
+    /// leaving the others empty, on a database that applied the original ones.
+    #[test]
+    fn test_integrity_frozen_upgraders() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "CREATE TABLE a (id INT, name TEXT)", "Baseline"),
+            create_schema_upgrader(0, 1, "", "Squashed"),
+            create_schema_upgrader(1, 0, "SQL2", "Desc2"),
+        ];
+        let db = vec![
+            create_applied_upgrader(0, 0, "CREATE TABLE a (id INT)", "Create a"),
+            create_applied_upgrader(0, 1, "ALTER TABLE a ADD name TEXT", "Add name"),
+            create_applied_upgrader(1, 0, "SQL2", "Desc2"),
+        ];
+        let frozen = PostgresUpgraderOptions::builder()
+            .frozen_upgraders((0, 0)..=(0, 1))
+            .build();
+
+        assert!(verify_integrity(&files, &db, &default_options()).is_err());
+        assert!(verify_integrity(&files, &db, &frozen).is_ok());
+
+        // Later upgraders are still compared, and frozen ones must still be present.
+        let changed = vec![
+            db[0].clone(),
+            db[1].clone(),
+            create_applied_upgrader(1, 0, "SQL2 changed", "Desc2"),
+        ];
+        assert_eq!(
+            find_violation(&files, &changed, &frozen)
+                .unwrap_err()
+                .kind(),
+            "text_changed"
+        );
+        let deleted = [files[0].clone(), files[2].clone()];
+        assert_eq!(
+            find_violation(&deleted, &db, &frozen).unwrap_err().kind(),
+            "missing_from_files"
+        );
+    }
+
+    fn out_of_order_options() -> PostgresUpgraderOptions {
+        PostgresUpgraderOptions::builder()
+            .allow_out_of_order(true)
+            .build()
+    }
+
+    /// User Story: A step appended to file 0 on one branch lands after file 1 from another
+    /// branch was already deployed.
+    #[test]
+    fn test_integrity_out_of_order_allows_gap() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "SQL0", "Desc0"),
+            create_schema_upgrader(0, 1, "SQL1", "Desc1"),
+            create_schema_upgrader(1, 0, "SQL2", "Desc2"),
+        ];
+        let db = vec![
+            create_applied_upgrader(0, 0, "SQL0", "Desc0"),
+            create_applied_upgrader(1, 0, "SQL2", "Desc2"),
+        ];
+
+        assert!(verify_integrity(&files, &db, &default_options()).is_err());
+        assert!(verify_integrity(&files, &db, &out_of_order_options()).is_ok());
+
+        let pending: Vec<_> = pending_upgraders(&files, &db)
+            .map(|u| (u.file_id, u.upgrader_id))
+            .collect();
+        assert_eq!(pending, vec![(0, 1)]);
+    }
+
+    /// User Story: After the late upgrader is applied, the ids are no longer in the order in
+    /// which they were applied.
+    #[test]
+    fn test_integrity_out_of_order_allows_chronological_interleaving() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "SQL0", "Desc0"),
+            create_schema_upgrader(0, 1, "SQL1", "Desc1"),
+            create_schema_upgrader(1, 0, "SQL2", "Desc2"),
+        ];
+        let mut db = vec![
+            create_applied_upgrader(0, 0, "SQL0", "Desc0"),
+            create_applied_upgrader(0, 1, "SQL1", "Desc1"),
+            create_applied_upgrader(1, 0, "SQL2", "Desc2"),
+        ];
+        db[1].applied_on = db[2].applied_on + chrono::Duration::seconds(10);
+
+        let err = find_violation(&files, &db, &default_options()).unwrap_err();
+        assert_eq!(err.kind(), "out_of_order");
+        assert!(verify_integrity(&files, &db, &out_of_order_options()).is_ok());
+        assert_eq!(pending_upgraders(&files, &db).count(), 0);
+    }
+
+    #[test]
+    fn test_integrity_out_of_order_still_detects_changes() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "SQL0", "Desc0"),
+            create_schema_upgrader(1, 0, "SQL2", "Desc2"),
+        ];
+        let db = vec![create_applied_upgrader(1, 0, "SQL2 changed", "Desc2")];
+        let err = find_violation(&files, &db, &out_of_order_options()).unwrap_err();
+        assert_eq!(err.kind(), "text_changed");
+        assert_eq!(err.ids(), (1, 0));
+
+        let db = vec![create_applied_upgrader(1, 0, "SQL2", "Other")];
+        let err = find_violation(&files, &db, &out_of_order_options()).unwrap_err();
+        assert_eq!(err.kind(), "description_changed");
+    }
+
+    #[test]
+    fn test_integrity_out_of_order_still_detects_deleted_upgrader() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "SQL0", "Desc0"),
+            create_schema_upgrader(1, 0, "SQL2", "Desc2"),
+        ];
+        let db = vec![
+            create_applied_upgrader(0, 0, "SQL0", "Desc0"),
+            create_applied_upgrader(0, 1, "SQL1", "Desc1"),
+        ];
+        let err = find_violation(&files, &db, &out_of_order_options()).unwrap_err();
+        assert_eq!(err.kind(), "missing_from_files");
+        assert_eq!(err.ids(), (0, 1));
+    }
+
+    #[test]
+    fn test_integrity_out_of_order_allows_db_ahead_of_files() {
+        let files = vec![create_schema_upgrader(0, 0, "SQL0", "Desc0")];
+        let db = vec![
+            create_applied_upgrader(0, 0, "SQL0", "Desc0"),
+            create_applied_upgrader(0, 1, "SQL1", "Desc1"),
+            create_applied_upgrader(1, 0, "SQL2", "Desc2"),
+        ];
+        assert!(verify_integrity(&files, &db, &out_of_order_options()).is_ok());
+        assert!(verify_integrity(&[], &db, &out_of_order_options()).is_ok());
+        assert_eq!(pending_upgraders(&files, &db).count(), 0);
+    }
+
+    #[test]
+    fn test_pending_upgraders_in_order() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "SQL0", "Desc0"),
+            create_schema_upgrader(0, 1, "SQL1", "Desc1"),
+            create_schema_upgrader(1, 0, "SQL2", "Desc2"),
+        ];
+        let db = vec![create_applied_upgrader(0, 0, "SQL0", "Desc0")];
+        let pending: Vec<_> = pending_upgraders(&files, &db)
+            .map(|u| (u.file_id, u.upgrader_id))
+            .collect();
+        assert_eq!(pending, vec![(0, 1), (1, 0)]);
+    }
+
+    /// User Story: An empty middle block was skipped by the loader, so the file ids have a
+    /// hole; after a partial history the next upgrader is the one after the hole, not the one
+    /// at the index given by the number of applied upgraders.
+    #[test]
+    fn test_pending_upgraders_after_skipped_empty_block() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "SQL0", "Desc0"),
+            create_schema_upgrader(0, 2, "SQL2", "Desc2"),
+            create_schema_upgrader(0, 3, "SQL3", "Desc3"),
+        ];
+        let db = vec![
+            create_applied_upgrader(0, 0, "SQL0", "Desc0"),
+            create_applied_upgrader(0, 2, "SQL2", "Desc2"),
+        ];
+        assert!(find_violation(&files, &db, &PostgresUpgraderOptions::default()).is_ok());
+        let pending: Vec<_> = pending_upgraders(&files, &db)
+            .map(|u| (u.file_id, u.upgrader_id, u.text.as_str()))
+            .collect();
+        assert_eq!(pending, vec![(0, 3, "SQL3")]);
+    }
+
+    /// User Story: A description was reworded after being applied; repair rewrites only that row.
+    #[test]
+    fn test_repairable_upgraders_rewrites_changed_rows() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "SQL0", "Create users"),
+            create_schema_upgrader(0, 1, "SQL1 -- reformatted", "Add email"),
+            create_schema_upgrader(1, 0, "SQL2", "Pending"),
+        ];
+        let mut db = vec![
+            create_applied_upgrader(0, 0, "SQL0", "Create user table"),
+            create_applied_upgrader(0, 1, "", "Add e-mail"),
+        ];
+        db[1].checksum = Some(crate::db_tracker::checksum("SQL1"));
+
+        let repaired = repairable_upgraders(&files, &db, &default_options()).unwrap();
+        assert_eq!(repaired.len(), 2);
+        assert_eq!(repaired[0].description, "Create users");
+        assert_eq!(repaired[0].text, "SQL0");
+        assert_eq!(repaired[1].description, "Add email");
+        assert_eq!(repaired[1].text, "");
+        assert_eq!(
+            repaired[1].checksum,
+            Some(crate::db_tracker::checksum("SQL1 -- reformatted"))
+        );
+
+        let matching = vec![create_applied_upgrader(0, 0, "SQL0", "Create users")];
+        assert!(
+            repairable_upgraders(&files, &matching, &default_options())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    /// User Story: Repair refuses histories whose order or identity diverges from the files.
+    #[test]
+    fn test_repairable_upgraders_refuses_structural_drift() {
+        let files = vec![
+            create_schema_upgrader(0, 0, "SQL0", "Desc0"),
+            create_schema_upgrader(0, 1, "SQL1", "Desc1"),
+        ];
+
+        let gap = vec![create_applied_upgrader(0, 1, "SQL1", "Reworded")];
+        let violation = repairable_upgraders(&files, &gap, &default_options()).unwrap_err();
+        assert_eq!(violation.kind(), "gap");
+
+        let deleted = vec![
+            create_applied_upgrader(0, 0, "SQL0", "Reworded"),
+            create_applied_upgrader(0, 1, "SQL1", "Desc1"),
+            create_applied_upgrader(0, 2, "SQL2", "Desc2"),
+        ];
+        let files_without_0_1 = vec![
+            create_schema_upgrader(0, 0, "SQL0", "Desc0"),
+            create_schema_upgrader(0, 2, "SQL2", "Desc2"),
+        ];
+        let violation =
+            repairable_upgraders(&files_without_0_1, &deleted, &default_options()).unwrap_err();
+        assert_eq!(violation.kind(), "missing_from_files");
+    }
 }
@@ -3,26 +3,78 @@
 //! A library for managing PostgreSQL database schema migrations with safety and integrity in mind.
 //! It supports both synchronous (blocking) and asynchronous (Tokio) execution modes.
 
+// Much of the shared machinery only serves the `postgres` and `tokio-postgres` backends, and
+// is unused when building for `sqlx` alone.
+#![cfg_attr(
+    not(any(feature = "postgres", feature = "tokio-postgres")),
+    allow(dead_code, unused_imports)
+)]
+
 #[cfg(feature = "tokio-postgres")]
 mod async_upgrade;
 #[cfg(feature = "postgres")]
 mod blocking_upgrade;
 mod db_tracker;
+mod embedded;
 mod error;
+mod export;
+mod identifier;
 mod integrity;
+mod maintenance;
+mod migration_set;
 mod options;
+mod outcome;
+mod plan;
 mod schema_loader;
+mod shared_macros;
+#[cfg(feature = "sqlx")]
+mod sqlx_upgrade;
+mod statements;
+mod status;
 mod tls;
+#[cfg(any(feature = "postgres", feature = "tokio-postgres"))]
 #[macro_use]
 mod upgrade_macros;
 
+pub use db_tracker::AppliedUpgrader;
+pub use embedded::Migrations;
 pub use error::UpgraderError;
+pub use maintenance::{MaintenanceWindow, WindowCallback};
+pub use migration_set::{DuplicateUpgraders, MigrationSet};
 #[cfg(feature = "tls")]
 pub use options::SslMode;
-pub use options::{PostgresUpgraderOptions, PostgresUpgraderOptionsBuilder};
+pub use options::{
+    ApplyHook, AsyncApplyHook, Clock, ContentComparator, PostgresUpgraderOptions,
+    PostgresUpgraderOptionsBuilder, ProgressHook,
+};
+pub use outcome::{IntegrityReport, UpgradeOutcome, UpgradeReport};
+pub use plan::{PlanBreakdown, PlanStep, UpgradePlan};
+pub use schema_loader::{new_upgrader, validate_folder};
+#[cfg(feature = "sqlx")]
+pub use sqlx_upgrade::upgrade_sqlx;
+pub use status::{MigrationInfo, MigrationStatus};
+
+/// Helpers shared with the bundled CLI; not part of the public API.
+#[doc(hidden)]
+pub mod __private {
+    #[cfg(any(feature = "postgres", feature = "tokio-postgres"))]
+    pub use crate::tls::is_unix_socket;
+}
 
 #[cfg(feature = "postgres")]
-pub use blocking_upgrade::upgrade_blocking;
+pub use blocking_upgrade::{
+    apply_file_blocking, baseline_blocking, check_integrity_blocking, current_sequence_blocking,
+    downgrade_blocking, dry_run_blocking, export_as_files_blocking, init_only_blocking,
+    is_up_to_date_blocking, last_applier_blocking, repair_blocking, status_blocking,
+    try_upgrade_blocking, upgrade_blocking, upgrade_blocking_embedded,
+    upgrade_with_client_blocking,
+};
 
 #[cfg(feature = "tokio-postgres")]
-pub use async_upgrade::upgrade_async;
+pub use async_upgrade::{
+    SchemaCheck, apply_file_async, baseline_async, check_integrity_async, current_sequence_async,
+    downgrade_async, dry_run_async, export_as_files_async, init_only_async, is_up_to_date_async,
+    last_applier_async, repair_async, status_async, try_upgrade_async, upgrade_async,
+    upgrade_async_embedded, upgrade_async_pooled, upgrade_async_with_shutdown,
+    upgrade_with_client_async, verify_from_scratch_async,
+};
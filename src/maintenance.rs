@@ -0,0 +1,308 @@
+use crate::UpgraderError;
+use chrono::{DateTime, FixedOffset, NaiveTime, Utc};
+use std::sync::Arc;
+
+/// Decides whether the given instant lies inside an approved maintenance window.
+pub type WindowCallback = Arc<dyn Fn(DateTime<Utc>) -> bool + Send + Sync>;
+
+/// When destructive or locking upgraders may run, see
+/// [`maintenance_window`](crate::PostgresUpgraderOptionsBuilder::maintenance_window).
+#[derive(Clone)]
+pub enum MaintenanceWindow {
+    /// A window that opens every day at `start` and closes at `end`, both read in the fixed
+    /// `offset` from UTC. `start` is inclusive and `end` exclusive; an `end` before `start`
+    /// means the window spans midnight.
+    Daily {
+        start: NaiveTime,
+        end: NaiveTime,
+        offset: FixedOffset,
+    },
+    /// A window decided by the callback, which is given the current time in UTC. Use it for
+    /// time zones with daylight saving time or for calendars of approved dates.
+    Callback(WindowCallback),
+}
+
+impl MaintenanceWindow {
+    /// A daily window from `start` to `end` in UTC.
+    pub fn daily_utc(start: NaiveTime, end: NaiveTime) -> Self {
+        Self::Daily {
+            start,
+            end,
+            offset: FixedOffset::east_opt(0).expect("zero offset is valid"),
+        }
+    }
+
+    /// Returns `true` if `now` lies inside the window.
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        match self {
+            Self::Daily { start, end, offset } => {
+                let time = now.with_timezone(offset).time();
+                if start <= end {
+                    *start <= time && time < *end
+                } else {
+                    *start <= time || time < *end
+                }
+            }
+            Self::Callback(callback) => callback(now),
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), UpgraderError> {
+        match self {
+            Self::Daily { start, end, .. } if start == end => {
+                Err(UpgraderError::ConfigurationError(format!(
+                    "maintenance window {} opens and closes at the same time.",
+                    self
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Debug for MaintenanceWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Daily { .. } => write!(f, "Daily({})", self),
+            Self::Callback(_) => f.write_str("Callback(..)"),
+        }
+    }
+}
+
+impl std::fmt::Display for MaintenanceWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Daily { start, end, offset } => write!(
+                f,
+                "{}-{} (UTC{})",
+                start.format("%H:%M"),
+                end.format("%H:%M"),
+                offset
+            ),
+            Self::Callback(_) => f.write_str("defined by a callback"),
+        }
+    }
+}
+
+/// Parses a daily window in UTC written as `HH:MM-HH:MM`, e.g. `22:00-02:00`.
+impl std::str::FromStr for MaintenanceWindow {
+    type Err = UpgraderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            UpgraderError::ConfigurationError(format!(
+                "Invalid maintenance window '{}', expected HH:MM-HH:MM (UTC).",
+                s
+            ))
+        };
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let parse = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|_| invalid());
+        let window = Self::daily_utc(parse(start)?, parse(end)?);
+        window.validate()?;
+        Ok(window)
+    }
+}
+
+/// Statements that take heavy locks or destroy data, as sequences of leading key words.
+const LOCKING_STATEMENTS: &[&[&str]] = &[
+    &["DROP"],
+    &["TRUNCATE"],
+    &["DELETE"],
+    &["ALTER", "TABLE"],
+    &["LOCK"],
+    &["CLUSTER"],
+    &["REINDEX"],
+    &["VACUUM", "FULL"],
+    &["REFRESH", "MATERIALIZED", "VIEW"],
+];
+
+/// Returns `true` if `sql` contains a statement that destroys data or takes locks which
+/// block other sessions, judged from the key words each statement starts with. `CREATE INDEX`
+/// counts unless it is `CONCURRENTLY`. Comments and string literals are ignored.
+pub(crate) fn is_destructive_or_locking(sql: &str) -> bool {
+    statement_words(sql).iter().any(|words| {
+        let starts_with = |prefix: &[&str]| {
+            words.len() >= prefix.len() && words.iter().zip(prefix).all(|(w, p)| w == p)
+        };
+        LOCKING_STATEMENTS.iter().any(|prefix| starts_with(prefix))
+            || ((starts_with(&["CREATE", "INDEX"]) || starts_with(&["CREATE", "UNIQUE", "INDEX"]))
+                && !words.iter().any(|w| w == "CONCURRENTLY"))
+    })
+}
+
+/// Fails if running `sql` for `upgrader` (its text, or its down block when downgrading) would
+/// take heavy locks outside the configured maintenance window.
+pub(crate) fn check_window(
+    options: &crate::PostgresUpgraderOptions,
+    upgrader: &crate::schema_loader::SchemaUpgrader,
+    sql: &str,
+) -> Result<(), UpgraderError> {
+    let Some(window) = &options.maintenance_window else {
+        return Ok(());
+    };
+    if options.ignore_maintenance_window
+        || window.contains(Utc::now())
+        || !is_destructive_or_locking(sql)
+    {
+        return Ok(());
+    }
+    Err(UpgraderError::MaintenanceWindowError(format!(
+        "Upgrader {}:{} ({}) is destructive or takes heavy locks and may only run inside the maintenance window {}.",
+        upgrader.file_id, upgrader.upgrader_id, upgrader.description, window
+    )))
+}
+
+/// Splits `sql` into statements of upper-cased words, without comments and literals.
+fn statement_words(sql: &str) -> Vec<Vec<String>> {
+    let mut statements = vec![Vec::new()];
+    let mut word = String::new();
+    let mut chars = sql.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let mut boundary = true;
+        match c {
+            '-' if chars.peek() == Some(&'-') => {
+                chars.find(|&c| c == '\n');
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+            }
+            '\'' | '"' => {
+                chars.find(|&q| q == c);
+            }
+            ';' => {
+                flush(&mut word, &mut statements);
+                statements.push(Vec::new());
+                continue;
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                word.extend(c.to_uppercase());
+                boundary = false;
+            }
+            _ => {}
+        }
+        if boundary {
+            flush(&mut word, &mut statements);
+        }
+    }
+    flush(&mut word, &mut statements);
+    statements
+}
+
+fn flush(word: &mut String, statements: &mut [Vec<String>]) {
+    if !word.is_empty() {
+        statements
+            .last_mut()
+            .expect("there is always a current statement")
+            .push(std::mem::take(word));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 1, hour, minute, 0).unwrap()
+    }
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_daily_window_boundaries() {
+        let window = MaintenanceWindow::daily_utc(time(1, 0), time(3, 0));
+        assert!(!window.contains(at(0, 59)));
+        assert!(window.contains(at(1, 0)));
+        assert!(window.contains(at(2, 59)));
+        assert!(!window.contains(at(3, 0)));
+    }
+
+    #[test]
+    fn test_daily_window_across_midnight() {
+        let window = MaintenanceWindow::daily_utc(time(22, 0), time(2, 0));
+        assert!(!window.contains(at(21, 59)));
+        assert!(window.contains(at(22, 0)));
+        assert!(window.contains(at(0, 0)));
+        assert!(window.contains(at(1, 59)));
+        assert!(!window.contains(at(2, 0)));
+        assert!(!window.contains(at(12, 0)));
+    }
+
+    #[test]
+    fn test_daily_window_with_offset() {
+        // 01:00-03:00 at UTC+02:00 is 23:00-01:00 UTC.
+        let window = MaintenanceWindow::Daily {
+            start: time(1, 0),
+            end: time(3, 0),
+            offset: FixedOffset::east_opt(2 * 3600).unwrap(),
+        };
+        assert!(!window.contains(at(22, 59)));
+        assert!(window.contains(at(23, 0)));
+        assert!(window.contains(at(0, 59)));
+        assert!(!window.contains(at(1, 0)));
+        assert_eq!(window.to_string(), "01:00-03:00 (UTC+02:00)");
+    }
+
+    #[test]
+    fn test_callback_window() {
+        let window = MaintenanceWindow::Callback(Arc::new(|now| now.timestamp() % 2 == 0));
+        assert!(window.contains(at(1, 0)));
+        assert_eq!(format!("{:?}", window), "Callback(..)");
+    }
+
+    #[test]
+    fn test_parse_window() {
+        let window: MaintenanceWindow = "22:00-02:30".parse().unwrap();
+        assert!(window.contains(at(2, 29)));
+        assert!(!window.contains(at(2, 30)));
+        assert_eq!(window.to_string(), "22:00-02:30 (UTC+00:00)");
+
+        for invalid in ["22:00", "25:00-01:00", "ab-cd", "01:00-01:00"] {
+            assert!(matches!(
+                invalid.parse::<MaintenanceWindow>(),
+                Err(UpgraderError::ConfigurationError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_destructive_or_locking_statements() {
+        for sql in [
+            "DROP TABLE users;",
+            "CREATE TABLE a (id INT);\ntruncate a;",
+            "ALTER TABLE users ADD COLUMN email TEXT;",
+            "DELETE FROM users WHERE id = 1;",
+            "CREATE INDEX idx ON users (id);",
+            "create unique index idx on users (id)",
+            "LOCK TABLE users IN ACCESS EXCLUSIVE MODE;",
+            "VACUUM FULL users;",
+        ] {
+            assert!(is_destructive_or_locking(sql), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_non_locking_statements() {
+        for sql in [
+            "CREATE TABLE users (id INT);",
+            "CREATE INDEX CONCURRENTLY idx ON users (id);",
+            "INSERT INTO log (message) VALUES ('DROP TABLE users;');",
+            "-- DROP TABLE users;\nCREATE TABLE users (id INT);",
+            "/* ALTER TABLE users */ CREATE VIEW v AS SELECT 1;",
+            "CREATE TABLE \"drop\" (id INT);",
+            "COMMENT ON TABLE users IS 'delete me later';",
+        ] {
+            assert!(!is_destructive_or_locking(sql), "{}", sql);
+        }
+    }
+}
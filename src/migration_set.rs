@@ -0,0 +1,581 @@
+use crate::schema_loader::{SchemaUpgrader, load_upgraders};
+use crate::{Migrations, PostgresUpgraderOptions, UpgraderError};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Maximum length of the SQL snippet reported for duplicate upgraders.
+const SNIPPET_LENGTH: usize = 60;
+
+/// Number of files a parallel cold start applies at once unless `max_parallel_files` is set.
+pub(crate) const PARALLEL_CONNECTIONS: usize = 4;
+
+/// How long a step of a parallel cold start waits for a lock before giving up.
+pub(crate) const PARALLEL_LOCK_TIMEOUT: &str = "5s";
+
+/// A set of upgraders loaded and validated once, ready to be applied to any number of databases.
+///
+/// Loading parses every file in the folder and checks the options, so services that upgrade
+/// many databases (tenants, shards) pay that cost only once. Clones share the loaded upgraders
+/// instead of copying their SQL, and the set can be shared between tasks or threads.
+///
+/// ```no_run
+/// # use postgresql_schema_upgrader::{MigrationSet, PostgresUpgraderOptions};
+/// # fn main() -> Result<(), postgresql_schema_upgrader::UpgraderError> {
+/// let options = PostgresUpgraderOptions::builder().build();
+/// let set = MigrationSet::load("./upgraders", &options)?;
+///
+/// for connection_string in ["host=shard1 user=postgres", "host=shard2 user=postgres"] {
+///     set.apply_blocking(connection_string)?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MigrationSet {
+    pub(crate) upgraders: Arc<[SchemaUpgrader]>,
+    pub(crate) options: PostgresUpgraderOptions,
+}
+
+impl MigrationSet {
+    /// Loads and validates the upgraders from the specified folder.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` if:
+    /// - Upgrader files cannot be loaded or are invalid.
+    /// - The options are inconsistent (e.g. `create_schema` without a schema).
+    /// - With `strict_schema_placeholder`, an upgrader uses the schema placeholder but no
+    ///   schema is set.
+    /// - With `strict_variables`, an upgrader uses a `{{...}}` token that is not a variable.
+    pub fn load(
+        upgraders_folder: impl AsRef<Path>,
+        options: &PostgresUpgraderOptions,
+    ) -> Result<Self, UpgraderError> {
+        options.validate()?;
+        let upgraders = load_upgraders(upgraders_folder, options)?;
+        options.check_schema_placeholder(&upgraders)?;
+        options.check_variables(&upgraders)?;
+
+        Ok(Self {
+            upgraders: upgraders.into(),
+            options: options.clone(),
+        })
+    }
+
+    /// Loads and validates upgraders embedded in the executable, e.g. with
+    /// [`embed_migrations!`](crate::embed_migrations), instead of reading a folder.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UpgraderError` for the same reasons as [`load`](Self::load).
+    pub fn from_migrations(
+        migrations: &Migrations,
+        options: &PostgresUpgraderOptions,
+    ) -> Result<Self, UpgraderError> {
+        options.validate()?;
+        let upgraders = migrations.load(options)?;
+        options.check_schema_placeholder(&upgraders)?;
+        options.check_variables(&upgraders)?;
+
+        Ok(Self {
+            upgraders: upgraders.into(),
+            options: options.clone(),
+        })
+    }
+
+    /// Returns the number of upgraders in the set.
+    pub fn len(&self) -> usize {
+        self.upgraders.len()
+    }
+
+    /// Returns `true` if the folder contained no upgraders.
+    pub fn is_empty(&self) -> bool {
+        self.upgraders.is_empty()
+    }
+
+    /// Returns the options the set was loaded with.
+    pub fn options(&self) -> &PostgresUpgraderOptions {
+        &self.options
+    }
+
+    /// Returns `true` if the set qualifies for `parallel_cold_start`: it has more than one
+    /// file, each upgrader runs in a transaction, and no file mentions an object that another
+    /// file creates, alters, drops or writes to. The check is textual and errs on the side of
+    /// finding a dependency, e.g. when a name only appears in a comment.
+    pub(crate) fn has_independent_files(&self) -> bool {
+        let files = self.files();
+        if files.len() < 2 || !self.upgraders.iter().all(|u| u.transactional) {
+            return false;
+        }
+
+        let names: Vec<Vec<String>> = files
+            .iter()
+            .map(|file| file.iter().flat_map(|u| sql_names(&u.text)).collect())
+            .collect();
+        names.iter().enumerate().all(|(i, file_names)| {
+            let touched = touched_objects(file_names);
+            names
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .all(|(_, other)| !other.iter().any(|name| touched.contains(&name)))
+        })
+    }
+
+    /// Returns the upgraders grouped by file, in id order.
+    pub(crate) fn files(&self) -> Vec<&[SchemaUpgrader]> {
+        self.upgraders
+            .chunk_by(|a, b| a.file_id == b.file_id)
+            .collect()
+    }
+
+    /// Returns a copy of the set whose upgrade stops after the last upgrader of `file_id`, or
+    /// an earlier configured target, and fails while a file before it has pending upgraders.
+    /// All upgraders are kept, so the recorded history is still checked in full.
+    pub(crate) fn limited_to_file(&self, file_id: i32) -> Result<MigrationSet, UpgraderError> {
+        let last = self
+            .upgraders
+            .iter()
+            .rfind(|u| u.file_id == file_id)
+            .ok_or_else(|| {
+                UpgraderError::ConfigurationError(format!(
+                    "File {} is not one of the loaded upgrader files.",
+                    file_id
+                ))
+            })?;
+        let mut set = self.clone();
+        let end = (last.file_id, last.upgrader_id);
+        set.options.target = Some(set.options.target.map_or(end, |target| target.min(end)));
+        set.options.only_file = Some(file_id);
+        Ok(set)
+    }
+
+    /// Finds upgraders whose SQL is identical, ignoring differences in whitespace.
+    ///
+    /// Identical upgraders under different ids are usually a copy-pasted file whose SQL was
+    /// never changed, and the second one typically fails at apply time. Each duplicate is
+    /// reported against the first upgrader with the same content.
+    pub fn duplicate_upgraders(&self) -> Vec<DuplicateUpgraders> {
+        let mut first_seen: HashMap<String, (i32, i32)> = HashMap::new();
+        let mut duplicates = Vec::new();
+
+        for upgrader in self.upgraders.iter() {
+            let normalized = upgrader
+                .text
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ids = (upgrader.file_id, upgrader.upgrader_id);
+
+            match first_seen.get(&normalized) {
+                Some(&first) => duplicates.push(DuplicateUpgraders {
+                    first,
+                    second: ids,
+                    snippet: snippet(&normalized),
+                }),
+                None => {
+                    first_seen.insert(normalized, ids);
+                }
+            }
+        }
+        duplicates
+    }
+}
+
+/// Two upgraders with the same SQL content, as reported by
+/// [`MigrationSet::duplicate_upgraders`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateUpgraders {
+    /// `(file_id, upgrader_id)` of the earlier upgrader.
+    pub first: (i32, i32),
+    /// `(file_id, upgrader_id)` of the later upgrader with the same content.
+    pub second: (i32, i32),
+    /// The beginning of the shared SQL, with whitespace collapsed.
+    pub snippet: String,
+}
+
+/// Keywords naming the kind of object that a `CREATE`, `ALTER` or `DROP` statement is about,
+/// such as `TABLE` in `ALTER TABLE users`.
+const OBJECT_KINDS: &[&str] = &[
+    "aggregate",
+    "collation",
+    "domain",
+    "extension",
+    "function",
+    "index",
+    "policy",
+    "procedure",
+    "role",
+    "rule",
+    "schema",
+    "sequence",
+    "table",
+    "trigger",
+    "type",
+    "view",
+];
+
+/// Words between a keyword and the object name, such as `IF NOT EXISTS`.
+const NAME_PREFIXES: &[&str] = &["if", "not", "exists", "concurrently", "only", "table"];
+
+/// Splits SQL into lowercase names and the `.` and `;` separators, dropping everything else.
+/// Quoted identifiers are lowercased as well, which can only make more names match.
+fn sql_names(sql: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '.' || c == ';' {
+            names.push(c.to_string());
+        } else if c == '"' {
+            let name: String = chars.by_ref().take_while(|&c| c != '"').collect();
+            names.push(name.to_lowercase());
+        } else if c.is_alphanumeric() || c == '_' {
+            let mut name = c.to_lowercase().to_string();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_' || c == '$') {
+                    break;
+                }
+                name.extend(c.to_lowercase());
+                chars.next();
+            }
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// Returns the unqualified names of the objects that the SQL split by [`sql_names`] creates,
+/// alters, drops or writes to.
+fn touched_objects(names: &[String]) -> Vec<&String> {
+    let mut touched = Vec::new();
+    for statement in names.split(|name| name == ";") {
+        let Some(verb) = statement.first() else {
+            continue;
+        };
+        match verb.as_str() {
+            "create" | "alter" | "drop" => {
+                if let Some(kind) = statement
+                    .iter()
+                    .position(|name| OBJECT_KINDS.contains(&name.as_str()))
+                {
+                    touched.extend(object_name(&statement[kind + 1..]));
+                }
+            }
+            "truncate" => touched.extend(object_name(&statement[1..])),
+            _ => {}
+        }
+        // Writes, also inside a `WITH` query or a function body.
+        for (i, name) in statement.iter().enumerate() {
+            let writes = name == "into"
+                || name == "update"
+                || (name == "from" && i > 0 && statement[i - 1] == "delete");
+            if writes {
+                touched.extend(object_name(&statement[i + 1..]));
+            }
+        }
+    }
+    touched
+}
+
+/// Returns the last part of the possibly qualified object name at the start of `names`, e.g.
+/// `users` for `IF EXISTS {{SCHEMA}}.users`.
+fn object_name(names: &[String]) -> Option<&String> {
+    let mut rest = names
+        .iter()
+        .skip_while(|name| NAME_PREFIXES.contains(&name.as_str()));
+    let mut object = rest.next().filter(|name| *name != "." && *name != "on")?;
+    while let (Some(dot), Some(part)) = (rest.next(), rest.next()) {
+        if dot != "." || part == "." {
+            break;
+        }
+        object = part;
+    }
+    Some(object)
+}
+
+fn snippet(sql: &str) -> String {
+    match sql.char_indices().nth(SNIPPET_LENGTH) {
+        Some((end, _)) => format!("{}...", &sql[..end]),
+        None => sql.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_load_success() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("000_init.sql"),
+            "--- 0: Create users\nCREATE TABLE users (id INT);\n--- 1: Add email\nALTER TABLE users ADD COLUMN email TEXT;",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("001_orders.sql"),
+            "--- 0: Create orders\nCREATE TABLE orders (id INT);",
+        )
+        .unwrap();
+
+        let options = PostgresUpgraderOptions::builder().schema("tenant").build();
+        let set = MigrationSet::load(dir.path(), &options).unwrap();
+
+        assert_eq!(set.len(), 3);
+        assert!(!set.is_empty());
+        assert_eq!(set.options().schema.as_deref(), Some("tenant"));
+    }
+
+    #[test]
+    fn test_load_is_independent_of_folder() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("000_init.sql"),
+            "--- 0: Create users\nCREATE TABLE users (id INT);",
+        )
+        .unwrap();
+
+        let options = PostgresUpgraderOptions::builder().build();
+        let set = MigrationSet::load(dir.path(), &options).unwrap();
+        drop(dir);
+
+        let clone = set.clone();
+        assert_eq!(clone.len(), 1);
+        assert_eq!(clone.upgraders[0].text, "CREATE TABLE users (id INT);");
+        assert!(Arc::ptr_eq(&clone.upgraders, &set.upgraders));
+    }
+
+    #[test]
+    fn test_load_empty_folder() {
+        let dir = tempdir().unwrap();
+        let options = PostgresUpgraderOptions::builder().build();
+        let set = MigrationSet::load(dir.path(), &options).unwrap();
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_load_invalid_files_fails() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("001_init.sql"), "--- 0: Init\nSELECT 1;").unwrap();
+
+        let options = PostgresUpgraderOptions::builder().build();
+        let result = MigrationSet::load(dir.path(), &options);
+        assert!(matches!(result, Err(UpgraderError::LoaderError(_))));
+    }
+
+    #[test]
+    fn test_load_invalid_options_fails() {
+        let dir = tempdir().unwrap();
+        let options = PostgresUpgraderOptions::builder()
+            .create_schema(true)
+            .build();
+        let result = MigrationSet::load(dir.path(), &options);
+        assert!(matches!(result, Err(UpgraderError::ExecutionError(_))));
+    }
+
+    #[test]
+    fn test_duplicate_upgraders() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("000_init.sql"),
+            "--- 0: Create users\nCREATE TABLE users (id INT);\n--- 1: Create orders\nCREATE TABLE orders (id INT);",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("001_copy.sql"),
+            "--- 0: Create users again\nCREATE TABLE users\n    (id INT);\n--- 1: Something new\nCREATE TABLE users (id BIGINT);",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("002_copy.sql"),
+            "--- 0: And again\nCREATE  TABLE users (id INT);",
+        )
+        .unwrap();
+
+        let options = PostgresUpgraderOptions::builder().build();
+        let set = MigrationSet::load(dir.path(), &options).unwrap();
+        let duplicates = set.duplicate_upgraders();
+
+        assert_eq!(
+            duplicates,
+            vec![
+                DuplicateUpgraders {
+                    first: (0, 0),
+                    second: (1, 0),
+                    snippet: "CREATE TABLE users (id INT);".to_string(),
+                },
+                DuplicateUpgraders {
+                    first: (0, 0),
+                    second: (2, 0),
+                    snippet: "CREATE TABLE users (id INT);".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_upgraders_none() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("000_init.sql"),
+            "--- 0: Create users\nCREATE TABLE users (id INT);\n--- 1: Create orders\nCREATE TABLE orders (id INT);",
+        )
+        .unwrap();
+
+        let options = PostgresUpgraderOptions::builder().build();
+        let set = MigrationSet::load(dir.path(), &options).unwrap();
+        assert!(set.duplicate_upgraders().is_empty());
+    }
+
+    #[test]
+    fn test_has_independent_files() {
+        let dir = tempdir().unwrap();
+        let options = PostgresUpgraderOptions::builder().build();
+        fs::write(
+            dir.path().join("000_users.sql"),
+            "--- 0: Create users\nCREATE TABLE users (id INT);",
+        )
+        .unwrap();
+        assert!(
+            !MigrationSet::load(dir.path(), &options)
+                .unwrap()
+                .has_independent_files()
+        );
+
+        fs::write(
+            dir.path().join("001_orders.sql"),
+            "--- 0: Create orders\nCREATE TABLE orders (id INT);",
+        )
+        .unwrap();
+        assert!(
+            MigrationSet::load(dir.path(), &options)
+                .unwrap()
+                .has_independent_files()
+        );
+
+        fs::write(
+            dir.path().join("002_index.sql"),
+            "--- 0 (no-transaction): Index orders\nCREATE INDEX CONCURRENTLY ON orders (id);",
+        )
+        .unwrap();
+        assert!(
+            !MigrationSet::load(dir.path(), &options)
+                .unwrap()
+                .has_independent_files()
+        );
+        fs::remove_file(dir.path().join("002_index.sql")).unwrap();
+
+        // Upgraders of the same file run in order on one connection.
+        fs::write(
+            dir.path().join("002_items.sql"),
+            "--- 0: Create items\nCREATE TABLE items (id INT);\n--- 1: Index items\nCREATE INDEX ON items (id);",
+        )
+        .unwrap();
+        let set = MigrationSet::load(dir.path(), &options).unwrap();
+        assert!(set.has_independent_files());
+        assert_eq!(
+            set.files().iter().map(|f| f.len()).collect::<Vec<_>>(),
+            vec![1, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_has_independent_files_detects_shared_objects() {
+        let independent = |files: &[&str]| {
+            let dir = tempdir().unwrap();
+            for (i, sql) in files.iter().enumerate() {
+                fs::write(
+                    dir.path().join(format!("{:03}_step.sql", i)),
+                    format!("--- 0: Step\n{}", sql),
+                )
+                .unwrap();
+            }
+            let options = PostgresUpgraderOptions::builder().build();
+            MigrationSet::load(dir.path(), &options)
+                .unwrap()
+                .has_independent_files()
+        };
+
+        assert!(independent(&[
+            "CREATE TABLE {{SCHEMA}}.users (id INT);\nINSERT INTO users VALUES (1);",
+            "CREATE TABLE \"Orders\" (id INT);",
+        ]));
+        // A file reading a table that another file creates.
+        assert!(!independent(&[
+            "CREATE TABLE users (id INT);",
+            "CREATE VIEW active AS SELECT * FROM users;",
+        ]));
+        // Files that would each succeed in either order, with different results.
+        assert!(!independent(&[
+            "DROP TABLE IF EXISTS legacy;",
+            "ALTER TABLE {{SCHEMA}}.\"legacy\" ADD COLUMN note TEXT;",
+        ]));
+        assert!(!independent(&[
+            "CREATE OR REPLACE FUNCTION f() RETURNS INT AS 'SELECT 1' LANGUAGE sql;",
+            "ALTER FUNCTION f() OWNER TO admin;",
+        ]));
+        // Writes to a table that neither file creates.
+        assert!(!independent(&[
+            "INSERT INTO audit VALUES (1);",
+            "DELETE FROM audit;",
+        ]));
+    }
+
+    #[test]
+    fn test_touched_objects() {
+        let names = sql_names(
+            "CREATE TABLE IF NOT EXISTS {{SCHEMA}}.users (id INT);\nCREATE UNIQUE INDEX users_id ON users (id);\nALTER TABLE users ALTER COLUMN id TYPE BIGINT;\nUPDATE \"Settings\" SET v = 1;\nTRUNCATE TABLE logs;",
+        );
+        assert_eq!(
+            touched_objects(&names),
+            vec!["users", "users_id", "users", "settings", "logs"]
+        );
+
+        // Unnamed indexes, and type names and result columns that are no objects.
+        let names = sql_names(
+            "CREATE INDEX ON users (id);\nCREATE FUNCTION f() RETURNS TABLE (id INT) AS $$ INSERT INTO audit VALUES (1); $$ LANGUAGE sql;",
+        );
+        assert_eq!(touched_objects(&names), vec!["f", "audit"]);
+    }
+
+    #[test]
+    fn test_limited_to_file() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("000_users.sql"),
+            "--- 0: Create users\nCREATE TABLE users (id INT);\n--- 1: Index users\nCREATE INDEX ON users (id);",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("001_orders.sql"),
+            "--- 0: Create orders\nCREATE TABLE orders (id INT);",
+        )
+        .unwrap();
+
+        let set =
+            MigrationSet::load(dir.path(), &PostgresUpgraderOptions::builder().build()).unwrap();
+        let limited = set.limited_to_file(0).unwrap();
+        assert_eq!(limited.options.target, Some((0, 1)));
+        assert_eq!(limited.options.only_file, Some(0));
+        assert_eq!(limited.upgraders.len(), 3);
+        assert!(matches!(
+            set.limited_to_file(2),
+            Err(UpgraderError::ConfigurationError(_))
+        ));
+
+        // An earlier configured target is kept.
+        let options = PostgresUpgraderOptions::builder().target((0, 0)).build();
+        let set = MigrationSet::load(dir.path(), &options).unwrap();
+        assert_eq!(set.limited_to_file(0).unwrap().options.target, Some((0, 0)));
+    }
+
+    #[test]
+    fn test_snippet_truncates_long_sql() {
+        let sql = "a".repeat(SNIPPET_LENGTH + 10);
+        assert_eq!(snippet(&sql), format!("{}...", "a".repeat(SNIPPET_LENGTH)));
+        assert_eq!(snippet("SELECT 1;"), "SELECT 1;");
+    }
+}
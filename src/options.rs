@@ -1,10 +1,93 @@
+use crate::UpgraderError;
+use crate::identifier;
+use crate::maintenance::MaintenanceWindow;
+use crate::schema_loader::SchemaUpgrader;
+use crate::status::MigrationInfo;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::RangeInclusive;
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Default for the `connect_retry_delay` option.
+const DEFAULT_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Decides whether the SQL of an upgrader file (first argument) matches the SQL recorded in
+/// the database when it was applied (second argument).
+pub type ContentComparator = Arc<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// Wrapper that lets options holding a comparator keep deriving `Debug`.
+#[derive(Clone)]
+pub(crate) struct Comparator(ContentComparator);
+
+impl std::fmt::Debug for Comparator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Comparator(..)")
+    }
+}
+
+impl std::ops::Deref for Comparator {
+    type Target = dyn Fn(&str, &str) -> bool + Send + Sync;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+/// Called with the upgrader that is about to be, or has just been, applied.
+pub type ApplyHook = Arc<dyn Fn(&MigrationInfo) + Send + Sync>;
+
+/// Like [`ApplyHook`], but returns a future that the upgrade awaits.
+pub type AsyncApplyHook =
+    Arc<dyn Fn(&MigrationInfo) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// Called with the number of upgraders applied so far and the number pending when the
+/// upgrade started.
+pub type ProgressHook = Arc<dyn Fn(usize, usize) + Send + Sync>;
+
+/// Returns the time recorded as `applied_on` of the upgrader being applied.
+pub type Clock = Arc<dyn Fn() -> DateTime<Utc> + Send + Sync>;
+
+/// Wrapper that lets options holding hooks keep deriving `Debug`.
+#[derive(Clone)]
+pub(crate) struct Hook<F>(F);
+
+impl<F> std::fmt::Debug for Hook<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Hook(..)")
+    }
+}
+
+impl<F> std::ops::Deref for Hook<F> {
+    type Target = F;
+
+    fn deref(&self) -> &F {
+        &self.0
+    }
+}
+
 /// SSL Mode for the PostgreSQL connection.
+///
+/// Unlike libpq's `require`, `Require` verifies the server certificate and host name; it is
+/// the same as `VerifyFull`.
 #[cfg(feature = "tls")]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum SslMode {
+    /// Connect without TLS.
     #[default]
     Disable,
+    /// Try TLS, and connect without it if the TLS connection fails.
+    Prefer,
+    /// Use TLS and verify the server certificate and host name.
     Require,
+    /// Use TLS and verify the server certificate chain, but not the host name.
+    VerifyCa,
+    /// Use TLS and verify the server certificate chain and host name.
+    VerifyFull,
 }
 
 /// Options for the PostgreSQL schema upgrader.
@@ -12,8 +95,67 @@ pub enum SslMode {
 pub struct PostgresUpgraderOptions {
     #[cfg(feature = "tls")]
     pub(crate) ssl_mode: SslMode,
+    #[cfg(feature = "tls")]
+    pub(crate) client_cert_pem: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    pub(crate) client_key_pem: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    pub(crate) root_cert_pem: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    pub(crate) trust_system_roots: bool,
     pub(crate) schema: Option<String>,
     pub(crate) create_schema: bool,
+    pub(crate) upgraders_table: Option<String>,
+    pub(crate) unquoted_identifiers: bool,
+    pub(crate) audit_integrity_failures: bool,
+    pub(crate) role_per_schema: bool,
+    pub(crate) role_template: Option<String>,
+    pub(crate) content_comparator: Option<Comparator>,
+    pub(crate) allow_out_of_order: bool,
+    pub(crate) max_error_chars: Option<usize>,
+    pub(crate) deploy_key: Option<String>,
+    pub(crate) parallel_cold_start: bool,
+    pub(crate) track_verification: bool,
+    pub(crate) maintenance_window: Option<MaintenanceWindow>,
+    pub(crate) ignore_maintenance_window: bool,
+    pub(crate) advisory_lock_id: Option<i64>,
+    pub(crate) always_quote_schema: bool,
+    pub(crate) table_comment: Option<String>,
+    pub(crate) tracking_table_owner: Option<String>,
+    pub(crate) dry_run: bool,
+    pub(crate) schema_placeholder: Option<String>,
+    pub(crate) strict_schema_placeholder: bool,
+    pub(crate) variables: HashMap<String, String>,
+    pub(crate) strict_variables: bool,
+    pub(crate) on_before_apply: Option<Hook<ApplyHook>>,
+    pub(crate) on_after_apply: Option<Hook<ApplyHook>>,
+    pub(crate) on_before_apply_async: Option<Hook<AsyncApplyHook>>,
+    pub(crate) on_after_apply_async: Option<Hook<AsyncApplyHook>>,
+    pub(crate) on_progress: Option<Hook<ProgressHook>>,
+    pub(crate) clock: Option<Hook<Clock>>,
+    pub(crate) store_checksums: bool,
+    pub(crate) compare_checksums: bool,
+    pub(crate) lock_timeout: Option<Duration>,
+    pub(crate) statement_timeout: Option<Duration>,
+    pub(crate) lock_wait_timeout: Option<Duration>,
+    pub(crate) applied_on_skew_tolerance: Duration,
+    pub(crate) recursive: bool,
+    pub(crate) extensions: Option<Vec<String>>,
+    pub(crate) skip_integrity_check: bool,
+    pub(crate) applied_by: Option<String>,
+    pub(crate) target: Option<(i32, i32)>,
+    pub(crate) allow_empty_upgraders: bool,
+    pub(crate) connect_retries: u32,
+    pub(crate) connect_retry_delay: Option<Duration>,
+    pub(crate) use_search_path: bool,
+    pub(crate) tracking_schema: Option<String>,
+    pub(crate) max_parallel_files: Option<usize>,
+    pub(crate) single_transaction: bool,
+    pub(crate) debug_statements: bool,
+    pub(crate) frozen_upgraders: Vec<RangeInclusive<(i32, i32)>>,
+    pub(crate) run_analyze_after: bool,
+    /// Set by `apply_file_*`: the file whose upgraders the upgrade is limited to.
+    pub(crate) only_file: Option<i32>,
 }
 
 impl PostgresUpgraderOptions {
@@ -22,22 +164,502 @@ impl PostgresUpgraderOptions {
         PostgresUpgraderOptionsBuilder::default()
     }
 
-    pub(crate) fn apply_schema_substitution(&self, sql: &str) -> String {
-        if let Some(schema) = &self.schema {
-            sql.replace("{{SCHEMA}}", schema)
+    /// Checks that the combination of options is usable.
+    pub(crate) fn validate(&self) -> Result<(), UpgraderError> {
+        if self.schema_placeholder().is_empty() {
+            return Err(UpgraderError::ConfigurationError(
+                "schema_placeholder must not be empty.".to_string(),
+            ));
+        }
+
+        if self.extensions().is_empty() {
+            return Err(UpgraderError::ConfigurationError(
+                "extensions must not be empty.".to_string(),
+            ));
+        }
+
+        for key in self.variables.keys() {
+            if !is_variable_key(key) {
+                return Err(UpgraderError::ConfigurationError(format!(
+                    "Variable '{}' must be non-empty and contain only ASCII letters, digits and underscores.",
+                    key
+                )));
+            }
+            if format!("{{{{{}}}}}", key) == self.schema_placeholder() {
+                return Err(UpgraderError::ConfigurationError(format!(
+                    "Variable '{}' is reserved for the schema name.",
+                    key
+                )));
+            }
+        }
+
+        let names = self
+            .schema
+            .iter()
+            .map(|s| ("Schema", s.as_str()))
+            .chain(
+                self.tracking_schema
+                    .iter()
+                    .map(|s| ("Tracking schema", s.as_str())),
+            )
+            .chain([("Upgraders table", self.upgraders_table_name())]);
+        for (kind, name) in names {
+            if !identifier::is_valid_identifier(name) {
+                return Err(UpgraderError::ConfigurationError(format!(
+                    "{} name '{}' is not a valid identifier.",
+                    kind, name
+                )));
+            }
+        }
+
+        if self.create_schema && self.schema.is_none() {
+            return Err(UpgraderError::ExecutionError(
+                "create_schema is enabled but no schema name is provided.".to_string(),
+            ));
+        }
+
+        if self.use_search_path && self.schema.is_none() {
+            return Err(UpgraderError::ConfigurationError(
+                "use_search_path is enabled but no schema name is provided.".to_string(),
+            ));
+        }
+
+        let migration_role = self.migration_role();
+        if self.role_per_schema {
+            match migration_role.as_deref() {
+                None => {
+                    return Err(UpgraderError::ConfigurationError(
+                        "role_per_schema is enabled but no schema name is provided.".to_string(),
+                    ));
+                }
+                Some(role) if !identifier::is_valid_identifier(role) => {
+                    return Err(UpgraderError::ConfigurationError(format!(
+                        "Role name '{}' derived from the schema is not a valid identifier.",
+                        role
+                    )));
+                }
+                Some(_) => {}
+            }
+        }
+
+        if let Some(owner) = &self.tracking_table_owner
+            && !identifier::is_valid_identifier(owner)
+        {
+            return Err(UpgraderError::ConfigurationError(format!(
+                "Tracking table owner '{}' is not a valid identifier.",
+                owner
+            )));
+        }
+
+        if self
+            .deploy_key
+            .as_deref()
+            .is_some_and(|key| key.trim().is_empty())
+        {
+            return Err(UpgraderError::ConfigurationError(
+                "deploy_key must not be empty.".to_string(),
+            ));
+        }
+
+        if self.store_checksums && self.content_comparator.is_some() {
+            return Err(UpgraderError::ConfigurationError(
+                "content_comparator needs the recorded SQL and cannot be used with store_checksums."
+                    .to_string(),
+            ));
+        }
+
+        if self.compare_checksums && self.content_comparator.is_some() {
+            return Err(UpgraderError::ConfigurationError(
+                "content_comparator needs the recorded SQL and cannot be used with compare_checksums."
+                    .to_string(),
+            ));
+        }
+
+        if let Some(window) = &self.maintenance_window {
+            window.validate()?;
+        }
+
+        if self.unquoted_identifiers {
+            let identifiers = self
+                .schema
+                .iter()
+                .map(|s| ("Schema", s.as_str()))
+                .chain(
+                    self.tracking_schema
+                        .iter()
+                        .map(|s| ("Tracking schema", s.as_str())),
+                )
+                .chain(
+                    self.upgraders_table
+                        .iter()
+                        .map(|t| ("Upgraders table", t.as_str())),
+                )
+                .chain(migration_role.iter().map(|r| ("Role", r.as_str())))
+                .chain(
+                    self.tracking_table_owner
+                        .iter()
+                        .map(|r| ("Tracking table owner", r.as_str())),
+                );
+
+            for (kind, name) in identifiers {
+                if !identifier::is_simple_identifier(name) {
+                    return Err(UpgraderError::ConfigurationError(format!(
+                        "{} name '{}' requires quoting and cannot be used with unquoted_identifiers.",
+                        kind, name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the role to run upgraders as, if `role_per_schema` is enabled and a schema is set.
+    pub(crate) fn migration_role(&self) -> Option<String> {
+        if !self.role_per_schema {
+            return None;
+        }
+        let schema = self.schema.as_deref()?;
+        Some(match &self.role_template {
+            Some(template) => template.replace("{schema}", schema),
+            None => schema.to_string(),
+        })
+    }
+
+    /// Returns `true` if the upgrader `ids` lies in one of the `frozen_upgraders` ranges.
+    pub(crate) fn is_frozen(&self, ids: (i32, i32)) -> bool {
+        self.frozen_upgraders
+            .iter()
+            .any(|range| range.contains(&ids))
+    }
+
+    /// Returns the name of the table that tracks applied upgraders.
+    pub(crate) fn upgraders_table_name(&self) -> &str {
+        self.upgraders_table
+            .as_deref()
+            .unwrap_or(crate::db_tracker::DEFAULT_UPGRADERS_TABLE)
+    }
+
+    /// Returns the schema of the tracking tables: the tracking schema, else the target schema.
+    pub(crate) fn tracking_schema(&self) -> Option<&str> {
+        self.tracking_schema.as_deref().or(self.schema.as_deref())
+    }
+
+    /// Returns the schemas `create_schema` creates: the target schema, then the tracking
+    /// schema if it is a different one.
+    pub(crate) fn schemas_to_create(&self) -> Vec<&str> {
+        let mut schemas: Vec<&str> = self.schema.iter().map(String::as_str).collect();
+        if let Some(tracking) = self.tracking_schema.as_deref()
+            && !schemas.contains(&tracking)
+        {
+            schemas.push(tracking);
+        }
+        schemas
+    }
+
+    /// Returns how many files a parallel cold start applies at once; 1 disables it.
+    pub(crate) fn parallel_files(&self) -> usize {
+        match self.max_parallel_files {
+            Some(max_files) => max_files.max(1),
+            None if self.parallel_cold_start => crate::migration_set::PARALLEL_CONNECTIONS,
+            None => 1,
+        }
+    }
+
+    /// Returns the id of the advisory lock taken while initializing the upgraders table: the
+    /// configured one, else one derived from the tracking schema name, else the fixed default.
+    /// `public` gets the default too, since without a schema the upgraders table usually
+    /// lands there, and both must serialize against each other.
+    pub(crate) fn advisory_lock_id(&self) -> i64 {
+        match (self.advisory_lock_id, self.tracking_schema()) {
+            (Some(lock_id), _) => lock_id,
+            (None, Some(schema)) if schema != "public" => crate::db_tracker::schema_lock_id(schema),
+            (None, _) => crate::db_tracker::ADVISORY_LOCK_ID,
+        }
+    }
+
+    /// Returns the comment to set on the upgraders table.
+    pub(crate) fn table_comment(&self) -> String {
+        self.table_comment.clone().unwrap_or_else(|| {
+            format!(
+                "Managed by postgresql-schema-upgrader v{}; do not edit",
+                env!("CARGO_PKG_VERSION")
+            )
+        })
+    }
+
+    /// Returns the maximum number of characters of a database error in upgrader failures.
+    pub(crate) fn max_error_chars(&self) -> usize {
+        self.max_error_chars
+            .unwrap_or(crate::error::DEFAULT_MAX_ERROR_CHARS)
+    }
+
+    /// Returns the lowercase extensions of upgrader files, without a leading dot.
+    pub(crate) fn extensions(&self) -> Vec<String> {
+        match &self.extensions {
+            Some(extensions) => extensions
+                .iter()
+                .map(|e| e.trim_start_matches('.').to_lowercase())
+                .collect(),
+            None => crate::schema_loader::default_extensions(),
+        }
+    }
+
+    /// Returns the wait between connection attempts.
+    pub(crate) fn connect_retry_delay(&self) -> Duration {
+        self.connect_retry_delay
+            .unwrap_or(DEFAULT_CONNECT_RETRY_DELAY)
+    }
+
+    /// Returns `true` if `upgrader` is not after the configured target.
+    pub(crate) fn within_target(&self, upgrader: &SchemaUpgrader) -> bool {
+        self.target
+            .is_none_or(|target| (upgrader.file_id, upgrader.upgrader_id) <= target)
+    }
+
+    /// With `apply_file_*`, fails if an upgrader of a file before the requested one is still
+    /// pending, as applying the file would skip ahead of it.
+    pub(crate) fn check_only_file<'a>(
+        &self,
+        mut pending: impl Iterator<Item = &'a SchemaUpgrader>,
+    ) -> Result<(), UpgraderError> {
+        let Some(file_id) = self.only_file else {
+            return Ok(());
+        };
+        match pending.find(|u| u.file_id < file_id) {
+            Some(upgrader) => Err(UpgraderError::ConfigurationError(format!(
+                "Cannot apply file {} alone: upgrader {}:{} of an earlier file is not applied yet.",
+                file_id, upgrader.file_id, upgrader.upgrader_id
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the statements setting the configured timeouts, with `scope` `"LOCAL"` for the
+    /// current transaction or `"SESSION"`, or `None` if no timeout is configured.
+    pub(crate) fn timeout_settings(&self, scope: &str) -> Option<String> {
+        let settings: Vec<String> = [
+            ("lock_timeout", self.lock_timeout),
+            ("statement_timeout", self.statement_timeout),
+        ]
+        .into_iter()
+        .filter_map(|(name, timeout)| {
+            // At least 1ms, since 0 would disable the timeout.
+            timeout.map(|t| format!("SET {} {} = '{}ms';", scope, name, t.as_millis().max(1)))
+        })
+        .collect();
+        (!settings.is_empty()).then(|| settings.join(" "))
+    }
+
+    /// Returns the `SET <scope> search_path` statement putting the schema first, if
+    /// `use_search_path` is enabled and a schema is set.
+    pub(crate) fn search_path_setting(&self, scope: &str) -> Option<String> {
+        let schema = self.schema.as_deref().filter(|_| self.use_search_path)?;
+        Some(format!(
+            "SET {} search_path TO {}, public;",
+            scope,
+            self.identifier(schema)
+        ))
+    }
+
+    /// Renders an identifier for use in SQL, quoted unless `unquoted_identifiers` is set.
+    pub(crate) fn identifier(&self, name: &str) -> String {
+        if self.unquoted_identifiers {
+            name.to_string()
         } else {
-            sql.to_string()
+            identifier::quote(name)
+        }
+    }
+
+    /// Returns the token replaced by the schema name, `{{SCHEMA}}` unless configured.
+    pub(crate) fn schema_placeholder(&self) -> &str {
+        self.schema_placeholder.as_deref().unwrap_or("{{SCHEMA}}")
+    }
+
+    /// Returns the tokens to substitute and their values: the schema placeholder, when a
+    /// schema is set, and `{{KEY}}` for every variable. Longer tokens come first, so that a
+    /// token is never cut short by another one it starts with.
+    fn substitutions(&self) -> Vec<(String, String)> {
+        let mut substitutions: Vec<(String, String)> = self
+            .variables
+            .iter()
+            .map(|(key, value)| (format!("{{{{{}}}}}", key), value.clone()))
+            .collect();
+        if let Some(schema) = &self.schema {
+            let schema = if self.always_quote_schema || !identifier::is_simple_identifier(schema) {
+                identifier::quote(schema)
+            } else {
+                schema.clone()
+            };
+            substitutions.push((self.schema_placeholder().to_string(), schema));
+        }
+        substitutions.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+        substitutions
+    }
+
+    /// Replaces the schema placeholder with the schema name, quoted if it requires quoting or
+    /// `always_quote_schema` is set, and every `{{KEY}}` with the value of that variable.
+    ///
+    /// The SQL is scanned once, so substituted values are never substituted again.
+    pub(crate) fn apply_substitutions(&self, sql: &str) -> String {
+        let substitutions = self.substitutions();
+        if substitutions.is_empty() {
+            return sql.to_string();
+        }
+
+        let mut result = String::with_capacity(sql.len());
+        let mut rest = sql;
+        while let Some(c) = rest.chars().next() {
+            match substitutions
+                .iter()
+                .find(|(token, _)| rest.starts_with(token.as_str()))
+            {
+                Some((token, value)) => {
+                    result.push_str(value);
+                    rest = &rest[token.len()..];
+                }
+                None => {
+                    result.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+        result
+    }
+
+    /// With `strict_variables`, rejects upgraders with `{{...}}` tokens that
+    /// [`apply_substitutions`](Self::apply_substitutions) would leave in place.
+    pub(crate) fn check_variables(
+        &self,
+        upgraders: &[SchemaUpgrader],
+    ) -> Result<(), UpgraderError> {
+        if !self.strict_variables {
+            return Ok(());
+        }
+        let substitutions = self.substitutions();
+        let resolved = |token: &str| substitutions.iter().any(|(t, _)| t == token);
+        for u in upgraders {
+            for sql in std::iter::once(u.text.as_str()).chain(u.down_text.as_deref()) {
+                if let Some(token) = find_unresolved_token(sql, resolved) {
+                    return Err(UpgraderError::ConfigurationError(format!(
+                        "Upgrader {}:{} uses '{}', which is not a configured variable.",
+                        u.file_id, u.upgrader_id, token
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// With `strict_schema_placeholder`, rejects upgraders that use the schema placeholder
+    /// when there is no schema to substitute.
+    pub(crate) fn check_schema_placeholder(
+        &self,
+        upgraders: &[SchemaUpgrader],
+    ) -> Result<(), UpgraderError> {
+        if !self.strict_schema_placeholder || self.schema.is_some() {
+            return Ok(());
+        }
+        let placeholder = self.schema_placeholder();
+        match upgraders.iter().find(|u| {
+            u.text.contains(placeholder)
+                || u.down_text
+                    .as_deref()
+                    .is_some_and(|t| t.contains(placeholder))
+        }) {
+            Some(u) => Err(UpgraderError::ConfigurationError(format!(
+                "Upgrader {}:{} uses the schema placeholder '{}' but no schema is configured.",
+                u.file_id, u.upgrader_id, placeholder
+            ))),
+            None => Ok(()),
         }
     }
 }
 
+fn is_variable_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Returns the first `{{KEY}}` token of `sql`, with a valid variable key, that is not
+/// `resolved`.
+fn find_unresolved_token(sql: &str, resolved: impl Fn(&str) -> bool) -> Option<&str> {
+    let mut rest = sql;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        if let Some(end) = after.find("}}") {
+            let token = &rest[start..start + 2 + end + 2];
+            if is_variable_key(&after[..end]) && !resolved(token) {
+                return Some(token);
+            }
+        }
+        rest = after;
+    }
+    None
+}
+
 /// A builder for `PostgresUpgraderOptions`.
 #[derive(Default)]
 pub struct PostgresUpgraderOptionsBuilder {
     #[cfg(feature = "tls")]
     ssl_mode: SslMode,
+    #[cfg(feature = "tls")]
+    client_cert_pem: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    client_key_pem: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    root_cert_pem: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    trust_system_roots: bool,
     schema: Option<String>,
     create_schema: bool,
+    upgraders_table: Option<String>,
+    unquoted_identifiers: bool,
+    audit_integrity_failures: bool,
+    role_per_schema: bool,
+    role_template: Option<String>,
+    content_comparator: Option<Comparator>,
+    allow_out_of_order: bool,
+    max_error_chars: Option<usize>,
+    deploy_key: Option<String>,
+    parallel_cold_start: bool,
+    track_verification: bool,
+    maintenance_window: Option<MaintenanceWindow>,
+    ignore_maintenance_window: bool,
+    advisory_lock_id: Option<i64>,
+    always_quote_schema: bool,
+    table_comment: Option<String>,
+    tracking_table_owner: Option<String>,
+    dry_run: bool,
+    schema_placeholder: Option<String>,
+    strict_schema_placeholder: bool,
+    variables: HashMap<String, String>,
+    strict_variables: bool,
+    on_before_apply: Option<Hook<ApplyHook>>,
+    on_after_apply: Option<Hook<ApplyHook>>,
+    on_before_apply_async: Option<Hook<AsyncApplyHook>>,
+    on_after_apply_async: Option<Hook<AsyncApplyHook>>,
+    on_progress: Option<Hook<ProgressHook>>,
+    clock: Option<Hook<Clock>>,
+    store_checksums: bool,
+    compare_checksums: bool,
+    lock_timeout: Option<Duration>,
+    statement_timeout: Option<Duration>,
+    lock_wait_timeout: Option<Duration>,
+    applied_on_skew_tolerance: Duration,
+    recursive: bool,
+    extensions: Option<Vec<String>>,
+    skip_integrity_check: bool,
+    applied_by: Option<String>,
+    target: Option<(i32, i32)>,
+    allow_empty_upgraders: bool,
+    connect_retries: u32,
+    connect_retry_delay: Option<Duration>,
+    use_search_path: bool,
+    tracking_schema: Option<String>,
+    max_parallel_files: Option<usize>,
+    single_transaction: bool,
+    debug_statements: bool,
+    frozen_upgraders: Vec<RangeInclusive<(i32, i32)>>,
+    run_analyze_after: bool,
 }
 
 impl PostgresUpgraderOptionsBuilder {
@@ -48,7 +670,46 @@ impl PostgresUpgraderOptionsBuilder {
         self
     }
 
+    /// Sets the PEM file holding the client certificate chain presented to the server with
+    /// `SslMode::Require`, for servers that require mutual TLS. Must be set together with
+    /// [`client_key_pem`](Self::client_key_pem).
+    #[cfg(feature = "tls")]
+    pub fn client_cert_pem(mut self, path: impl Into<PathBuf>) -> Self {
+        self.client_cert_pem = Some(path.into());
+        self
+    }
+
+    /// Sets the PEM file holding the private key of the
+    /// [`client_cert_pem`](Self::client_cert_pem) certificate.
+    #[cfg(feature = "tls")]
+    pub fn client_key_pem(mut self, path: impl Into<PathBuf>) -> Self {
+        self.client_key_pem = Some(path.into());
+        self
+    }
+
+    /// Sets a PEM file of CA certificates to verify the server with, e.g. the private CA of an
+    /// internal cluster. Every certificate in the file is trusted, and the bundled webpki roots
+    /// are not unless [`trust_system_roots`](Self::trust_system_roots) is set.
+    #[cfg(feature = "tls")]
+    pub fn root_cert_pem(mut self, path: impl Into<PathBuf>) -> Self {
+        self.root_cert_pem = Some(path.into());
+        self
+    }
+
+    /// With [`root_cert_pem`](Self::root_cert_pem), whether to also trust the bundled webpki
+    /// roots. Defaults to `false`; without `root_cert_pem` the webpki roots are always used.
+    #[cfg(feature = "tls")]
+    pub fn trust_system_roots(mut self, trust: bool) -> Self {
+        self.trust_system_roots = trust;
+        self
+    }
+
     /// Sets the target schema for migrations.
+    ///
+    /// With a schema, the upgraders table and `{{SCHEMA}}` are always qualified with it,
+    /// which includes `schema("public")`. Without one, names are left unqualified and
+    /// resolve through the connection's `search_path`, which is `public` unless the role or
+    /// connection changes it.
     pub fn schema(mut self, schema: impl Into<String>) -> Self {
         self.schema = Some(schema.into());
         self
@@ -60,69 +721,1196 @@ impl PostgresUpgraderOptionsBuilder {
         self
     }
 
-    /// Builds a `PostgresUpgraderOptions` instance.
-    pub fn build(self) -> PostgresUpgraderOptions {
-        PostgresUpgraderOptions {
-            #[cfg(feature = "tls")]
-            ssl_mode: self.ssl_mode,
-            schema: self.schema,
-            create_schema: self.create_schema,
-        }
+    /// Sets the name of the table that tracks applied upgraders. Defaults to `$upgraders$`.
+    pub fn upgraders_table(mut self, table: impl Into<String>) -> Self {
+        self.upgraders_table = Some(table.into());
+        self
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Whether to emit the schema and upgraders table names without double quotes, so that
+    /// PostgreSQL's usual case folding applies to them.
+    ///
+    /// Only lowercase, non-reserved identifiers are accepted in this mode. The default
+    /// `$upgraders$` table, which belongs to the library, stays quoted, like the other tracking
+    /// tables; a custom [`upgraders_table`] is emitted unquoted. Defaults to `false`.
+    ///
+    /// [`upgraders_table`]: Self::upgraders_table
+    pub fn unquoted_identifiers(mut self, unquoted: bool) -> Self {
+        self.unquoted_identifiers = unquoted;
+        self
+    }
 
-    #[test]
-    fn test_builder_defaults() {
-        let options = PostgresUpgraderOptions::builder().build();
-        assert!(options.schema.is_none());
-        assert!(!options.create_schema);
-        #[cfg(feature = "tls")]
-        assert_eq!(options.ssl_mode, SslMode::Disable);
+    /// Whether to record integrity violations in a `$upgrader_audit$` table next to the
+    /// upgraders table before the upgrade is aborted. Each row captures the kind of
+    /// violation, the offending upgrader, the message, the host and the time, giving a trail
+    /// of blocked deploys. Defaults to `false`.
+    pub fn audit_integrity_failures(mut self, audit: bool) -> Self {
+        self.audit_integrity_failures = audit;
+        self
     }
 
-    #[test]
-    fn test_builder_custom_values() {
-        let options = PostgresUpgraderOptions::builder()
-            .schema("my_schema")
-            .create_schema(true)
-            .build();
+    /// Whether to run each upgrader as the role matching the schema, via `SET LOCAL ROLE`, so
+    /// that the objects it creates are owned by that role. Requires a schema. The role name
+    /// is the schema name unless a [`role_template`] is set. Defaults to `false`.
+    ///
+    /// [`role_template`]: Self::role_template
+    pub fn role_per_schema(mut self, enabled: bool) -> Self {
+        self.role_per_schema = enabled;
+        self
+    }
 
-        assert_eq!(options.schema.as_deref(), Some("my_schema"));
-        assert!(options.create_schema);
+    /// Sets the template used to derive the role name from the schema when
+    /// [`role_per_schema`] is enabled. `{schema}` is replaced with the schema name, e.g.
+    /// `tenant_{schema}`.
+    ///
+    /// [`role_per_schema`]: Self::role_per_schema
+    pub fn role_template(mut self, template: impl Into<String>) -> Self {
+        self.role_template = Some(template.into());
+        self
     }
 
-    #[test]
-    fn test_apply_schema_substitution_no_schema() {
-        let options = PostgresUpgraderOptions::builder().build();
-        let sql = "CREATE TABLE {{SCHEMA}}.test (id INT)";
-        let result = options.apply_schema_substitution(sql);
-        // Should remain unchanged if no schema is provided (or we might want to fail/strip?
-        // Current impl returns as is, which is correct behavior for "no substitution").
-        assert_eq!(result, sql);
+    /// Sets a custom comparison between the SQL in an upgrader file and the SQL recorded in
+    /// the database, replacing the built-in comparison that ignores leading and trailing
+    /// whitespace. Use it for team-specific normalization, such as ignoring a license header.
+    ///
+    /// The comparator decides what counts as "unchanged", so an overly lenient one
+    /// silently undermines the integrity guarantee: modified upgraders will no longer be
+    /// detected.
+    pub fn content_comparator(mut self, comparator: ContentComparator) -> Self {
+        self.content_comparator = Some(Comparator(comparator));
+        self
     }
 
-    #[test]
-    fn test_apply_schema_substitution_with_schema() {
-        let options = PostgresUpgraderOptions::builder()
-            .schema("my_schema")
-            .build();
-        let sql = "CREATE TABLE {{SCHEMA}}.test (id INT)";
-        let result = options.apply_schema_substitution(sql);
-        assert_eq!(result, "CREATE TABLE my_schema.test (id INT)");
+    /// Whether to apply every upgrader that is missing from the upgraders table, even if
+    /// upgraders with higher ids have already been applied. Defaults to `false`.
+    ///
+    /// This suits feature-branch workflows, where a step appended to an existing file on one
+    /// branch may land after a later file from another branch was already deployed. Instead
+    /// of failing with a gap, the missing upgraders are applied in id order, and the
+    /// integrity check no longer requires the recorded history to be a gap-free prefix
+    /// applied in chronological order. Recorded upgraders are still compared with the files,
+    /// and one that is missing from the files is still an error.
+    ///
+    /// The trade-off is that upgraders no longer run in the same order against every
+    /// database: an upgrader may run after later ones it was never tested with, so each one
+    /// must be independent of the upgraders developed in parallel with it. Use the
+    /// `sequence` column to see the order in which a database actually received them.
+    pub fn allow_out_of_order(mut self, allow: bool) -> Self {
+        self.allow_out_of_order = allow;
+        self
     }
 
-    #[test]
-    fn test_apply_schema_substitution_multiple_occurrences() {
-        let options = PostgresUpgraderOptions::builder().schema("public").build();
-        let sql = "SELECT * FROM {{SCHEMA}}.users JOIN {{SCHEMA}}.posts ON ...";
-        let result = options.apply_schema_substitution(sql);
-        assert_eq!(
-            result,
-            "SELECT * FROM public.users JOIN public.posts ON ..."
+    /// Sets the maximum number of characters of a database error shown when an upgrader
+    /// fails. Longer messages, such as errors listing thousands of conflicting rows, are cut
+    /// off with a `...(truncated)` suffix; the SQLSTATE is always kept. Defaults to 4096.
+    pub fn max_error_chars(mut self, max_chars: usize) -> Self {
+        self.max_error_chars = Some(max_chars);
+        self
+    }
+
+    /// Identifies the logical deploy running the upgrade, e.g. a release tag or CI pipeline
+    /// id. Once the database is fully upgraded the key is recorded in a `"$upgrader_deploys$"`
+    /// table next to the upgraders table, so a retried or repeated deploy with the same key
+    /// can be recognized as already processed instead of being counted again.
+    pub fn deploy_key(mut self, key: impl Into<String>) -> Self {
+        self.deploy_key = Some(key.into());
+        self
+    }
+
+    /// **Experimental.** Applies the upgraders of a fresh database concurrently, one file
+    /// per connection, instead of one after another. Only used by `MigrationSet::apply_*` and
+    /// `upgrade_async` / `upgrade_blocking`, and only when the upgraders table is empty, there
+    /// is more than one file, every upgrader runs in a transaction and the files look
+    /// independent (see below); otherwise the sequential upgrade runs as usual.
+    ///
+    /// The SQL of up to four files (see [`max_parallel_files`](Self::max_parallel_files))
+    /// runs at the same time, and the upgraders of each file run in order on its
+    /// connection. Upgraders are still recorded one by one in id order, so the history looks
+    /// like a sequential run.
+    ///
+    /// The parallel run is speculative and may fall back: if any upgrader fails or waits
+    /// too long for a lock, the ones after it are rolled back and the upgrade continues
+    /// sequentially from there, finishing with the usual integrity check. The error of the
+    /// failed step is logged as a warning with the `tracing` feature; the sequential run then
+    /// reports its own error if the upgrader fails again.
+    ///
+    /// Files applied concurrently cannot see each other's uncommitted changes, so this is
+    /// only correct when no file depends on an earlier one (e.g. each creates unrelated
+    /// tables). Dependencies are detected conservatively from the SQL text: if any file
+    /// mentions an object that another file creates, alters, drops, truncates or writes to,
+    /// the upgrade runs sequentially. Names are compared without their schema, so a name
+    /// shared by unrelated objects, or only mentioned in a comment, also disables the
+    /// parallel run. Objects created implicitly, such as the sequence of a `SERIAL` column,
+    /// and SQL built at run time, e.g. with `EXECUTE`, are not seen; files relying on them
+    /// must not be applied in parallel.
+    pub fn parallel_cold_start(mut self, enabled: bool) -> Self {
+        self.parallel_cold_start = enabled;
+        self
+    }
+
+    /// Records in a `last_verified_on` column when each applied upgrader was last confirmed
+    /// to match its file, as an audit trail that the schema was intact at that time. The
+    /// timestamp is set by every successful upgrade, also when nothing was pending, while
+    /// `applied_on` keeps the time of the first application. Disabled by default, since it
+    /// writes to the upgraders table and takes its lock on every run.
+    pub fn track_verification(mut self, enabled: bool) -> Self {
+        self.track_verification = enabled;
+        self
+    }
+
+    /// Restricts upgraders that destroy data or take heavy locks to a maintenance window.
+    ///
+    /// Before each such upgrader is applied, the current time is checked against `window`;
+    /// outside it the upgrade stops with `UpgraderError::MaintenanceWindowError`, keeping the
+    /// upgraders applied before it. Upgraders are considered locking if one of their
+    /// statements starts with `DROP`, `TRUNCATE`, `DELETE`, `ALTER TABLE`, `LOCK`, `CLUSTER`,
+    /// `REINDEX`, `VACUUM FULL` or `REFRESH MATERIALIZED VIEW`, or creates an index without
+    /// `CONCURRENTLY`. All other upgraders run at any time.
+    pub fn maintenance_window(mut self, window: MaintenanceWindow) -> Self {
+        self.maintenance_window = Some(window);
+        self
+    }
+
+    /// Applies locking upgraders even outside the `maintenance_window`, e.g. for an approved
+    /// emergency change.
+    pub fn ignore_maintenance_window(mut self, ignore: bool) -> Self {
+        self.ignore_maintenance_window = ignore;
+        self
+    }
+
+    /// Sets the id of the advisory lock that serializes creating and updating the upgraders
+    /// table. Applications that share a database but not their upgraders can use distinct
+    /// ids to avoid waiting for each other; every instance of the same application must use
+    /// the same id. Defaults to an id derived from the [`schema`](Self::schema) name, or to
+    /// 42004200 without a schema. The next two ids are taken as well: `lock_id + 1` around
+    /// `(no-transaction)` upgraders and `lock_id + 2` while applying upgraders.
+    pub fn advisory_lock_id(mut self, lock_id: i64) -> Self {
+        self.advisory_lock_id = Some(lock_id);
+        self
+    }
+
+    /// Whether to always double-quote the schema name substituted for `{{SCHEMA}}` in
+    /// upgrader SQL. By default it is quoted only when it has to be, i.e. when it contains
+    /// uppercase letters or other characters PostgreSQL would fold or reject, or is a
+    /// reserved word such as `order`. Defaults to `false`.
+    pub fn always_quote_schema(mut self, always: bool) -> Self {
+        self.always_quote_schema = always;
+        self
+    }
+
+    /// Sets the comment put on the upgraders table, which tells whoever finds the table what
+    /// manages it. Defaults to `Managed by postgresql-schema-upgrader vX.Y.Z; do not edit`,
+    /// with the version of this crate. The comment is only changed when it differs, so that
+    /// an up to date table is not locked.
+    pub fn table_comment(mut self, comment: impl Into<String>) -> Self {
+        self.table_comment = Some(comment.into());
+        self
+    }
+
+    /// Hands the upgraders table over to `role` when an upgrade creates it, with
+    /// `ALTER TABLE ... OWNER TO`, so that a bootstrap role can create the table for an
+    /// application role that should not be able to. The connecting user must be a member of
+    /// `role`. An existing table keeps its owner. Unset by default, which leaves the table
+    /// owned by the connecting user.
+    pub fn tracking_table_owner(mut self, role: impl Into<String>) -> Self {
+        self.tracking_table_owner = Some(role.into());
+        self
+    }
+
+    /// Whether to only report what an upgrade would do. The upgrade then takes the same lock
+    /// and runs the same integrity check as usual, but creates, applies and records nothing;
+    /// the upgraders it would apply are returned by [`dry_run_blocking`] and
+    /// [`dry_run_async`]. Defaults to `false`.
+    ///
+    /// [`dry_run_blocking`]: crate::dry_run_blocking
+    /// [`dry_run_async`]: crate::dry_run_async
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Sets the token replaced by the schema name in upgrader SQL, e.g. `:schema:` for SQL
+    /// that legitimately contains `{{SCHEMA}}`. Defaults to `{{SCHEMA}}`; must not be empty.
+    pub fn schema_placeholder(mut self, placeholder: impl Into<String>) -> Self {
+        self.schema_placeholder = Some(placeholder.into());
+        self
+    }
+
+    /// Whether loading upgraders whose SQL contains the
+    /// [`schema_placeholder`](Self::schema_placeholder) fails with
+    /// `UpgraderError::ConfigurationError` when no [`schema`](Self::schema) is set. Defaults to
+    /// `false`, which leaves the token in the SQL unchanged.
+    pub fn strict_schema_placeholder(mut self, strict: bool) -> Self {
+        self.strict_schema_placeholder = strict;
+        self
+    }
+
+    /// Registers a variable: `{{KEY}}` in upgrader SQL is replaced by `value`, e.g.
+    /// `variable("TABLESPACE", "fast_ssd")`. Keys may only contain ASCII letters, digits and
+    /// underscores. Setting the same key again replaces its value.
+    pub fn variable(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.variables.insert(key.into(), value.into());
+        self
+    }
+
+    /// Whether loading upgraders whose SQL contains a `{{...}}` token that is neither a
+    /// [`variable`](Self::variable) nor the schema placeholder of a configured schema fails
+    /// with `UpgraderError::ConfigurationError`. Defaults to `false`, which leaves such tokens
+    /// in the SQL unchanged.
+    pub fn strict_variables(mut self, strict: bool) -> Self {
+        self.strict_variables = strict;
+        self
+    }
+
+    /// Sets a callback invoked just before each upgrader is executed, e.g. for audit logging.
+    /// It runs inside the upgrader's transaction, so the upgrader may still fail or be rolled
+    /// back afterwards.
+    pub fn on_before_apply(mut self, hook: ApplyHook) -> Self {
+        self.on_before_apply = Some(Hook(hook));
+        self
+    }
+
+    /// Sets a callback invoked just after each upgrader has been committed, e.g. to invalidate
+    /// caches.
+    pub fn on_after_apply(mut self, hook: ApplyHook) -> Self {
+        self.on_after_apply = Some(Hook(hook));
+        self
+    }
+
+    /// Like [`on_before_apply`](Self::on_before_apply), but the returned future is awaited
+    /// before the upgrader is executed. Only supported by the async entry points; the
+    /// blocking ones fail with `UpgraderError::ConfigurationError` when it is set.
+    pub fn on_before_apply_async(mut self, hook: AsyncApplyHook) -> Self {
+        self.on_before_apply_async = Some(Hook(hook));
+        self
+    }
+
+    /// Like [`on_after_apply`](Self::on_after_apply), but the returned future is awaited
+    /// before the next upgrader is started. Only supported by the async entry points.
+    pub fn on_after_apply_async(mut self, hook: AsyncApplyHook) -> Self {
+        self.on_after_apply_async = Some(Hook(hook));
+        self
+    }
+
+    /// Sets a callback invoked after each commit of the upgrade with
+    /// `(applied_so_far, total_pending)`, e.g. to drive a progress bar. `total_pending` is
+    /// counted once, before the first upgrader is applied, and covers the upgraders up to the
+    /// [`target`](Self::target). Not called when nothing is pending.
+    ///
+    /// With [`single_transaction`](Self::single_transaction) it is called once, and with
+    /// [`parallel_cold_start`](Self::parallel_cold_start) once per batch of files.
+    pub fn on_progress(mut self, hook: ProgressHook) -> Self {
+        self.on_progress = Some(Hook(hook));
+        self
+    }
+
+    /// Takes the `applied_on` of each upgrader from `clock` instead of the database's `now()`,
+    /// the start of its transaction, e.g. `Arc::new(Utc::now)`, or a fixed time for
+    /// reproducible tests. The clock must not go backwards between upgraders: one recorded
+    /// before the previous upgrader is an integrity violation.
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = Some(Hook(clock));
+        self
+    }
+
+    /// Records a SHA-256 checksum of each upgrader's SQL in a `checksum` column instead of
+    /// the SQL itself, and compares checksums in the integrity check. This keeps the
+    /// upgraders table small for large DDL. Disabled by default.
+    ///
+    /// Upgraders recorded before it was enabled keep their SQL and are checked against its
+    /// checksum, computed on load. Since the SQL of newer upgraders is not stored, it cannot
+    /// be combined with `content_comparator`, and `export_as_files_*` cannot export them.
+    pub fn store_checksums(mut self, enabled: bool) -> Self {
+        self.store_checksums = enabled;
+        self
+    }
+
+    /// Reads a SHA-256 checksum of each recorded upgrader's SQL, computed by the database,
+    /// instead of the SQL itself when loading the history for the integrity check. This saves
+    /// memory and network for histories of thousands of upgraders; the check passes and fails
+    /// as it does without it. Disabled by default.
+    ///
+    /// Needs PostgreSQL 11 or later. Like `store_checksums`, it cannot be combined with
+    /// `content_comparator`, and [`MigrationStatus::applied`] then mostly holds checksums
+    /// rather than SQL. `repair_*` and `export_as_files_*` still read the SQL.
+    ///
+    /// [`MigrationStatus::applied`]: crate::MigrationStatus::applied
+    pub fn compare_checksums(mut self, enabled: bool) -> Self {
+        self.compare_checksums = enabled;
+        self
+    }
+
+    /// Sets `lock_timeout` in every transaction of the upgrade, so that waiting for the
+    /// upgrade lock or for a lock taken by an upgrader's SQL fails with
+    /// `UpgraderError::TimeoutError` instead of blocking indefinitely. Unset by default,
+    /// which keeps the server's setting.
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets `statement_timeout` in every transaction of the upgrade, so that an upgrader whose
+    /// SQL runs longer fails with `UpgraderError::TimeoutError` and is rolled back. Unset by
+    /// default, which keeps the server's setting.
+    pub fn statement_timeout(mut self, timeout: Duration) -> Self {
+        self.statement_timeout = Some(timeout);
+        self
+    }
+
+    /// Bounds the wait for the advisory locks that serialize upgrades of the same schema: the
+    /// one taken to initialize the upgraders table, the one taken before each upgrader, and
+    /// the session lock taken before each `(no-transaction)` upgrader.
+    /// When set, each lock is retried until `timeout` elapses, and the upgrade fails with
+    /// `UpgraderError::LockTimeout` if another process still holds it, e.g. while applying a
+    /// long upgrader. Unset by default, which waits for the locks indefinitely.
+    pub fn lock_wait_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_wait_timeout = Some(timeout);
+        self
+    }
+
+    /// How far the `applied_on` of a recorded upgrader may lie before the latest one recorded
+    /// ahead of it without failing the integrity check, e.g. when upgrades run from servers
+    /// whose [`clock`](Self::clock)s disagree slightly, or across a database failover.
+    /// Defaults to zero, which requires `applied_on` to never decrease.
+    pub fn applied_on_skew_tolerance(mut self, tolerance: Duration) -> Self {
+        self.applied_on_skew_tolerance = tolerance;
+        self
+    }
+
+    /// Whether to load upgrader files from subdirectories of the upgraders folder as well, so
+    /// that large projects can group them, e.g. by feature. File ids are still taken from the
+    /// leading number of each file name and must be sequential from 0 across all directories.
+    /// Defaults to `false`, which rejects subdirectories.
+    pub fn recursive(mut self, enabled: bool) -> Self {
+        self.recursive = enabled;
+        self
+    }
+
+    /// Sets the file extensions loaded as upgrader files, e.g. `["pgsql", "sql.tera"]` for
+    /// templated migrations. Matching is case-insensitive; other files are ignored. Defaults
+    /// to `["sql", "ddl"]`. An empty list is rejected with `ConfigurationError`.
+    pub fn extensions(mut self, extensions: Vec<String>) -> Self {
+        self.extensions = Some(extensions);
+        self
+    }
+
+    /// Whether to check the recorded upgraders against the files before applying pending
+    /// ones. Defaults to `true`. Disabling it lets an operator knowingly apply pending
+    /// upgraders despite a mismatch, e.g. while recovering from an edited file; every such
+    /// upgrade logs a warning with the `tracing` feature. Status and downgrades still check.
+    pub fn verify_integrity(mut self, enabled: bool) -> Self {
+        self.skip_integrity_check = !enabled;
+        self
+    }
+
+    /// Identifies who applies the upgrade, e.g. a user or service account, for audit trails.
+    /// It is recorded in the `applied_by` column of every upgrader this upgrade applies, next
+    /// to the host. Unset by default, which leaves the column NULL.
+    pub fn applied_by(mut self, applied_by: impl Into<String>) -> Self {
+        self.applied_by = Some(applied_by.into());
+        self
+    }
+
+    /// Stops the upgrade after the upgrader `target`, a `(file_id, upgrader_id)` pair, for
+    /// staged rollouts; later upgraders stay pending for a subsequent upgrade. The target must
+    /// be one of the loaded upgraders. Unset by default, which applies every pending upgrader.
+    /// Setting it disables [`parallel_cold_start`](Self::parallel_cold_start).
+    pub fn target(mut self, target: (i32, i32)) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Whether upgraders whose SQL is empty are kept, e.g. placeholders for ids whose SQL was
+    /// removed. They are recorded with empty text, so they keep their id and get a row in the
+    /// upgraders table like any other upgrader, and the upgrader ids of each file must then be
+    /// contiguous from 0. Defaults to `false`, which skips them and therefore accepts the holes
+    /// they leave in the ids.
+    pub fn allow_empty_upgraders(mut self, allow: bool) -> Self {
+        self.allow_empty_upgraders = allow;
+        self
+    }
+
+    /// Retries connecting up to `retries` more times when the server is not reachable or not
+    /// accepting connections yet, e.g. while its container starts, waiting
+    /// [`connect_retry_delay`](Self::connect_retry_delay) between attempts. Errors the server
+    /// reports otherwise, such as a wrong password, fail at once. Defaults to `0`.
+    pub fn connect_retries(mut self, retries: u32) -> Self {
+        self.connect_retries = retries;
+        self
+    }
+
+    /// Sets the wait between connection attempts with
+    /// [`connect_retries`](Self::connect_retries). Defaults to one second.
+    pub fn connect_retry_delay(mut self, delay: Duration) -> Self {
+        self.connect_retry_delay = Some(delay);
+        self
+    }
+
+    /// Whether to resolve unqualified names in upgraders to the schema via `SET LOCAL
+    /// search_path TO <schema>, public` at the start of each upgrade transaction, so that
+    /// upgraders work without the schema placeholder. Requires a schema. The placeholder is
+    /// still substituted where it is used, and the upgraders table is always schema-qualified.
+    /// Defaults to `false`.
+    pub fn use_search_path(mut self, enabled: bool) -> Self {
+        self.use_search_path = enabled;
+        self
+    }
+
+    /// Sets the schema of the upgraders table and the other tracking tables, e.g. a central
+    /// `meta` schema, while upgraders target the [`schema`](Self::schema). Defaults to the
+    /// target schema. It is created along with it with `create_schema`.
+    pub fn tracking_schema(mut self, schema: impl Into<String>) -> Self {
+        self.tracking_schema = Some(schema.into());
+        self
+    }
+
+    /// **Experimental.** Sets how many files a [`parallel_cold_start`] applies at the same
+    /// time, each on its own connection, and enables it when greater than 1. Defaults to 4
+    /// with `parallel_cold_start`, and to 1 without it, which applies every upgrader
+    /// sequentially.
+    ///
+    /// The same caveat applies: files must not depend on each other, and files that look
+    /// dependent are applied sequentially.
+    ///
+    /// [`parallel_cold_start`]: Self::parallel_cold_start
+    pub fn max_parallel_files(mut self, max_files: usize) -> Self {
+        self.max_parallel_files = Some(max_files);
+        self
+    }
+
+    /// Whether to apply every pending upgrader, and record it, in one transaction that is
+    /// committed at the end, so that a failing upgrader leaves the database as it was before
+    /// the upgrade. The upgraders table stays locked for the whole upgrade. Upgraders marked
+    /// `(no-transaction)` cannot run this way and make the upgrade fail with a
+    /// `ConfigurationError`, and [`parallel_cold_start`](Self::parallel_cold_start) is not
+    /// used. Defaults to `false`, which commits after each upgrader.
+    pub fn single_transaction(mut self, enabled: bool) -> Self {
+        self.single_transaction = enabled;
+        self
+    }
+
+    /// Whether to execute the SQL of each upgrader one statement at a time instead of as a
+    /// single batch, so that a failure reports which statement failed, e.g. `statement 3 of
+    /// upgrader 1:0 failed: ...`, in the `statement` of `UpgraderError::ExecutionFailed`.
+    /// Statements are split at semicolons outside strings, quoted identifiers, dollar-quoted
+    /// bodies and comments; `BEGIN ATOMIC` function bodies are not supported. Upgraders marked
+    /// `(no-transaction)` still run as a batch. Defaults to `false`.
+    pub fn debug_statements(mut self, enabled: bool) -> Self {
+        self.debug_statements = enabled;
+        self
+    }
+
+    /// Marks the upgraders in `range`, from and including one `(file_id, upgrader_id)` pair up
+    /// to another, as frozen: the integrity check no longer compares their SQL and description
+    /// with the files, so that old upgraders can be squashed, e.g. into a single baseline
+    /// upgrader followed by empty ones, without rewriting the upgraders table. Frozen upgraders
+    /// must still be present in the files, in the same order, and a fresh database applies
+    /// their SQL from the files as usual. May be called repeatedly to freeze several ranges.
+    pub fn frozen_upgraders(mut self, range: RangeInclusive<(i32, i32)>) -> Self {
+        self.frozen_upgraders.push(range);
+        self
+    }
+
+    /// Whether to refresh the planner statistics once an upgrade has applied upgraders, which
+    /// bulk data changes leave stale: `ANALYZE` of every table in the schema, or of the whole
+    /// database without one. It runs after the last upgrader is committed, outside any
+    /// transaction, and not at all when nothing was applied. A failure is returned as an
+    /// `ExecutionError`, with the upgraders left applied. Defaults to `false`.
+    pub fn run_analyze_after(mut self, enabled: bool) -> Self {
+        self.run_analyze_after = enabled;
+        self
+    }
+
+    /// Builds a `PostgresUpgraderOptions` instance.
+    pub fn build(self) -> PostgresUpgraderOptions {
+        PostgresUpgraderOptions {
+            #[cfg(feature = "tls")]
+            ssl_mode: self.ssl_mode,
+            #[cfg(feature = "tls")]
+            client_cert_pem: self.client_cert_pem,
+            #[cfg(feature = "tls")]
+            client_key_pem: self.client_key_pem,
+            #[cfg(feature = "tls")]
+            root_cert_pem: self.root_cert_pem,
+            #[cfg(feature = "tls")]
+            trust_system_roots: self.trust_system_roots,
+            schema: self.schema,
+            create_schema: self.create_schema,
+            upgraders_table: self.upgraders_table,
+            unquoted_identifiers: self.unquoted_identifiers,
+            audit_integrity_failures: self.audit_integrity_failures,
+            role_per_schema: self.role_per_schema,
+            role_template: self.role_template,
+            content_comparator: self.content_comparator,
+            allow_out_of_order: self.allow_out_of_order,
+            max_error_chars: self.max_error_chars,
+            deploy_key: self.deploy_key,
+            parallel_cold_start: self.parallel_cold_start,
+            track_verification: self.track_verification,
+            maintenance_window: self.maintenance_window,
+            ignore_maintenance_window: self.ignore_maintenance_window,
+            advisory_lock_id: self.advisory_lock_id,
+            always_quote_schema: self.always_quote_schema,
+            table_comment: self.table_comment,
+            tracking_table_owner: self.tracking_table_owner,
+            dry_run: self.dry_run,
+            schema_placeholder: self.schema_placeholder,
+            strict_schema_placeholder: self.strict_schema_placeholder,
+            variables: self.variables,
+            strict_variables: self.strict_variables,
+            on_before_apply: self.on_before_apply,
+            on_after_apply: self.on_after_apply,
+            on_before_apply_async: self.on_before_apply_async,
+            on_after_apply_async: self.on_after_apply_async,
+            on_progress: self.on_progress,
+            clock: self.clock,
+            store_checksums: self.store_checksums,
+            compare_checksums: self.compare_checksums,
+            lock_timeout: self.lock_timeout,
+            statement_timeout: self.statement_timeout,
+            lock_wait_timeout: self.lock_wait_timeout,
+            applied_on_skew_tolerance: self.applied_on_skew_tolerance,
+            recursive: self.recursive,
+            extensions: self.extensions,
+            skip_integrity_check: self.skip_integrity_check,
+            applied_by: self.applied_by,
+            target: self.target,
+            allow_empty_upgraders: self.allow_empty_upgraders,
+            connect_retries: self.connect_retries,
+            connect_retry_delay: self.connect_retry_delay,
+            use_search_path: self.use_search_path,
+            tracking_schema: self.tracking_schema,
+            max_parallel_files: self.max_parallel_files,
+            single_transaction: self.single_transaction,
+            debug_statements: self.debug_statements,
+            frozen_upgraders: self.frozen_upgraders,
+            run_analyze_after: self.run_analyze_after,
+            only_file: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_defaults() {
+        let options = PostgresUpgraderOptions::builder().build();
+        assert!(options.schema.is_none());
+        assert!(!options.create_schema);
+        assert_eq!(options.upgraders_table_name(), "$upgraders$");
+        assert!(!options.unquoted_identifiers);
+        assert!(!options.audit_integrity_failures);
+        assert!(options.migration_role().is_none());
+        assert!(!options.allow_out_of_order);
+        assert!(!options.skip_integrity_check);
+        assert_eq!(options.connect_retries, 0);
+        assert_eq!(options.connect_retry_delay(), Duration::from_secs(1));
+        assert_eq!(options.max_error_chars(), 4096);
+        assert_eq!(
+            options.table_comment(),
+            format!(
+                "Managed by postgresql-schema-upgrader v{}; do not edit",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert_eq!(options.advisory_lock_id(), 42_00_42_00);
+        #[cfg(feature = "tls")]
+        assert_eq!(options.ssl_mode, SslMode::Disable);
+    }
+
+    #[test]
+    fn test_builder_custom_values() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("my_schema")
+            .create_schema(true)
+            .build();
+
+        assert_eq!(options.schema.as_deref(), Some("my_schema"));
+        assert!(options.create_schema);
+    }
+
+    #[test]
+    fn test_apply_schema_substitution_no_schema() {
+        let options = PostgresUpgraderOptions::builder().build();
+        let sql = "CREATE TABLE {{SCHEMA}}.test (id INT)";
+        let result = options.apply_substitutions(sql);
+        // Should remain unchanged if no schema is provided (or we might want to fail/strip?
+        // Current impl returns as is, which is correct behavior for "no substitution").
+        assert_eq!(result, sql);
+    }
+
+    #[test]
+    fn test_apply_schema_substitution_with_schema() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("my_schema")
+            .build();
+        let sql = "CREATE TABLE {{SCHEMA}}.test (id INT)";
+        let result = options.apply_substitutions(sql);
+        assert_eq!(result, "CREATE TABLE my_schema.test (id INT)");
+    }
+
+    #[test]
+    fn test_apply_schema_substitution_multiple_occurrences() {
+        let options = PostgresUpgraderOptions::builder().schema("public").build();
+        let sql = "SELECT * FROM {{SCHEMA}}.users JOIN {{SCHEMA}}.posts ON ...";
+        let result = options.apply_substitutions(sql);
+        assert_eq!(
+            result,
+            "SELECT * FROM public.users JOIN public.posts ON ..."
+        );
+    }
+
+    #[test]
+    fn test_apply_schema_substitution_quotes_when_required() {
+        let sql = "CREATE TABLE {{SCHEMA}}.users (id INT)";
+        for (schema, expected) in [
+            ("Order", "CREATE TABLE \"Order\".users (id INT)"),
+            ("order", "CREATE TABLE \"order\".users (id INT)"),
+            ("MyApp", "CREATE TABLE \"MyApp\".users (id INT)"),
+            ("my-app", "CREATE TABLE \"my-app\".users (id INT)"),
+        ] {
+            let options = PostgresUpgraderOptions::builder().schema(schema).build();
+            assert_eq!(options.apply_substitutions(sql), expected);
+        }
+    }
+
+    #[test]
+    fn test_apply_schema_substitution_always_quote() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("my_schema")
+            .always_quote_schema(true)
+            .build();
+        let sql = "CREATE TABLE {{SCHEMA}}.test (id INT)";
+        let result = options.apply_substitutions(sql);
+        assert_eq!(result, "CREATE TABLE \"my_schema\".test (id INT)");
+    }
+
+    #[test]
+    fn test_advisory_lock_id_derived_from_schema() {
+        let billing = PostgresUpgraderOptions::builder().schema("billing").build();
+        let orders = PostgresUpgraderOptions::builder().schema("orders").build();
+        assert_eq!(
+            billing.advisory_lock_id(),
+            crate::db_tracker::schema_lock_id("billing")
+        );
+        assert_ne!(billing.advisory_lock_id(), orders.advisory_lock_id());
+
+        let explicit = PostgresUpgraderOptions::builder()
+            .schema("billing")
+            .advisory_lock_id(7)
+            .build();
+        assert_eq!(explicit.advisory_lock_id(), 7);
+
+        // Upgrades of public with and without the schema set serialize against each other.
+        let public = PostgresUpgraderOptions::builder().schema("public").build();
+        assert_eq!(
+            public.advisory_lock_id(),
+            PostgresUpgraderOptions::builder()
+                .build()
+                .advisory_lock_id()
+        );
+    }
+
+    #[test]
+    fn test_public_schema_is_qualified() {
+        let public = PostgresUpgraderOptions::builder().schema("public").build();
+        assert_eq!(
+            crate::db_tracker::table_name(&public),
+            "\"public\".\"$upgraders$\""
+        );
+        assert_eq!(
+            public.apply_substitutions("CREATE TABLE {{SCHEMA}}.t (id INT)"),
+            "CREATE TABLE public.t (id INT)"
+        );
+
+        let none = PostgresUpgraderOptions::builder().build();
+        assert_eq!(crate::db_tracker::table_name(&none), "\"$upgraders$\"");
+        assert_eq!(
+            none.apply_substitutions("CREATE TABLE {{SCHEMA}}.t (id INT)"),
+            "CREATE TABLE {{SCHEMA}}.t (id INT)"
+        );
+    }
+
+    #[test]
+    fn test_validate_create_schema_without_schema_fails() {
+        let options = PostgresUpgraderOptions::builder()
+            .create_schema(true)
+            .build();
+        let err = options.validate().unwrap_err();
+        assert!(err.to_string().contains("no schema name is provided"));
+    }
+
+    #[test]
+    fn test_validate_create_schema_with_schema() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("my_schema")
+            .create_schema(true)
+            .build();
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn test_identifier_quoted_by_default() {
+        let options = PostgresUpgraderOptions::builder().build();
+        assert_eq!(options.identifier("MyTable"), "\"MyTable\"");
+    }
+
+    #[test]
+    fn test_identifier_unquoted() {
+        let options = PostgresUpgraderOptions::builder()
+            .upgraders_table("schema_upgraders")
+            .unquoted_identifiers(true)
+            .build();
+        assert!(options.validate().is_ok());
+        assert_eq!(options.identifier("schema_upgraders"), "schema_upgraders");
+    }
+
+    #[test]
+    fn test_validate_unquoted_accepts_default_table() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("tenant")
+            .unquoted_identifiers(true)
+            .build();
+        assert!(options.validate().is_ok());
+
+        let options = PostgresUpgraderOptions::builder()
+            .upgraders_table("$custom$")
+            .unquoted_identifiers(true)
+            .build();
+        let err = options.validate().unwrap_err();
+        assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+        assert!(err.to_string().contains("$custom$"));
+    }
+
+    #[test]
+    fn test_validate_unquoted_rejects_schema_needing_quotes() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("MySchema")
+            .upgraders_table("schema_upgraders")
+            .unquoted_identifiers(true)
+            .build();
+        let err = options.validate().unwrap_err();
+        assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+        assert!(err.to_string().contains("MySchema"));
+    }
+
+    #[test]
+    fn test_validate_quoted_accepts_any_table_name() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("MySchema")
+            .upgraders_table("Schema Upgraders")
+            .build();
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_invalid_identifiers() {
+        for options in [
+            PostgresUpgraderOptions::builder()
+                .schema("tenant\"; DROP SCHEMA public; --")
+                .build(),
+            PostgresUpgraderOptions::builder()
+                .tracking_schema("tracking\"")
+                .build(),
+            PostgresUpgraderOptions::builder()
+                .upgraders_table("upgraders\0")
+                .build(),
+            PostgresUpgraderOptions::builder().schema("").build(),
+            PostgresUpgraderOptions::builder()
+                .schema("a".repeat(64))
+                .build(),
+        ] {
+            assert!(matches!(
+                options.validate(),
+                Err(UpgraderError::ConfigurationError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_validate_tracking_table_owner() {
+        let options = PostgresUpgraderOptions::builder()
+            .tracking_table_owner("app\"owner")
+            .build();
+        assert!(matches!(
+            options.validate(),
+            Err(UpgraderError::ConfigurationError(_))
+        ));
+
+        let options = PostgresUpgraderOptions::builder()
+            .tracking_table_owner("AppOwner")
+            .build();
+        assert!(options.validate().is_ok());
+        let options = PostgresUpgraderOptions::builder()
+            .unquoted_identifiers(true)
+            .upgraders_table("schema_upgraders")
+            .tracking_table_owner("AppOwner")
+            .build();
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn test_migration_role_from_schema() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("tenant_a")
+            .role_per_schema(true)
+            .build();
+        assert!(options.validate().is_ok());
+        assert_eq!(options.migration_role().as_deref(), Some("tenant_a"));
+    }
+
+    #[test]
+    fn test_migration_role_from_template() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("a")
+            .role_per_schema(true)
+            .role_template("tenant_{schema}_owner")
+            .build();
+        assert!(options.validate().is_ok());
+        assert_eq!(options.migration_role().as_deref(), Some("tenant_a_owner"));
+    }
+
+    #[test]
+    fn test_migration_role_template_ignored_when_disabled() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("a")
+            .role_template("tenant_{schema}")
+            .build();
+        assert!(options.migration_role().is_none());
+    }
+
+    #[test]
+    fn test_validate_role_per_schema_without_schema_fails() {
+        let options = PostgresUpgraderOptions::builder()
+            .role_per_schema(true)
+            .build();
+        let err = options.validate().unwrap_err();
+        assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+    }
+
+    #[test]
+    fn test_validate_invalid_role_fails() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("a")
+            .role_per_schema(true)
+            .role_template("tenant\"{schema}")
+            .build();
+        let err = options.validate().unwrap_err();
+        assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+
+        let options = PostgresUpgraderOptions::builder()
+            .schema("a")
+            .role_per_schema(true)
+            .role_template("Tenant_{schema}")
+            .upgraders_table("schema_upgraders")
+            .unquoted_identifiers(true)
+            .build();
+        let err = options.validate().unwrap_err();
+        assert!(err.to_string().contains("Tenant_a"));
+    }
+
+    #[test]
+    fn test_validate_empty_deploy_key_fails() {
+        let options = PostgresUpgraderOptions::builder().deploy_key(" ").build();
+        let err = options.validate().unwrap_err();
+        assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+
+        let options = PostgresUpgraderOptions::builder()
+            .deploy_key("release-1.2.0")
+            .build();
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_empty_maintenance_window_fails() {
+        let noon = chrono::NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let options = PostgresUpgraderOptions::builder()
+            .maintenance_window(MaintenanceWindow::daily_utc(noon, noon))
+            .build();
+        let err = options.validate().unwrap_err();
+        assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+    }
+
+    #[test]
+    fn test_validate_checksums_with_comparator_fails() {
+        let options = PostgresUpgraderOptions::builder()
+            .store_checksums(true)
+            .content_comparator(Arc::new(|a: &str, b: &str| a == b))
+            .build();
+        let err = options.validate().unwrap_err();
+        assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+
+        let options = PostgresUpgraderOptions::builder()
+            .store_checksums(true)
+            .build();
+        assert!(options.validate().is_ok());
+
+        let options = PostgresUpgraderOptions::builder()
+            .compare_checksums(true)
+            .content_comparator(Arc::new(|a: &str, b: &str| a == b))
+            .build();
+        let err = options.validate().unwrap_err();
+        assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+    }
+
+    #[test]
+    fn test_extensions() {
+        let options = PostgresUpgraderOptions::builder().build();
+        assert_eq!(options.extensions(), vec!["sql", "ddl"]);
+
+        let options = PostgresUpgraderOptions::builder()
+            .extensions(vec![".PgSQL".to_string(), "sql.tera".to_string()])
+            .build();
+        assert_eq!(options.extensions(), vec!["pgsql", "sql.tera"]);
+        assert!(options.validate().is_ok());
+
+        let options = PostgresUpgraderOptions::builder()
+            .extensions(Vec::new())
+            .build();
+        let err = options.validate().unwrap_err();
+        assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+    }
+
+    #[test]
+    fn test_timeout_settings() {
+        let options = PostgresUpgraderOptions::builder().build();
+        assert_eq!(options.timeout_settings("LOCAL"), None);
+
+        let options = PostgresUpgraderOptions::builder()
+            .lock_timeout(Duration::from_secs(5))
+            .build();
+        assert_eq!(
+            options.timeout_settings("LOCAL").as_deref(),
+            Some("SET LOCAL lock_timeout = '5000ms';")
+        );
+
+        let options = PostgresUpgraderOptions::builder()
+            .lock_timeout(Duration::from_micros(10))
+            .statement_timeout(Duration::from_secs(60))
+            .build();
+        assert_eq!(
+            options.timeout_settings("SESSION").as_deref(),
+            Some("SET SESSION lock_timeout = '1ms'; SET SESSION statement_timeout = '60000ms';")
+        );
+    }
+
+    #[test]
+    fn test_parallel_files() {
+        let options = PostgresUpgraderOptions::builder().build();
+        assert_eq!(options.parallel_files(), 1);
+
+        let options = PostgresUpgraderOptions::builder()
+            .parallel_cold_start(true)
+            .build();
+        assert_eq!(options.parallel_files(), 4);
+
+        let options = PostgresUpgraderOptions::builder()
+            .max_parallel_files(8)
+            .build();
+        assert_eq!(options.parallel_files(), 8);
+
+        let options = PostgresUpgraderOptions::builder()
+            .parallel_cold_start(true)
+            .max_parallel_files(0)
+            .build();
+        assert_eq!(options.parallel_files(), 1);
+    }
+
+    #[test]
+    fn test_search_path_setting() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("tenant_a")
+            .build();
+        assert_eq!(options.search_path_setting("LOCAL"), None);
+
+        let options = PostgresUpgraderOptions::builder()
+            .schema("tenant_a")
+            .use_search_path(true)
+            .build();
+        assert_eq!(
+            options.search_path_setting("LOCAL").as_deref(),
+            Some("SET LOCAL search_path TO \"tenant_a\", public;")
+        );
+
+        let options = PostgresUpgraderOptions::builder()
+            .use_search_path(true)
+            .build();
+        let err = options.validate().unwrap_err();
+        assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+    }
+
+    #[test]
+    fn test_debug_with_content_comparator() {
+        let options = PostgresUpgraderOptions::builder()
+            .content_comparator(Arc::new(|a: &str, b: &str| a == b))
+            .build();
+        let debug = format!("{:?}", options);
+        assert!(debug.contains("content_comparator: Some(Comparator(..))"));
+        assert!(options.content_comparator.as_ref().unwrap()("a", "a"));
+    }
+
+    #[test]
+    fn test_debug_with_hooks() {
+        let options = PostgresUpgraderOptions::builder()
+            .on_after_apply(Arc::new(|_: &MigrationInfo| {}))
+            .build();
+        let debug = format!("{:?}", options);
+        assert!(debug.contains("on_after_apply: Some(Hook(..))"));
+        assert!(debug.contains("on_before_apply: None"));
+    }
+
+    #[test]
+    fn test_custom_schema_placeholder() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("my_schema")
+            .schema_placeholder(":schema:")
+            .build();
+        assert_eq!(
+            options.apply_substitutions("CREATE TABLE :schema:.t (v TEXT DEFAULT '{{SCHEMA}}')"),
+            "CREATE TABLE my_schema.t (v TEXT DEFAULT '{{SCHEMA}}')"
+        );
+
+        let options = PostgresUpgraderOptions::builder()
+            .schema_placeholder("")
+            .build();
+        assert!(matches!(
+            options.validate(),
+            Err(UpgraderError::ConfigurationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_strict_schema_placeholder() {
+        let upgraders = vec![
+            SchemaUpgrader {
+                file_id: 0,
+                upgrader_id: 0,
+                description: "Create users".to_string(),
+                text: "CREATE TABLE users (id INT);".to_string(),
+                down_text: None,
+                transactional: true,
+            },
+            SchemaUpgrader {
+                file_id: 0,
+                upgrader_id: 1,
+                description: "Create orders".to_string(),
+                text: "CREATE TABLE orders (id INT);".to_string(),
+                down_text: Some("DROP TABLE {{SCHEMA}}.orders;".to_string()),
+                transactional: true,
+            },
+        ];
+
+        // Without strict mode the token is left in place.
+        let options = PostgresUpgraderOptions::builder().build();
+        assert!(options.check_schema_placeholder(&upgraders).is_ok());
+
+        let options = PostgresUpgraderOptions::builder()
+            .strict_schema_placeholder(true)
+            .build();
+        let err = options.check_schema_placeholder(&upgraders).unwrap_err();
+        assert!(
+            matches!(err, UpgraderError::ConfigurationError(ref m) if m.contains("Upgrader 0:1"))
+        );
+        assert!(options.check_schema_placeholder(&upgraders[..1]).is_ok());
+
+        let options = PostgresUpgraderOptions::builder()
+            .schema("my_schema")
+            .strict_schema_placeholder(true)
+            .build();
+        assert!(options.check_schema_placeholder(&upgraders).is_ok());
+    }
+
+    #[test]
+    fn test_variables() {
+        let options = PostgresUpgraderOptions::builder()
+            .schema("app")
+            .variable("TABLESPACE", "fast_ssd")
+            .variable("OWNER", "{{SCHEMA}}_owner")
+            .build();
+        assert_eq!(
+            options.apply_substitutions(
+                "CREATE TABLE {{SCHEMA}}.t (id INT) TABLESPACE {{TABLESPACE}}; \
+                 ALTER TABLE {{SCHEMA}}.t OWNER TO {{OWNER}}; SELECT '{{UNKNOWN}}';"
+            ),
+            // Substituted values are not substituted again.
+            "CREATE TABLE app.t (id INT) TABLESPACE fast_ssd; \
+             ALTER TABLE app.t OWNER TO {{SCHEMA}}_owner; SELECT '{{UNKNOWN}}';"
+        );
+
+        // Without a schema, only the variables are substituted.
+        let options = PostgresUpgraderOptions::builder()
+            .variable("TABLESPACE", "fast_ssd")
+            .build();
+        assert_eq!(
+            options.apply_substitutions("CREATE TABLE {{SCHEMA}}.t () TABLESPACE {{TABLESPACE}}"),
+            "CREATE TABLE {{SCHEMA}}.t () TABLESPACE fast_ssd"
+        );
+    }
+
+    #[test]
+    fn test_validate_variable_keys() {
+        for key in ["", "TABLE SPACE", "OWNER}}", "SCHEMA"] {
+            let options = PostgresUpgraderOptions::builder()
+                .variable(key, "value")
+                .build();
+            assert!(
+                matches!(
+                    options.validate(),
+                    Err(UpgraderError::ConfigurationError(_))
+                ),
+                "{:?}",
+                key
+            );
+        }
+
+        // With another placeholder, SCHEMA is an ordinary variable.
+        let options = PostgresUpgraderOptions::builder()
+            .schema_placeholder(":schema:")
+            .variable("SCHEMA", "value")
+            .build();
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn test_strict_variables() {
+        let upgraders = vec![SchemaUpgrader {
+            file_id: 0,
+            upgrader_id: 0,
+            description: "Create users".to_string(),
+            text: "CREATE TABLE {{SCHEMA}}.users (id INT) TABLESPACE {{TABLESPACE}}; \
+                   SELECT '{{not a token}}';"
+                .to_string(),
+            down_text: None,
+            transactional: true,
+        }];
+
+        let builder = || {
+            PostgresUpgraderOptions::builder()
+                .schema("app")
+                .strict_variables(true)
+        };
+        let err = builder().build().check_variables(&upgraders).unwrap_err();
+        assert!(
+            matches!(err, UpgraderError::ConfigurationError(ref m) if m.contains("'{{TABLESPACE}}'"))
+        );
+        assert!(
+            builder()
+                .variable("TABLESPACE", "fast_ssd")
+                .build()
+                .check_variables(&upgraders)
+                .is_ok()
+        );
+
+        // Without a schema the placeholder is unresolved too.
+        let options = PostgresUpgraderOptions::builder()
+            .variable("TABLESPACE", "fast_ssd")
+            .strict_variables(true)
+            .build();
+        let err = options.check_variables(&upgraders).unwrap_err();
+        assert!(
+            matches!(err, UpgraderError::ConfigurationError(ref m) if m.contains("'{{SCHEMA}}'"))
         );
     }
 }
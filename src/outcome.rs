@@ -0,0 +1,167 @@
+use crate::UpgraderError;
+use crate::integrity::IntegrityViolation;
+
+/// What an upgrade did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UpgradeReport {
+    /// The upgraders applied by this upgrade, as `(file_id, upgrader_id, description)`, in the
+    /// order they were applied.
+    pub applied: Vec<(i32, i32, String)>,
+    /// The number of upgraders that were already recorded in the database, by earlier or
+    /// concurrent upgrades.
+    pub already_applied: usize,
+    /// With [`dry_run`](crate::PostgresUpgraderOptionsBuilder::dry_run), the upgraders that
+    /// would have been applied, in order; empty otherwise.
+    pub pending: Vec<(i32, i32, String)>,
+}
+
+/// The integrity violation that blocked an upgrade. Checking stops at the first violation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct IntegrityReport {
+    /// A short, stable name for the kind of violation: `out_of_order`, `gap`,
+    /// `missing_from_files`, `text_changed` or `description_changed`.
+    pub kind: &'static str,
+    /// The `(file_id, upgrader_id)` of the offending upgrader.
+    pub ids: (i32, i32),
+    /// The same message as the corresponding `UpgraderError::IntegrityError`.
+    pub message: String,
+}
+
+impl From<IntegrityViolation> for IntegrityReport {
+    fn from(violation: IntegrityViolation) -> Self {
+        IntegrityReport {
+            kind: violation.kind(),
+            ids: violation.ids(),
+            message: violation.to_string(),
+        }
+    }
+}
+
+/// The result of [`try_upgrade_async`](crate::try_upgrade_async) and
+/// [`try_upgrade_blocking`](crate::try_upgrade_blocking), for callers that branch on what
+/// happened rather than handle an error.
+#[derive(Debug)]
+pub enum UpgradeOutcome {
+    /// At least one upgrader was applied.
+    Applied(UpgradeReport),
+    /// With [`dry_run`](crate::PostgresUpgraderOptionsBuilder::dry_run), at least one
+    /// upgrader would have been applied; see [`UpgradeReport::pending`].
+    WouldApply(UpgradeReport),
+    /// Every upgrader was already applied; the database was not changed.
+    UpToDate,
+    /// The upgraders recorded in the database do not match the files, so nothing more was
+    /// applied.
+    IntegrityBlocked(IntegrityReport),
+    /// The upgrade failed for any other reason. Upgraders committed before the failure remain
+    /// applied.
+    Failed(UpgraderError),
+}
+
+impl UpgradeOutcome {
+    pub(crate) fn from_result(result: Result<UpgradeReport, FlowError>) -> Self {
+        match result {
+            Ok(report) if !report.pending.is_empty() => UpgradeOutcome::WouldApply(report),
+            Ok(report) if report.applied.is_empty() => UpgradeOutcome::UpToDate,
+            Ok(report) => UpgradeOutcome::Applied(report),
+            Err(FlowError::Integrity(violation)) => {
+                UpgradeOutcome::IntegrityBlocked(violation.into())
+            }
+            Err(FlowError::Other(e)) => UpgradeOutcome::Failed(e),
+        }
+    }
+}
+
+/// Error of the upgrade flow, keeping integrity violations structured until the caller
+/// decides whether they are an error.
+#[derive(Debug)]
+pub(crate) enum FlowError {
+    Integrity(IntegrityViolation),
+    Other(UpgraderError),
+}
+
+impl From<IntegrityViolation> for FlowError {
+    fn from(violation: IntegrityViolation) -> Self {
+        FlowError::Integrity(violation)
+    }
+}
+
+impl From<UpgraderError> for FlowError {
+    fn from(error: UpgraderError) -> Self {
+        FlowError::Other(error)
+    }
+}
+
+impl From<FlowError> for UpgraderError {
+    fn from(error: FlowError) -> Self {
+        match error {
+            FlowError::Integrity(violation) => violation.into(),
+            FlowError::Other(e) => e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outcome_from_result() {
+        let report = UpgradeReport {
+            applied: vec![(0, 1, "Add email".to_string())],
+            already_applied: 1,
+            pending: Vec::new(),
+        };
+        assert!(matches!(
+            UpgradeOutcome::from_result(Ok(report.clone())),
+            UpgradeOutcome::Applied(r) if r == report
+        ));
+        assert!(matches!(
+            UpgradeOutcome::from_result(Ok(UpgradeReport {
+                applied: Vec::new(),
+                already_applied: 2,
+                pending: Vec::new(),
+            })),
+            UpgradeOutcome::UpToDate
+        ));
+        assert!(matches!(
+            UpgradeOutcome::from_result(Ok(UpgradeReport {
+                applied: Vec::new(),
+                already_applied: 1,
+                pending: vec![(0, 1, "Add email".to_string())],
+            })),
+            UpgradeOutcome::WouldApply(_)
+        ));
+
+        let violation = IntegrityViolation::TextChanged {
+            file_id: 0,
+            upgrader_id: 1,
+        };
+        match UpgradeOutcome::from_result(Err(violation.clone().into())) {
+            UpgradeOutcome::IntegrityBlocked(report) => {
+                assert_eq!(report.kind, "text_changed");
+                assert_eq!(report.ids, (0, 1));
+                assert_eq!(report.message, violation.to_string());
+            }
+            other => panic!("Expected IntegrityBlocked, got {:?}", other),
+        }
+
+        assert!(matches!(
+            UpgradeOutcome::from_result(Err(
+                UpgraderError::ExecutionError("boom".to_string()).into()
+            )),
+            UpgradeOutcome::Failed(UpgraderError::ExecutionError(_))
+        ));
+    }
+
+    #[test]
+    fn test_flow_error_into_upgrader_error() {
+        let violation = IntegrityViolation::MissingFromFiles {
+            file_id: 2,
+            upgrader_id: 0,
+        };
+        let error: UpgraderError = FlowError::from(violation.clone()).into();
+        assert!(matches!(error, UpgraderError::IntegrityError(m) if m == violation.to_string()));
+    }
+}
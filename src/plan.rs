@@ -0,0 +1,644 @@
+use crate::db_tracker::AppliedUpgrader;
+use crate::integrity::{pending_upgraders, verify_integrity};
+use crate::{MigrationSet, UpgraderError};
+use chrono::{DateTime, Utc};
+
+/// A single operation an upgrade would perform.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlanStep {
+    /// Create the target schema.
+    CreateSchema { schema: String },
+    /// Create the table that tracks applied upgraders, serialized by an advisory lock.
+    CreateUpgradersTable {
+        table: String,
+        advisory_lock_id: i64,
+    },
+    /// Create the table that records integrity failures.
+    CreateAuditTable { table: String },
+    /// Create the table that records processed deploy keys.
+    CreateDeploysTable { table: String },
+    /// Take the advisory lock that serializes upgrades at the start of each upgrader
+    /// transaction. The upgraders table itself is not locked.
+    AcquireUpgradeLock { advisory_lock_id: i64 },
+    /// Apply an upgrader and record it, in its own transaction.
+    ApplyUpgrader {
+        file_id: i32,
+        upgrader_id: i32,
+        description: String,
+        /// The upgrader SQL after schema substitution.
+        sql: String,
+        /// The role the SQL runs as, if `role_per_schema` is enabled.
+        role: Option<String>,
+    },
+    /// Record the deploy key once every upgrader is applied.
+    RecordDeployKey { deploy_key: String },
+}
+
+/// The ordered operations an upgrade would perform against a database.
+///
+/// The plan reflects the database at the time it was built; another process may apply
+/// upgraders before the plan is acted upon.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpgradePlan {
+    pub steps: Vec<PlanStep>,
+    /// The upgraders already recorded in the database, in id order. They passed the same
+    /// integrity check as an upgrade.
+    pub applied: Vec<AppliedUpgrader>,
+}
+
+impl UpgradePlan {
+    /// Returns `true` if the upgrade would not change the database.
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Returns the number of upgraders that would be applied.
+    pub fn pending_upgraders(&self) -> usize {
+        self.steps
+            .iter()
+            .filter(|s| matches!(s, PlanStep::ApplyUpgrader { .. }))
+            .count()
+    }
+
+    /// Returns a displayable per-upgrader breakdown: each applied upgrader with when and where
+    /// it was applied, followed by each pending one.
+    pub fn breakdown(&self) -> PlanBreakdown<'_> {
+        PlanBreakdown {
+            plan: self,
+            applied_on: None,
+        }
+    }
+}
+
+/// Lists applied and pending upgraders of an [`UpgradePlan`]; see [`UpgradePlan::breakdown`].
+pub struct PlanBreakdown<'a> {
+    plan: &'a UpgradePlan,
+    applied_on: Option<&'a dyn Fn(DateTime<Utc>) -> String>,
+}
+
+impl<'a> PlanBreakdown<'a> {
+    /// Shows `applied_on` as `format` returns it instead of in UTC, e.g. in a local time zone.
+    pub fn with_applied_on(mut self, format: &'a dyn Fn(DateTime<Utc>) -> String) -> Self {
+        self.applied_on = Some(format);
+        self
+    }
+}
+
+impl std::fmt::Display for PlanBreakdown<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let plan = self.plan;
+
+        writeln!(f, "Applied upgraders (integrity verified):")?;
+        if plan.applied.is_empty() {
+            writeln!(f, "   (none)")?;
+        }
+        for upgrader in &plan.applied {
+            writeln!(
+                f,
+                "   {}:{} {} - applied {} by {}",
+                upgrader.file_id,
+                upgrader.upgrader_id,
+                upgrader.description,
+                match self.applied_on {
+                    Some(format) => format(upgrader.applied_on),
+                    None => upgrader
+                        .applied_on
+                        .format("%Y-%m-%d %H:%M:%S UTC")
+                        .to_string(),
+                },
+                upgrader
+                    .applied_host
+                    .as_deref()
+                    .unwrap_or(crate::db_tracker::UNKNOWN_HOST)
+            )?;
+        }
+
+        writeln!(f, "Pending upgraders:")?;
+        if plan.pending_upgraders() == 0 {
+            writeln!(f, "   (none)")?;
+        }
+        for step in &plan.steps {
+            if let PlanStep::ApplyUpgrader {
+                file_id,
+                upgrader_id,
+                description,
+                ..
+            } = step
+            {
+                writeln!(
+                    f,
+                    "   {}:{} {} - not recorded in the upgraders table",
+                    file_id, upgrader_id, description
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for UpgradePlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.steps.is_empty() {
+            return writeln!(f, "Nothing to do: the database is up to date.");
+        }
+
+        for (i, step) in self.steps.iter().enumerate() {
+            write!(f, "{}. ", i + 1)?;
+            match step {
+                PlanStep::CreateSchema { schema } => {
+                    writeln!(f, "Create schema \"{}\" if it does not exist", schema)?
+                }
+                PlanStep::CreateUpgradersTable {
+                    table,
+                    advisory_lock_id,
+                } => writeln!(
+                    f,
+                    "Create upgraders table {} under advisory lock {}",
+                    table, advisory_lock_id
+                )?,
+                PlanStep::CreateAuditTable { table } => {
+                    writeln!(f, "Create audit table {}", table)?
+                }
+                PlanStep::CreateDeploysTable { table } => {
+                    writeln!(f, "Create deploys table {}", table)?
+                }
+                PlanStep::AcquireUpgradeLock { advisory_lock_id } => writeln!(
+                    f,
+                    "Take advisory lock {} at the start of each upgrader transaction",
+                    advisory_lock_id
+                )?,
+                PlanStep::ApplyUpgrader {
+                    file_id,
+                    upgrader_id,
+                    description,
+                    sql,
+                    role,
+                } => {
+                    write!(
+                        f,
+                        "Apply upgrader {}:{} ({}) and record it, in one transaction",
+                        file_id, upgrader_id, description
+                    )?;
+                    match role {
+                        Some(role) => writeln!(f, ", as role {}", role)?,
+                        None => writeln!(f)?,
+                    }
+                    writeln!(f, "   --- {}: {}", upgrader_id, description)?;
+                    for line in sql.lines() {
+                        writeln!(f, "   {}", line)?;
+                    }
+                }
+                PlanStep::RecordDeployKey { deploy_key } => {
+                    writeln!(f, "Record deploy key '{}' as processed", deploy_key)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a read-only inspection found in the database.
+pub(crate) struct DatabaseState {
+    /// The schemas `create_schema` would create that do not exist yet.
+    pub(crate) missing_schemas: Vec<String>,
+    pub(crate) upgraders_table_exists: bool,
+    pub(crate) audit_table_exists: bool,
+    pub(crate) deploys_table_exists: bool,
+    /// Whether `deploy_key` from the options is already recorded.
+    pub(crate) deploy_key_recorded: bool,
+    pub(crate) applied: Vec<AppliedUpgrader>,
+}
+
+/// Works out the steps an upgrade of `set` would take against a database in `state`.
+///
+/// Fails with the same integrity error the upgrade itself would return.
+pub(crate) fn build_plan(
+    set: &MigrationSet,
+    state: &DatabaseState,
+) -> Result<UpgradePlan, UpgraderError> {
+    let options = &set.options;
+    verify_integrity(&set.upgraders, &state.applied, options)?;
+
+    let mut steps = Vec::new();
+
+    if options.create_schema {
+        for schema in &state.missing_schemas {
+            steps.push(PlanStep::CreateSchema {
+                schema: schema.clone(),
+            });
+        }
+    }
+
+    if !state.upgraders_table_exists {
+        steps.push(PlanStep::CreateUpgradersTable {
+            table: crate::db_tracker::table_name(options),
+            advisory_lock_id: options.advisory_lock_id(),
+        });
+    }
+
+    if options.audit_integrity_failures && !state.audit_table_exists {
+        steps.push(PlanStep::CreateAuditTable {
+            table: crate::db_tracker::audit_table_name(options),
+        });
+    }
+
+    if options.deploy_key.is_some() && !state.deploys_table_exists {
+        steps.push(PlanStep::CreateDeploysTable {
+            table: crate::db_tracker::deploys_table_name(options),
+        });
+    }
+
+    let pending: Vec<_> = pending_upgraders(&set.upgraders, &state.applied)
+        .filter(|u| options.within_target(u))
+        .collect();
+    if !pending.is_empty() {
+        steps.push(PlanStep::AcquireUpgradeLock {
+            advisory_lock_id: crate::db_tracker::upgrade_lock_id(options),
+        });
+
+        let role = options
+            .migration_role()
+            .map(|role| options.identifier(&role));
+        for upgrader in pending {
+            steps.push(PlanStep::ApplyUpgrader {
+                file_id: upgrader.file_id,
+                upgrader_id: upgrader.upgrader_id,
+                description: upgrader.description.clone(),
+                sql: options.apply_substitutions(&upgrader.text),
+                role: role.clone(),
+            });
+        }
+    }
+
+    if let Some(deploy_key) = &options.deploy_key
+        && !state.deploy_key_recorded
+    {
+        steps.push(PlanStep::RecordDeployKey {
+            deploy_key: deploy_key.clone(),
+        });
+    }
+
+    Ok(UpgradePlan {
+        steps,
+        applied: state.applied.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PostgresUpgraderOptions;
+    use crate::schema_loader::SchemaUpgrader;
+    use chrono::Utc;
+
+    fn migration_set(options: PostgresUpgraderOptions) -> MigrationSet {
+        MigrationSet {
+            upgraders: vec![
+                SchemaUpgrader {
+                    file_id: 0,
+                    upgrader_id: 0,
+                    description: "Create users".to_string(),
+                    text: "CREATE TABLE {{SCHEMA}}.users (id INT);".to_string(),
+                    down_text: None,
+                    transactional: true,
+                },
+                SchemaUpgrader {
+                    file_id: 0,
+                    upgrader_id: 1,
+                    description: "Add email".to_string(),
+                    text: "ALTER TABLE {{SCHEMA}}.users ADD COLUMN email TEXT;".to_string(),
+                    down_text: None,
+                    transactional: true,
+                },
+            ]
+            .into(),
+            options,
+        }
+    }
+
+    fn applied(set: &MigrationSet, count: usize) -> Vec<AppliedUpgrader> {
+        set.upgraders
+            .iter()
+            .take(count)
+            .map(|u| AppliedUpgrader {
+                file_id: u.file_id,
+                upgrader_id: u.upgrader_id,
+                description: u.description.clone(),
+                text: u.text.clone(),
+                applied_on: Utc::now(),
+                sequence: None,
+                applied_host: None,
+                applied_by: None,
+                last_verified_on: None,
+                checksum: None,
+            })
+            .collect()
+    }
+
+    /// User Story: First deploy to an empty database creates everything and applies all upgraders.
+    #[test]
+    fn test_plan_fresh_database() {
+        let set = migration_set(
+            PostgresUpgraderOptions::builder()
+                .schema("app")
+                .create_schema(true)
+                .build(),
+        );
+        let state = DatabaseState {
+            missing_schemas: vec!["app".to_string()],
+            upgraders_table_exists: false,
+            audit_table_exists: false,
+            deploys_table_exists: false,
+            deploy_key_recorded: false,
+            applied: vec![],
+        };
+
+        let plan = build_plan(&set, &state).unwrap();
+        assert_eq!(plan.steps.len(), 5);
+        assert_eq!(
+            plan.steps[0],
+            PlanStep::CreateSchema {
+                schema: "app".to_string()
+            }
+        );
+        assert!(matches!(
+            plan.steps[1],
+            PlanStep::CreateUpgradersTable { .. }
+        ));
+        assert!(matches!(plan.steps[2], PlanStep::AcquireUpgradeLock { .. }));
+        match &plan.steps[3] {
+            PlanStep::ApplyUpgrader { sql, role, .. } => {
+                assert_eq!(sql, "CREATE TABLE app.users (id INT);");
+                assert!(role.is_none());
+            }
+            other => panic!("Unexpected step: {:?}", other),
+        }
+        assert_eq!(plan.pending_upgraders(), 2);
+    }
+
+    /// User Story: Only the upgraders missing from the database are planned.
+    #[test]
+    fn test_plan_partially_applied() {
+        let set = migration_set(
+            PostgresUpgraderOptions::builder()
+                .schema("app")
+                .role_per_schema(true)
+                .build(),
+        );
+        let state = DatabaseState {
+            missing_schemas: Vec::new(),
+            upgraders_table_exists: true,
+            audit_table_exists: false,
+            deploys_table_exists: false,
+            deploy_key_recorded: false,
+            applied: applied(&set, 1),
+        };
+
+        let plan = build_plan(&set, &state).unwrap();
+        assert_eq!(plan.steps.len(), 2);
+        match &plan.steps[1] {
+            PlanStep::ApplyUpgrader {
+                file_id,
+                upgrader_id,
+                role,
+                ..
+            } => {
+                assert_eq!((*file_id, *upgrader_id), (0, 1));
+                assert_eq!(role.as_deref(), Some("\"app\""));
+            }
+            other => panic!("Unexpected step: {:?}", other),
+        }
+    }
+
+    /// User Story: The upgraders after the target are not planned.
+    #[test]
+    fn test_plan_stops_at_target() {
+        let set = migration_set(PostgresUpgraderOptions::builder().target((0, 0)).build());
+        let state = DatabaseState {
+            missing_schemas: Vec::new(),
+            upgraders_table_exists: true,
+            audit_table_exists: false,
+            deploys_table_exists: false,
+            deploy_key_recorded: false,
+            applied: vec![],
+        };
+
+        let plan = build_plan(&set, &state).unwrap();
+        assert_eq!(plan.pending_upgraders(), 1);
+        assert!(matches!(
+            plan.steps.last(),
+            Some(PlanStep::ApplyUpgrader {
+                file_id: 0,
+                upgrader_id: 0,
+                ..
+            })
+        ));
+    }
+
+    /// User Story: An up to date database needs no steps, not even the lock.
+    #[test]
+    fn test_plan_up_to_date() {
+        let set = migration_set(PostgresUpgraderOptions::builder().build());
+        let state = DatabaseState {
+            missing_schemas: Vec::new(),
+            upgraders_table_exists: true,
+            audit_table_exists: false,
+            deploys_table_exists: false,
+            deploy_key_recorded: false,
+            applied: applied(&set, 2),
+        };
+
+        let plan = build_plan(&set, &state).unwrap();
+        assert!(plan.is_empty());
+        assert_eq!(plan.pending_upgraders(), 0);
+        assert_eq!(
+            plan.to_string(),
+            "Nothing to do: the database is up to date.\n"
+        );
+    }
+
+    /// User Story: A deploy with a new key records it after the pending upgraders.
+    #[test]
+    fn test_plan_new_deploy_key() {
+        let set = migration_set(
+            PostgresUpgraderOptions::builder()
+                .deploy_key("release-1")
+                .build(),
+        );
+        let state = DatabaseState {
+            missing_schemas: Vec::new(),
+            upgraders_table_exists: true,
+            audit_table_exists: false,
+            deploys_table_exists: false,
+            deploy_key_recorded: false,
+            applied: applied(&set, 1),
+        };
+
+        let plan = build_plan(&set, &state).unwrap();
+        assert_eq!(plan.steps.len(), 4);
+        assert_eq!(
+            plan.steps[0],
+            PlanStep::CreateDeploysTable {
+                table: "\"$upgrader_deploys$\"".to_string(),
+            }
+        );
+        assert_eq!(
+            plan.steps[3],
+            PlanStep::RecordDeployKey {
+                deploy_key: "release-1".to_string(),
+            }
+        );
+        assert!(
+            plan.to_string()
+                .contains("4. Record deploy key 'release-1' as processed")
+        );
+    }
+
+    /// User Story: Repeating a processed deploy on an up to date database has nothing to do.
+    #[test]
+    fn test_plan_deploy_key_already_processed() {
+        let set = migration_set(
+            PostgresUpgraderOptions::builder()
+                .deploy_key("release-1")
+                .build(),
+        );
+        let state = DatabaseState {
+            missing_schemas: Vec::new(),
+            upgraders_table_exists: true,
+            audit_table_exists: false,
+            deploys_table_exists: true,
+            deploy_key_recorded: true,
+            applied: applied(&set, 2),
+        };
+        assert!(build_plan(&set, &state).unwrap().is_empty());
+
+        // A processed key does not hide upgraders added since.
+        let state = DatabaseState {
+            applied: applied(&set, 1),
+            ..state
+        };
+        assert_eq!(build_plan(&set, &state).unwrap().pending_upgraders(), 1);
+    }
+
+    /// User Story: The plan refuses to proceed exactly where the upgrade would.
+    #[test]
+    fn test_plan_integrity_violation() {
+        let set = migration_set(PostgresUpgraderOptions::builder().build());
+        let mut db = applied(&set, 1);
+        db[0].text = "CREATE TABLE something_else (id INT);".to_string();
+        let state = DatabaseState {
+            missing_schemas: Vec::new(),
+            upgraders_table_exists: true,
+            audit_table_exists: false,
+            deploys_table_exists: false,
+            deploy_key_recorded: false,
+            applied: db,
+        };
+
+        let result = build_plan(&set, &state);
+        assert!(matches!(result, Err(UpgraderError::IntegrityError(_))));
+    }
+
+    #[test]
+    fn test_plan_display() {
+        let set = migration_set(PostgresUpgraderOptions::builder().build());
+        let state = DatabaseState {
+            missing_schemas: Vec::new(),
+            upgraders_table_exists: true,
+            audit_table_exists: false,
+            deploys_table_exists: false,
+            deploy_key_recorded: false,
+            applied: applied(&set, 1),
+        };
+
+        let plan = build_plan(&set, &state).unwrap();
+        assert_eq!(
+            plan.to_string(),
+            "1. Take advisory lock 42004202 at the start of each upgrader transaction\n\
+             2. Apply upgrader 0:1 (Add email) and record it, in one transaction\n   \
+             --- 1: Add email\n   \
+             ALTER TABLE {{SCHEMA}}.users ADD COLUMN email TEXT;\n"
+        );
+    }
+
+    /// User Story: With `allow_out_of_order`, upgraders skipped by the database are planned.
+    #[test]
+    fn test_plan_out_of_order_fills_gap() {
+        let set = migration_set(
+            PostgresUpgraderOptions::builder()
+                .allow_out_of_order(true)
+                .build(),
+        );
+        let mut db = applied(&set, 2);
+        db.remove(0);
+        let state = DatabaseState {
+            missing_schemas: Vec::new(),
+            upgraders_table_exists: true,
+            audit_table_exists: false,
+            deploys_table_exists: false,
+            deploy_key_recorded: false,
+            applied: db,
+        };
+
+        let plan = build_plan(&set, &state).unwrap();
+        assert_eq!(plan.pending_upgraders(), 1);
+        match &plan.steps[1] {
+            PlanStep::ApplyUpgrader {
+                file_id,
+                upgrader_id,
+                ..
+            } => assert_eq!((*file_id, *upgrader_id), (0, 0)),
+            other => panic!("Unexpected step: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_plan_breakdown() {
+        let set = migration_set(PostgresUpgraderOptions::builder().build());
+        let mut db = applied(&set, 1);
+        db[0].applied_on = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        db[0].applied_host = Some("pod-a".to_string());
+        let state = DatabaseState {
+            missing_schemas: Vec::new(),
+            upgraders_table_exists: true,
+            audit_table_exists: false,
+            deploys_table_exists: false,
+            deploy_key_recorded: false,
+            applied: db,
+        };
+
+        let plan = build_plan(&set, &state).unwrap();
+        assert_eq!(plan.applied.len(), 1);
+        assert_eq!(
+            plan.breakdown().to_string(),
+            "Applied upgraders (integrity verified):\n   \
+             0:0 Create users - applied 2023-11-14 22:13:20 UTC by pod-a\n\
+             Pending upgraders:\n   \
+             0:1 Add email - not recorded in the upgraders table\n"
+        );
+        let local = |at: DateTime<Utc>| at.format("%H:%M").to_string();
+        assert!(
+            plan.breakdown()
+                .with_applied_on(&local)
+                .to_string()
+                .contains("0:0 Create users - applied 22:13 by pod-a\n")
+        );
+    }
+
+    #[test]
+    fn test_plan_breakdown_fresh_database() {
+        let set = migration_set(PostgresUpgraderOptions::builder().build());
+        let state = DatabaseState {
+            missing_schemas: Vec::new(),
+            upgraders_table_exists: false,
+            audit_table_exists: false,
+            deploys_table_exists: false,
+            deploy_key_recorded: false,
+            applied: vec![],
+        };
+
+        let breakdown = build_plan(&set, &state).unwrap().breakdown().to_string();
+        assert!(breakdown.starts_with("Applied upgraders (integrity verified):\n   (none)\n"));
+        assert!(breakdown.contains("0:1 Add email - not recorded"));
+    }
+}
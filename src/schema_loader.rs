@@ -1,4 +1,4 @@
-use crate::UpgraderError;
+use crate::{MigrationInfo, PostgresUpgraderOptions, UpgraderError};
 use std::fs;
 use std::path::{Path, PathBuf};
 
@@ -8,12 +8,45 @@ pub(crate) struct SchemaUpgrader {
     pub(crate) upgrader_id: i32,
     pub(crate) description: String,
     pub(crate) text: String,
+    /// SQL undoing `text`, from the optional `--- <id> down:` block of the same file.
+    pub(crate) down_text: Option<String>,
+    /// Whether `text` runs in a transaction; `false` for `--- <id> (no-transaction): <desc>`
+    /// headers.
+    pub(crate) transactional: bool,
 }
 
+/// Extensions of the files loaded as upgraders unless others are configured.
+const DEFAULT_EXTENSIONS: &[&str] = &["sql", "ddl"];
+
+/// Returns [`DEFAULT_EXTENSIONS`] in the form the loader takes.
+pub(crate) fn default_extensions() -> Vec<String> {
+    DEFAULT_EXTENSIONS.iter().map(|e| e.to_string()).collect()
+}
+
+/// Marks an upgrader header whose SQL runs outside a transaction.
+const NO_TRANSACTION: &str = "(no-transaction)";
+
+/// A block of an upgrader file, started by a header line.
+enum Block {
+    /// `--- <id>: <description>` or `--- <id> (no-transaction): <description>`
+    Up {
+        upgrader_id: i32,
+        description: String,
+        transactional: bool,
+    },
+    /// `--- <id> down:`, undoing the upgrader with the same id.
+    Down { upgrader_id: i32 },
+}
+
+/// Loads the upgraders of `upgraders_folder`, from its files with one of the configured
+/// extensions. With `recursive`, the files of its subdirectories are loaded too, and file ids
+/// are sequential across all of them.
 pub(crate) fn load_upgraders(
     upgraders_folder: impl AsRef<Path>,
+    options: &PostgresUpgraderOptions,
 ) -> Result<Vec<SchemaUpgrader>, UpgraderError> {
     let upgraders_folder = upgraders_folder.as_ref();
+    let extensions = options.extensions();
 
     if !upgraders_folder.exists() {
         return Err(UpgraderError::LoaderError(format!(
@@ -30,61 +63,289 @@ pub(crate) fn load_upgraders(
     }
 
     let mut files: Vec<(i32, PathBuf)> = Vec::new();
+    collect_files(upgraders_folder, options.recursive, &extensions, &mut files)?;
 
-    for entry in
-        fs::read_dir(upgraders_folder).map_err(|e| UpgraderError::LoaderError(e.to_string()))?
-    {
-        let entry = entry.map_err(|e| UpgraderError::LoaderError(e.to_string()))?;
-        let path = entry.path();
+    files.sort_by_key(|k| k.0);
 
-        if path.is_dir() {
+    validate_file_ids(files.iter().map(|(id, path)| (*id, path.as_path())))?;
+
+    let mut upgraders = Vec::new();
+
+    for (file_id, path) in files {
+        let bytes = read_upgrader_file(&path)?;
+        let content = String::from_utf8(bytes).map_err(|e| {
+            UpgraderError::LoaderError(format!(
+                "File {:?} is not valid UTF-8 (invalid byte at offset {}); upgrader files must \
+                 be saved as UTF-8, e.g. re-save a Latin-1 / Windows-1252 file as UTF-8",
+                path,
+                e.utf8_error().valid_up_to()
+            ))
+        })?;
+        upgraders.extend(parse_file(
+            file_id,
+            &path,
+            &content,
+            options.allow_empty_upgraders,
+        )?);
+    }
+
+    validate_sequence(&upgraders, options.allow_empty_upgraders)?;
+    Ok(upgraders)
+}
+
+/// Loads the upgraders of `upgraders_folder` with the default options, without connecting to a
+/// database, e.g. to catch numbering mistakes in a pre-commit hook or CI lint step.
+///
+/// # Errors
+///
+/// Returns `UpgraderError::LoaderError` if the folder cannot be read, or if a file name,
+/// header or id sequence is invalid.
+pub fn validate_folder(
+    upgraders_folder: impl AsRef<Path>,
+) -> Result<Vec<MigrationInfo>, UpgraderError> {
+    let upgraders = load_upgraders(upgraders_folder, &PostgresUpgraderOptions::default())?;
+    Ok(upgraders.iter().map(MigrationInfo::from).collect())
+}
+
+/// Starts a new upgrader with `description` in `upgraders_folder`, following the rules of
+/// [`validate_folder`]: in a new file named after the next file id and the description, e.g.
+/// `003_add_email.sql`, or with `append` as the next upgrader of the last file. Returns the
+/// path of the file written and the `(file_id, upgrader_id)` of the new upgrader, whose header
+/// is followed by an empty line for its SQL.
+///
+/// # Errors
+///
+/// Returns `UpgraderError::LoaderError` if the folder does not pass [`validate_folder`], or
+/// has no file to append to, and `UpgraderError::ConfigurationError` if the description is
+/// empty, spans several lines or is too long.
+pub fn new_upgrader(
+    upgraders_folder: impl AsRef<Path>,
+    description: &str,
+    append: bool,
+) -> Result<(PathBuf, (i32, i32)), UpgraderError> {
+    let upgraders_folder = upgraders_folder.as_ref();
+    let description = description.trim();
+    if description.is_empty() || description.contains(['\n', '\r']) {
+        return Err(UpgraderError::ConfigurationError(
+            "An upgrader description must be a single, non-empty line".to_string(),
+        ));
+    }
+    if description.chars().count() > crate::db_tracker::MAX_DESCRIPTION_CHARS {
+        return Err(UpgraderError::ConfigurationError(format!(
+            "An upgrader description must not be longer than {} characters",
+            crate::db_tracker::MAX_DESCRIPTION_CHARS
+        )));
+    }
+
+    validate_folder(upgraders_folder)?;
+    let mut files = Vec::new();
+    collect_files(upgraders_folder, false, &default_extensions(), &mut files)?;
+    files.sort_by_key(|k| k.0);
+    let header = |upgrader_id| format!("--- {}: {}\n\n", upgrader_id, description);
+
+    if append {
+        let Some((file_id, path)) = files.pop() else {
+            return Err(UpgraderError::LoaderError(format!(
+                "No upgrader file to append to in {:?}",
+                upgraders_folder
+            )));
+        };
+        if is_gzipped(&path) {
             return Err(UpgraderError::LoaderError(format!(
-                "Nested directory found: {:?}",
+                "Cannot append to the compressed file {:?}",
                 path
             )));
         }
+        let content = fs::read_to_string(&path).map_err(|e| {
+            UpgraderError::LoaderError(format!("Failed to read file {:?}: {}", path, e))
+        })?;
+        let upgrader_id = parse_file(file_id, &path, &content, true)?.len() as i32;
+        let separator = if content.is_empty() || content.ends_with('\n') {
+            "\n"
+        } else {
+            "\n\n"
+        };
+        fs::write(
+            &path,
+            format!("{}{}{}", content, separator, header(upgrader_id)),
+        )
+        .map_err(|e| {
+            UpgraderError::LoaderError(format!("Failed to write file {:?}: {}", path, e))
+        })?;
+        return Ok((path, (file_id, upgrader_id)));
+    }
 
-        if let Some(filename) = path.file_name().and_then(|s| s.to_str()) {
-            // Ignore hidden files
-            if filename.starts_with('.') {
-                continue;
-            }
+    // Keep the zero padding of the existing names, e.g. `0001_`.
+    let width = files
+        .last()
+        .and_then(|(_, path)| path.file_name()?.to_str()?.split('_').next().map(str::len))
+        .unwrap_or(3);
+    let file_id = files.len() as i32;
+    let path = upgraders_folder.join(format!(
+        "{:0width$}_{}.sql",
+        file_id,
+        slugify(description),
+        width = width
+    ));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, header(0).as_bytes()))
+        .map_err(|e| {
+            UpgraderError::LoaderError(format!("Failed to create file {:?}: {}", path, e))
+        })?;
+    Ok((path, (file_id, 0)))
+}
 
-            // check extension
-            let extension = path
-                .extension()
-                .and_then(|s| s.to_str())
-                .map(|s| s.to_lowercase());
-            match extension.as_deref() {
-                Some("sql") | Some("ddl") => {}
-                _ => continue, // Ignore non-sql/ddl files
-            }
+/// Returns `description` in lowercase with every run of characters other than ASCII letters
+/// and digits replaced by one underscore, for use in a file name.
+fn slugify(description: &str) -> String {
+    let mut slug = String::new();
+    for c in description.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('_') {
+            slug.push('_');
+        }
+    }
+    match slug.trim_end_matches('_') {
+        "" => "upgrader".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
 
-            let parts: Vec<&str> = filename.split('_').collect();
-            if let Some(first_part) = parts.first() {
-                if let Ok(id) = first_part.parse::<i32>() {
-                    files.push((id, path));
-                } else {
+/// Adds the upgrader files of `folder` to `files`, with their file ids, descending into
+/// subdirectories with `recursive` and rejecting them otherwise.
+fn collect_files(
+    folder: &Path,
+    recursive: bool,
+    extensions: &[String],
+    files: &mut Vec<(i32, PathBuf)>,
+) -> Result<(), UpgraderError> {
+    for entry in fs::read_dir(folder).map_err(|e| UpgraderError::LoaderError(e.to_string()))? {
+        let entry = entry.map_err(|e| UpgraderError::LoaderError(e.to_string()))?;
+        let path = entry.path();
+
+        if let Some(filename) = path.file_name().and_then(|s| s.to_str()) {
+            if path.is_dir() {
+                if !recursive {
                     return Err(UpgraderError::LoaderError(format!(
-                        "File name must start with a number: {:?}",
-                        filename
+                        "Nested directory found: {:?}",
+                        path
                     )));
                 }
+                // Ignore hidden directories
+                if !filename.starts_with('.') {
+                    collect_files(&path, recursive, extensions, files)?;
+                }
+                continue;
+            }
+
+            if let Some(id) = upgrader_file_id(&path, extensions)? {
+                files.push((id, path));
             }
         }
     }
+    Ok(())
+}
 
-    files.sort_by_key(|k| k.0);
+/// Returns the content of the upgrader file at `path`, decompressed if it is gzipped.
+fn read_upgrader_file(path: &Path) -> Result<Vec<u8>, UpgraderError> {
+    let bytes = fs::read(path).map_err(|e| {
+        UpgraderError::LoaderError(format!("Failed to read file {:?}: {}", path, e))
+    })?;
+    #[cfg(feature = "gzip")]
+    if is_gzipped(path) {
+        let mut content = Vec::new();
+        std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&bytes[..]), &mut content)
+            .map_err(|e| {
+                UpgraderError::LoaderError(format!("Failed to decompress file {:?}: {}", path, e))
+            })?;
+        return Ok(content);
+    }
+    Ok(bytes)
+}
 
-    // Validate file IDs are sequential starting from 0
-    for (idx, (file_id, path)) in files.iter().enumerate() {
-        if *file_id != idx as i32 {
-            if *file_id == 0 && idx != 0 {
+/// Whether `path` is loaded as a gzipped upgrader file, e.g. `000_init.sql.gz`. Always
+/// `false` without the `gzip` feature, which ignores such files.
+fn is_gzipped(path: &Path) -> bool {
+    cfg!(feature = "gzip")
+        && path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .is_some_and(|name| name.to_lowercase().ends_with(GZIP_SUFFIX))
+}
+
+/// Suffix of gzipped upgrader files, after their extension.
+const GZIP_SUFFIX: &str = ".gz";
+
+/// Suffixes of editor backups and temporary files, e.g. `000_init.sql~` or `000_init.sql.swp`,
+/// which are ignored whatever their extension.
+const BACKUP_SUFFIXES: [&str; 4] = ["~", ".swp", ".tmp", ".bak"];
+
+/// Returns the file id of the upgrader file at `path`, from the leading number of its name,
+/// or `None` for hidden files, editor backups and files without one of `extensions`, which
+/// are ignored.
+pub(crate) fn upgrader_file_id(
+    path: &Path,
+    extensions: &[String],
+) -> Result<Option<i32>, UpgraderError> {
+    let Some(filename) = path.file_name().and_then(|s| s.to_str()) else {
+        return Ok(None);
+    };
+
+    // Ignore hidden files
+    if filename.starts_with('.') {
+        return Ok(None);
+    }
+
+    // Ignore editor backups and autosaves (e.g. Emacs' `#000_init.sql#`)
+    let lowercase = filename.to_lowercase();
+    if lowercase.starts_with('#')
+        || BACKUP_SUFFIXES
+            .iter()
+            .any(|suffix| lowercase.ends_with(suffix))
+    {
+        return Ok(None);
+    }
+
+    // check extension, which may have several parts (e.g. `sql.tera`), before `.gz`
+    let lowercase = match lowercase.strip_suffix(GZIP_SUFFIX) {
+        Some(stem) if is_gzipped(path) => stem,
+        _ => &lowercase,
+    };
+    let has_extension = extensions.iter().any(|extension| {
+        lowercase
+            .strip_suffix(extension.as_str())
+            .is_some_and(|stem| stem.len() > 1 && stem.ends_with('.'))
+    });
+    if !has_extension {
+        return Ok(None); // Ignore files with other extensions
+    }
+
+    let first_part = filename.split('_').next().unwrap_or_default();
+    match first_part.parse::<i32>() {
+        Ok(id) => Ok(Some(id)),
+        Err(_) => Err(UpgraderError::LoaderError(format!(
+            "File name must start with a number: {:?}",
+            filename
+        ))),
+    }
+}
+
+/// Checks that the file ids of `files`, sorted by id, are sequential starting from 0.
+pub(crate) fn validate_file_ids<'a>(
+    files: impl IntoIterator<Item = (i32, &'a Path)>,
+) -> Result<(), UpgraderError> {
+    for (idx, (file_id, path)) in files.into_iter().enumerate() {
+        if file_id != idx as i32 {
+            if file_id == 0 && idx != 0 {
                 return Err(UpgraderError::LoaderError(format!(
                     "Duplicate file ID 0 found: {:?}",
                     path
                 )));
-            } else if *file_id < idx as i32 {
+            } else if file_id < idx as i32 {
                 return Err(UpgraderError::LoaderError(format!(
                     "Duplicate file ID {} found: {:?}",
                     file_id, path
@@ -98,83 +359,219 @@ pub(crate) fn load_upgraders(
         }
     }
 
-    let mut upgraders = Vec::new();
+    Ok(())
+}
 
-    for (file_id, path) in files {
-        let content = fs::read_to_string(&path).map_err(|e| {
-            UpgraderError::LoaderError(format!("Failed to read file {:?}: {}", path, e))
-        })?;
-        let lines = content.lines();
-
-        let mut current_upgrader_id: Option<i32> = None;
-        let mut current_description: Option<String> = None;
-        let mut current_sql = String::new();
-        let mut expected_upgrader_id = 0;
-
-        for line in lines {
-            if let Some(header_part) = line.strip_prefix("--- ") {
-                // If we have a current upgrader, push it
-                if let (Some(uid), Some(desc)) = (current_upgrader_id, &current_description) {
-                    let trimmed_sql = current_sql.trim().to_string();
-                    if !trimmed_sql.is_empty() {
-                        upgraders.push(SchemaUpgrader {
-                            file_id,
-                            upgrader_id: uid,
-                            description: desc.trim().to_string(),
-                            text: trimmed_sql,
-                        });
-                    }
-                }
+/// Checks that the `(file_id, upgrader_id)` pairs of all loaded `upgraders` are strictly
+/// ascending, with the upgrader ids of each file starting at 0, which the integrity check and
+/// the upgrade loop rely on. With `contiguous`, as when empty upgraders are kept, the ids of a
+/// file must have no holes either; otherwise holes are accepted, since an empty upgrader that
+/// was skipped leaves one.
+pub(crate) fn validate_sequence(
+    upgraders: &[SchemaUpgrader],
+    contiguous: bool,
+) -> Result<(), UpgraderError> {
+    let mut previous: Option<(i32, i32)> = None;
+    for upgrader in upgraders {
+        let current = (upgrader.file_id, upgrader.upgrader_id);
+        let expected = match previous {
+            Some((file_id, upgrader_id)) if file_id == current.0 => upgrader_id + 1,
+            _ => 0,
+        };
+        let in_sequence = previous.is_none_or(|previous| current > previous)
+            && if contiguous {
+                current.1 == expected
+            } else {
+                current.1 >= expected
+            };
+        if !in_sequence {
+            return Err(UpgraderError::LoaderError(match previous {
+                Some((file_id, upgrader_id)) => format!(
+                    "Upgrader {}:{} is out of sequence after {}:{}",
+                    current.0, current.1, file_id, upgrader_id
+                ),
+                None => format!(
+                    "Upgrader {}:{} is out of sequence; upgrader ids start at 0",
+                    current.0, current.1
+                ),
+            }));
+        }
+        previous = Some(current);
+    }
+    Ok(())
+}
+
+/// Parses the upgraders of one file, named `path` in error messages. Upgraders without SQL
+/// are skipped unless `allow_empty`.
+pub(crate) fn parse_file(
+    file_id: i32,
+    path: &Path,
+    content: &str,
+    allow_empty: bool,
+) -> Result<Vec<SchemaUpgrader>, UpgraderError> {
+    let mut upgraders = Vec::new();
+    // Some editors start UTF-8 files with a byte order mark, which would hide the first header.
+    let lines = content.strip_prefix('\u{feff}').unwrap_or(content).lines();
+
+    let mut current_block: Option<Block> = None;
+    let mut current_sql = String::new();
+    let mut expected_upgrader_id = 0;
+
+    for line in lines {
+        // One or more spaces may follow the dashes.
+        if let Some(header_part) = line
+            .strip_prefix("---")
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+        {
+            // If we have a current block, push it
+            if let Some(block) = current_block.take() {
+                push_block(
+                    file_id,
+                    path,
+                    block,
+                    &current_sql,
+                    allow_empty,
+                    &mut upgraders,
+                )?;
+            }
 
-                // Reset for next
-                current_sql.clear();
-
-                // Parse new header: "--- <id>: <desc>"
-                if let Some((id_str, desc_str)) = header_part.split_once(':') {
-                    if let Ok(uid) = id_str.trim().parse::<i32>() {
-                        if uid != expected_upgrader_id {
-                            return Err(UpgraderError::LoaderError(format!(
-                                "Invalid upgrader sequence in file {:?}. Expected ID {}, found {}",
-                                path, expected_upgrader_id, uid
-                            )));
-                        }
-
-                        current_upgrader_id = Some(uid);
-                        current_description = Some(desc_str.trim().to_string());
-                        expected_upgrader_id += 1;
-                    } else {
+            // Reset for next
+            current_sql.clear();
+
+            // Parse new header: "--- <id>: <desc>" or "--- <id> down:". Without a colon the
+            // description is empty, and a description keeps any colons after the first.
+            let (id_str, desc_str) = header_part.split_once(':').unwrap_or((header_part, ""));
+            let id_str = id_str.trim();
+            if let Some(down_id_str) = id_str
+                .strip_suffix("down")
+                .filter(|rest| rest.ends_with(char::is_whitespace))
+            {
+                if let Ok(uid) = down_id_str.trim().parse::<i32>() {
+                    if uid >= expected_upgrader_id {
                         return Err(UpgraderError::LoaderError(format!(
-                            "Invalid upgrader ID format in file {:?}: {}",
-                            path, line
+                            "Down block for upgrader {} in file {:?} must follow the upgrader",
+                            uid, path
                         )));
                     }
+                    current_block = Some(Block::Down { upgrader_id: uid });
                 } else {
                     return Err(UpgraderError::LoaderError(format!(
-                        "Invalid upgrader header format in file {:?}: {}",
+                        "Invalid upgrader ID format in file {:?}: {}",
                         path, line
                     )));
                 }
+            } else if let Ok(uid) = id_str
+                .strip_suffix(NO_TRANSACTION)
+                .unwrap_or(id_str)
+                .trim_end()
+                .parse::<i32>()
+            {
+                if uid != expected_upgrader_id {
+                    return Err(UpgraderError::LoaderError(format!(
+                        "Invalid upgrader sequence in file {:?}. Expected ID {}, found {}",
+                        path, expected_upgrader_id, uid
+                    )));
+                }
+
+                let description = desc_str.trim();
+                if description.chars().count() > crate::db_tracker::MAX_DESCRIPTION_CHARS {
+                    return Err(UpgraderError::LoaderError(format!(
+                        "Description of upgrader {} in file {:?} is longer than {} characters",
+                        uid,
+                        path,
+                        crate::db_tracker::MAX_DESCRIPTION_CHARS
+                    )));
+                }
+
+                current_block = Some(Block::Up {
+                    upgrader_id: uid,
+                    description: description.to_string(),
+                    transactional: !id_str.ends_with(NO_TRANSACTION),
+                });
+                expected_upgrader_id += 1;
             } else {
-                current_sql.push_str(line);
-                current_sql.push('\n');
+                return Err(UpgraderError::LoaderError(format!(
+                    "Invalid upgrader ID format in file {:?}: {}",
+                    path, line
+                )));
             }
+        } else {
+            current_sql.push_str(line);
+            current_sql.push('\n');
         }
+    }
+
+    // Push the last block
+    if let Some(block) = current_block {
+        push_block(
+            file_id,
+            path,
+            block,
+            &current_sql,
+            allow_empty,
+            &mut upgraders,
+        )?;
+    }
+
+    Ok(upgraders)
+}
 
-        // Push the last upgrader
-        if let (Some(uid), Some(desc)) = (current_upgrader_id, current_description) {
-            let trimmed_sql = current_sql.trim().to_string();
-            if !trimmed_sql.is_empty() {
+/// Adds a finished block of `file_id` to `upgraders`. Upgraders without SQL are skipped unless
+/// `allow_empty`.
+fn push_block(
+    file_id: i32,
+    path: &Path,
+    block: Block,
+    sql: &str,
+    allow_empty: bool,
+    upgraders: &mut Vec<SchemaUpgrader>,
+) -> Result<(), UpgraderError> {
+    let trimmed_sql = sql.trim().to_string();
+    match block {
+        Block::Up {
+            upgrader_id,
+            description,
+            transactional,
+        } => {
+            if allow_empty || !trimmed_sql.is_empty() {
                 upgraders.push(SchemaUpgrader {
                     file_id,
-                    upgrader_id: uid,
-                    description: desc.trim().to_string(),
+                    upgrader_id,
+                    description,
                     text: trimmed_sql,
+                    down_text: None,
+                    transactional,
                 });
             }
         }
+        Block::Down { upgrader_id } => {
+            let upgrader = upgraders
+                .iter_mut()
+                .rev()
+                .take_while(|u| u.file_id == file_id)
+                .find(|u| u.upgrader_id == upgrader_id)
+                .ok_or_else(|| {
+                    UpgraderError::LoaderError(format!(
+                        "Down block for upgrader {} in file {:?} has no upgrader SQL to undo",
+                        upgrader_id, path
+                    ))
+                })?;
+            if upgrader.down_text.is_some() {
+                return Err(UpgraderError::LoaderError(format!(
+                    "Duplicate down block for upgrader {} in file {:?}",
+                    upgrader_id, path
+                )));
+            }
+            if trimmed_sql.is_empty() {
+                return Err(UpgraderError::LoaderError(format!(
+                    "Empty down block for upgrader {} in file {:?}",
+                    upgrader_id, path
+                )));
+            }
+            upgrader.down_text = Some(trimmed_sql);
+        }
     }
-
-    Ok(upgraders)
+    Ok(())
 }
 
 #[cfg(test)]
@@ -203,7 +600,7 @@ mod tests {
         let mut f1 = File::create(file1).unwrap();
         writeln!(f1, "--- 0: Create orders\nCREATE TABLE orders (id INT);").unwrap();
 
-        let result = load_upgraders(folder).unwrap();
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default()).unwrap();
         assert_eq!(result.len(), 3);
 
         assert_eq!(result[0].file_id, 0);
@@ -230,7 +627,7 @@ mod tests {
 
         fs::create_dir(folder.join("nested")).unwrap();
 
-        let result = load_upgraders(folder);
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default());
         assert!(result.is_err());
         match result.unwrap_err() {
             UpgraderError::LoaderError(e) => assert!(e.contains("Nested directory found")),
@@ -238,6 +635,70 @@ mod tests {
         }
     }
 
+    /// User Story: Developer groups migrations by feature in subdirectories, with recursion enabled.
+    #[test]
+    fn test_load_upgraders_recursive() {
+        let dir = tempdir().unwrap();
+        let folder = dir.path();
+
+        fs::create_dir_all(folder.join("users")).unwrap();
+        fs::create_dir_all(folder.join("orders/archive")).unwrap();
+        fs::create_dir(folder.join(".git")).unwrap();
+        let mut f0 = File::create(folder.join("users/000_users.sql")).unwrap();
+        writeln!(f0, "--- 0: Create users\nCREATE TABLE users (id INT);").unwrap();
+        let mut f1 = File::create(folder.join("orders/001_orders.sql")).unwrap();
+        writeln!(f1, "--- 0: Create orders\nCREATE TABLE orders (id INT);").unwrap();
+        let mut f2 = File::create(folder.join("orders/archive/002_archive.ddl")).unwrap();
+        writeln!(f2, "--- 0: Create archive\nCREATE TABLE archive (id INT);").unwrap();
+        let mut f3 = File::create(folder.join("003_email.sql")).unwrap();
+        writeln!(
+            f3,
+            "--- 0: Add email\nALTER TABLE users ADD COLUMN email TEXT;"
+        )
+        .unwrap();
+        File::create(folder.join(".git/000_ignored.sql")).unwrap();
+
+        let recursive = PostgresUpgraderOptions::builder().recursive(true).build();
+        let result = load_upgraders(folder, &recursive).unwrap();
+        let loaded: Vec<_> = result
+            .iter()
+            .map(|u| (u.file_id, u.description.as_str()))
+            .collect();
+        assert_eq!(
+            loaded,
+            vec![
+                (0, "Create users"),
+                (1, "Create orders"),
+                (2, "Create archive"),
+                (3, "Add email")
+            ]
+        );
+    }
+
+    /// User Story: File ids in different subdirectories collide or leave a gap.
+    #[test]
+    fn test_load_upgraders_recursive_validates_ids() {
+        let dir = tempdir().unwrap();
+        let folder = dir.path();
+
+        fs::create_dir(folder.join("a")).unwrap();
+        fs::create_dir(folder.join("b")).unwrap();
+        File::create(folder.join("a/000_init.sql")).unwrap();
+        File::create(folder.join("b/000_dup.sql")).unwrap();
+        let recursive = PostgresUpgraderOptions::builder().recursive(true).build();
+        match load_upgraders(folder, &recursive).unwrap_err() {
+            UpgraderError::LoaderError(e) => assert!(e.contains("Duplicate file ID 0")),
+            _ => panic!("Expected LoaderError"),
+        }
+
+        fs::remove_file(folder.join("b/000_dup.sql")).unwrap();
+        File::create(folder.join("b/002_more.sql")).unwrap();
+        match load_upgraders(folder, &recursive).unwrap_err() {
+            UpgraderError::LoaderError(e) => assert!(e.contains("Missing file ID 1")),
+            _ => panic!("Expected LoaderError"),
+        }
+    }
+
     /// User Story: Developer provides a file that does not start with a number.
     #[test]
     fn test_load_upgraders_invalid_filename_fails() {
@@ -246,7 +707,7 @@ mod tests {
 
         File::create(folder.join("not_a_number_init.sql")).unwrap();
 
-        let result = load_upgraders(folder);
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default());
         assert!(result.is_err());
         match result.unwrap_err() {
             UpgraderError::LoaderError(e) => {
@@ -266,13 +727,10 @@ mod tests {
         let mut f0 = File::create(file0).unwrap();
         writeln!(f0, "--- not_an_id: Description\nSQL;").unwrap();
 
-        let result = load_upgraders(folder);
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default());
         assert!(result.is_err());
         match result.unwrap_err() {
-            UpgraderError::LoaderError(e) => assert!(
-                e.contains("Invalid upgrader ID format")
-                    || e.contains("Invalid upgrader header format")
-            ),
+            UpgraderError::LoaderError(e) => assert!(e.contains("Invalid upgrader ID format")),
             _ => panic!("Expected LoaderError"),
         }
     }
@@ -285,7 +743,7 @@ mod tests {
 
         File::create(folder.join("001_init.sql")).unwrap();
 
-        let result = load_upgraders(folder);
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default());
         assert!(result.is_err());
         match result.unwrap_err() {
             UpgraderError::LoaderError(e) => assert!(e.contains("Missing file ID 0")),
@@ -302,7 +760,7 @@ mod tests {
         File::create(folder.join("000_init.sql")).unwrap();
         File::create(folder.join("002_more.sql")).unwrap();
 
-        let result = load_upgraders(folder);
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default());
         assert!(result.is_err());
         match result.unwrap_err() {
             UpgraderError::LoaderError(e) => assert!(e.contains("Missing file ID 1")),
@@ -319,7 +777,7 @@ mod tests {
         File::create(folder.join("000_init.sql")).unwrap();
         File::create(folder.join("000_dup.sql")).unwrap();
 
-        let result = load_upgraders(folder);
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default());
         assert!(result.is_err());
         match result.unwrap_err() {
             UpgraderError::LoaderError(e) => assert!(e.contains("Duplicate file ID 0")),
@@ -338,7 +796,7 @@ mod tests {
         writeln!(f0, "--- 0: Step 0\nSQL;").unwrap();
         writeln!(f0, "--- 2: Step 2\nSQL;").unwrap(); // Skipped 1
 
-        let result = load_upgraders(folder);
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default());
         assert!(result.is_err());
         match result.unwrap_err() {
             UpgraderError::LoaderError(e) => assert!(e.contains("Invalid upgrader sequence")),
@@ -356,7 +814,7 @@ mod tests {
         let mut f0 = File::create(file0).unwrap();
         writeln!(f0, "--- 1: Step 1\nSQL;").unwrap();
 
-        let result = load_upgraders(folder);
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default());
         assert!(result.is_err());
         match result.unwrap_err() {
             UpgraderError::LoaderError(e) => assert!(e.contains("Invalid upgrader sequence")),
@@ -375,7 +833,7 @@ mod tests {
         let mut f0 = File::create(file0).unwrap();
         writeln!(f0, "--- 0: README\nThis is just text.").unwrap();
 
-        let result = load_upgraders(folder).unwrap();
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default()).unwrap();
         assert_eq!(result.len(), 0);
     }
 
@@ -390,13 +848,126 @@ mod tests {
         let mut f0 = File::create(file0).unwrap();
         writeln!(f0, "--- 0: Empty\n\n--- 1: Real\nSELECT 1;").unwrap();
 
-        let result = load_upgraders(folder).unwrap();
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default()).unwrap();
 
         // ID 0 is skipped because text is empty. ID 1 is loaded.
         assert_eq!(result.len(), 1);
         assert_eq!(result[0].upgrader_id, 1);
     }
 
+    /// User Story: Developer keeps an empty upgrader as a placeholder for its id.
+    #[test]
+    fn test_load_upgraders_empty_sql_block_allowed() {
+        let dir = tempdir().unwrap();
+        let folder = dir.path();
+
+        let file0 = folder.join("000_init.sql");
+        let mut f0 = File::create(file0).unwrap();
+        writeln!(
+            f0,
+            "--- 0: Empty\n\n--- 0 down:\nSELECT 0;\n--- 1: Real\nSELECT 1;"
+        )
+        .unwrap();
+
+        let options = PostgresUpgraderOptions::builder()
+            .allow_empty_upgraders(true)
+            .build();
+        let result = load_upgraders(folder, &options).unwrap();
+
+        assert_eq!(result.len(), 2);
+        assert_eq!((result[0].upgrader_id, result[0].text.as_str()), (0, ""));
+        assert_eq!(result[0].down_text.as_deref(), Some("SELECT 0;"));
+        assert_eq!(result[1].upgrader_id, 1);
+    }
+
+    /// User Story: The flattened sequence of all files is strictly ascending and starts at 0 in
+    /// each file; holes are only accepted where empty upgraders may have been skipped.
+    #[test]
+    fn test_validate_sequence() {
+        let upgrader = |file_id, upgrader_id| SchemaUpgrader {
+            file_id,
+            upgrader_id,
+            description: "Step".to_string(),
+            text: "SELECT 1;".to_string(),
+            down_text: None,
+            transactional: true,
+        };
+        let sparse = [upgrader(0, 0), upgrader(0, 2), upgrader(1, 0)];
+        for contiguous in [false, true] {
+            assert!(validate_sequence(&[], contiguous).is_ok());
+            assert!(
+                validate_sequence(
+                    &[upgrader(0, 0), upgrader(0, 1), upgrader(1, 0)],
+                    contiguous
+                )
+                .is_ok()
+            );
+
+            let shuffled = [upgrader(0, 0), upgrader(1, 0), upgrader(0, 1)];
+            match validate_sequence(&shuffled, contiguous).unwrap_err() {
+                UpgraderError::LoaderError(e) => {
+                    assert!(e.contains("Upgrader 0:1 is out of sequence after 1:0"))
+                }
+                _ => panic!("Expected LoaderError"),
+            }
+            assert!(validate_sequence(&[upgrader(0, 0), upgrader(0, 0)], contiguous).is_err());
+            assert!(validate_sequence(&[upgrader(0, -1)], contiguous).is_err());
+            assert!(validate_sequence(&[upgrader(0, 0), upgrader(1, -1)], contiguous).is_err());
+        }
+
+        // Holes are left by skipped empty upgraders, so they are only rejected when those are
+        // kept.
+        assert!(validate_sequence(&sparse, false).is_ok());
+        match validate_sequence(&sparse, true).unwrap_err() {
+            UpgraderError::LoaderError(e) => {
+                assert!(e.contains("Upgrader 0:2 is out of sequence after 0:0"))
+            }
+            _ => panic!("Expected LoaderError"),
+        }
+        match validate_sequence(&[upgrader(0, 0), upgrader(1, 1)], true).unwrap_err() {
+            UpgraderError::LoaderError(e) => {
+                assert!(e.contains("Upgrader 1:1 is out of sequence after 0:0"))
+            }
+            _ => panic!("Expected LoaderError"),
+        }
+        match validate_sequence(&[upgrader(0, 1)], true).unwrap_err() {
+            UpgraderError::LoaderError(e) => {
+                assert!(e.contains("Upgrader 0:1 is out of sequence; upgrader ids start at 0"))
+            }
+            _ => panic!("Expected LoaderError"),
+        }
+    }
+
+    /// User Story: A lint tool checks the migrations folder in CI without a database.
+    #[test]
+    fn test_validate_folder() {
+        let dir = tempdir().unwrap();
+        let folder = dir.path();
+
+        let mut f0 = File::create(folder.join("000_init.sql")).unwrap();
+        writeln!(f0, "--- 0: Create users\nCREATE TABLE users (id INT);").unwrap();
+        let mut f1 = File::create(folder.join("001_orders.sql")).unwrap();
+        writeln!(f1, "--- 0: Create orders\nCREATE TABLE orders (id INT);").unwrap();
+
+        let migrations = validate_folder(folder).unwrap();
+        assert_eq!(
+            migrations[1],
+            MigrationInfo {
+                file_id: 1,
+                upgrader_id: 0,
+                description: "Create orders".to_string(),
+                text: "CREATE TABLE orders (id INT);".to_string(),
+            }
+        );
+
+        let mut f3 = File::create(folder.join("003_gap.sql")).unwrap();
+        writeln!(f3, "--- 0: Gap\nSELECT 1;").unwrap();
+        assert!(matches!(
+            validate_folder(folder),
+            Err(UpgraderError::LoaderError(_))
+        ));
+    }
+
     /// User Story: Developer uses .ddl or uppercase .SQL extensions.
     #[test]
     fn test_load_upgraders_extensions_allowed() {
@@ -411,10 +982,70 @@ mod tests {
         let mut f1 = File::create(file1).unwrap();
         writeln!(f1, "--- 0: SQL\nSELECT 2;").unwrap();
 
-        let result = load_upgraders(folder).unwrap();
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default()).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].file_id, 0);
+        assert_eq!(result[1].file_id, 1);
+    }
+
+    /// User Story: Developer's editor leaves backups and swap files next to the upgraders.
+    /// The loader should IGNORE them, so they neither load twice nor break the file ids.
+    #[test]
+    fn test_load_upgraders_ignores_editor_backups() {
+        let dir = tempdir().unwrap();
+        let folder = dir.path();
+
+        let mut f0 = File::create(folder.join("000_init.sql")).unwrap();
+        writeln!(f0, "--- 0: Init\nSELECT 1;").unwrap();
+        let mut f1 = File::create(folder.join("001_users.sql")).unwrap();
+        writeln!(f1, "--- 0: Users\nSELECT 2;").unwrap();
+        for backup in [
+            "000_init.sql~",
+            "000_init.sql.swp",
+            "#000_init.sql#",
+            "001_users.sql.BAK",
+            "002_draft.sql.tmp",
+            "003_draft.tmp",
+            "#notes.sql",
+        ] {
+            let mut f = File::create(folder.join(backup)).unwrap();
+            writeln!(f, "--- 0: Backup\nSELECT 3;").unwrap();
+        }
+
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default()).unwrap();
         assert_eq!(result.len(), 2);
         assert_eq!(result[0].file_id, 0);
         assert_eq!(result[1].file_id, 1);
+
+        // Even when a backup suffix is configured as an extension.
+        let options = PostgresUpgraderOptions::builder()
+            .extensions(vec!["sql".to_string(), "tmp".to_string()])
+            .build();
+        assert_eq!(load_upgraders(folder, &options).unwrap().len(), 2);
+    }
+
+    /// User Story: Developer templates migrations and loads files with custom extensions.
+    #[test]
+    fn test_load_upgraders_custom_extensions() {
+        let dir = tempdir().unwrap();
+        let folder = dir.path();
+
+        let mut f0 = File::create(folder.join("000_init.sql.tera")).unwrap();
+        writeln!(f0, "--- 0: Tera\nSELECT 1;").unwrap();
+        let mut f1 = File::create(folder.join("001_more.PGSQL")).unwrap();
+        writeln!(f1, "--- 0: Pgsql\nSELECT 2;").unwrap();
+        File::create(folder.join("002_plain.sql")).unwrap();
+        File::create(folder.join("tera")).unwrap();
+
+        let options = PostgresUpgraderOptions::builder()
+            .extensions(vec!["sql.tera".to_string(), "pgsql".to_string()])
+            .build();
+        let result = load_upgraders(folder, &options).unwrap();
+        let loaded: Vec<_> = result
+            .iter()
+            .map(|u| (u.file_id, u.description.as_str()))
+            .collect();
+        assert_eq!(loaded, vec![(0, "Tera"), (1, "Pgsql")]);
     }
 
     /// User Story: Developer writes upgraders out of order (e.g., 0, then 2).
@@ -431,7 +1062,7 @@ mod tests {
         writeln!(f0, "--- 2: Wrong\nSELECT 2;").unwrap();
         writeln!(f0, "--- 1: Late\nSELECT 3;").unwrap();
 
-        let result = load_upgraders(folder);
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default());
         assert!(result.is_err());
         match result.unwrap_err() {
             UpgraderError::LoaderError(e) => assert!(
@@ -440,4 +1071,328 @@ mod tests {
             _ => panic!("Expected LoaderError"),
         }
     }
+
+    /// User Story: Developer marks an upgrader that cannot run in a transaction, such as
+    /// `CREATE INDEX CONCURRENTLY`.
+    #[test]
+    fn test_load_upgraders_no_transaction() {
+        let dir = tempdir().unwrap();
+        let folder = dir.path();
+
+        let mut f0 = File::create(folder.join("000_init.sql")).unwrap();
+        writeln!(f0, "--- 0: Create users\nCREATE TABLE users (id INT);").unwrap();
+        writeln!(
+            f0,
+            "--- 1 (no-transaction): Index users\nCREATE INDEX CONCURRENTLY users_id ON users (id);"
+        )
+        .unwrap();
+
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default()).unwrap();
+        assert_eq!(result.len(), 2);
+        assert!(result[0].transactional);
+        assert!(!result[1].transactional);
+        assert_eq!(result[1].upgrader_id, 1);
+        assert_eq!(result[1].description, "Index users");
+
+        let mut f1 = File::create(folder.join("001_bad.sql")).unwrap();
+        writeln!(f1, "--- (no-transaction): Missing id\nSELECT 1;").unwrap();
+        match load_upgraders(folder, &PostgresUpgraderOptions::default()).unwrap_err() {
+            UpgraderError::LoaderError(e) => assert!(e.contains("Invalid upgrader ID format")),
+            other => panic!("Expected LoaderError, got {:?}", other),
+        }
+    }
+
+    /// User Story: Developer pairs upgraders with down blocks to be able to roll them back.
+    #[test]
+    fn test_load_upgraders_down_blocks() {
+        let dir = tempdir().unwrap();
+        let folder = dir.path();
+
+        let mut f0 = File::create(folder.join("000_init.sql")).unwrap();
+        writeln!(f0, "--- 0: Create users\nCREATE TABLE users (id INT);").unwrap();
+        writeln!(
+            f0,
+            "--- 1: Add email\nALTER TABLE users ADD COLUMN email TEXT;"
+        )
+        .unwrap();
+        writeln!(f0, "--- 1 down:\nALTER TABLE users DROP COLUMN email;").unwrap();
+        writeln!(f0, "--- 0 down:\nDROP TABLE users;").unwrap();
+        writeln!(f0, "--- 2: Create orders\nCREATE TABLE orders (id INT);").unwrap();
+
+        let result = load_upgraders(folder, &PostgresUpgraderOptions::default()).unwrap();
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[0].text, "CREATE TABLE users (id INT);");
+        assert_eq!(result[0].down_text.as_deref(), Some("DROP TABLE users;"));
+        assert_eq!(result[1].text, "ALTER TABLE users ADD COLUMN email TEXT;");
+        assert_eq!(
+            result[1].down_text.as_deref(),
+            Some("ALTER TABLE users DROP COLUMN email;")
+        );
+        assert_eq!(result[2].upgrader_id, 2);
+        assert_eq!(result[2].down_text, None);
+    }
+
+    /// User Story: Developer writes a down block that does not follow its upgrader, or writes
+    /// it twice, or leaves it empty.
+    #[test]
+    fn test_load_upgraders_invalid_down_blocks_fail() {
+        for (content, expected) in [
+            ("--- 0 down:\nDROP TABLE users;", "must follow the upgrader"),
+            (
+                "--- 0: Create users\nCREATE TABLE users (id INT);\n--- 1 down:\nSELECT 1;",
+                "must follow the upgrader",
+            ),
+            (
+                "--- 0: Create users\nCREATE TABLE users (id INT);\n--- 0 down:\nDROP TABLE users;\n--- 0 down:\nDROP TABLE users;",
+                "Duplicate down block",
+            ),
+            (
+                "--- 0: Create users\nCREATE TABLE users (id INT);\n--- 0 down:\n\n",
+                "Empty down block",
+            ),
+            (
+                "--- 0: Empty\n\n--- 0 down:\nSELECT 1;",
+                "has no upgrader SQL",
+            ),
+            (
+                "--- 0: Create users\nSELECT 1;\n--- x down:\nSELECT 1;",
+                "Invalid upgrader ID format",
+            ),
+        ] {
+            let dir = tempdir().unwrap();
+            fs::write(dir.path().join("000_init.sql"), content).unwrap();
+
+            match load_upgraders(dir.path(), &PostgresUpgraderOptions::default()) {
+                Err(UpgraderError::LoaderError(e)) => assert!(e.contains(expected), "{}", e),
+                other => panic!("Expected LoaderError for {:?}, got {:?}", content, other),
+            }
+        }
+    }
+
+    /// User Story: Developer saves an upgrader file with an editor that writes a UTF-8 byte
+    /// order mark.
+    #[test]
+    fn test_load_upgraders_utf8_bom() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("000_init.sql"),
+            "\u{feff}--- 0: Create users\nCREATE TABLE users (id INT);",
+        )
+        .unwrap();
+
+        let result = load_upgraders(dir.path(), &PostgresUpgraderOptions::default()).unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].description, "Create users");
+        assert_eq!(result[0].text, "CREATE TABLE users (id INT);");
+    }
+
+    /// User Story: Developer saves an upgrader file as Latin-1.
+    #[test]
+    fn test_load_upgraders_non_utf8_fails() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("000_init.sql"),
+            b"--- 0: Caf\xe9\nCREATE TABLE users (id INT);",
+        )
+        .unwrap();
+
+        match load_upgraders(dir.path(), &PostgresUpgraderOptions::default()) {
+            Err(UpgraderError::LoaderError(e)) => {
+                assert!(e.contains("000_init.sql"), "{}", e);
+                assert!(
+                    e.contains("not valid UTF-8 (invalid byte at offset 10)"),
+                    "{}",
+                    e
+                );
+            }
+            other => panic!("Expected LoaderError, got {:?}", other),
+        }
+    }
+
+    /// User Story: Developer on Windows saves upgrader files with CRLF line endings.
+    #[test]
+    fn test_load_upgraders_crlf() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("000_init.sql"),
+            "--- 0: Create users\r\nCREATE TABLE users (id INT);\r\n--- 0 down:\r\nDROP TABLE users;\r\n\r\n--- 1 (no-transaction): Index users\r\nCREATE INDEX CONCURRENTLY users_id ON users (id);\r",
+        )
+        .unwrap();
+
+        let result = load_upgraders(dir.path(), &PostgresUpgraderOptions::default()).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].description, "Create users");
+        assert_eq!(result[0].text, "CREATE TABLE users (id INT);");
+        assert_eq!(result[0].down_text.as_deref(), Some("DROP TABLE users;"));
+        assert_eq!(result[1].description, "Index users");
+        assert!(!result[1].transactional);
+        assert_eq!(
+            result[1].text,
+            "CREATE INDEX CONCURRENTLY users_id ON users (id);"
+        );
+    }
+
+    /// User Story: Developer writes headers with irregular spacing around the id and colon.
+    #[test]
+    fn test_load_upgraders_irregular_header_spacing() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("000_init.sql"),
+            "---   0  :   Create users  \nCREATE TABLE users (id INT);\n---\t0\tdown :\nDROP TABLE users;\n--- 1   (no-transaction)  :Index users\t\nCREATE INDEX CONCURRENTLY users_id ON users (id);\n----- not a header\n",
+        )
+        .unwrap();
+
+        let result = load_upgraders(dir.path(), &PostgresUpgraderOptions::default()).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].upgrader_id, 0);
+        assert_eq!(result[0].description, "Create users");
+        assert_eq!(result[0].down_text.as_deref(), Some("DROP TABLE users;"));
+        assert_eq!(result[1].upgrader_id, 1);
+        assert_eq!(result[1].description, "Index users");
+        assert!(!result[1].transactional);
+        assert_eq!(
+            result[1].text,
+            "CREATE INDEX CONCURRENTLY users_id ON users (id);\n----- not a header"
+        );
+    }
+
+    /// User Story: Developer leaves out the description, or uses colons inside it.
+    #[test]
+    fn test_load_upgraders_header_colons() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("000_init.sql"),
+            "--- 0\nCREATE TABLE users (id INT);\n--- 0 down\nDROP TABLE users;\n--- 1: Migrate v1:v2 mapping, see http://wiki\nSELECT 1;\n--- 2 (no-transaction)\nSELECT 2;",
+        )
+        .unwrap();
+
+        let result = load_upgraders(dir.path(), &PostgresUpgraderOptions::default()).unwrap();
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[0].description, "");
+        assert_eq!(result[0].down_text.as_deref(), Some("DROP TABLE users;"));
+        assert_eq!(
+            result[1].description,
+            "Migrate v1:v2 mapping, see http://wiki"
+        );
+        assert_eq!(result[2].description, "");
+        assert!(!result[2].transactional);
+    }
+
+    /// User Story: Developer writes a description longer than the upgraders table stores.
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_load_upgraders_gzip() {
+        use std::io::Write;
+
+        let content = "--- 0: Create users\nCREATE TABLE users (id INT);\n--- 1: Add email\nALTER TABLE users ADD COLUMN email TEXT;";
+        let plain = tempdir().unwrap();
+        fs::write(plain.path().join("000_init.sql"), content).unwrap();
+        fs::write(plain.path().join("001_more.ddl"), "--- 0: More\nSELECT 1;").unwrap();
+
+        let gzipped = tempdir().unwrap();
+        for (name, content) in [
+            ("000_init.sql.gz", content),
+            ("001_more.DDL.GZ", "--- 0: More\nSELECT 1;"),
+        ] {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(content.as_bytes()).unwrap();
+            fs::write(gzipped.path().join(name), encoder.finish().unwrap()).unwrap();
+        }
+        // Other compressed files are still ignored.
+        fs::write(gzipped.path().join("002_notes.txt.gz"), "").unwrap();
+
+        assert_eq!(
+            validate_folder(gzipped.path()).unwrap(),
+            validate_folder(plain.path()).unwrap()
+        );
+
+        fs::write(gzipped.path().join("002_broken.sql.gz"), "not gzip").unwrap();
+        match load_upgraders(gzipped.path(), &PostgresUpgraderOptions::default()) {
+            Err(UpgraderError::LoaderError(e)) => {
+                assert!(e.contains("Failed to decompress file"), "{}", e)
+            }
+            other => panic!("Expected LoaderError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_new_upgrader() {
+        let dir = tempdir().unwrap();
+        let (path, ids) = new_upgrader(dir.path(), "Create users", false).unwrap();
+        assert_eq!(path, dir.path().join("000_create_users.sql"));
+        assert_eq!(ids, (0, 0));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "--- 0: Create users\n\n"
+        );
+
+        fs::write(&path, "--- 0: Create users\nCREATE TABLE users (id INT);").unwrap();
+        let (path, ids) = new_upgrader(dir.path(), "Add e-mail (unique!)", true).unwrap();
+        assert_eq!(ids, (0, 1));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "--- 0: Create users\nCREATE TABLE users (id INT);\n\n--- 1: Add e-mail (unique!)\n\n"
+        );
+
+        let (path, ids) = new_upgrader(dir.path(), "  Café orders ", false).unwrap();
+        assert_eq!(path, dir.path().join("001_caf_orders.sql"));
+        assert_eq!(ids, (1, 0));
+
+        // Generated files pass validation, and keep the padding of existing names.
+        fs::write(path, "--- 0: Café orders\nCREATE TABLE orders (id INT);").unwrap();
+        let loaded = load_upgraders(dir.path(), &PostgresUpgraderOptions::default()).unwrap();
+        assert_eq!(loaded.len(), 2);
+        fs::rename(
+            dir.path().join("001_caf_orders.sql"),
+            dir.path().join("0001_orders.sql"),
+        )
+        .unwrap();
+        let (path, _) = new_upgrader(dir.path(), "???", false).unwrap();
+        assert_eq!(path, dir.path().join("0002_upgrader.sql"));
+    }
+
+    #[test]
+    fn test_new_upgrader_fails() {
+        let dir = tempdir().unwrap();
+        assert!(matches!(
+            new_upgrader(dir.path(), "First", true),
+            Err(UpgraderError::LoaderError(_))
+        ));
+        for description in ["", "  ", "Two\nlines"] {
+            assert!(matches!(
+                new_upgrader(dir.path(), description, false),
+                Err(UpgraderError::ConfigurationError(_))
+            ));
+        }
+
+        // An invalid folder is not extended.
+        fs::write(dir.path().join("001_gap.sql"), "--- 0: Gap\nSELECT 1;").unwrap();
+        assert!(matches!(
+            new_upgrader(dir.path(), "Next", false),
+            Err(UpgraderError::LoaderError(_))
+        ));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_load_upgraders_long_description_fails() {
+        let dir = tempdir().unwrap();
+        let header = |length| format!("--- 0: {}\nSELECT 1;", "é".repeat(length));
+        fs::write(dir.path().join("000_init.sql"), header(500)).unwrap();
+        let result = load_upgraders(dir.path(), &PostgresUpgraderOptions::default()).unwrap();
+        assert_eq!(result[0].description.chars().count(), 500);
+
+        fs::write(dir.path().join("000_init.sql"), header(501)).unwrap();
+        match load_upgraders(dir.path(), &PostgresUpgraderOptions::default()) {
+            Err(UpgraderError::LoaderError(e)) => assert!(
+                e.contains("Description of upgrader 0 in file")
+                    && e.contains("000_init.sql")
+                    && e.contains("longer than 500 characters"),
+                "{}",
+                e
+            ),
+            other => panic!("Expected LoaderError, got {:?}", other),
+        }
+    }
 }
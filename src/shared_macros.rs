@@ -0,0 +1,25 @@
+/// Emits a `tracing` event at `$level` with the `tracing` feature, and expands to nothing
+/// without it.
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        tracing::$level!($($arg)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {};
+}
+
+/// Calls the `on_progress` hook of the options, if set, with `$applied` of `$total`.
+macro_rules! report_progress {
+    ($options:expr, $applied:expr, $total:expr) => {{
+        if let Some(hook) = &$options.on_progress {
+            hook($applied, $total);
+        }
+    }};
+}
+
+pub(crate) use report_progress;
+pub(crate) use trace_event;
@@ -0,0 +1,608 @@
+use crate::db_tracker::{self, AppliedUpgrader};
+use crate::integrity::{check_before_apply, pending_upgraders};
+use crate::schema_loader::SchemaUpgrader;
+use crate::shared_macros::{report_progress, trace_event};
+use crate::{MigrationSet, PostgresUpgraderOptions, UpgradeReport, UpgraderError};
+use sqlx::postgres::{PgConnection, PgPool, PgRow};
+use sqlx::{Connection, Row};
+
+/// Asynchronously applies schema upgrades from the specified folder to the database, over a
+/// connection taken from an existing `sqlx` pool.
+///
+/// The upgraders table and the advisory locks taken during initialization and around each
+/// upgrader are the same as with [`upgrade_async`](crate::upgrade_async), so both can be used
+/// against the same database. The connection and TLS options are not used, as that is
+/// configured on the pool.
+///
+/// Returns an [`UpgradeReport`] of what was applied.
+///
+/// # Errors
+///
+/// Returns `UpgraderError` if:
+/// - No connection can be taken from the pool.
+/// - Upgrader files cannot be loaded or are invalid.
+/// - The options use a feature this backend does not support: `single_transaction`,
+///   `track_verification`, `deploy_key`, `audit_integrity_failures`, `run_analyze_after`,
+///   `parallel_cold_start`, `max_parallel_files`, or `(no-transaction)` upgraders.
+/// - An integrity violation is detected.
+/// - Execution of a migration step fails.
+pub async fn upgrade_sqlx(
+    pool: &PgPool,
+    upgraders_folder: impl AsRef<std::path::Path>,
+    options: &PostgresUpgraderOptions,
+) -> Result<UpgradeReport, UpgraderError> {
+    let set = MigrationSet::load(upgraders_folder, options)?;
+    check_supported(&set)?;
+
+    let mut connection = pool.acquire().await.map_err(|e| {
+        UpgraderError::ConnectionError(format!("Failed to acquire a connection: {}", e))
+    })?;
+    run_upgrade(&mut connection, &set).await
+}
+
+/// Rejects options whose machinery only exists in the `postgres` / `tokio-postgres` backends,
+/// rather than silently ignoring them.
+fn check_supported(set: &MigrationSet) -> Result<(), UpgraderError> {
+    let options = &set.options;
+    let unsupported = [
+        ("single_transaction", options.single_transaction),
+        ("track_verification", options.track_verification),
+        ("deploy_key", options.deploy_key.is_some()),
+        ("audit_integrity_failures", options.audit_integrity_failures),
+        ("run_analyze_after", options.run_analyze_after),
+        ("parallel_cold_start", options.parallel_cold_start),
+        (
+            "max_parallel_files",
+            options
+                .max_parallel_files
+                .is_some_and(|max_files| max_files > 1),
+        ),
+    ];
+    if let Some((name, _)) = unsupported.iter().find(|(_, enabled)| *enabled) {
+        return Err(UpgraderError::ConfigurationError(format!(
+            "{} is not supported by upgrade_sqlx.",
+            name
+        )));
+    }
+    if let Some(upgrader) = set.upgraders.iter().find(|u| !u.transactional) {
+        return Err(UpgraderError::ConfigurationError(format!(
+            "Upgrader {}:{} is marked (no-transaction), which upgrade_sqlx does not support.",
+            upgrader.file_id, upgrader.upgrader_id
+        )));
+    }
+    if let Some(target) = options.target
+        && !set
+            .upgraders
+            .iter()
+            .any(|u| (u.file_id, u.upgrader_id) == target)
+    {
+        return Err(UpgraderError::ConfigurationError(format!(
+            "Upgrade target {}:{} is not one of the loaded upgraders.",
+            target.0, target.1
+        )));
+    }
+    Ok(())
+}
+
+async fn run_upgrade(
+    connection: &mut PgConnection,
+    set: &MigrationSet,
+) -> Result<UpgradeReport, UpgraderError> {
+    let options = &set.options;
+
+    let in_recovery: bool = sqlx::query_scalar("SELECT pg_is_in_recovery()")
+        .fetch_one(&mut *connection)
+        .await
+        .map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to check recovery status: {}", e))
+        })?;
+    if in_recovery {
+        return Err(UpgraderError::ConfigurationError(
+            "target is a read replica / in recovery; cannot run migrations. Connect to the primary instead."
+                .to_string(),
+        ));
+    }
+
+    // Dry run: check under the lock as below, but create and record nothing. Without an
+    // upgraders table, every upgrader is pending.
+    if options.dry_run {
+        return dry_run(connection, set).await;
+    }
+
+    if options.create_schema {
+        for schema in options.schemas_to_create() {
+            let sql = format!(
+                "CREATE SCHEMA IF NOT EXISTS {};",
+                options.identifier(schema)
+            );
+            sqlx::raw_sql(&sql)
+                .execute(&mut *connection)
+                .await
+                .map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to create schema: {}", e))
+                })?;
+        }
+    }
+
+    init_upgraders_table(connection, options).await?;
+
+    let migration_role = options
+        .migration_role()
+        .map(|role| options.identifier(&role));
+
+    // Fast path: if the database is already up to date, return without taking the advisory
+    // upgrade lock. Anything else falls through to the locked loop below, which re-reads and
+    // re-verifies the applied upgraders.
+    let applied = load_applied_upgraders(connection, options).await?;
+    let total_pending = pending_upgraders(&set.upgraders, &applied)
+        .filter(|u| options.within_target(u))
+        .count();
+    if total_pending == 0 && check_before_apply(&set.upgraders, &applied, options).is_ok() {
+        return Ok(UpgradeReport {
+            applied: Vec::new(),
+            already_applied: applied.len(),
+            pending: Vec::new(),
+        });
+    }
+    let mut applied_now = Vec::new();
+    loop {
+        let mut transaction = connection.begin().await.map_err(|e| {
+            UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
+        })?;
+        lock_upgraders_table(&mut transaction, options).await?;
+        let applied = load_applied_upgraders(&mut transaction, options).await?;
+        check_before_apply(&set.upgraders, &applied, options)?;
+
+        // With a target, the upgraders after it stay pending
+        let Some(upgrader) =
+            pending_upgraders(&set.upgraders, &applied).find(|u| options.within_target(u))
+        else {
+            transaction.commit().await.map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e))
+            })?;
+            return Ok(UpgradeReport {
+                already_applied: applied.len().saturating_sub(applied_now.len()),
+                applied: applied_now,
+                pending: Vec::new(),
+            });
+        };
+
+        crate::maintenance::check_window(options, upgrader, &upgrader.text)?;
+        let sql = options.apply_substitutions(&upgrader.text);
+        let info = crate::status::MigrationInfo::from(upgrader);
+        trace_event!(
+            info,
+            file_id = upgrader.file_id,
+            upgrader_id = upgrader.upgrader_id,
+            description = %upgrader.description,
+            "Applying upgrader {}:{}",
+            upgrader.file_id,
+            upgrader.upgrader_id
+        );
+        if let Some(hook) = &options.on_before_apply {
+            hook(&info);
+        }
+        if let Some(hook) = &options.on_before_apply_async {
+            hook(&info).await;
+        }
+
+        if let Some(role) = &migration_role {
+            execute(&mut transaction, &format!("SET LOCAL ROLE {};", role))
+                .await
+                .map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to set role {}: {}", role, e))
+                })?;
+        }
+        execute_upgrader_sql(&mut transaction, &sql, upgrader, options).await?;
+        // Record as the connecting user, who owns the upgraders table
+        if migration_role.is_some() {
+            execute(&mut transaction, "RESET ROLE;")
+                .await
+                .map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to reset role: {}", e))
+                })?;
+        }
+
+        record_upgrader(&mut transaction, options, upgrader).await?;
+        transaction.commit().await.map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e))
+        })?;
+        applied_now.push((
+            upgrader.file_id,
+            upgrader.upgrader_id,
+            upgrader.description.clone(),
+        ));
+        report_progress!(options, applied_now.len(), total_pending);
+
+        if let Some(hook) = &options.on_after_apply {
+            hook(&info);
+        }
+        if let Some(hook) = &options.on_after_apply_async {
+            hook(&info).await;
+        }
+    }
+}
+
+/// Reports the upgraders an upgrade would apply, in [`UpgradeReport::pending`], after the same
+/// checks under the upgrade lock, and rolls back.
+async fn dry_run(
+    connection: &mut PgConnection,
+    set: &MigrationSet,
+) -> Result<UpgradeReport, UpgraderError> {
+    let options = &set.options;
+    let mut transaction = connection.begin().await.map_err(|e| {
+        UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
+    })?;
+    let table_exists: bool = sqlx::query_scalar("SELECT to_regclass($1) IS NOT NULL")
+        .bind(db_tracker::table_name(options))
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(|e| UpgraderError::ExecutionError(format!("Failed to check table: {}", e)))?;
+    let applied = if table_exists {
+        lock_upgraders_table(&mut transaction, options).await?;
+        load_applied_upgraders(&mut transaction, options).await?
+    } else {
+        Vec::new()
+    };
+    check_before_apply(&set.upgraders, &applied, options)?;
+    let pending = pending_upgraders(&set.upgraders, &applied)
+        .filter(|u| options.within_target(u))
+        .map(|u| (u.file_id, u.upgrader_id, u.description.clone()))
+        .collect();
+
+    transaction.rollback().await.map_err(|e| {
+        UpgraderError::ExecutionError(format!("Failed to roll back transaction: {}", e))
+    })?;
+    Ok(UpgradeReport {
+        applied: Vec::new(),
+        already_applied: applied.len(),
+        pending,
+    })
+}
+
+/// Runs `sql`, which may hold several statements, without parameters.
+async fn execute(connection: &mut PgConnection, sql: &str) -> Result<(), sqlx::Error> {
+    sqlx::raw_sql(sql).execute(connection).await.map(|_| ())
+}
+
+/// Runs the SQL `sql` of `upgrader`: as one batch, or with `debug_statements` one statement
+/// at a time, so that a failure names its statement.
+async fn execute_upgrader_sql(
+    connection: &mut PgConnection,
+    sql: &str,
+    upgrader: &SchemaUpgrader,
+    options: &PostgresUpgraderOptions,
+) -> Result<(), UpgraderError> {
+    if !options.debug_statements {
+        return execute(connection, sql)
+            .await
+            .map_err(|e| upgrader_error(&e, upgrader, options, None));
+    }
+    for (index, statement) in crate::statements::split_statements(sql)
+        .into_iter()
+        .enumerate()
+    {
+        execute(connection, statement)
+            .await
+            .map_err(|e| upgrader_error(&e, upgrader, options, Some(index + 1)))?;
+    }
+    Ok(())
+}
+
+/// Like `init_upgraders_table` of the other backends: creates the upgraders table, or brings
+/// one created by an older version up to date, under the advisory lock.
+async fn init_upgraders_table(
+    connection: &mut PgConnection,
+    options: &PostgresUpgraderOptions,
+) -> Result<(), UpgraderError> {
+    let mut transaction = connection.begin().await.map_err(|e| {
+        UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
+    })?;
+
+    if let Some(timeouts) = options.timeout_settings("LOCAL") {
+        execute(&mut transaction, &timeouts)
+            .await
+            .map_err(|e| UpgraderError::ExecutionError(format!("Failed to set timeouts: {}", e)))?;
+    }
+    let lock_id = options.advisory_lock_id();
+    acquire_xact_lock(&mut transaction, options, lock_id, "advisory lock").await?;
+    trace_event!(debug, lock_id, "Acquired advisory lock");
+
+    let table = db_tracker::table_name(options);
+    let created: bool = sqlx::query_scalar("SELECT to_regclass($1) IS NULL")
+        .bind(&table)
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(|e| UpgraderError::ExecutionError(format!("Failed to check table: {}", e)))?;
+    execute(&mut transaction, &db_tracker::create_table_sql(&table))
+        .await
+        .map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to create upgraders table: {}", e))
+        })?;
+
+    // Columns and the comment are checked first, so that an up to date table is never
+    // altered, which would conflict with the lock held by a running upgrade.
+    let checksum_column: &[(&str, &str)] = if options.store_checksums {
+        &[db_tracker::CHECKSUM_COLUMN]
+    } else {
+        &[]
+    };
+    for (column, column_type) in db_tracker::ADDED_COLUMNS.iter().chain(checksum_column) {
+        let exists: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM pg_attribute WHERE attrelid = to_regclass($1) AND attname = $2 AND NOT attisdropped)",
+        )
+        .bind(&table)
+        .bind(column)
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to inspect upgraders table: {}", e))
+        })?;
+        if !exists {
+            let alter_sql = format!(
+                "ALTER TABLE {} ADD COLUMN {} {};",
+                table, column, column_type
+            );
+            execute(&mut transaction, &alter_sql).await.map_err(|e| {
+                UpgraderError::ExecutionError(format!(
+                    "Failed to add column {} to upgraders table: {}",
+                    column, e
+                ))
+            })?;
+        }
+    }
+
+    let comment = options.table_comment();
+    let comment_differs: bool = sqlx::query_scalar(
+        "SELECT obj_description(to_regclass($1), 'pg_class') IS DISTINCT FROM $2",
+    )
+    .bind(&table)
+    .bind(&comment)
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(|e| {
+        UpgraderError::ExecutionError(format!("Failed to inspect upgraders table: {}", e))
+    })?;
+    if comment_differs {
+        let comment_sql = format!(
+            "COMMENT ON TABLE {} IS '{}';",
+            table,
+            comment.replace('\'', "''")
+        );
+        execute(&mut transaction, &comment_sql).await.map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to comment upgraders table: {}", e))
+        })?;
+    }
+
+    let missing_sql = format!(
+        "SELECT EXISTS (SELECT 1 FROM {} WHERE sequence IS NULL)",
+        table
+    );
+    let missing_sequence: bool = sqlx::query_scalar(&missing_sql)
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to inspect upgraders table: {}", e))
+        })?;
+    if missing_sequence {
+        execute(&mut transaction, &db_tracker::backfill_sequence_sql(&table))
+            .await
+            .map_err(|e| {
+                UpgraderError::ExecutionError(format!(
+                    "Failed to backfill upgrader sequence: {}",
+                    e
+                ))
+            })?;
+    }
+
+    if created && let Some(owner_sql) = db_tracker::change_owner_sql(&table, options) {
+        execute(&mut transaction, &owner_sql).await.map_err(|e| {
+            UpgraderError::ExecutionError(format!(
+                "Failed to change owner of upgraders table: {}",
+                e
+            ))
+        })?;
+    }
+
+    transaction
+        .commit()
+        .await
+        .map_err(|e| UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e)))
+}
+
+/// Like `lock_upgraders_table` of the other backends: applies the session settings of the
+/// upgrade to the transaction and takes the advisory lock that serializes upgrader
+/// transactions.
+async fn lock_upgraders_table(
+    connection: &mut PgConnection,
+    options: &PostgresUpgraderOptions,
+) -> Result<(), UpgraderError> {
+    // Set first, so that they also cover the SQL run later in the same transaction.
+    if let Some(timeouts) = options.timeout_settings("LOCAL") {
+        execute(connection, &timeouts)
+            .await
+            .map_err(|e| UpgraderError::ExecutionError(format!("Failed to set timeouts: {}", e)))?;
+    }
+    if let Some(search_path) = options.search_path_setting("LOCAL") {
+        execute(connection, &search_path).await.map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to set search_path: {}", e))
+        })?;
+    }
+
+    let lock_id = db_tracker::upgrade_lock_id(options);
+    acquire_xact_lock(connection, options, lock_id, "upgrade lock").await?;
+    trace_event!(debug, lock_id, "Acquired upgrade lock");
+    Ok(())
+}
+
+/// Takes the transaction-level advisory lock `lock_id`, called `lock` in errors. With
+/// `lock_wait_timeout` the lock is polled, and `UpgraderError::LockTimeout` returned once the
+/// timeout elapses; without it the wait is unbounded.
+async fn acquire_xact_lock(
+    connection: &mut PgConnection,
+    options: &PostgresUpgraderOptions,
+    lock_id: i64,
+    lock: &str,
+) -> Result<(), UpgraderError> {
+    let Some(timeout) = options.lock_wait_timeout else {
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(lock_id)
+            .execute(&mut *connection)
+            .await
+            .map_err(|e| execution_error(&e, format!("Failed to acquire {}: {}", lock, e)))?;
+        return Ok(());
+    };
+
+    let deadline = std::time::Instant::now() + timeout;
+    loop {
+        let acquired: bool = sqlx::query_scalar("SELECT pg_try_advisory_xact_lock($1)")
+            .bind(lock_id)
+            .fetch_one(&mut *connection)
+            .await
+            .map_err(|e| execution_error(&e, format!("Failed to acquire {}: {}", lock, e)))?;
+        if acquired {
+            return Ok(());
+        }
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        if remaining.is_zero() {
+            return Err(UpgraderError::LockTimeout(format!(
+                "The {} {} was not acquired within {:?}",
+                lock, lock_id, timeout
+            )));
+        }
+        tokio::time::sleep(remaining.min(db_tracker::LOCK_WAIT_POLL_INTERVAL)).await;
+    }
+}
+
+async fn load_applied_upgraders(
+    connection: &mut PgConnection,
+    options: &PostgresUpgraderOptions,
+) -> Result<Vec<AppliedUpgrader>, UpgraderError> {
+    // Select every column so that tables created by older versions, which lack newer
+    // columns, can still be read.
+    let table = db_tracker::table_name(options);
+    let select_sql = if options.compare_checksums {
+        let columns: Vec<String> = sqlx::query_scalar(db_tracker::COLUMNS_SQL)
+            .bind(&table)
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to inspect upgraders table: {}", e))
+            })?;
+        db_tracker::checksum_select_sql(&table, &columns)
+    } else {
+        format!("SELECT * FROM {} ORDER BY file_id, upgrader_id;", table)
+    };
+    let rows = sqlx::query(&select_sql)
+        .fetch_all(connection)
+        .await
+        .map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to load applied upgraders: {}", e))
+        })?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(AppliedUpgrader {
+                file_id: row.try_get("file_id")?,
+                upgrader_id: row.try_get("upgrader_id")?,
+                description: row.try_get("description")?,
+                // Not stored for upgraders recorded with store_checksums, and not read with
+                // compare_checksums.
+                text: optional_column::<String>(row, "text")?.unwrap_or_default(),
+                applied_on: row.try_get("applied_on")?,
+                sequence: optional_column(row, "sequence")?,
+                applied_host: optional_column(row, "applied_host")?,
+                applied_by: optional_column(row, "applied_by")?,
+                last_verified_on: optional_column(row, "last_verified_on")?,
+                checksum: optional_column(row, "checksum")?,
+            })
+        })
+        .collect::<Result<_, sqlx::Error>>()
+        .map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to load applied upgraders: {}", e))
+        })
+}
+
+/// Reads the nullable column `name` of `row`, or `None` if the upgraders table was created
+/// by an older version without it. Any other error, e.g. an unexpected column type, is
+/// returned.
+fn optional_column<'r, T>(row: &'r PgRow, name: &str) -> Result<Option<T>, sqlx::Error>
+where
+    T: sqlx::Decode<'r, sqlx::Postgres> + sqlx::Type<sqlx::Postgres>,
+{
+    match row.try_get::<Option<T>, _>(name) {
+        Err(sqlx::Error::ColumnNotFound(_)) => Ok(None),
+        result => result,
+    }
+}
+
+async fn record_upgrader(
+    connection: &mut PgConnection,
+    options: &PostgresUpgraderOptions,
+    upgrader: &SchemaUpgrader,
+) -> Result<(), UpgraderError> {
+    sqlx::query(&db_tracker::record_upgrader_sql(options))
+        .bind(upgrader.file_id)
+        .bind(upgrader.upgrader_id)
+        .bind(&upgrader.description)
+        .bind(db_tracker::recorded_content(options, upgrader))
+        .bind(db_tracker::host_name())
+        .bind(&options.applied_by)
+        .bind(db_tracker::applied_on(options))
+        .execute(connection)
+        .await
+        .map_err(|e| {
+            UpgraderError::ExecutionError(format!(
+                "Failed to record upgrader {}: {}",
+                upgrader.upgrader_id, e
+            ))
+        })?;
+    Ok(())
+}
+
+/// Wraps `message` in `TimeoutError` if `error` is PostgreSQL cancelling a statement for
+/// `lock_timeout` or `statement_timeout`, and in `ExecutionError` otherwise.
+fn execution_error(error: &sqlx::Error, message: String) -> UpgraderError {
+    match error.as_database_error() {
+        Some(db_error)
+            if matches!(db_error.code().as_deref(), Some("55P03" | "57014"))
+                && db_error.message().contains("timeout") =>
+        {
+            UpgraderError::TimeoutError(message)
+        }
+        _ => UpgraderError::ExecutionError(message),
+    }
+}
+
+/// Wraps the error of executing the SQL of `upgrader`, or of its 1-based `statement`, in
+/// `ExecutionFailed`, or in `TimeoutError` if a timeout cancelled it.
+fn upgrader_error(
+    error: &sqlx::Error,
+    upgrader: &SchemaUpgrader,
+    options: &PostgresUpgraderOptions,
+    statement: Option<usize>,
+) -> UpgraderError {
+    let code = error
+        .as_database_error()
+        .and_then(|db_error| db_error.code().map(|code| code.into_owned()));
+    let source = crate::error::truncate_error(
+        &error.to_string(),
+        code.as_deref(),
+        options.max_error_chars(),
+    );
+    match execution_error(
+        error,
+        format!(
+            "Failed to execute upgrader {}: {}",
+            upgrader.upgrader_id, source
+        ),
+    ) {
+        UpgraderError::ExecutionError(_) => UpgraderError::ExecutionFailed {
+            file_id: upgrader.file_id,
+            upgrader_id: upgrader.upgrader_id,
+            statement,
+            source,
+        },
+        timeout => timeout,
+    }
+}
@@ -0,0 +1,191 @@
+/// Splits `sql` into its statements at the semicolons ending them, ignoring semicolons in
+/// string literals, quoted identifiers, dollar-quoted bodies and comments. Each statement is
+/// returned as written, trimmed and without its semicolon; pieces holding only whitespace
+/// and comments are dropped.
+///
+/// SQL-standard function bodies (`BEGIN ATOMIC ... END`) are not recognized, so their
+/// statements are split apart.
+pub(crate) fn split_statements(sql: &str) -> Vec<&str> {
+    let bytes = sql.as_bytes();
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut has_code = false;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b';' => {
+                if has_code {
+                    statements.push(sql[start..i].trim());
+                }
+                start = i + 1;
+                has_code = false;
+                i += 1;
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                i = find(bytes, i + 2, b"\n").map_or(bytes.len(), |end| end + 1);
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => i = block_comment_end(bytes, i),
+            b'\'' => {
+                // E'...' strings take backslash escapes.
+                let escapes = i > 0
+                    && matches!(bytes[i - 1], b'e' | b'E')
+                    && (i < 2 || !is_identifier_byte(bytes[i - 2]));
+                has_code = true;
+                i = quoted_end(bytes, i, b'\'', escapes);
+            }
+            b'"' => {
+                has_code = true;
+                i = quoted_end(bytes, i, b'"', false);
+            }
+            b'$' if i == 0 || !is_identifier_byte(bytes[i - 1]) => {
+                has_code = true;
+                i = match dollar_tag(bytes, i) {
+                    Some(tag) => {
+                        find(bytes, i + tag.len(), tag).map_or(bytes.len(), |end| end + tag.len())
+                    }
+                    None => i + 1,
+                };
+            }
+            byte => {
+                has_code |= !byte.is_ascii_whitespace();
+                i += 1;
+            }
+        }
+    }
+    if has_code {
+        statements.push(sql[start..].trim());
+    }
+    statements
+}
+
+/// Whether `byte` can be part of an identifier, which a dollar quote cannot follow.
+fn is_identifier_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'$' || byte >= 0x80
+}
+
+/// Returns the position of `needle` in `bytes` at or after `from`.
+fn find(bytes: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    bytes
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| from + position)
+}
+
+/// Returns the position after the comment starting at `start`, which may nest.
+fn block_comment_end(bytes: &[u8], start: usize) -> usize {
+    let mut depth = 0;
+    let mut i = start;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(b"/*") {
+            depth += 1;
+            i += 2;
+        } else if bytes[i..].starts_with(b"*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return i;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+/// Returns the position after the text quoted with `quote` starting at `start`, where a
+/// doubled quote stands for itself and, with `escapes`, a backslash escapes the next byte.
+fn quoted_end(bytes: &[u8], start: usize, quote: u8, escapes: bool) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if escapes => i += 2,
+            byte if byte == quote && bytes.get(i + 1) == Some(&quote) => i += 2,
+            byte if byte == quote => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Returns the opening tag of the dollar quote starting at `start`, e.g. `$$` or `$body$`,
+/// or `None` if the `$` does not start one, as in a parameter like `$1`.
+fn dollar_tag(bytes: &[u8], start: usize) -> Option<&[u8]> {
+    let name = &bytes[start + 1..];
+    let length = name
+        .iter()
+        .position(|&byte| !(byte.is_ascii_alphanumeric() || byte == b'_' || byte >= 0x80))?;
+    let starts_with_digit = name.first().is_some_and(u8::is_ascii_digit);
+    (name[length] == b'$' && !starts_with_digit).then(|| &bytes[start..start + length + 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_statements() {
+        assert_eq!(
+            split_statements("CREATE TABLE a (id INT);\nINSERT INTO a VALUES (1);\n"),
+            vec!["CREATE TABLE a (id INT)", "INSERT INTO a VALUES (1)"]
+        );
+        // The last statement needs no semicolon, and empty pieces are dropped.
+        assert_eq!(
+            split_statements(";; SELECT 1;\n  ;\nSELECT 2"),
+            vec!["SELECT 1", "SELECT 2"]
+        );
+        assert!(split_statements("  \n").is_empty());
+    }
+
+    #[test]
+    fn test_split_statements_quoting() {
+        assert_eq!(
+            split_statements("INSERT INTO t VALUES ('a;b', 'it''s;'); SELECT \"x;y\" FROM t;"),
+            vec![
+                "INSERT INTO t VALUES ('a;b', 'it''s;')",
+                "SELECT \"x;y\" FROM t"
+            ]
+        );
+        assert_eq!(
+            split_statements(r"SELECT E'\';'; SELECT 2"),
+            vec![r"SELECT E'\';'", "SELECT 2"]
+        );
+        // A backslash only escapes in E'' strings.
+        assert_eq!(
+            split_statements(r"SELECT 'C:\'; SELECT 2"),
+            vec![r"SELECT 'C:\'", "SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn test_split_statements_dollar_quoting() {
+        let function = "CREATE FUNCTION f() RETURNS INT AS $body$\nBEGIN\n  PERFORM 1; RETURN $$;$$::INT;\nEND;\n$body$ LANGUAGE plpgsql";
+        assert_eq!(
+            split_statements(&format!("{};\nSELECT f();", function)),
+            vec![function, "SELECT f()"]
+        );
+        assert_eq!(
+            split_statements("DO $$ BEGIN PERFORM 1; END $$; SELECT 2"),
+            vec!["DO $$ BEGIN PERFORM 1; END $$", "SELECT 2"]
+        );
+        // Parameters and identifiers with dollars do not start dollar quotes.
+        assert_eq!(
+            split_statements("PREPARE p AS SELECT $1; SELECT a$b$ FROM t; SELECT 3"),
+            vec!["PREPARE p AS SELECT $1", "SELECT a$b$ FROM t", "SELECT 3"]
+        );
+    }
+
+    #[test]
+    fn test_split_statements_comments() {
+        assert_eq!(
+            split_statements(
+                "-- first; still a comment\nSELECT 1; /* block; /* nested; */ still; */ SELECT 2;\n-- trailing;"
+            ),
+            vec![
+                "-- first; still a comment\nSELECT 1",
+                "/* block; /* nested; */ still; */ SELECT 2"
+            ]
+        );
+    }
+}
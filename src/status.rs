@@ -0,0 +1,210 @@
+use crate::db_tracker::{AppliedUpgrader, checksum};
+use crate::integrity::{db_ahead_upgraders, find_violation, pending_upgraders};
+use crate::schema_loader::SchemaUpgrader;
+use crate::{IntegrityReport, MigrationSet, UpgraderError};
+
+/// An upgrader loaded from the migration files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MigrationInfo {
+    pub file_id: i32,
+    pub upgrader_id: i32,
+    pub description: String,
+    /// The upgrader SQL, before schema substitution.
+    pub text: String,
+}
+
+impl MigrationInfo {
+    /// Returns the SHA-256 of the SQL without leading and trailing ASCII whitespace, in
+    /// lowercase hex, as recorded with `store_checksums`.
+    pub fn checksum(&self) -> String {
+        checksum(&self.text)
+    }
+}
+
+impl From<&SchemaUpgrader> for MigrationInfo {
+    fn from(upgrader: &SchemaUpgrader) -> Self {
+        MigrationInfo {
+            file_id: upgrader.file_id,
+            upgrader_id: upgrader.upgrader_id,
+            description: upgrader.description.clone(),
+            text: upgrader.text.clone(),
+        }
+    }
+}
+
+/// The migration state of a database, compared with the migration files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MigrationStatus {
+    /// The upgraders recorded in the database, in id order.
+    pub applied: Vec<AppliedUpgrader>,
+    /// The file upgraders not recorded in the database, in id order.
+    pub pending: Vec<MigrationInfo>,
+    /// The first integrity violation, if the recorded upgraders do not match the files. An
+    /// upgrade would fail with it.
+    pub drift: Option<IntegrityReport>,
+    /// The `(file_id, upgrader_id)` of recorded upgraders newer than the last file upgrader,
+    /// in id order, e.g. when an older build runs against a newer database. Upgrades accept
+    /// this, so it is not drift.
+    pub ahead: Vec<(i32, i32)>,
+}
+
+impl MigrationStatus {
+    /// Returns `true` if every upgrader is applied and the database matches the files.
+    pub fn is_up_to_date(&self) -> bool {
+        self.pending.is_empty() && self.drift.is_none()
+    }
+
+    /// Like [`is_up_to_date`](Self::is_up_to_date), but reports drift as the
+    /// `IntegrityError` an upgrade would fail with.
+    pub(crate) fn check_up_to_date(self) -> Result<bool, UpgraderError> {
+        self.check_integrity()?;
+        Ok(self.pending.is_empty())
+    }
+
+    /// Returns the drift as the `IntegrityError` an upgrade would fail with.
+    pub(crate) fn check_integrity(&self) -> Result<(), UpgraderError> {
+        match &self.drift {
+            Some(drift) => Err(UpgraderError::IntegrityError(drift.message.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Compares the upgraders recorded in the database with those of `set`.
+pub(crate) fn build_status(set: &MigrationSet, applied: Vec<AppliedUpgrader>) -> MigrationStatus {
+    let drift = find_violation(&set.upgraders, &applied, &set.options)
+        .err()
+        .map(IntegrityReport::from);
+    let pending = pending_upgraders(&set.upgraders, &applied)
+        .map(MigrationInfo::from)
+        .collect();
+    let ahead = db_ahead_upgraders(&set.upgraders, &applied);
+    MigrationStatus {
+        applied,
+        pending,
+        drift,
+        ahead,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PostgresUpgraderOptions;
+    use chrono::Utc;
+
+    fn upgrader(file_id: i32, upgrader_id: i32, text: &str) -> SchemaUpgrader {
+        SchemaUpgrader {
+            file_id,
+            upgrader_id,
+            description: format!("Step {}:{}", file_id, upgrader_id),
+            text: text.to_string(),
+            down_text: None,
+            transactional: true,
+        }
+    }
+
+    fn applied(upgrader: &SchemaUpgrader) -> AppliedUpgrader {
+        AppliedUpgrader {
+            file_id: upgrader.file_id,
+            upgrader_id: upgrader.upgrader_id,
+            description: upgrader.description.clone(),
+            text: upgrader.text.clone(),
+            applied_on: Utc::now(),
+            sequence: None,
+            applied_host: None,
+            applied_by: None,
+            last_verified_on: None,
+            checksum: None,
+        }
+    }
+
+    fn migration_set() -> MigrationSet {
+        MigrationSet {
+            upgraders: vec![
+                upgrader(0, 0, "CREATE TABLE users (id INT);"),
+                upgrader(0, 1, "ALTER TABLE users ADD COLUMN email TEXT;"),
+                upgrader(1, 0, "CREATE TABLE orders (id INT);"),
+            ]
+            .into(),
+            options: PostgresUpgraderOptions::default(),
+        }
+    }
+
+    #[test]
+    fn test_status_pending() {
+        let set = migration_set();
+        let status = build_status(&set, vec![applied(&set.upgraders[0])]);
+        assert_eq!(status.applied.len(), 1);
+        let pending: Vec<_> = status
+            .pending
+            .iter()
+            .map(|u| (u.file_id, u.upgrader_id))
+            .collect();
+        assert_eq!(pending, vec![(0, 1), (1, 0)]);
+        assert_eq!(status.pending[0].text, set.upgraders[1].text);
+        assert_eq!(
+            status.pending[0].checksum(),
+            checksum("ALTER TABLE users ADD COLUMN email TEXT;")
+        );
+        assert_eq!(status.drift, None);
+        assert!(status.ahead.is_empty());
+        assert!(!status.is_up_to_date());
+
+        let all = set.upgraders.iter().map(applied).collect();
+        assert!(build_status(&set, all).is_up_to_date());
+    }
+
+    #[test]
+    fn test_status_ahead() {
+        let set = migration_set();
+        let mut newer = upgrader(2, 0, "CREATE TABLE invoices (id INT);");
+        newer.description = "Create invoices".to_string();
+        let recorded = set.upgraders.iter().chain([&newer]).map(applied).collect();
+
+        let status = build_status(&set, recorded);
+        assert_eq!(status.ahead, vec![(2, 0)]);
+        assert_eq!(status.drift, None);
+        assert!(status.is_up_to_date());
+    }
+
+    #[test]
+    fn test_status_drift() {
+        let set = migration_set();
+        let mut changed = applied(&set.upgraders[0]);
+        changed.text = "CREATE TABLE users (id BIGINT);".to_string();
+
+        let status = build_status(&set, vec![changed]);
+        let drift = status.drift.as_ref().unwrap();
+        assert_eq!(drift.kind, "text_changed");
+        assert_eq!(drift.ids, (0, 0));
+        assert_eq!(status.pending.len(), 2);
+        assert!(!status.is_up_to_date());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_status_serialize() {
+        use chrono::TimeZone;
+
+        let set = migration_set();
+        let mut first = applied(&set.upgraders[0]);
+        first.applied_on = Utc.with_ymd_and_hms(2024, 7, 1, 12, 30, 0).unwrap();
+        let mut changed = applied(&set.upgraders[1]);
+        changed.text = "ALTER TABLE users ADD COLUMN name TEXT;".to_string();
+
+        let status = build_status(&set, vec![first.clone(), changed]);
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["applied"][0]["applied_on"], "2024-07-01T12:30:00Z");
+        assert_eq!(json["pending"][0]["description"], "Step 1:0");
+        assert_eq!(json["drift"]["kind"], "text_changed");
+        assert_eq!(json["drift"]["ids"], serde_json::json!([0, 1]));
+        assert_eq!(json["ahead"], serde_json::json!([]));
+
+        let round_trip: AppliedUpgrader =
+            serde_json::from_value(json["applied"][0].clone()).unwrap();
+        assert_eq!(round_trip, first);
+    }
+}
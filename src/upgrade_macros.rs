@@ -1,19 +1,156 @@
+#[cfg(feature = "tokio-postgres")]
 macro_rules! do_await {
     ($e:expr) => {
         $e.await
     };
 }
 
+#[cfg(feature = "postgres")]
 macro_rules! do_sync {
     ($e:expr) => {
         $e
     };
 }
 
+/// Calls the sync hook named `$sync_hook` of the options with `$info`, then, in async mode
+/// only, awaits the async one named `$async_hook`.
+macro_rules! run_apply_hooks {
+    ($options:ident, $sync_hook:ident, $async_hook:ident, $info:expr, do_await) => {{
+        if let Some(hook) = &$options.$sync_hook {
+            hook($info);
+        }
+        if let Some(hook) = &$options.$async_hook {
+            hook($info).await;
+        }
+    }};
+    ($options:ident, $sync_hook:ident, $async_hook:ident, $info:expr, do_sync) => {{
+        if let Some(hook) = &$options.$sync_hook {
+            hook($info);
+        }
+    }};
+}
+
+/// Renders a driver error for an `ExecutionError`, including the server's message and
+/// SQLSTATE, shortened to the configured `max_error_chars`.
+macro_rules! describe_db_error {
+    ($error:ident, $options:ident) => {{
+        let message = match $error.as_db_error() {
+            Some(db_error) => format!("{}: {}", $error, db_error),
+            None => $error.to_string(),
+        };
+        crate::error::truncate_error(
+            &message,
+            $error.code().map(|code| code.code()),
+            $options.max_error_chars(),
+        )
+    }};
+}
+
+/// Wraps `$message` in `TimeoutError` if the driver error `$error` is PostgreSQL cancelling a
+/// statement for `lock_timeout` or `statement_timeout`, and in `ExecutionError` otherwise.
+macro_rules! execution_error {
+    ($error:ident, $message:expr) => {{
+        let message = $message;
+        match $error.as_db_error() {
+            Some(db_error)
+                if matches!(db_error.code().code(), "55P03" | "57014")
+                    && db_error.message().contains("timeout") =>
+            {
+                UpgraderError::TimeoutError(message)
+            }
+            _ => UpgraderError::ExecutionError(message),
+        }
+    }};
+}
+
+/// Wraps the driver error `$error` of executing the SQL of `$upgrader`, or of its 1-based
+/// `$statement`, in `ExecutionFailed`, or in `TimeoutError` if a timeout cancelled it.
+macro_rules! upgrader_error {
+    ($error:ident, $upgrader:expr, $options:ident) => {
+        upgrader_error!($error, $upgrader, $options, None)
+    };
+    ($error:ident, $upgrader:expr, $options:ident, $statement:expr) => {{
+        let upgrader = $upgrader;
+        let source = describe_db_error!($error, $options);
+        match execution_error!(
+            $error,
+            format!(
+                "Failed to execute upgrader {}: {}",
+                upgrader.upgrader_id, source
+            )
+        ) {
+            UpgraderError::ExecutionError(_) => UpgraderError::ExecutionFailed {
+                file_id: upgrader.file_id,
+                upgrader_id: upgrader.upgrader_id,
+                statement: $statement,
+                source,
+            },
+            timeout => timeout,
+        }
+    }};
+}
+
+/// Executes the SQL `$sql` of `$upgrader` in `$transaction`: as one batch, or with
+/// `debug_statements` one statement at a time, so that a failure names its statement.
+macro_rules! execute_upgrader_sql {
+    ($transaction:ident, $sql:expr, $upgrader:expr, $options:ident, $await_runner:ident) => {{
+        let sql: &str = $sql;
+        if $options.debug_statements {
+            let mut executed = Ok(());
+            for (index, statement) in crate::statements::split_statements(sql)
+                .into_iter()
+                .enumerate()
+            {
+                if let Err(e) = $await_runner!($transaction.batch_execute(statement)) {
+                    executed = Err(upgrader_error!(e, $upgrader, $options, Some(index + 1)));
+                    break;
+                }
+            }
+            executed
+        } else {
+            $await_runner!($transaction.batch_execute(sql))
+                .map_err(|e| upgrader_error!(e, $upgrader, $options))
+        }
+    }};
+}
+
+/// Evaluates the connection attempt `$connect`, a `Result<Result<Client, Error>, UpgraderError>`,
+/// again after `connect_retry_delay` while it fails to connect for a reason that may pass,
+/// such as a server that is not accepting connections yet, up to `connect_retries` times.
+macro_rules! retry_connect {
+    ($connect:expr, $options:expr, $sleep:path, $await_runner:ident) => {{
+        let mut attempt = 0;
+        loop {
+            match $await_runner!($connect)? {
+                Ok(client) => break Ok(client),
+                Err(e)
+                    if attempt < $options.connect_retries
+                        && crate::error::is_transient_connect_error(
+                            e.as_db_error().map(|db| db.code().code()),
+                        ) =>
+                {
+                    attempt += 1;
+                    trace_event!(
+                        warn,
+                        attempt,
+                        error = %e,
+                        "Failed to connect to the database; retrying"
+                    );
+                    $await_runner!($sleep($options.connect_retry_delay()));
+                }
+                Err(e) => break Err(UpgraderError::ConnectionError(e.to_string())),
+            }
+        }
+    }};
+}
+
 macro_rules! impl_create_schema_if_needed {
-    ($client:ident, $schema:ident, $await_runner:ident) => {{
-        if let Some(schema_name) = $schema {
-            let sql = format!("CREATE SCHEMA IF NOT EXISTS \"{0}\";", schema_name);
+    ($client:ident, $options:ident, $await_runner:ident) => {{
+        for schema_name in $options.schemas_to_create() {
+            let sql = format!(
+                "CREATE SCHEMA IF NOT EXISTS {0};",
+                $options.identifier(schema_name)
+            );
             $await_runner!($client.execute(&sql, &[])).map_err(|e| {
                 UpgraderError::ExecutionError(format!("Failed to create schema: {:?}", e))
             })?;
@@ -23,37 +160,138 @@ macro_rules! impl_create_schema_if_needed {
 }
 
 macro_rules! impl_init_upgraders_table {
-    ($client:ident, $schema:ident, $await_runner:ident) => {
+    ($client:ident, $options:ident, $await_runner:ident, $sleep:path) => {
         {
             #[allow(unused_mut)]
             let mut transaction = $await_runner!($client.transaction()).map_err(|e| {
                 UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
             })?;
 
-            $await_runner!(transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&crate::db_tracker::ADVISORY_LOCK_ID]))
-                .map_err(|e| {
-                    UpgraderError::ExecutionError(format!("Failed to acquire advisory lock: {:?}", e))
+            if let Some(timeouts) = $options.timeout_settings("LOCAL") {
+                $await_runner!(transaction.batch_execute(&timeouts)).map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to set timeouts: {}", e))
                 })?;
+            }
+            impl_acquire_xact_lock!(transaction, $options, $options.advisory_lock_id(), "advisory lock", $await_runner, $sleep);
+            trace_event!(debug, lock_id = $options.advisory_lock_id(), "Acquired advisory lock");
 
-            let table = crate::db_tracker::table_name($schema);
-            let create_sql = format!(
-                r#" 
-                CREATE TABLE IF NOT EXISTS {} (
-                    file_id INT,
-                    upgrader_id INT,
-                    description VARCHAR(500),
-                    text TEXT,
-                    applied_on TIMESTAMPTZ,
-                    PRIMARY KEY (file_id, upgrader_id)
-                );
-            "#,
-                table
-            );
+            let table = crate::db_tracker::table_name($options);
+            let create_sql = crate::db_tracker::create_table_sql(&table);
 
+            let row = $await_runner!(transaction.query_one("SELECT to_regclass($1) IS NULL", &[&table]))
+                .map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to check table: {:?}", e))
+                })?;
+            let created: bool = row.get(0);
             $await_runner!(transaction.execute(&create_sql, &[])).map_err(|e| {
                 UpgraderError::ExecutionError(format!("Failed to create upgraders table: {:?}", e))
             })?;
 
+            // Bring tables created by older versions up to date. Columns are checked first so
+            // that an up to date table is never ALTERed, which would conflict with the lock
+            // held by a running upgrade.
+            let checksum_column: &[(&str, &str)] = if $options.store_checksums {
+                &[crate::db_tracker::CHECKSUM_COLUMN]
+            } else {
+                &[]
+            };
+            for (column, column_type) in crate::db_tracker::ADDED_COLUMNS.iter().chain(checksum_column) {
+                let row = $await_runner!(transaction.query_one(
+                    "SELECT EXISTS (SELECT 1 FROM pg_attribute WHERE attrelid = to_regclass($1) AND attname = $2 AND NOT attisdropped)",
+                    &[&table, column],
+                ))
+                .map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to inspect upgraders table: {:?}", e))
+                })?;
+
+                if !row.get::<_, bool>(0) {
+                    let alter_sql = format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, column_type);
+                    $await_runner!(transaction.execute(&alter_sql, &[])).map_err(|e| {
+                        UpgraderError::ExecutionError(format!("Failed to add column {} to upgraders table: {:?}", column, e))
+                    })?;
+                }
+            }
+
+            // Like the columns, the comment is compared first: COMMENT ON takes a lock that
+            // conflicts with the one held by a running upgrade.
+            let comment = $options.table_comment();
+            let row = $await_runner!(transaction.query_one(
+                "SELECT obj_description(to_regclass($1), 'pg_class') IS DISTINCT FROM $2",
+                &[&table, &comment],
+            ))
+            .map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to inspect upgraders table: {:?}", e))
+            })?;
+            if row.get::<_, bool>(0) {
+                let comment_sql = format!(
+                    "COMMENT ON TABLE {} IS '{}';",
+                    table,
+                    comment.replace('\'', "''")
+                );
+                $await_runner!(transaction.execute(&comment_sql, &[])).map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to comment upgraders table: {:?}", e))
+                })?;
+            }
+
+            // Number upgraders recorded without a sequence, continuing after the highest one.
+            let missing_sql = format!("SELECT EXISTS (SELECT 1 FROM {} WHERE sequence IS NULL)", table);
+            let row = $await_runner!(transaction.query_one(&missing_sql, &[])).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to inspect upgraders table: {:?}", e))
+            })?;
+            if row.get::<_, bool>(0) {
+                let backfill_sql = crate::db_tracker::backfill_sequence_sql(&table);
+                $await_runner!(transaction.execute(&backfill_sql, &[])).map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to backfill upgrader sequence: {:?}", e))
+                })?;
+            }
+
+            // Only a table created here is handed over; an existing one keeps its owner.
+            if created && let Some(owner_sql) = crate::db_tracker::change_owner_sql(&table, $options) {
+                $await_runner!(transaction.execute(&owner_sql, &[])).map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to change owner of upgraders table: {:?}", e))
+                })?;
+            }
+
+            if $options.audit_integrity_failures {
+                let audit_table = crate::db_tracker::audit_table_name($options);
+                let create_audit_sql = format!(
+                    r#"
+                    CREATE TABLE IF NOT EXISTS {} (
+                        id BIGSERIAL PRIMARY KEY,
+                        violation_kind VARCHAR(50),
+                        file_id INT,
+                        upgrader_id INT,
+                        message TEXT,
+                        host TEXT,
+                        recorded_on TIMESTAMPTZ
+                    );
+                "#,
+                    audit_table
+                );
+
+                $await_runner!(transaction.execute(&create_audit_sql, &[])).map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to create audit table: {:?}", e))
+                })?;
+            }
+
+            if $options.deploy_key.is_some() {
+                let deploys_table = crate::db_tracker::deploys_table_name($options);
+                let create_deploys_sql = format!(
+                    r#"
+                    CREATE TABLE IF NOT EXISTS {} (
+                        deploy_key TEXT PRIMARY KEY,
+                        host TEXT,
+                        completed_on TIMESTAMPTZ
+                    );
+                "#,
+                    deploys_table
+                );
+
+                $await_runner!(transaction.execute(&create_deploys_sql, &[])).map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to create deploys table: {:?}", e))
+                })?;
+            }
+
             $await_runner!(transaction.commit()).map_err(|e| {
                 UpgraderError::ExecutionError(format!("Failed to commit transaction: {:?}", e))
             })?;
@@ -63,68 +301,478 @@ macro_rules! impl_init_upgraders_table {
     }
 }
 
+/// Takes the transaction-level advisory lock `$lock_id`, called `$lock` in errors. With
+/// `lock_wait_timeout` the lock is polled, and `UpgraderError::LockTimeout` returned once the
+/// timeout elapses; without it the wait is unbounded.
+macro_rules! impl_acquire_xact_lock {
+    ($transaction:ident, $options:ident, $lock_id:expr, $lock:expr, $await_runner:ident, $sleep:path) => {{
+        let lock_id: i64 = $lock_id;
+        match $options.lock_wait_timeout {
+            None => {
+                $await_runner!(
+                    $transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&lock_id])
+                )
+                .map_err(|e| {
+                    execution_error!(e, format!("Failed to acquire {}: {:?}", $lock, e))
+                })?;
+            }
+            Some(timeout) => {
+                let deadline = std::time::Instant::now() + timeout;
+                loop {
+                    let row = $await_runner!(
+                        $transaction.query_one("SELECT pg_try_advisory_xact_lock($1)", &[&lock_id])
+                    )
+                    .map_err(|e| {
+                        execution_error!(e, format!("Failed to acquire {}: {:?}", $lock, e))
+                    })?;
+                    if row.get::<_, bool>(0) {
+                        break;
+                    }
+                    let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+                    if remaining.is_zero() {
+                        return Err(UpgraderError::LockTimeout(format!(
+                            "The {} {} was not acquired within {:?}",
+                            $lock, lock_id, timeout
+                        )));
+                    }
+                    $await_runner!($sleep(
+                        remaining.min(crate::db_tracker::LOCK_WAIT_POLL_INTERVAL)
+                    ));
+                }
+            }
+        }
+    }};
+}
+
 macro_rules! impl_lock_upgraders_table {
-    ($transaction:ident, $schema:ident, $await_runner:ident) => {{
-        let table = crate::db_tracker::table_name($schema);
-        let lock_sql = format!("LOCK TABLE {} IN EXCLUSIVE MODE;", table);
+    ($transaction:ident, $options:ident, $await_runner:ident, $sleep:path) => {{
+        // Set first, so that they also cover the SQL run later in the same transaction.
+        if let Some(timeouts) = $options.timeout_settings("LOCAL") {
+            $await_runner!($transaction.batch_execute(&timeouts)).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to set timeouts: {}", e))
+            })?;
+        }
+        if let Some(search_path) = $options.search_path_setting("LOCAL") {
+            $await_runner!($transaction.batch_execute(&search_path)).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to set search_path: {}", e))
+            })?;
+        }
 
-        $await_runner!($transaction.execute(&lock_sql, &[])).map_err(|e| {
-            UpgraderError::ExecutionError(format!("Failed to lock upgraders table: {:?}", e))
-        })?;
+        let lock_id = crate::db_tracker::upgrade_lock_id($options);
+        impl_acquire_xact_lock!(
+            $transaction,
+            $options,
+            lock_id,
+            "upgrade lock",
+            $await_runner,
+            $sleep
+        );
+        trace_event!(debug, lock_id, "Acquired upgrade lock");
         Ok(())
     }};
 }
 
 macro_rules! impl_load_applied_upgraders {
-    ($client:ident, $schema:ident, $await_runner:ident) => {
-        {
-            let table = crate::db_tracker::table_name($schema);
-            let select_sql = format!(
-                "SELECT file_id, upgrader_id, description, text, applied_on FROM {} ORDER BY file_id, upgrader_id;",
-                table
+    ($client:ident, $options:ident, $await_runner:ident, $with_text:expr) => {{
+        let table = crate::db_tracker::table_name($options);
+        // Select every column so that tables created by older versions, which lack
+        // newer columns, can still be read without being altered first.
+        let select_sql = if $options.compare_checksums && !$with_text {
+            let rows = $await_runner!($client.query(crate::db_tracker::COLUMNS_SQL, &[&table]))
+                .map_err(|e| {
+                    UpgraderError::ExecutionError(format!(
+                        "Failed to inspect upgraders table: {:?}",
+                        e
+                    ))
+                })?;
+            let columns: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+            crate::db_tracker::checksum_select_sql(&table, &columns)
+        } else {
+            format!("SELECT * FROM {} ORDER BY file_id, upgrader_id;", table)
+        };
+
+        let rows = $await_runner!($client.query(&select_sql, &[])).map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to load applied upgraders: {:?}", e))
+        })?;
+
+        let mut applied = Vec::new();
+        for row in rows {
+            applied.push(crate::db_tracker::AppliedUpgrader {
+                file_id: row.get("file_id"),
+                upgrader_id: row.get("upgrader_id"),
+                description: row.get("description"),
+                // Not stored for upgraders recorded with store_checksums, and not read with
+                // compare_checksums.
+                text: row
+                    .try_get::<_, Option<String>>("text")
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default(),
+                applied_on: row.get("applied_on"),
+                sequence: row.try_get("sequence").ok().flatten(),
+                applied_host: row.try_get("applied_host").ok().flatten(),
+                applied_by: row.try_get("applied_by").ok().flatten(),
+                last_verified_on: row.try_get("last_verified_on").ok().flatten(),
+                checksum: row.try_get("checksum").ok().flatten(),
+            });
+        }
+        Ok(applied)
+    }};
+}
+
+macro_rules! impl_record_upgrader {
+    ($client:ident, $options:ident, $upgrader:ident, $await_runner:ident) => {{
+        let insert_sql = crate::db_tracker::record_upgrader_sql($options);
+        let content = crate::db_tracker::recorded_content($options, $upgrader);
+
+        $await_runner!($client.execute(
+            &insert_sql,
+            &[
+                &$upgrader.file_id,
+                &$upgrader.upgrader_id,
+                &$upgrader.description,
+                &content,
+                &crate::db_tracker::host_name(),
+                &$options.applied_by,
+                &crate::db_tracker::applied_on($options),
+            ],
+        ))
+        .map_err(|e| {
+            UpgraderError::ExecutionError(format!(
+                "Failed to record upgrader {}: {:?}",
+                $upgrader.upgrader_id, e
+            ))
+        })?;
+        Ok(())
+    }};
+}
+
+macro_rules! impl_delete_upgrader {
+    ($client:ident, $options:ident, $upgrader:ident, $await_runner:ident) => {{
+        let table = crate::db_tracker::table_name($options);
+        let delete_sql = format!(
+            "DELETE FROM {} WHERE file_id = $1 AND upgrader_id = $2;",
+            table
+        );
+
+        $await_runner!($client.execute(&delete_sql, &[&$upgrader.file_id, &$upgrader.upgrader_id]))
+            .map_err(|e| {
+                UpgraderError::ExecutionError(format!(
+                    "Failed to delete upgrader {}: {:?}",
+                    $upgrader.upgrader_id, e
+                ))
+            })?;
+        Ok(())
+    }};
+}
+
+macro_rules! impl_repair_upgrader {
+    ($client:ident, $options:ident, $applied:ident, $await_runner:ident) => {{
+        let table = crate::db_tracker::table_name($options);
+        // Rows recorded with a checksum keep one, and their text column stays NULL.
+        let (content_column, content) = match &$applied.checksum {
+            Some(checksum) => ("checksum", checksum),
+            None => ("text", &$applied.text),
+        };
+        let update_sql = format!(
+            "UPDATE {} SET description = $3, {} = $4 WHERE file_id = $1 AND upgrader_id = $2;",
+            table, content_column
+        );
+
+        $await_runner!($client.execute(
+            &update_sql,
+            &[
+                &$applied.file_id,
+                &$applied.upgrader_id,
+                &$applied.description,
+                content,
+            ],
+        ))
+        .map_err(|e| {
+            UpgraderError::ExecutionError(format!(
+                "Failed to repair upgrader {}: {:?}",
+                $applied.upgrader_id, e
+            ))
+        })?;
+        Ok(())
+    }};
+}
+
+macro_rules! impl_apply_without_transaction {
+    ($client:ident, $options:ident, $upgraders:ident, $upgrader:ident, $await_runner:ident, $sleep:path) => {{
+        let lock_id = crate::db_tracker::no_transaction_lock_id($options);
+        let deadline = $options
+            .lock_wait_timeout
+            .map(|timeout| (std::time::Instant::now() + timeout, timeout));
+        // Polled rather than waited for: a session blocked on the lock would hold a snapshot,
+        // which CREATE INDEX CONCURRENTLY in the session holding it waits for.
+        loop {
+            let row =
+                $await_runner!($client.query_one("SELECT pg_try_advisory_lock($1)", &[&lock_id]))
+                    .map_err(|e| {
+                    execution_error!(e, format!("Failed to acquire advisory lock: {:?}", e))
+                })?;
+            if row.get::<_, bool>(0) {
+                break;
+            }
+            let mut poll_interval = crate::db_tracker::NO_TRANSACTION_POLL_INTERVAL;
+            if let Some((deadline, timeout)) = deadline {
+                let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+                if remaining.is_zero() {
+                    return Err(UpgraderError::LockTimeout(format!(
+                        "The advisory lock {} for upgrader {}:{} was not acquired within {:?}",
+                        lock_id, $upgrader.file_id, $upgrader.upgrader_id, timeout
+                    )));
+                }
+                poll_interval = poll_interval.min(remaining);
+            }
+            $await_runner!($sleep(poll_interval));
+        }
+        trace_event!(debug, lock_id, "Acquired session advisory lock");
+
+        let result = $await_runner!(run_without_transaction(
+            $client, $options, $upgraders, $upgrader
+        ));
+        let unlocked =
+            $await_runner!($client.execute("SELECT pg_advisory_unlock($1)", &[&lock_id]));
+        let applied = result?;
+        unlocked.map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to release advisory lock: {:?}", e))
+        })?;
+        Ok(applied)
+    }};
+}
+
+macro_rules! impl_run_without_transaction {
+    ($client:ident, $options:ident, $upgraders:ident, $upgrader:ident, $await_runner:ident) => {{
+        use crate::integrity::{check_before_apply, pending_upgraders};
+
+        // Another upgrade may have applied the upgrader before the lock was taken.
+        let mut transaction = $await_runner!($client.transaction()).map_err(|e| {
+            UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
+        })?;
+        $await_runner!(lock_upgraders_table(&mut transaction, $options))?;
+        let applied_upgraders = $await_runner!(load_applied_upgraders(
+            Executor::from(&mut transaction),
+            $options
+        ))?;
+        check_before_apply($upgraders, &applied_upgraders, $options)?;
+        let still_pending = pending_upgraders($upgraders, &applied_upgraders)
+            .next()
+            .is_some_and(|u| (u.file_id, u.upgrader_id) == ($upgrader.file_id, $upgrader.upgrader_id));
+        let in_progress_table = crate::db_tracker::in_progress_table_name($options);
+        if still_pending {
+            let create_sql = format!(
+                "CREATE TABLE IF NOT EXISTS {} (file_id INT, upgrader_id INT, applied_host TEXT, started_on TIMESTAMPTZ, PRIMARY KEY (file_id, upgrader_id));",
+                in_progress_table
             );
+            $await_runner!(transaction.batch_execute(&create_sql)).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to create in-progress table: {}", e))
+            })?;
+        }
+        $await_runner!(transaction.commit()).map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e))
+        })?;
+        if !still_pending {
+            return Ok(false);
+        }
 
-            let rows = $await_runner!($client.query(&select_sql, &[])).map_err(|e| {
-                UpgraderError::ExecutionError(format!("Failed to load applied upgraders: {:?}", e))
+        crate::maintenance::check_window($options, $upgrader, &$upgrader.text)?;
+        let sql = $options.apply_substitutions(&$upgrader.text);
+        let info = crate::status::MigrationInfo::from($upgrader);
+        trace_event!(
+            info,
+            file_id = $upgrader.file_id,
+            upgrader_id = $upgrader.upgrader_id,
+            description = %$upgrader.description,
+            "Applying upgrader {}:{} without a transaction",
+            $upgrader.file_id,
+            $upgrader.upgrader_id
+        );
+        run_apply_hooks!($options, on_before_apply, on_before_apply_async, &info, $await_runner);
+
+        // Marked before the SQL runs, under the session lock, and as the connecting user. A
+        // mark left behind means an earlier upgrade was interrupted between running the SQL
+        // and recording it.
+        let inserted = $await_runner!($client.execute(
+            &format!(
+                "INSERT INTO {} (file_id, upgrader_id, applied_host, started_on) VALUES ($1, $2, $3, now()) ON CONFLICT (file_id, upgrader_id) DO NOTHING;",
+                in_progress_table
+            ),
+            &[&$upgrader.file_id, &$upgrader.upgrader_id, &crate::db_tracker::host_name()],
+        ))
+        .map_err(|e| {
+            UpgraderError::ExecutionError(format!(
+                "Failed to mark upgrader {}:{} in progress: {}",
+                $upgrader.file_id, $upgrader.upgrader_id, e
+            ))
+        })?;
+        let interrupted = inserted == 0;
+
+        let migration_role = $options
+            .migration_role()
+            .map(|role| $options.identifier(&role));
+        if let Some(role) = &migration_role {
+            $await_runner!($client.batch_execute(&format!("SET ROLE {};", role))).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to set role {}: {}", role, e))
+            })?;
+        }
+        let timeouts = $options.timeout_settings("SESSION");
+        if let Some(timeouts) = &timeouts {
+            $await_runner!($client.batch_execute(timeouts)).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to set timeouts: {}", e))
             })?;
+        }
+        let search_path = $options.search_path_setting("SESSION");
+        if let Some(search_path) = &search_path {
+            $await_runner!($client.batch_execute(search_path)).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to set search_path: {}", e))
+            })?;
+        }
 
-            let mut applied = Vec::new();
-            for row in rows {
-                applied.push(crate::db_tracker::AppliedUpgrader {
-                    file_id: row.get("file_id"),
-                    upgrader_id: row.get("upgrader_id"),
-                    description: row.get("description"),
-                    text: row.get("text"),
-                    applied_on: row.get("applied_on"),
-                });
+        let mut executed = $await_runner!($client.batch_execute(&sql));
+
+        // Reset even after a failure, as the settings outlive the statement.
+        if timeouts.is_some() {
+            $await_runner!($client.batch_execute("RESET lock_timeout; RESET statement_timeout;"))
+                .map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to reset timeouts: {}", e))
+                })?;
+        }
+        if search_path.is_some() {
+            $await_runner!($client.batch_execute("RESET search_path;")).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to reset search_path: {}", e))
+            })?;
+        }
+        if migration_role.is_some() {
+            $await_runner!($client.batch_execute("RESET ROLE;")).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to reset role: {}", e))
+            })?;
+        }
+        // Running the SQL of an interrupted upgrader again fails if it already created its
+        // objects. Only a single statement is then known to have run in full, so only such
+        // an upgrader is recorded as applied, unless building an index concurrently was
+        // interrupted and left an invalid index in the upgrader's schema behind.
+        if interrupted
+            && let Err(e) = &executed
+            && e.code().is_some_and(|code| crate::db_tracker::is_duplicate_object(code.code()))
+        {
+            let statements = crate::statements::split_statements(&sql).len();
+            if statements > 1 {
+                return Err(UpgraderError::ExecutionError(format!(
+                    "Upgrader {}:{} was interrupted in an earlier upgrade, and some of its {} statements may not have run; drop the objects it created and upgrade again to run it from the start: {}",
+                    $upgrader.file_id,
+                    $upgrader.upgrader_id,
+                    statements,
+                    describe_db_error!(e, $options)
+                )));
             }
-            Ok(applied)
+            let row = $await_runner!($client.query_one(
+                "SELECT EXISTS (SELECT 1 FROM pg_index i JOIN pg_class c ON c.oid = i.indexrelid JOIN pg_namespace n ON n.oid = c.relnamespace WHERE NOT i.indisvalid AND n.nspname = COALESCE($1::TEXT, current_schema()))",
+                &[&$options.schema]
+            ))
+            .map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to check for invalid indexes: {}", e))
+            })?;
+            if row.get::<_, bool>(0) {
+                return Err(UpgraderError::ExecutionError(format!(
+                    "Upgrader {}:{} was interrupted in an earlier upgrade and left an invalid index; drop it and upgrade again: {}",
+                    $upgrader.file_id, $upgrader.upgrader_id, describe_db_error!(e, $options)
+                )));
+            }
+            trace_event!(
+                warn,
+                file_id = $upgrader.file_id,
+                upgrader_id = $upgrader.upgrader_id,
+                "Recording upgrader {}:{}, applied by an interrupted upgrade",
+                $upgrader.file_id,
+                $upgrader.upgrader_id
+            );
+            executed = Ok(());
         }
-    }
+        let delete_mark_sql = format!(
+            "DELETE FROM {} WHERE file_id = $1 AND upgrader_id = $2;",
+            in_progress_table
+        );
+        if executed.is_err() {
+            // Best effort: the SQL failed rather than being interrupted.
+            let _ = $await_runner!($client.execute(
+                &delete_mark_sql,
+                &[&$upgrader.file_id, &$upgrader.upgrader_id]
+            ));
+        }
+        executed.map_err(|e| upgrader_error!(e, $upgrader, $options))?;
+
+        // The SQL is already committed, so a failure from here on leaves it applied but not
+        // recorded, and the next upgrade recognizes it by the mark.
+        let mut transaction = $await_runner!($client.transaction()).map_err(|e| {
+            UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
+        })?;
+        $await_runner!(lock_upgraders_table(&mut transaction, $options))?;
+        $await_runner!(record_upgrader(
+            Executor::from(&mut transaction),
+            $options,
+            $upgrader
+        ))?;
+        $await_runner!(transaction.execute(
+            &delete_mark_sql,
+            &[&$upgrader.file_id, &$upgrader.upgrader_id]
+        ))
+        .map_err(|e| {
+            UpgraderError::ExecutionError(format!(
+                "Failed to clear the in-progress mark of upgrader {}:{}: {}",
+                $upgrader.file_id, $upgrader.upgrader_id, e
+            ))
+        })?;
+        $await_runner!(transaction.commit()).map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e))
+        })?;
+        run_apply_hooks!($options, on_after_apply, on_after_apply_async, &info, $await_runner);
+        Ok(true)
+    }};
 }
 
-macro_rules! impl_record_upgrader {
-    ($client:ident, $schema:ident, $upgrader:ident, $await_runner:ident) => {
+macro_rules! impl_record_verification {
+    ($client:ident, $options:ident, $verified:ident, $await_runner:ident) => {{
+        let table = crate::db_tracker::table_name($options);
+        let update_sql = format!(
+            "UPDATE {} SET last_verified_on = now() WHERE (file_id, upgrader_id) IN (SELECT * FROM unnest($1::INT[], $2::INT[]));",
+            table
+        );
+        let file_ids: Vec<i32> = $verified.iter().map(|u| u.file_id).collect();
+        let upgrader_ids: Vec<i32> = $verified.iter().map(|u| u.upgrader_id).collect();
+
+        $await_runner!($client.execute(&update_sql, &[&file_ids, &upgrader_ids])).map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to record verification: {:?}", e))
+        })?;
+        Ok(())
+    }};
+}
+
+macro_rules! impl_record_integrity_failure {
+    ($client:ident, $options:ident, $violation:ident, $await_runner:ident) => {
         {
-            let table = crate::db_tracker::table_name($schema);
+            let table = crate::db_tracker::audit_table_name($options);
             let insert_sql = format!(
-                "INSERT INTO {} (file_id, upgrader_id, description, text, applied_on) VALUES ($1, $2, $3, $4, now());",
+                "INSERT INTO {} (violation_kind, file_id, upgrader_id, message, host, recorded_on) VALUES ($1, $2, $3, $4, $5, clock_timestamp());",
                 table
             );
+            let (file_id, upgrader_id) = $violation.ids();
 
             $await_runner!($client.execute(
                 &insert_sql,
                 &[
-                    &$upgrader.file_id,
-                    &$upgrader.upgrader_id,
-                    &$upgrader.description,
-                    &$upgrader.text,
+                    &$violation.kind(),
+                    &file_id,
+                    &upgrader_id,
+                    &$violation.to_string(),
+                    &crate::db_tracker::host_name(),
                 ],
             ))
             .map_err(|e| {
                 UpgraderError::ExecutionError(format!(
-                    "Failed to record upgrader {}: {:?}",
-                    $upgrader.upgrader_id, e
+                    "Failed to record integrity failure: {:?}",
+                    e
                 ))
             })?;
             Ok(())
@@ -132,80 +780,991 @@ macro_rules! impl_record_upgrader {
     }
 }
 
+macro_rules! impl_record_deploy_key {
+    ($client:ident, $options:ident, $deploy_key:ident, $await_runner:ident) => {{
+        let table = crate::db_tracker::deploys_table_name($options);
+        let insert_sql = format!(
+            "INSERT INTO {} (deploy_key, host, completed_on) VALUES ($1, $2, clock_timestamp()) ON CONFLICT (deploy_key) DO NOTHING;",
+            table
+        );
+
+        $await_runner!($client.execute(
+            &insert_sql,
+            &[&$deploy_key, &crate::db_tracker::host_name()]
+        ))
+        .map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to record deploy key: {:?}", e))
+        })?;
+        Ok(())
+    }};
+}
+
+macro_rules! impl_deploy_key_recorded {
+    ($client:ident, $options:ident, $deploy_key:ident, $await_runner:ident) => {{
+        let table = crate::db_tracker::deploys_table_name($options);
+        let select_sql = format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE deploy_key = $1)",
+            table
+        );
+
+        let row = $await_runner!($client.query_one(&select_sql, &[&$deploy_key])).map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to check deploy key: {:?}", e))
+        })?;
+        Ok(row.get(0))
+    }};
+}
+
+macro_rules! impl_current_sequence {
+    ($client:ident, $options:ident, $await_runner:ident) => {{
+        let table = crate::db_tracker::table_name($options);
+        let row =
+            $await_runner!($client.query_one("SELECT to_regclass($1) IS NOT NULL", &[&table]))
+                .map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to check table: {:?}", e))
+                })?;
+
+        if row.get::<_, bool>(0) {
+            let select_sql = format!("SELECT MAX(sequence) FROM {};", table);
+            let row = $await_runner!($client.query_one(&select_sql, &[])).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to read current sequence: {:?}", e))
+            })?;
+            Ok(row.get(0))
+        } else {
+            Ok(None)
+        }
+    }};
+}
+
+macro_rules! impl_schema_exists {
+    ($client:ident, $schema:ident, $await_runner:ident) => {{
+        let row = $await_runner!($client.query_one(
+            "SELECT EXISTS (SELECT 1 FROM pg_namespace WHERE nspname = $1)",
+            &[&$schema]
+        ))
+        .map_err(|e| UpgraderError::ExecutionError(format!("Failed to check schema: {:?}", e)))?;
+        Ok(row.get(0))
+    }};
+}
+
+/// Refreshes the planner statistics for `run_analyze_after`: `ANALYZE` of every table in the
+/// schema, or of the whole database without one.
+macro_rules! impl_analyze {
+    ($client:ident, $options:ident, $await_runner:ident) => {{
+        let sql = match &$options.schema {
+            Some(schema) => {
+                let rows = $await_runner!($client.query(
+                    "SELECT format('%I.%I', schemaname, tablename) FROM pg_tables WHERE schemaname = $1 ORDER BY tablename",
+                    &[schema]
+                ))
+                .map_err(|e| UpgraderError::ExecutionError(format!("Failed to list tables: {:?}", e)))?;
+                let tables: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+                if tables.is_empty() {
+                    return Ok(());
+                }
+                format!("ANALYZE {};", tables.join(", "))
+            }
+            None => "ANALYZE;".to_string(),
+        };
+        trace_event!(info, schema = $options.schema.as_deref(), "Analyzing tables");
+        $await_runner!($client.execute(&sql, &[]))
+            .map_err(|e| UpgraderError::ExecutionError(format!("Failed to analyze tables: {}", e)))?;
+        Ok(())
+    }};
+}
+
+macro_rules! impl_ensure_not_in_recovery {
+    ($client:ident, $await_runner:ident) => {{
+        let row = $await_runner!($client.query_one("SELECT pg_is_in_recovery()", &[])).map_err(
+            |e| UpgraderError::ExecutionError(format!("Failed to check recovery status: {:?}", e)),
+        )?;
+        if row.get::<_, bool>(0) {
+            return Err(UpgraderError::ConfigurationError(
+                "target is a read replica / in recovery; cannot run migrations. Connect to the primary instead (e.g. target_session_attrs=read-write with multiple hosts)."
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }};
+}
+
+macro_rules! impl_relation_exists {
+    ($client:ident, $relation:ident, $await_runner:ident) => {{
+        let row =
+            $await_runner!($client.query_one("SELECT to_regclass($1) IS NOT NULL", &[&$relation]))
+                .map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to check table: {:?}", e))
+                })?;
+        Ok(row.get(0))
+    }};
+}
+
 macro_rules! run_upgrade_flow {
     (
         $client:ident,
         $options:ident,
-        $upgraders_folder:ident,
+        $upgraders:expr,
         $tracker_mod:path,
-        $await_runner:ident,
-        $($tx_ref:tt)*
-    ) => {
+        $await_runner:ident
+    ) => {{
+        use crate::integrity::{check_before_apply, pending_upgraders};
+        use $tracker_mod::{
+            Executor, analyze, apply_without_transaction, create_schema_if_needed,
+            ensure_not_in_recovery, init_upgraders_table, load_applied_upgraders,
+            lock_upgraders_table, record_deploy_key, record_integrity_failure, record_upgrader,
+            record_verification, relation_exists,
+        };
+
+        let upgraders: &[crate::schema_loader::SchemaUpgrader] = $upgraders;
+
+        if $options.skip_integrity_check {
+            trace_event!(
+                warn,
+                "Integrity check is disabled; applying pending upgraders without verifying the recorded history"
+            );
+        }
+
+        if let Some(target) = $options.target
+            && !upgraders
+                .iter()
+                .any(|u| (u.file_id, u.upgrader_id) == target)
         {
-            use $tracker_mod::{init_upgraders_table, lock_upgraders_table, load_applied_upgraders, record_upgrader, create_schema_if_needed};
-            use crate::integrity::verify_integrity;
-            use crate::schema_loader::load_upgraders;
-
-            // 0. Create Schema
-            if $options.create_schema {
-                if $options.schema.is_none() {
-                    return Err(UpgraderError::ExecutionError("create_schema is enabled but no schema name is provided.".to_string()));
-                }
-                $await_runner!(create_schema_if_needed(&mut $client, $options.schema.as_deref()))?;
+            return Err(UpgraderError::ConfigurationError(format!(
+                "Upgrade target {}:{} is not one of the loaded upgraders.",
+                target.0, target.1
+            ))
+            .into());
+        }
+
+        if $options.single_transaction
+            && let Some(upgrader) = upgraders.iter().find(|u| !u.transactional)
+        {
+            return Err(UpgraderError::ConfigurationError(format!(
+                "Upgrader {}:{} is marked (no-transaction) and cannot run with single_transaction.",
+                upgrader.file_id, upgrader.upgrader_id
+            ))
+            .into());
+        }
+
+        // A hot standby rejects every write with a confusing read-only transaction error,
+        // so refuse up front, before any DDL.
+        $await_runner!(ensure_not_in_recovery(Executor::from(&mut *$client)))?;
+
+        // Dry run: check under the lock as below, but create and record nothing. Without an
+        // upgraders table, every upgrader is pending.
+        if $options.dry_run {
+            #[allow(unused_mut)]
+            let mut transaction = $await_runner!($client.transaction()).map_err(|e| {
+                UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
+            })?;
+            let table_exists = $await_runner!(relation_exists(
+                Executor::from(&mut transaction),
+                &crate::db_tracker::table_name($options)
+            ))?;
+            let applied_upgraders = if table_exists {
+                $await_runner!(lock_upgraders_table(&mut transaction, $options))?;
+                $await_runner!(load_applied_upgraders(
+                    Executor::from(&mut transaction),
+                    $options
+                ))?
+            } else {
+                Vec::new()
+            };
+            check_before_apply(upgraders, &applied_upgraders, $options)?;
+            $options.check_only_file(pending_upgraders(upgraders, &applied_upgraders))?;
+            let pending = pending_upgraders(upgraders, &applied_upgraders)
+                .filter(|u| $options.within_target(u))
+                .map(|u| (u.file_id, u.upgrader_id, u.description.clone()))
+                .collect();
+
+            $await_runner!(transaction.rollback()).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to roll back transaction: {}", e))
+            })?;
+            return Ok(crate::outcome::UpgradeReport {
+                applied: Vec::new(),
+                already_applied: applied_upgraders.len(),
+                pending,
+            });
+        }
+
+        // 0. Create Schema
+        if $options.create_schema {
+            $await_runner!(create_schema_if_needed(
+                Executor::from(&mut *$client),
+                $options
+            ))?;
+        }
+
+        // 1. Initialize Table
+        $await_runner!(init_upgraders_table($client, $options))?;
+
+        // 2. Fast path: if the database is already up to date, return without taking the
+        // advisory upgrade lock. Anything else falls through to the locked loop below, which
+        // re-reads and re-verifies the applied upgraders, so work that appears between this
+        // unlocked read and the lock is still handled correctly. Recording the verification
+        // needs the lock, so it always takes the locked path.
+        let applied_upgraders = $await_runner!(load_applied_upgraders(
+            Executor::from(&mut *$client),
+            $options
+        ))?;
+        if !$options.track_verification
+            && check_before_apply(upgraders, &applied_upgraders, $options).is_ok()
+            && !pending_upgraders(upgraders, &applied_upgraders).any(|u| $options.within_target(u))
+        {
+            if let Some(deploy_key) = &$options.deploy_key {
+                $await_runner!(record_deploy_key(
+                    Executor::from(&mut *$client),
+                    $options,
+                    deploy_key
+                ))?;
             }
+            return Ok(crate::outcome::UpgradeReport {
+                applied: Vec::new(),
+                already_applied: applied_upgraders.len(),
+                pending: Vec::new(),
+            });
+        }
 
-            // 1. Initialize Table
-            $await_runner!(init_upgraders_table(&mut $client, $options.schema.as_deref()))?;
+        let migration_role = $options
+            .migration_role()
+            .map(|role| $options.identifier(&role));
 
-            // 2. Load Upgraders from Files
-            let upgraders = load_upgraders($upgraders_folder)?;
+        // Counted from the unlocked read, so a concurrent upgrade can leave the last report
+        // short of the total.
+        let total_pending = pending_upgraders(upgraders, &applied_upgraders)
+            .filter(|u| $options.within_target(u))
+            .count();
+        let mut applied_now = Vec::new();
+        // Applied in the current transaction and awaiting its commit to run on_after_apply.
+        // With single_transaction that is every upgrader of the run.
+        let mut uncommitted = Vec::new();
+        let already_applied = 'transactions: loop {
+            let mut transaction = $await_runner!($client.transaction()).map_err(|e| {
+                UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
+            })?;
 
             loop {
-                let mut transaction = $await_runner!($client.transaction())
-                    .map_err(|e| UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e)))?;
+                $await_runner!(lock_upgraders_table(&mut transaction, $options))?;
 
-                $await_runner!(lock_upgraders_table(&mut transaction, $options.schema.as_deref()))?;
-
-                let applied_upgraders = $await_runner!(load_applied_upgraders($($tx_ref)* transaction, $options.schema.as_deref()))?;
+                let applied_upgraders = $await_runner!(load_applied_upgraders(
+                    Executor::from(&mut transaction),
+                    $options
+                ))?;
 
                 // Verify Integrity
-                verify_integrity(&upgraders, &applied_upgraders)?;
+                if let Err(violation) = check_before_apply(upgraders, &applied_upgraders, $options) {
+                    if $options.audit_integrity_failures {
+                        // Best effort: the integrity error is what the caller needs to see, so
+                        // failing to write the audit row must not replace it.
+                        if $await_runner!(record_integrity_failure(
+                            Executor::from(&mut transaction),
+                            $options,
+                            &violation
+                        ))
+                        .is_ok()
+                        {
+                            let _ = $await_runner!(transaction.commit());
+                        }
+                    }
+                    return Err(violation.into());
+                }
+                $options.check_only_file(pending_upgraders(upgraders, &applied_upgraders))?;
 
-                let upgrader_to_apply = if applied_upgraders.len() < upgraders.len() {
-                     Some(&upgraders[applied_upgraders.len()])
-                } else {
-                     None
-                };
+                // With a target, the upgraders after it stay pending
+                let upgrader_to_apply = pending_upgraders(upgraders, &applied_upgraders)
+                    .find(|u| $options.within_target(u));
 
-                if let Some(upgrader) = upgrader_to_apply {
-                    let sql = $options.apply_schema_substitution(&upgrader.text);
+                if let Some(upgrader) = upgrader_to_apply.filter(|u| !u.transactional) {
+                    // Released first: the upgrader runs outside any transaction, under a session
+                    // lock, and is re-checked under the upgrade lock once that is held.
+                    $await_runner!(transaction.rollback()).map_err(|e| {
+                        UpgraderError::ExecutionError(format!("Failed to roll back transaction: {}", e))
+                    })?;
+                    if $await_runner!(apply_without_transaction($client, $options, upgraders, upgrader))? {
+                        applied_now.push((
+                            upgrader.file_id,
+                            upgrader.upgrader_id,
+                            upgrader.description.clone(),
+                        ));
+                        report_progress!($options, applied_now.len(), total_pending);
+                    }
+                    continue 'transactions;
+                } else if let Some(upgrader) = upgrader_to_apply {
+                    crate::maintenance::check_window($options, upgrader, &upgrader.text)?;
+                    let sql = $options.apply_substitutions(&upgrader.text);
+                    let info = crate::status::MigrationInfo::from(upgrader);
+                    trace_event!(
+                        info,
+                        file_id = upgrader.file_id,
+                        upgrader_id = upgrader.upgrader_id,
+                        description = %upgrader.description,
+                        "Applying upgrader {}:{}",
+                        upgrader.file_id,
+                        upgrader.upgrader_id
+                    );
+                    run_apply_hooks!(
+                        $options,
+                        on_before_apply,
+                        on_before_apply_async,
+                        &info,
+                        $await_runner
+                    );
+
+                    if let Some(role) = &migration_role {
+                        $await_runner!(transaction.batch_execute(&format!("SET LOCAL ROLE {};", role)))
+                            .map_err(|e| {
+                                UpgraderError::ExecutionError(format!(
+                                    "Failed to set role {}: {}",
+                                    role, e
+                                ))
+                            })?;
+                    }
 
                     // Execute
-                    $await_runner!(transaction.batch_execute(&sql))
-                        .map_err(|e| UpgraderError::ExecutionError(format!("Failed to execute upgrader {}: {}", upgrader.upgrader_id, e)))?;
+                    execute_upgrader_sql!(transaction, &sql, upgrader, $options, $await_runner)?;
+
+                    // Record as the connecting user, who owns the upgraders table
+                    if migration_role.is_some() {
+                        $await_runner!(transaction.batch_execute("RESET ROLE;")).map_err(|e| {
+                            UpgraderError::ExecutionError(format!("Failed to reset role: {}", e))
+                        })?;
+                    }
 
                     // Record
-                    $await_runner!(record_upgrader($($tx_ref)* transaction, $options.schema.as_deref(), upgrader))?;
+                    $await_runner!(record_upgrader(
+                        Executor::from(&mut transaction),
+                        $options,
+                        upgrader
+                    ))?;
+                    applied_now.push((
+                        upgrader.file_id,
+                        upgrader.upgrader_id,
+                        upgrader.description.clone(),
+                    ));
+                    uncommitted.push(info);
 
-                    $await_runner!(transaction.commit())
-                        .map_err(|e| UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e)))?;
+                    // The next upgrader is applied in the same transaction, which still holds
+                    // the lock and sees the rows recorded so far.
+                    if $options.single_transaction {
+                        continue;
+                    }
+                    $await_runner!(transaction.commit()).map_err(|e| {
+                        UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e))
+                    })?;
+                    if !uncommitted.is_empty() {
+                        report_progress!($options, applied_now.len(), total_pending);
+                    }
+                    for info in uncommitted.drain(..) {
+                        run_apply_hooks!(
+                            $options,
+                            on_after_apply,
+                            on_after_apply_async,
+                            &info,
+                            $await_runner
+                        );
+                    }
+                    continue 'transactions;
                 } else {
-                    // All upgraders applied
-                    $await_runner!(transaction.commit())
-                        .map_err(|e| UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e)))?;
-                    break;
+                    // All upgraders, or all up to the target, applied
+                    if $options.track_verification {
+                        let verified: Vec<_> = applied_upgraders
+                            .iter()
+                            .filter(|db_u| {
+                                upgraders.iter().any(|u| {
+                                    (u.file_id, u.upgrader_id) == (db_u.file_id, db_u.upgrader_id)
+                                })
+                            })
+                            .collect();
+                        $await_runner!(record_verification(
+                            Executor::from(&mut transaction),
+                            $options,
+                            &verified
+                        ))?;
+                    }
+                    if let Some(deploy_key) = &$options.deploy_key {
+                        $await_runner!(record_deploy_key(
+                            Executor::from(&mut transaction),
+                            $options,
+                            deploy_key
+                        ))?;
+                    }
+                    $await_runner!(transaction.commit()).map_err(|e| {
+                        UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e))
+                    })?;
+                    if !uncommitted.is_empty() {
+                        report_progress!($options, applied_now.len(), total_pending);
+                    }
+                    for info in uncommitted.drain(..) {
+                        run_apply_hooks!(
+                            $options,
+                            on_after_apply,
+                            on_after_apply_async,
+                            &info,
+                            $await_runner
+                        );
+                    }
+                    break 'transactions applied_upgraders.len().saturating_sub(applied_now.len());
                 }
             }
-            Ok(())
+        };
+        if $options.run_analyze_after && !applied_now.is_empty() {
+            $await_runner!(analyze(Executor::from(&mut *$client), $options))?;
         }
-    }
+        Ok(crate::outcome::UpgradeReport {
+            applied: applied_now,
+            already_applied,
+            pending: Vec::new(),
+        })
+    }};
+}
+
+macro_rules! run_downgrade_flow {
+    (
+        $client:ident,
+        $options:ident,
+        $upgraders:expr,
+        $target:expr,
+        $tracker_mod:path,
+        $await_runner:ident
+    ) => {{
+        use crate::integrity::find_violation;
+        use $tracker_mod::{
+            Executor, delete_upgrader, ensure_not_in_recovery, init_upgraders_table,
+            load_applied_upgraders, lock_upgraders_table, record_integrity_failure,
+        };
+
+        let upgraders: &[crate::schema_loader::SchemaUpgrader] = $upgraders;
+        let target: (i32, i32) = $target;
+
+        if !upgraders
+            .iter()
+            .any(|u| (u.file_id, u.upgrader_id) == target)
+        {
+            return Err(UpgraderError::ConfigurationError(format!(
+                "Downgrade target {}:{} is not one of the loaded upgraders.",
+                target.0, target.1
+            )));
+        }
+
+        $await_runner!(ensure_not_in_recovery(Executor::from(&mut *$client)))?;
+        $await_runner!(init_upgraders_table($client, $options))?;
+
+        let migration_role = $options
+            .migration_role()
+            .map(|role| $options.identifier(&role));
+
+        // Same locked loop as the upgrade: one transaction per undone upgrader, each
+        // re-reading and re-verifying the applied upgraders under the lock.
+        loop {
+            let mut transaction = $await_runner!($client.transaction()).map_err(|e| {
+                UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
+            })?;
+
+            $await_runner!(lock_upgraders_table(&mut transaction, $options))?;
+
+            let applied_upgraders = $await_runner!(load_applied_upgraders(
+                Executor::from(&mut transaction),
+                $options
+            ))?;
+
+            // Verify Integrity, comparing the forward text only
+            if let Err(violation) = find_violation(upgraders, &applied_upgraders, $options) {
+                if $options.audit_integrity_failures {
+                    if $await_runner!(record_integrity_failure(
+                        Executor::from(&mut transaction),
+                        $options,
+                        &violation
+                    ))
+                    .is_ok()
+                    {
+                        let _ = $await_runner!(transaction.commit());
+                    }
+                }
+                return Err(violation.into());
+            }
+
+            // Applied upgraders past the target, latest id first. All of them are checked for
+            // a down block before the first is undone, so that a missing one never leaves the
+            // database partially downgraded.
+            let mut to_undo = Vec::new();
+            for db_u in applied_upgraders
+                .iter()
+                .rev()
+                .filter(|db_u| (db_u.file_id, db_u.upgrader_id) > target)
+            {
+                let upgrader = upgraders
+                    .iter()
+                    .find(|u| (u.file_id, u.upgrader_id) == (db_u.file_id, db_u.upgrader_id));
+                match upgrader.and_then(|u| u.down_text.as_deref().map(|down| (u, down))) {
+                    Some(step) => to_undo.push(step),
+                    None => {
+                        return Err(UpgraderError::ConfigurationError(format!(
+                            "Upgrader {}:{} ({}) has no down block; cannot downgrade to {}:{}.",
+                            db_u.file_id, db_u.upgrader_id, db_u.description, target.0, target.1
+                        )));
+                    }
+                }
+            }
+
+            let Some(&(upgrader, down_text)) = to_undo.first() else {
+                $await_runner!(transaction.commit()).map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e))
+                })?;
+                break;
+            };
+
+            crate::maintenance::check_window($options, upgrader, down_text)?;
+            let sql = $options.apply_substitutions(down_text);
+
+            if let Some(role) = &migration_role {
+                $await_runner!(transaction.batch_execute(&format!("SET LOCAL ROLE {};", role)))
+                    .map_err(|e| {
+                        UpgraderError::ExecutionError(format!("Failed to set role {}: {}", role, e))
+                    })?;
+            }
+
+            $await_runner!(transaction.batch_execute(&sql)).map_err(|e| {
+                execution_error!(
+                    e,
+                    format!(
+                        "Failed to execute down block of upgrader {}: {}",
+                        upgrader.upgrader_id,
+                        describe_db_error!(e, $options)
+                    )
+                )
+            })?;
+
+            if migration_role.is_some() {
+                $await_runner!(transaction.batch_execute("RESET ROLE;")).map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to reset role: {}", e))
+                })?;
+            }
+
+            $await_runner!(delete_upgrader(
+                Executor::from(&mut transaction),
+                $options,
+                upgrader
+            ))?;
+
+            $await_runner!(transaction.commit()).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e))
+            })?;
+        }
+        Ok(())
+    }};
+}
+
+macro_rules! run_baseline_flow {
+    (
+        $client:ident,
+        $options:ident,
+        $upgraders:expr,
+        $target:expr,
+        $tracker_mod:path,
+        $await_runner:ident
+    ) => {{
+        use crate::integrity::find_violation;
+        use $tracker_mod::{
+            Executor, create_schema_if_needed, ensure_not_in_recovery, init_upgraders_table,
+            load_applied_upgraders, lock_upgraders_table, record_upgrader,
+        };
+
+        let upgraders: &[crate::schema_loader::SchemaUpgrader] = $upgraders;
+        let target: (i32, i32) = $target;
+
+        if !upgraders
+            .iter()
+            .any(|u| (u.file_id, u.upgrader_id) == target)
+        {
+            return Err(UpgraderError::ConfigurationError(format!(
+                "Baseline target {}:{} is not one of the loaded upgraders.",
+                target.0, target.1
+            )));
+        }
+
+        $await_runner!(ensure_not_in_recovery(Executor::from(&mut *$client)))?;
+        if $options.create_schema {
+            $await_runner!(create_schema_if_needed(
+                Executor::from(&mut *$client),
+                $options
+            ))?;
+        }
+        $await_runner!(init_upgraders_table($client, $options))?;
+
+        let mut transaction = $await_runner!($client.transaction()).map_err(|e| {
+            UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
+        })?;
+        $await_runner!(lock_upgraders_table(&mut transaction, $options))?;
+
+        let applied_upgraders = $await_runner!(load_applied_upgraders(
+            Executor::from(&mut transaction),
+            $options
+        ))?;
+        if let Some(db_u) = applied_upgraders
+            .iter()
+            .find(|db_u| (db_u.file_id, db_u.upgrader_id) <= target)
+        {
+            return Err(UpgraderError::ConfigurationError(format!(
+                "Upgrader {}:{} is already recorded; cannot baseline up to {}:{}.",
+                db_u.file_id, db_u.upgrader_id, target.0, target.1
+            )));
+        }
+
+        let baselined = upgraders
+            .iter()
+            .take_while(|u| (u.file_id, u.upgrader_id) <= target);
+        for upgrader in baselined {
+            $await_runner!(record_upgrader(
+                Executor::from(&mut transaction),
+                $options,
+                upgrader
+            ))?;
+        }
+
+        // Upgraders recorded after the target would now look applied before the baselined
+        // ones; the transaction is rolled back when the history no longer checks out.
+        let applied_upgraders = $await_runner!(load_applied_upgraders(
+            Executor::from(&mut transaction),
+            $options
+        ))?;
+        find_violation(upgraders, &applied_upgraders, $options)?;
+
+        $await_runner!(transaction.commit()).map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e))
+        })?;
+        trace_event!(
+            info,
+            file_id = target.0,
+            upgrader_id = target.1,
+            "Recorded upgraders as applied without running them"
+        );
+        Ok(())
+    }};
+}
+
+macro_rules! run_repair_flow {
+    ($client:ident, $options:ident, $upgraders:expr, $tracker_mod:path, $await_runner:ident) => {{
+        use crate::integrity::repairable_upgraders;
+        use $tracker_mod::{
+            Executor, ensure_not_in_recovery, init_upgraders_table,
+            load_applied_upgraders_with_text, lock_upgraders_table, repair_upgrader,
+        };
+
+        $await_runner!(ensure_not_in_recovery(Executor::from(&mut *$client)))?;
+        $await_runner!(init_upgraders_table($client, $options))?;
+
+        let mut transaction = $await_runner!($client.transaction()).map_err(|e| {
+            UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
+        })?;
+        $await_runner!(lock_upgraders_table(&mut transaction, $options))?;
+
+        let applied_upgraders = $await_runner!(load_applied_upgraders_with_text(
+            Executor::from(&mut transaction),
+            $options
+        ))?;
+        let repaired = repairable_upgraders($upgraders, &applied_upgraders, $options)?;
+        for applied in &repaired {
+            $await_runner!(repair_upgrader(
+                Executor::from(&mut transaction),
+                $options,
+                applied
+            ))?;
+        }
+
+        $await_runner!(transaction.commit()).map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e))
+        })?;
+        trace_event!(
+            info,
+            repaired = repaired.len(),
+            "Rewrote recorded upgraders to match the files"
+        );
+        Ok(repaired.len())
+    }};
+}
+
+macro_rules! prepare_parallel_cold_start {
+    (
+        $client:ident,
+        $set:ident,
+        $tracker_mod:path,
+        $await_runner:ident
+    ) => {{
+        use $tracker_mod::{
+            Executor, create_schema_if_needed, ensure_not_in_recovery, init_upgraders_table,
+            load_applied_upgraders,
+        };
+        let options = &$set.options;
+
+        $await_runner!(ensure_not_in_recovery(Executor::from(&mut *$client)))?;
+
+        if options.create_schema {
+            $await_runner!(create_schema_if_needed(
+                Executor::from(&mut *$client),
+                options
+            ))?;
+        }
+        $await_runner!(init_upgraders_table($client, options))?;
+
+        let applied = $await_runner!(load_applied_upgraders(
+            Executor::from(&mut *$client),
+            options
+        ))?;
+        Ok(applied.is_empty())
+    }};
+}
+
+macro_rules! run_parallel_step {
+    (
+        $client:ident,
+        $options:ident,
+        $upgrader:ident,
+        $tracker_mod:path,
+        $await_runner:ident,
+        $wait_turn:expr
+    ) => {{
+        use $tracker_mod::{Executor, lock_upgraders_table, record_upgrader};
+
+        crate::maintenance::check_window($options, $upgrader, &$upgrader.text)?;
+
+        let mut transaction = $await_runner!($client.transaction()).map_err(|e| {
+            UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
+        })?;
+
+        if let Some(timeouts) = $options.timeout_settings("LOCAL") {
+            $await_runner!(transaction.batch_execute(&timeouts)).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to set timeouts: {}", e))
+            })?;
+        }
+        if let Some(search_path) = $options.search_path_setting("LOCAL") {
+            $await_runner!(transaction.batch_execute(&search_path)).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to set search_path: {}", e))
+            })?;
+        }
+
+        // A step blocked on a lock held by a later step, which is itself waiting for its
+        // turn to record, would wait forever. The timeout turns that into a failure, after
+        // which the remaining upgraders are applied sequentially. It replaces the configured
+        // lock_timeout while the SQL runs.
+        $await_runner!(transaction.batch_execute(&format!(
+            "SET LOCAL lock_timeout = '{}';",
+            crate::migration_set::PARALLEL_LOCK_TIMEOUT
+        )))
+        .map_err(|e| UpgraderError::ExecutionError(format!("Failed to set lock_timeout: {}", e)))?;
+
+        let migration_role = $options
+            .migration_role()
+            .map(|role| $options.identifier(&role));
+        if let Some(role) = &migration_role {
+            $await_runner!(transaction.batch_execute(&format!("SET LOCAL ROLE {};", role)))
+                .map_err(|e| {
+                    UpgraderError::ExecutionError(format!("Failed to set role {}: {}", role, e))
+                })?;
+        }
+
+        let sql = $options.apply_substitutions(&$upgrader.text);
+        let info = crate::status::MigrationInfo::from($upgrader);
+        trace_event!(
+            info,
+            file_id = $upgrader.file_id,
+            upgrader_id = $upgrader.upgrader_id,
+            description = %$upgrader.description,
+            "Applying upgrader {}:{} in parallel",
+            $upgrader.file_id,
+            $upgrader.upgrader_id
+        );
+        run_apply_hooks!(
+            $options,
+            on_before_apply,
+            on_before_apply_async,
+            &info,
+            $await_runner
+        );
+        execute_upgrader_sql!(transaction, &sql, $upgrader, $options, $await_runner)?;
+
+        if migration_role.is_some() {
+            $await_runner!(transaction.batch_execute("RESET ROLE;")).map_err(|e| {
+                UpgraderError::ExecutionError(format!("Failed to reset role: {}", e))
+            })?;
+        }
+
+        // Record in id order only, so that applied_on and sequence look exactly like a
+        // sequential run. If an earlier step failed, this one is rolled back as well.
+        if !$wait_turn {
+            return Err(UpgraderError::ExecutionError(format!(
+                "Upgrader {}:{} was rolled back because an earlier upgrader failed",
+                $upgrader.file_id, $upgrader.upgrader_id
+            )));
+        }
+
+        $await_runner!(lock_upgraders_table(&mut transaction, $options))?;
+        $await_runner!(record_upgrader(
+            Executor::from(&mut transaction),
+            $options,
+            $upgrader
+        ))?;
+
+        $await_runner!(transaction.commit()).map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e))
+        })?;
+        run_apply_hooks!(
+            $options,
+            on_after_apply,
+            on_after_apply_async,
+            &info,
+            $await_runner
+        );
+        Ok(())
+    }};
+}
+
+macro_rules! run_plan_flow {
+    (
+        $client:ident,
+        $set:ident,
+        $tracker_mod:path,
+        $await_runner:ident
+    ) => {{
+        use $tracker_mod::{
+            Executor, deploy_key_recorded, load_applied_upgraders, relation_exists, schema_exists,
+        };
+
+        let options = &$set.options;
+
+        // Inspect the database in a read-only transaction, without locking the upgraders
+        // table, so that planning never blocks or is blocked by a running upgrade.
+        #[allow(unused_mut)]
+        let mut transaction = $await_runner!($client.build_transaction().read_only(true).start())
+            .map_err(|e| {
+            UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
+        })?;
+
+        let mut missing_schemas = Vec::new();
+        for schema in options.schemas_to_create() {
+            if !$await_runner!(schema_exists(Executor::from(&mut transaction), schema))? {
+                missing_schemas.push(schema.to_string());
+            }
+        }
+        let upgraders_table_exists = $await_runner!(relation_exists(
+            Executor::from(&mut transaction),
+            &crate::db_tracker::table_name(options)
+        ))?;
+        let audit_table_exists = $await_runner!(relation_exists(
+            Executor::from(&mut transaction),
+            &crate::db_tracker::audit_table_name(options)
+        ))?;
+        let deploys_table_exists = $await_runner!(relation_exists(
+            Executor::from(&mut transaction),
+            &crate::db_tracker::deploys_table_name(options)
+        ))?;
+        let deploy_key_recorded = match &options.deploy_key {
+            Some(deploy_key) if deploys_table_exists => $await_runner!(deploy_key_recorded(
+                Executor::from(&mut transaction),
+                options,
+                deploy_key
+            ))?,
+            _ => false,
+        };
+        let applied = if upgraders_table_exists {
+            $await_runner!(load_applied_upgraders(
+                Executor::from(&mut transaction),
+                options
+            ))?
+        } else {
+            Vec::new()
+        };
+
+        $await_runner!(transaction.commit()).map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e))
+        })?;
+
+        crate::plan::build_plan(
+            $set,
+            &crate::plan::DatabaseState {
+                missing_schemas,
+                upgraders_table_exists,
+                audit_table_exists,
+                deploys_table_exists,
+                deploy_key_recorded,
+                applied,
+            },
+        )
+    }};
+}
+
+/// Reads the upgraders recorded in the database and compares them with the set, for
+/// `status_*`. Like planning, never writes or locks anything.
+macro_rules! run_status_flow {
+    (
+        $client:ident,
+        $set:ident,
+        $tracker_mod:path,
+        $await_runner:ident
+    ) => {{
+        use $tracker_mod::{Executor, load_applied_upgraders, relation_exists};
+
+        let options = &$set.options;
+
+        #[allow(unused_mut)]
+        let mut transaction = $await_runner!($client.build_transaction().read_only(true).start())
+            .map_err(|e| {
+            UpgraderError::ConnectionError(format!("Failed to start transaction: {}", e))
+        })?;
+
+        let upgraders_table_exists = $await_runner!(relation_exists(
+            Executor::from(&mut transaction),
+            &crate::db_tracker::table_name(options)
+        ))?;
+        let applied = if upgraders_table_exists {
+            $await_runner!(load_applied_upgraders(
+                Executor::from(&mut transaction),
+                options
+            ))?
+        } else {
+            Vec::new()
+        };
+
+        $await_runner!(transaction.commit()).map_err(|e| {
+            UpgraderError::ExecutionError(format!("Failed to commit transaction: {}", e))
+        })?;
+
+        Ok(crate::status::build_status($set, applied))
+    }};
 }
 
+pub(crate) use describe_db_error;
+#[cfg(feature = "tokio-postgres")]
 pub(crate) use do_await;
+#[cfg(feature = "postgres")]
 pub(crate) use do_sync;
+pub(crate) use execute_upgrader_sql;
+pub(crate) use execution_error;
+pub(crate) use impl_acquire_xact_lock;
+pub(crate) use impl_analyze;
+pub(crate) use impl_apply_without_transaction;
 pub(crate) use impl_create_schema_if_needed;
+pub(crate) use impl_current_sequence;
+pub(crate) use impl_delete_upgrader;
+pub(crate) use impl_deploy_key_recorded;
+pub(crate) use impl_ensure_not_in_recovery;
 pub(crate) use impl_init_upgraders_table;
 pub(crate) use impl_load_applied_upgraders;
 pub(crate) use impl_lock_upgraders_table;
+pub(crate) use impl_record_deploy_key;
+pub(crate) use impl_record_integrity_failure;
 pub(crate) use impl_record_upgrader;
+pub(crate) use impl_record_verification;
+pub(crate) use impl_relation_exists;
+pub(crate) use impl_repair_upgrader;
+pub(crate) use impl_run_without_transaction;
+pub(crate) use impl_schema_exists;
+pub(crate) use prepare_parallel_cold_start;
+pub(crate) use retry_connect;
+pub(crate) use run_apply_hooks;
+pub(crate) use run_baseline_flow;
+pub(crate) use run_downgrade_flow;
+pub(crate) use run_parallel_step;
+pub(crate) use run_plan_flow;
+pub(crate) use run_repair_flow;
+pub(crate) use run_status_flow;
 pub(crate) use run_upgrade_flow;
+pub(crate) use upgrader_error;
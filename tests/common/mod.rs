@@ -138,6 +138,27 @@ impl BlockingTestClient {
             .unwrap_or_else(|_| panic!("Table {} should exist", table_ref));
     }
 
+    pub fn count_rows(&mut self, table_ref: &str) -> i64 {
+        let sql = format!("SELECT count(*) FROM {}", table_ref);
+        self.client
+            .query_one(&sql, &[])
+            .expect("Query failed")
+            .get(0)
+    }
+
+    pub fn get_sequences(&mut self, schema: Option<&str>) -> Vec<Option<i64>> {
+        let table_ref = match schema {
+            Some(s) => format!("\"{}\".\"$upgraders$\"", s),
+            None => "\"$upgraders$\"".to_string(),
+        };
+        let sql = format!(
+            "SELECT sequence FROM {} ORDER BY file_id, upgrader_id",
+            table_ref
+        );
+        let rows = self.client.query(&sql, &[]).expect("Query failed");
+        rows.iter().map(|row| row.get("sequence")).collect()
+    }
+
     pub fn get_upgraders(&mut self, schema: Option<&str>) -> Vec<TestUpgraderRow> {
         let table_ref = match schema {
             Some(s) => format!("\"{}\".\"$upgraders$\"", s),
@@ -210,6 +231,28 @@ impl AsyncTestClient {
             .unwrap_or_else(|_| panic!("Table {} should exist", table_ref));
     }
 
+    pub async fn count_rows(&self, table_ref: &str) -> i64 {
+        let sql = format!("SELECT count(*) FROM {}", table_ref);
+        self.client
+            .query_one(&sql, &[])
+            .await
+            .expect("Query failed")
+            .get(0)
+    }
+
+    pub async fn get_sequences(&self, schema: Option<&str>) -> Vec<Option<i64>> {
+        let table_ref = match schema {
+            Some(s) => format!("\"{}\".\"$upgraders$\"", s),
+            None => "\"$upgraders$\"".to_string(),
+        };
+        let sql = format!(
+            "SELECT sequence FROM {} ORDER BY file_id, upgrader_id",
+            table_ref
+        );
+        let rows = self.client.query(&sql, &[]).await.expect("Query failed");
+        rows.iter().map(|row| row.get("sequence")).collect()
+    }
+
     pub async fn get_upgraders(&self, schema: Option<&str>) -> Vec<TestUpgraderRow> {
         let table_ref = match schema {
             Some(s) => format!("\"{}\".\"$upgraders$\"", s),
@@ -0,0 +1,3 @@
+--- 0: Create and fill items
+CREATE TABLE {{SCHEMA}}.items (id INT);
+INSERT INTO {{SCHEMA}}.items SELECT generate_series(1, 3);
@@ -0,0 +1,5 @@
+--- 0: Create authors
+CREATE TABLE authors (id INT PRIMARY KEY);
+
+--- 1: Fill authors
+INSERT INTO authors VALUES (1), (2);
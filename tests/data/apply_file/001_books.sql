@@ -0,0 +1,2 @@
+--- 0: Create books
+CREATE TABLE books (id INT PRIMARY KEY, author_id INT REFERENCES authors(id));
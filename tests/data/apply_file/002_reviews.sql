@@ -0,0 +1,2 @@
+--- 0: Create reviews
+CREATE TABLE reviews (id INT PRIMARY KEY, book_id INT REFERENCES books(id));
@@ -0,0 +1,14 @@
+--- 0: Create ledger
+CREATE TABLE ledger (id INT, note TEXT);
+INSERT INTO ledger VALUES (1, 'first; not the end');
+
+--- 1: Add a function and fail
+-- A comment; with a semicolon
+CREATE FUNCTION ledger_count() RETURNS BIGINT AS $$
+BEGIN
+    RETURN (SELECT count(*) FROM ledger);
+END;
+$$ LANGUAGE plpgsql;
+INSERT INTO ledger VALUES (ledger_count() + 1, E'it\'s; fine');
+SELECT 1 / (ledger_count() - 2);
+INSERT INTO ledger VALUES (3, 'never');
@@ -0,0 +1,11 @@
+--- 0: Create users
+CREATE TABLE users (id INT);
+
+--- 0 down:
+DROP TABLE users;
+
+--- 1: Add email
+ALTER TABLE users ADD COLUMN email TEXT;
+
+--- 1 down:
+ALTER TABLE users DROP COLUMN email;
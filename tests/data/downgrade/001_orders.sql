@@ -0,0 +1,5 @@
+--- 0: Create orders
+CREATE TABLE orders (id INT);
+
+--- 0 down:
+DROP TABLE orders;
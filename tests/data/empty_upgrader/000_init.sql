@@ -0,0 +1,7 @@
+--- 0: Create foo
+CREATE TABLE {{SCHEMA}}.foo (id INT);
+
+--- 1: Placeholder
+
+--- 2: Create bar
+CREATE TABLE {{SCHEMA}}.bar (id INT);
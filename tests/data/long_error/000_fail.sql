@@ -0,0 +1,6 @@
+--- 0: Fail with a very long error
+DO $$
+BEGIN
+    RAISE EXCEPTION 'conflicting rows: %', repeat('row, ', 10000);
+END
+$$;
@@ -0,0 +1,4 @@
+--- 0: Create items
+CREATE TABLE items (id INT);
+--- 1: Add name
+ALTER TABLE items ADD COLUMN name TEXT;
@@ -0,0 +1,12 @@
+--- 0: Create items
+CREATE TABLE items (id INT);
+CREATE TYPE item_state AS ENUM ('new');
+
+--- 1 (no-transaction): Index items concurrently
+CREATE INDEX CONCURRENTLY items_id_idx ON items (id);
+
+--- 2 (no-transaction): Add done state
+ALTER TYPE item_state ADD VALUE 'done';
+
+--- 3: Use done state
+ALTER TABLE items ADD COLUMN state item_state DEFAULT 'done';
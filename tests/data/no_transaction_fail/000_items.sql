@@ -0,0 +1,6 @@
+--- 0: Create items
+CREATE TABLE items (id INT);
+CREATE TYPE item_state AS ENUM ('new');
+
+--- 1 (no-transaction): Index items concurrently
+CREATE INDEX CONCURRENTLY items_id_idx ON missing_items (id);
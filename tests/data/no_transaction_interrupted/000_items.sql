@@ -0,0 +1,9 @@
+--- 0: Create items
+CREATE TABLE items (id INT);
+
+--- 1 (no-transaction): Index items concurrently
+CREATE INDEX CONCURRENTLY items_id_idx ON items (id);
+
+--- 2 (no-transaction): Create archive tables
+CREATE TABLE items_archive (id INT);
+CREATE TABLE items_archive_log (id INT);
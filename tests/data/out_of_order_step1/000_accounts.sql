@@ -0,0 +1,2 @@
+--- 0: Create accounts
+CREATE TABLE accounts (id INT);
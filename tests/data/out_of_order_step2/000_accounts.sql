@@ -0,0 +1,5 @@
+--- 0: Create accounts
+CREATE TABLE accounts (id INT);
+
+--- 1: Add account email
+ALTER TABLE accounts ADD COLUMN email TEXT;
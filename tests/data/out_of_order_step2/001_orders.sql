@@ -0,0 +1,2 @@
+--- 0: Create orders
+CREATE TABLE orders (id INT);
@@ -0,0 +1,3 @@
+--- 0: Create customers
+CREATE TABLE customers (id INT PRIMARY KEY);
+SELECT pg_sleep(1);
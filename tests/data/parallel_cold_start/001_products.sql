@@ -0,0 +1,3 @@
+--- 0: Create products
+CREATE TABLE products (id INT PRIMARY KEY);
+SELECT pg_sleep(1);
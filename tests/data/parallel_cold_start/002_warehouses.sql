@@ -0,0 +1,3 @@
+--- 0: Create warehouses
+CREATE TABLE warehouses (id INT PRIMARY KEY);
+SELECT pg_sleep(1);
@@ -0,0 +1,3 @@
+--- 0: Create suppliers
+CREATE TABLE suppliers (id INT PRIMARY KEY);
+SELECT pg_sleep(1);
@@ -0,0 +1,3 @@
+--- 0: Create invoices
+CREATE TABLE invoices (id INT PRIMARY KEY);
+SELECT pg_sleep(1);
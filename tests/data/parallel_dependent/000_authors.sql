@@ -0,0 +1,3 @@
+--- 0: Create authors
+CREATE TABLE {{SCHEMA}}.authors (id INT PRIMARY KEY);
+SELECT pg_sleep(1);
@@ -0,0 +1,2 @@
+--- 0: Create books
+CREATE TABLE {{SCHEMA}}.books (id INT PRIMARY KEY, author_id INT REFERENCES {{SCHEMA}}.authors (id));
@@ -0,0 +1,2 @@
+--- 0: Create shelves
+CREATE TABLE {{SCHEMA}}.shelves (id INT PRIMARY KEY);
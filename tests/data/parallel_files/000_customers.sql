@@ -0,0 +1,6 @@
+--- 0: Create customers
+CREATE TABLE customers (id INT PRIMARY KEY);
+SELECT pg_sleep(1);
+
+--- 1: Fill customers
+INSERT INTO customers VALUES (1), (2);
@@ -0,0 +1,6 @@
+--- 0: Create products
+CREATE TABLE products (id INT PRIMARY KEY);
+SELECT pg_sleep(1);
+
+--- 1: Fill products
+INSERT INTO products VALUES (1), (2);
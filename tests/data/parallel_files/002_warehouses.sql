@@ -0,0 +1,6 @@
+--- 0: Create warehouses
+CREATE TABLE warehouses (id INT PRIMARY KEY);
+SELECT pg_sleep(1);
+
+--- 1: Fill warehouses
+INSERT INTO warehouses VALUES (1), (2);
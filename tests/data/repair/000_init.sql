@@ -0,0 +1,2 @@
+--- 0: Create the foo table
+CREATE TABLE foo (id INT);
@@ -0,0 +1,2 @@
+--- 0: Create bar
+CREATE TABLE bar (id INT);
@@ -0,0 +1,4 @@
+--- 0: Create foo
+CREATE TABLE foo (id INT);
+--- 1: Create bar
+CREATE TABLE bar (id INT);
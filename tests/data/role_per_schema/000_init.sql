@@ -0,0 +1,4 @@
+--- 0: Create owned table
+CREATE TABLE {{SCHEMA}}.owned (id INT);
+--- 1: Create owned view
+CREATE VIEW {{SCHEMA}}.owned_view AS SELECT id FROM {{SCHEMA}}.owned;
@@ -0,0 +1,2 @@
+--- 0: fixed_step
+CREATE TABLE side_effect_table (id INT);
\ No newline at end of file
@@ -0,0 +1,6 @@
+--- 0: Create items
+CREATE TABLE items (id INT PRIMARY KEY);
+INSERT INTO items VALUES (1);
+
+--- 1 (no-transaction): Index items
+CREATE INDEX CONCURRENTLY IF NOT EXISTS items_id_idx ON items (id);
@@ -0,0 +1,2 @@
+--- 0: Add item
+INSERT INTO items VALUES (2);
@@ -0,0 +1,4 @@
+--- 0: Create t0a
+CREATE TABLE seq_t0a (id INT);
+--- 1: Create t0b
+CREATE TABLE seq_t0b (id INT);
@@ -0,0 +1,4 @@
+--- 0: Create t1a
+CREATE TABLE seq_t1a (id INT);
+--- 1: Create t1b
+CREATE TABLE seq_t1b (id INT);
@@ -0,0 +1,4 @@
+--- 0: Create t2a
+CREATE TABLE seq_t2a (id INT);
+--- 1: Create t2b
+CREATE TABLE seq_t2b (id INT);
@@ -0,0 +1,2 @@
+--- 0: init
+CREATE TABLE shutdown_table (id INT);
\ No newline at end of file
@@ -0,0 +1,2 @@
+--- 0: slow
+SELECT pg_sleep(60);
\ No newline at end of file
@@ -0,0 +1,5 @@
+--- 0: Create accounts
+CREATE TABLE accounts (id INT);
+
+--- 1: Create ledger
+CREATE TABLE ledger (id INT);
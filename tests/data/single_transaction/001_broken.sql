@@ -0,0 +1,2 @@
+--- 0: Broken step
+ALTER TABLE missing_table ADD COLUMN x INT;
@@ -0,0 +1,2 @@
+--- 0: Slow step
+SELECT pg_sleep(10);
@@ -0,0 +1,3 @@
+--- 0: Baseline
+CREATE TABLE foo (id INT);
+CREATE TABLE bar (id INT);
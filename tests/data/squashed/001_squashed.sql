@@ -0,0 +1,2 @@
+--- 0: Squashed into the baseline
+SELECT 1;
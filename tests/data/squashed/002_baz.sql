@@ -0,0 +1,2 @@
+--- 0: Create baz
+CREATE TABLE baz (id INT);
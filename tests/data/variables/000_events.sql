@@ -0,0 +1,3 @@
+--- 0: Create events
+CREATE TABLE {{SCHEMA}}.{{TABLE}} (id INT);
+COMMENT ON TABLE {{SCHEMA}}.{{TABLE}} IS '{{COMMENT}}';
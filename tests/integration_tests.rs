@@ -1,9 +1,22 @@
 mod common;
 
 use common::{AsyncTestClient, BlockingTestClient, PostgresContainer};
-use postgresql_schema_upgrader::{PostgresUpgraderOptions, upgrade_async, upgrade_blocking};
-use std::sync::{Arc, Barrier};
+use postgresql_schema_upgrader::{
+    ApplyHook, AsyncApplyHook, MaintenanceWindow, MigrationSet, PlanStep, PostgresUpgraderOptions,
+    ProgressHook, UpgradeOutcome, UpgraderError, apply_file_async, apply_file_blocking,
+    baseline_async, baseline_blocking, check_integrity_async, check_integrity_blocking,
+    current_sequence_async, current_sequence_blocking, downgrade_async, downgrade_blocking,
+    dry_run_async, dry_run_blocking, export_as_files_async, export_as_files_blocking,
+    init_only_async, init_only_blocking, is_up_to_date_async, is_up_to_date_blocking,
+    last_applier_async, last_applier_blocking, repair_async, repair_blocking, status_async,
+    status_blocking, try_upgrade_async, try_upgrade_blocking, upgrade_async,
+    upgrade_async_embedded, upgrade_async_pooled, upgrade_async_with_shutdown, upgrade_blocking,
+    upgrade_blocking_embedded, upgrade_with_client_async, upgrade_with_client_blocking,
+    verify_from_scratch_async,
+};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 // --- Macros ---
 
@@ -25,6 +38,172 @@ macro_rules! run_upgrade {
     };
 }
 
+macro_rules! try_upgrade {
+    (async, $folder:expr, $conn:expr, $opts:expr) => {
+        try_upgrade_async($folder, $conn, $opts).await
+    };
+    (blocking, $folder:expr, $conn:expr, $opts:expr) => {
+        try_upgrade_blocking($folder, $conn, $opts)
+    };
+}
+
+macro_rules! dry_run {
+    (async, $folder:expr, $conn:expr, $opts:expr) => {
+        dry_run_async($folder, $conn, $opts).await
+    };
+    (blocking, $folder:expr, $conn:expr, $opts:expr) => {
+        dry_run_blocking($folder, $conn, $opts)
+    };
+}
+
+macro_rules! status {
+    (async, $folder:expr, $conn:expr, $opts:expr) => {
+        status_async($folder, $conn, $opts).await
+    };
+    (blocking, $folder:expr, $conn:expr, $opts:expr) => {
+        status_blocking($folder, $conn, $opts)
+    };
+}
+
+macro_rules! apply_set {
+    (async, $set:expr, $conn:expr) => {
+        $set.apply_async($conn).await
+    };
+    (blocking, $set:expr, $conn:expr) => {
+        $set.apply_blocking($conn)
+    };
+}
+
+macro_rules! plan_set {
+    (async, $set:expr, $conn:expr) => {
+        $set.plan_async($conn).await
+    };
+    (blocking, $set:expr, $conn:expr) => {
+        $set.plan_blocking($conn)
+    };
+}
+
+macro_rules! current_sequence {
+    (async, $conn:expr, $opts:expr) => {
+        current_sequence_async($conn, $opts).await
+    };
+    (blocking, $conn:expr, $opts:expr) => {
+        current_sequence_blocking($conn, $opts)
+    };
+}
+
+macro_rules! init_only {
+    (async, $conn:expr, $opts:expr) => {
+        init_only_async($conn, $opts).await
+    };
+    (blocking, $conn:expr, $opts:expr) => {
+        init_only_blocking($conn, $opts)
+    };
+}
+
+macro_rules! is_up_to_date {
+    (async, $folder:expr, $conn:expr, $opts:expr) => {
+        is_up_to_date_async($folder, $conn, $opts).await
+    };
+    (blocking, $folder:expr, $conn:expr, $opts:expr) => {
+        is_up_to_date_blocking($folder, $conn, $opts)
+    };
+}
+
+macro_rules! check_integrity {
+    (async, $folder:expr, $conn:expr, $opts:expr) => {
+        check_integrity_async($folder, $conn, $opts).await
+    };
+    (blocking, $folder:expr, $conn:expr, $opts:expr) => {
+        check_integrity_blocking($folder, $conn, $opts)
+    };
+}
+
+macro_rules! last_applier {
+    (async, $conn:expr, $opts:expr) => {
+        last_applier_async($conn, $opts).await
+    };
+    (blocking, $conn:expr, $opts:expr) => {
+        last_applier_blocking($conn, $opts)
+    };
+}
+
+macro_rules! export_as_files {
+    (async, $conn:expr, $opts:expr, $dir:expr) => {
+        export_as_files_async($conn, $opts, $dir).await
+    };
+    (blocking, $conn:expr, $opts:expr, $dir:expr) => {
+        export_as_files_blocking($conn, $opts, $dir)
+    };
+}
+
+macro_rules! downgrade {
+    (async, $folder:expr, $conn:expr, $opts:expr, $target:expr) => {
+        downgrade_async($folder, $conn, $opts, $target).await
+    };
+    (blocking, $folder:expr, $conn:expr, $opts:expr, $target:expr) => {
+        downgrade_blocking($folder, $conn, $opts, $target)
+    };
+}
+
+macro_rules! upgrade_with_client {
+    (async, $client:expr, $folder:expr, $opts:expr) => {
+        upgrade_with_client_async($client, $folder, $opts).await
+    };
+    (blocking, $client:expr, $folder:expr, $opts:expr) => {
+        upgrade_with_client_blocking($client, $folder, $opts)
+    };
+}
+
+macro_rules! connect_raw {
+    (async, $conn:expr) => {{
+        let (client, connection) = tokio_postgres::connect($conn, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        client
+    }};
+    (blocking, $conn:expr) => {
+        postgres::Client::connect($conn, postgres::NoTls).unwrap()
+    };
+}
+
+macro_rules! baseline {
+    (async, $folder:expr, $conn:expr, $opts:expr, $up_to:expr) => {
+        baseline_async($folder, $conn, $opts, $up_to).await
+    };
+    (blocking, $folder:expr, $conn:expr, $opts:expr, $up_to:expr) => {
+        baseline_blocking($folder, $conn, $opts, $up_to)
+    };
+}
+
+macro_rules! apply_file {
+    (async, $folder:expr, $conn:expr, $opts:expr, $file_id:expr) => {
+        apply_file_async($folder, $conn, $opts, $file_id).await
+    };
+    (blocking, $folder:expr, $conn:expr, $opts:expr, $file_id:expr) => {
+        apply_file_blocking($folder, $conn, $opts, $file_id)
+    };
+}
+
+macro_rules! upgrade_embedded {
+    (async, $migrations:expr, $conn:expr, $opts:expr) => {
+        upgrade_async_embedded($migrations, $conn, $opts).await
+    };
+    (blocking, $migrations:expr, $conn:expr, $opts:expr) => {
+        upgrade_blocking_embedded($migrations, $conn, $opts)
+    };
+}
+
+macro_rules! repair {
+    (async, $folder:expr, $conn:expr, $opts:expr) => {
+        repair_async($folder, $conn, $opts).await
+    };
+    (blocking, $folder:expr, $conn:expr, $opts:expr) => {
+        repair_blocking($folder, $conn, $opts)
+    };
+}
+
 macro_rules! get_client {
     (async, $conn:expr) => {
         AsyncTestClient::connect($conn).await
@@ -40,6 +219,7 @@ macro_rules! define_test_both_modes {
             use super::*;
 
             #[test]
+            #[allow(unused_macros)]
             fn blocking() {
                 macro_rules! m_await {
                     ($e:expr) => {
@@ -51,17 +231,107 @@ macro_rules! define_test_both_modes {
                         run_upgrade!(blocking, $f, $c, $o)
                     };
                 }
+                macro_rules! m_try_upgrade {
+                    ($f:expr, $c:expr, $o:expr) => {
+                        try_upgrade!(blocking, $f, $c, $o)
+                    };
+                }
+                macro_rules! m_dry_run {
+                    ($f:expr, $c:expr, $o:expr) => {
+                        dry_run!(blocking, $f, $c, $o)
+                    };
+                }
+                macro_rules! m_status {
+                    ($f:expr, $c:expr, $o:expr) => {
+                        status!(blocking, $f, $c, $o)
+                    };
+                }
                 macro_rules! m_client {
                     ($c:expr) => {
                         get_client!(blocking, $c)
                     };
                 }
+                macro_rules! m_apply {
+                    ($s:expr, $c:expr) => {
+                        apply_set!(blocking, $s, $c)
+                    };
+                }
+                macro_rules! m_plan {
+                    ($s:expr, $c:expr) => {
+                        plan_set!(blocking, $s, $c)
+                    };
+                }
+                macro_rules! m_current_sequence {
+                    ($c:expr, $o:expr) => {
+                        current_sequence!(blocking, $c, $o)
+                    };
+                }
+                macro_rules! m_init_only {
+                    ($c:expr, $o:expr) => {
+                        init_only!(blocking, $c, $o)
+                    };
+                }
+                macro_rules! m_is_up_to_date {
+                    ($f:expr, $c:expr, $o:expr) => {
+                        is_up_to_date!(blocking, $f, $c, $o)
+                    };
+                }
+                macro_rules! m_check_integrity {
+                    ($f:expr, $c:expr, $o:expr) => {
+                        check_integrity!(blocking, $f, $c, $o)
+                    };
+                }
+                macro_rules! m_last_applier {
+                    ($c:expr, $o:expr) => {
+                        last_applier!(blocking, $c, $o)
+                    };
+                }
+                macro_rules! m_export_as_files {
+                    ($c:expr, $o:expr, $d:expr) => {
+                        export_as_files!(blocking, $c, $o, $d)
+                    };
+                }
+                macro_rules! m_downgrade {
+                    ($f:expr, $c:expr, $o:expr, $t:expr) => {
+                        downgrade!(blocking, $f, $c, $o, $t)
+                    };
+                }
+                macro_rules! m_baseline {
+                    ($f:expr, $c:expr, $o:expr, $u:expr) => {
+                        baseline!(blocking, $f, $c, $o, $u)
+                    };
+                }
+                macro_rules! m_apply_file {
+                    ($f:expr, $c:expr, $o:expr, $i:expr) => {
+                        apply_file!(blocking, $f, $c, $o, $i)
+                    };
+                }
+                macro_rules! m_upgrade_with_client {
+                    ($cl:expr, $f:expr, $o:expr) => {
+                        upgrade_with_client!(blocking, $cl, $f, $o)
+                    };
+                }
+                macro_rules! m_connect_raw {
+                    ($c:expr) => {
+                        connect_raw!(blocking, $c)
+                    };
+                }
+                macro_rules! m_repair {
+                    ($f:expr, $c:expr, $o:expr) => {
+                        repair!(blocking, $f, $c, $o)
+                    };
+                }
+                macro_rules! m_upgrade_embedded {
+                    ($m:expr, $c:expr, $o:expr) => {
+                        upgrade_embedded!(blocking, $m, $c, $o)
+                    };
+                }
 
                 $body
             }
 
             #[tokio::test]
-            #[allow(unused_mut)]
+            #[allow(unused_mut, unused_macros)]
             async fn async_mode() {
                 macro_rules! m_await {
                     ($e:expr) => {
@@ -73,11 +343,101 @@ macro_rules! define_test_both_modes {
                         run_upgrade!(async, $f, $c, $o)
                     };
                 }
+                macro_rules! m_try_upgrade {
+                    ($f:expr, $c:expr, $o:expr) => {
+                        try_upgrade!(async, $f, $c, $o)
+                    };
+                }
+                macro_rules! m_dry_run {
+                    ($f:expr, $c:expr, $o:expr) => {
+                        dry_run!(async, $f, $c, $o)
+                    };
+                }
+                macro_rules! m_status {
+                    ($f:expr, $c:expr, $o:expr) => {
+                        status!(async, $f, $c, $o)
+                    };
+                }
                 macro_rules! m_client {
                     ($c:expr) => {
                         get_client!(async, $c)
                     };
                 }
+                macro_rules! m_apply {
+                    ($s:expr, $c:expr) => {
+                        apply_set!(async, $s, $c)
+                    };
+                }
+                macro_rules! m_plan {
+                    ($s:expr, $c:expr) => {
+                        plan_set!(async, $s, $c)
+                    };
+                }
+                macro_rules! m_current_sequence {
+                    ($c:expr, $o:expr) => {
+                        current_sequence!(async, $c, $o)
+                    };
+                }
+                macro_rules! m_init_only {
+                    ($c:expr, $o:expr) => {
+                        init_only!(async, $c, $o)
+                    };
+                }
+                macro_rules! m_is_up_to_date {
+                    ($f:expr, $c:expr, $o:expr) => {
+                        is_up_to_date!(async, $f, $c, $o)
+                    };
+                }
+                macro_rules! m_check_integrity {
+                    ($f:expr, $c:expr, $o:expr) => {
+                        check_integrity!(async, $f, $c, $o)
+                    };
+                }
+                macro_rules! m_last_applier {
+                    ($c:expr, $o:expr) => {
+                        last_applier!(async, $c, $o)
+                    };
+                }
+                macro_rules! m_export_as_files {
+                    ($c:expr, $o:expr, $d:expr) => {
+                        export_as_files!(async, $c, $o, $d)
+                    };
+                }
+                macro_rules! m_downgrade {
+                    ($f:expr, $c:expr, $o:expr, $t:expr) => {
+                        downgrade!(async, $f, $c, $o, $t)
+                    };
+                }
+                macro_rules! m_baseline {
+                    ($f:expr, $c:expr, $o:expr, $u:expr) => {
+                        baseline!(async, $f, $c, $o, $u)
+                    };
+                }
+                macro_rules! m_apply_file {
+                    ($f:expr, $c:expr, $o:expr, $i:expr) => {
+                        apply_file!(async, $f, $c, $o, $i)
+                    };
+                }
+                macro_rules! m_upgrade_with_client {
+                    ($cl:expr, $f:expr, $o:expr) => {
+                        upgrade_with_client!(async, $cl, $f, $o)
+                    };
+                }
+                macro_rules! m_connect_raw {
+                    ($c:expr) => {
+                        connect_raw!(async, $c)
+                    };
+                }
+                macro_rules! m_repair {
+                    ($f:expr, $c:expr, $o:expr) => {
+                        repair!(async, $f, $c, $o)
+                    };
+                }
+                macro_rules! m_upgrade_embedded {
+                    ($m:expr, $c:expr, $o:expr) => {
+                        upgrade_embedded!(async, $m, $c, $o)
+                    };
+                }
 
                 $body
             }
@@ -92,12 +452,14 @@ define_test_both_modes!(basic_flow, {
     let options = PostgresUpgraderOptions::builder().build();
 
     // Step 1
-    m_upgrade!(
+    let report = m_upgrade!(
         "tests/data/basic_flow_step1",
         &container.connection_string,
         &options
     )
     .unwrap();
+    assert_eq!(report.applied, vec![(0, 0, "Create foo".to_string())]);
+    assert_eq!(report.already_applied, 0);
 
     let mut client = m_client!(&container.connection_string);
     m_await!(client.ensure_table_exists("foo", None));
@@ -108,109 +470,2645 @@ define_test_both_modes!(basic_flow, {
     assert_eq!(rows[0].upgrader_id, 0);
 
     // Step 2
-    m_upgrade!(
+    let report = m_upgrade!(
         "tests/data/basic_flow_step2",
         &container.connection_string,
         &options
     )
     .unwrap();
+    assert_eq!(report.applied, vec![(1, 0, "Create bar".to_string())]);
+    assert_eq!(report.already_applied, 1);
 
     let rows = m_await!(client.get_upgraders(None));
     assert_eq!(rows.len(), 2);
+
+    // Nothing left to apply
+    let report = m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert!(report.applied.is_empty());
+    assert_eq!(report.already_applied, 2);
 });
 
-define_test_both_modes!(schema_support, {
+define_test_both_modes!(is_up_to_date, {
     let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let conn = &container.connection_string;
 
-    // Create schema manually first
-    let mut client = m_client!(&container.connection_string);
-    m_await!(client.execute("CREATE SCHEMA my_schema"));
+    assert!(!m_is_up_to_date!("tests/data/basic_flow_step1", conn, &options).unwrap());
+    m_upgrade!("tests/data/basic_flow_step1", conn, &options).unwrap();
+    assert!(m_is_up_to_date!("tests/data/basic_flow_step1", conn, &options).unwrap());
+    assert!(!m_is_up_to_date!("tests/data/basic_flow_step2", conn, &options).unwrap());
+
+    // Drift fails as the upgrade would.
+    let err = m_is_up_to_date!("tests/data/schema_support", conn, &options).unwrap_err();
+    assert!(matches!(err, UpgraderError::IntegrityError(_)), "{:?}", err);
+});
+
+define_test_both_modes!(check_integrity, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let conn = &container.connection_string;
 
+    m_check_integrity!("tests/data/basic_flow_step1", conn, &options).unwrap();
+    m_upgrade!("tests/data/basic_flow_step1", conn, &options).unwrap();
+    m_check_integrity!("tests/data/basic_flow_step1", conn, &options).unwrap();
+    // Pending upgraders are not a mismatch.
+    m_check_integrity!("tests/data/basic_flow_step2", conn, &options).unwrap();
+
+    let err = m_check_integrity!("tests/data/schema_support", conn, &options).unwrap_err();
+    let expected = m_upgrade!("tests/data/schema_support", conn, &options).unwrap_err();
+    assert_eq!(err.to_string(), expected.to_string());
+
+    // The check does not honour verify_integrity(false), which only affects upgrades.
     let options = PostgresUpgraderOptions::builder()
-        .schema("my_schema")
+        .verify_integrity(false)
+        .build();
+    let err = m_check_integrity!("tests/data/schema_support", conn, &options).unwrap_err();
+    assert!(matches!(err, UpgraderError::IntegrityError(_)), "{:?}", err);
+    let mut client = m_client!(conn);
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 1);
+});
+
+define_test_both_modes!(init_only, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .schema("provisioned")
+        .create_schema(true)
         .build();
 
+    // Creating what already exists is a no-op.
+    for _ in 0..2 {
+        m_init_only!(&container.connection_string, &options).unwrap();
+    }
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.ensure_table_exists("\"$upgraders$\"", Some("provisioned")));
+    assert!(m_await!(client.get_upgraders(Some("provisioned"))).is_empty());
+
+    // The upgrade then only applies the upgraders.
+    let options = PostgresUpgraderOptions::builder()
+        .schema("provisioned")
+        .build();
     m_upgrade!(
         "tests/data/schema_support",
         &container.connection_string,
         &options
     )
     .unwrap();
-
-    m_await!(client.ensure_table_exists("foo", Some("my_schema")));
-    let rows = m_await!(client.get_upgraders(Some("my_schema")));
-    assert_eq!(rows.len(), 1);
+    assert_eq!(m_await!(client.get_upgraders(Some("provisioned"))).len(), 1);
 });
 
-define_test_both_modes!(schema_auto_create, {
+define_test_both_modes!(tracking_table_owner, {
     let container = PostgresContainer::start();
-    let schema_name = "auto_created_schema";
-
     let mut client = m_client!(&container.connection_string);
-    m_await!(client.ensure_schema_does_not_exist(schema_name));
+    m_await!(client.execute("CREATE ROLE app_owner NOLOGIN"));
 
     let options = PostgresUpgraderOptions::builder()
-        .schema(schema_name)
+        .schema("owned")
         .create_schema(true)
+        .tracking_table_owner("app_owner")
         .build();
-
     m_upgrade!(
-        "tests/data/schema_auto_create",
+        "tests/data/schema_support",
         &container.connection_string,
         &options
     )
     .unwrap();
+    let owned_by = |schema: &str| {
+        format!(
+            "pg_tables WHERE schemaname = '{}' AND tablename = '$upgraders$' AND tableowner = 'app_owner'",
+            schema
+        )
+    };
+    assert_eq!(m_await!(client.count_rows(&owned_by("owned"))), 1);
 
-    m_await!(client.ensure_schema_exists(schema_name));
-    m_await!(client.ensure_table_exists("test_table", Some(schema_name)));
+    // An existing table keeps its owner.
+    let options = PostgresUpgraderOptions::builder()
+        .schema("kept")
+        .create_schema(true)
+        .build();
+    m_init_only!(&container.connection_string, &options).unwrap();
+    let options = PostgresUpgraderOptions::builder()
+        .schema("kept")
+        .tracking_table_owner("app_owner")
+        .build();
+    m_upgrade!(
+        "tests/data/schema_support",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert_eq!(m_await!(client.count_rows(&owned_by("kept"))), 0);
 });
 
-// Concurrency tests need distinct implementations due to thread vs tokio::spawn differences.
-
-#[test]
-fn concurrency_blocking() {
+define_test_both_modes!(upgrade_with_client, {
     let container = PostgresContainer::start();
-    let folder = "tests/data/concurrency";
-    let connection_string = Arc::new(container.connection_string.clone());
-    let n_threads = 10;
-    let mut handles = vec![];
-    let barrier = Arc::new(Barrier::new(n_threads));
+    let options = PostgresUpgraderOptions::builder().build();
+    let mut raw = m_connect_raw!(&container.connection_string);
 
-    for _ in 0..n_threads {
-        let conn_str = connection_string.clone();
-        let b = barrier.clone();
-        handles.push(thread::spawn(move || {
-            b.wait();
-            let options = PostgresUpgraderOptions::builder().build();
-            upgrade_blocking(folder, &conn_str, &options)
-        }));
-    }
+    m_upgrade_with_client!(&mut raw, "tests/data/basic_flow_step1", &options).unwrap();
+    // The same client can run the next upgrade, so none was left open in a transaction.
+    m_upgrade_with_client!(&mut raw, "tests/data/basic_flow_step2", &options).unwrap();
 
-    for handle in handles {
-        handle.join().unwrap().unwrap();
-    }
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.ensure_table_exists("foo", None));
+    let rows = m_await!(client.get_upgraders(None));
+    assert_eq!(rows.len(), 2);
+});
 
-    let mut client = BlockingTestClient::connect(&container.connection_string);
+define_test_both_modes!(store_checksums, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .store_checksums(true)
+        .build();
+
+    // Recorded before checksums were enabled, so only the SQL is stored.
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &PostgresUpgraderOptions::builder().build()
+    )
+    .unwrap();
+    m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.ensure_table_exists("bar", None));
+    assert_eq!(
+        m_await!(client.count_rows(
+            r#""$upgraders$" WHERE file_id = 0 AND text IS NOT NULL AND checksum IS NULL"#
+        )),
+        1
+    );
+    assert_eq!(
+        m_await!(client.count_rows(
+            r#""$upgraders$" WHERE file_id = 1 AND text IS NULL AND length(checksum) = 64"#
+        )),
+        1
+    );
+
+    // Both kinds of rows still validate, and a changed upgrader is detected.
+    m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &PostgresUpgraderOptions::builder().build()
+    )
+    .unwrap();
+    m_await!(
+        client.execute(r#"UPDATE "$upgraders$" SET checksum = repeat('0', 64) WHERE file_id = 1"#)
+    );
+    let err = m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    assert!(matches!(err, UpgraderError::IntegrityError(_)));
+});
+
+define_test_both_modes!(compare_checksums, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .compare_checksums(true)
+        .build();
+
+    // One row with the SQL and one with a stored checksum.
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.execute(
+        r#"UPDATE "$upgraders$" SET text = E'\n ' || text || E' \t\n' WHERE file_id = 0"#
+    ));
+    m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &PostgresUpgraderOptions::builder()
+            .store_checksums(true)
+            .compare_checksums(true)
+            .build()
+    )
+    .unwrap();
+
+    // Only checksums are read, and surrounding whitespace is ignored as when comparing the SQL.
+    let status = m_status!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert!(status.is_up_to_date());
+    assert!(status.applied.iter().all(|u| u.text.is_empty()));
+    assert_eq!(
+        status.applied[0].checksum.as_ref().map(String::len),
+        Some(64)
+    );
+
+    // Non-ASCII whitespace is trimmed outside the database.
+    m_await!(
+        client.execute("UPDATE \"$upgraders$\" SET text = text || '\u{a0}' WHERE file_id = 0")
+    );
+    m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    m_await!(
+        client
+            .execute(r#"UPDATE "$upgraders$" SET text = text || ' -- changed' WHERE file_id = 0"#)
+    );
+    let err = m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    assert!(matches!(err, UpgraderError::IntegrityError(_)), "{:?}", err);
+
+    // Repair still rewrites the SQL, not a checksum.
+    assert_eq!(
+        m_repair!(
+            "tests/data/basic_flow_step2",
+            &container.connection_string,
+            &options
+        )
+        .unwrap(),
+        1
+    );
+    assert_eq!(
+        m_await!(client.count_rows(
+            r#""$upgraders$" WHERE file_id = 0 AND text NOT LIKE '%changed' AND checksum IS NULL"#
+        )),
+        1
+    );
+});
+
+define_test_both_modes!(baseline, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let folder = "tests/data/basic_flow_step2";
+
+    // The schema of step 1 already exists, created without the upgrader.
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.execute("CREATE TABLE foo (id INT)"));
+
+    let err = m_baseline!(folder, &container.connection_string, &options, (2, 0)).unwrap_err();
+    assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+
+    m_baseline!(folder, &container.connection_string, &options, (0, 0)).unwrap();
+    let rows = m_await!(client.get_upgraders(None));
+    assert_eq!(rows.len(), 1);
+    assert_eq!((rows[0].file_id, rows[0].upgrader_id), (0, 0));
+    assert_eq!(
+        m_await!(client.count_rows("pg_tables WHERE tablename = 'bar'")),
+        0
+    );
+
+    let err = m_baseline!(folder, &container.connection_string, &options, (1, 0)).unwrap_err();
+    assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 1);
+
+    // Only the upgrader after the baseline runs; running "CREATE TABLE foo" would fail.
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    m_await!(client.ensure_table_exists("bar", None));
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 2);
+});
+
+define_test_both_modes!(apply_file, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let folder = "tests/data/apply_file";
+    let mut client = m_client!(&container.connection_string);
+
+    // File 1 cannot skip ahead of file 0.
+    let err = m_apply_file!(folder, &container.connection_string, &options, 1).unwrap_err();
+    assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+    assert!(m_await!(client.get_upgraders(None)).is_empty());
+
+    let err = m_apply_file!(folder, &container.connection_string, &options, 7).unwrap_err();
+    assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+
+    let report = m_apply_file!(folder, &container.connection_string, &options, 0).unwrap();
+    assert_eq!(report.applied.len(), 2);
+    m_await!(client.ensure_table_exists("authors", None));
+    assert_eq!(
+        m_await!(client.count_rows("pg_tables WHERE tablename = 'books'")),
+        0
+    );
+
+    let err = m_apply_file!(folder, &container.connection_string, &options, 2).unwrap_err();
+    assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+
+    let report = m_apply_file!(folder, &container.connection_string, &options, 1).unwrap();
+    assert_eq!(report.applied, vec![(1, 0, "Create books".to_string())]);
+    assert!(
+        m_apply_file!(folder, &container.connection_string, &options, 1)
+            .unwrap()
+            .applied
+            .is_empty()
+    );
+    assert_eq!(
+        m_await!(client.count_rows("pg_tables WHERE tablename = 'reviews'")),
+        0
+    );
+
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 4);
+});
+
+define_test_both_modes!(upgrade_to_target, {
+    let container = PostgresContainer::start();
+    let folder = "tests/data/downgrade";
+    let options = PostgresUpgraderOptions::builder().target((0, 0)).build();
+
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 1);
+
+    // Already at the target: nothing more is applied.
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 1);
+
+    let options = PostgresUpgraderOptions::builder().target((0, 1)).build();
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    let rows = m_await!(client.get_upgraders(None));
+    assert_eq!(rows.len(), 2);
+    assert_eq!((rows[1].file_id, rows[1].upgrader_id), (0, 1));
+    assert_eq!(
+        m_await!(client.count_rows("pg_tables WHERE tablename = 'orders'")),
+        0
+    );
+
+    let options = PostgresUpgraderOptions::builder().target((5, 0)).build();
+    let err = m_upgrade!(folder, &container.connection_string, &options).unwrap_err();
+    assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+
+    let options = PostgresUpgraderOptions::builder().build();
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 3);
+});
+
+define_test_both_modes!(empty_upgraders, {
+    let container = PostgresContainer::start();
+    let folder = "tests/data/empty_upgrader";
+
+    // Skipped by default: only two rows land.
+    let options = PostgresUpgraderOptions::builder()
+        .schema("skipped")
+        .create_schema(true)
+        .build();
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    let mut client = m_client!(&container.connection_string);
+    let rows = m_await!(client.get_upgraders(Some("skipped")));
+    let mut ids: Vec<_> = rows.iter().map(|r| r.upgrader_id).collect();
+    ids.sort();
+    assert_eq!(ids, vec![0, 2]);
+
+    let options = PostgresUpgraderOptions::builder()
+        .schema("kept")
+        .create_schema(true)
+        .allow_empty_upgraders(true)
+        .build();
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    let rows = m_await!(client.get_upgraders(Some("kept")));
+    let mut ids: Vec<_> = rows.iter().map(|r| r.upgrader_id).collect();
+    ids.sort();
+    assert_eq!(ids, vec![0, 1, 2]);
+    assert_eq!(
+        m_await!(client.count_rows("kept.\"$upgraders$\" WHERE upgrader_id = 1 AND text = ''")),
+        1
+    );
+
+    // The recorded empty upgrader passes the integrity check on the next run.
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+});
+
+define_test_both_modes!(empty_block_after_partial_history, {
+    let container = PostgresContainer::start();
+    let folder = "tests/data/empty_upgrader";
+    let mut client = m_client!(&container.connection_string);
+
+    let options = PostgresUpgraderOptions::builder()
+        .schema("app")
+        .create_schema(true)
+        .target((0, 0))
+        .build();
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    assert_eq!(m_await!(client.get_upgraders(Some("app"))).len(), 1);
+
+    // The skipped empty block 1 leaves a hole in the ids; the next upgrader is 2.
+    let options = PostgresUpgraderOptions::builder().schema("app").build();
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    m_await!(client.ensure_table_exists("bar", Some("app")));
+    let mut ids: Vec<_> = m_await!(client.get_upgraders(Some("app")))
+        .iter()
+        .map(|r| r.upgrader_id)
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec![0, 2]);
+
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    assert_eq!(m_await!(client.get_upgraders(Some("app"))).len(), 2);
+});
+
+define_test_both_modes!(repair, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let conn = &container.connection_string;
+
+    m_upgrade!("tests/data/basic_flow_step2", conn, &options).unwrap();
+
+    // Moving an upgrader to another position cannot be repaired; nothing is rewritten.
+    let err = m_repair!("tests/data/repair_renumbered", conn, &options).unwrap_err();
+    assert!(matches!(err, UpgraderError::IntegrityError(_)), "{:?}", err);
+
+    // A reworded description blocks upgrades until it is repaired.
+    let err = m_upgrade!("tests/data/repair", conn, &options).unwrap_err();
+    assert!(matches!(err, UpgraderError::IntegrityError(_)), "{:?}", err);
+    assert_eq!(m_repair!("tests/data/repair", conn, &options).unwrap(), 1);
+    let status = m_status!("tests/data/repair", conn, &options).unwrap();
+    assert!(status.is_up_to_date(), "{:?}", status.drift);
+    assert_eq!(status.applied[0].description, "Create the foo table");
+    assert_eq!(m_repair!("tests/data/repair", conn, &options).unwrap(), 0);
+    m_upgrade!("tests/data/repair", conn, &options).unwrap();
+});
+
+define_test_both_modes!(no_transaction_upgraders, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+
+    // The session lock is released after a failure, even though the client stays open.
+    let mut raw = m_connect_raw!(&container.connection_string);
+    let err =
+        m_upgrade_with_client!(&mut raw, "tests/data/no_transaction_fail", &options).unwrap_err();
+    assert!(matches!(err, UpgraderError::ExecutionFailed { .. }));
+
+    m_upgrade!(
+        "tests/data/no_transaction",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 4);
+    assert_eq!(
+        m_await!(client.count_rows("pg_indexes WHERE indexname = 'items_id_idx'")),
+        1
+    );
+    assert_eq!(
+        m_await!(client.count_rows("pg_locks WHERE locktype = 'advisory'")),
+        0
+    );
+});
+
+define_test_both_modes!(interrupted_upgrades, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let mut client = m_client!(&container.connection_string);
+
+    let first = PostgresUpgraderOptions::builder().target((0, 0)).build();
+    m_upgrade!(
+        "tests/data/no_transaction",
+        &container.connection_string,
+        &first
+    )
+    .unwrap();
+
+    // Recording fails after the SQL ran, as if the process died in between.
+    m_await!(client.execute(
+        "CREATE FUNCTION interrupt() RETURNS trigger LANGUAGE plpgsql AS $$ BEGIN RAISE EXCEPTION 'interrupted'; END $$;"
+    ));
+    m_await!(client.execute(
+        "CREATE TRIGGER interrupt BEFORE INSERT ON \"$upgraders$\" FOR EACH ROW WHEN (NEW.upgrader_id = 1) EXECUTE FUNCTION interrupt();"
+    ));
+    m_upgrade!(
+        "tests/data/no_transaction",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 1);
+    assert_eq!(
+        m_await!(client.count_rows("pg_indexes WHERE indexname = 'items_id_idx'")),
+        1
+    );
+
+    // The interrupted upgrader is recognized and recorded rather than failing on the existing
+    // index, and a transactional upgrader interrupted the same way is applied once.
+    m_await!(client.execute("DROP TRIGGER interrupt ON \"$upgraders$\";"));
+    m_await!(client.execute(
+        "CREATE TRIGGER interrupt BEFORE INSERT ON \"$upgraders$\" FOR EACH ROW WHEN (NEW.upgrader_id = 3) EXECUTE FUNCTION interrupt();"
+    ));
+    m_upgrade!(
+        "tests/data/no_transaction",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 3);
+    assert_eq!(
+        m_await!(client.count_rows(
+            "information_schema.columns WHERE table_name = 'items' AND column_name = 'state'"
+        )),
+        0
+    );
+
+    m_await!(client.execute("DROP TRIGGER interrupt ON \"$upgraders$\";"));
+    m_upgrade!(
+        "tests/data/no_transaction",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert_eq!(
+        m_await!(client.get_sequences(None)),
+        vec![Some(1), Some(2), Some(3), Some(4)]
+    );
+    assert_eq!(m_await!(client.count_rows("\"$upgrader_in_progress$\"")), 0);
+});
+
+define_test_both_modes!(interrupted_multi_statement_upgrader, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let folder = "tests/data/no_transaction_interrupted";
+    let mut client = m_client!(&container.connection_string);
+
+    let first = PostgresUpgraderOptions::builder().target((0, 0)).build();
+    m_upgrade!(folder, &container.connection_string, &first).unwrap();
+
+    // Both no-transaction upgraders were interrupted: the index was built, but only the first
+    // statement of the archive upgrader ran.
+    m_await!(client.execute(
+        "CREATE TABLE \"$upgrader_in_progress$\" (file_id INT, upgrader_id INT, applied_host TEXT, started_on TIMESTAMPTZ, PRIMARY KEY (file_id, upgrader_id));"
+    ));
+    m_await!(client.execute(
+        "INSERT INTO \"$upgrader_in_progress$\" VALUES (0, 1, 'host', now()), (0, 2, 'host', now());"
+    ));
+    m_await!(client.execute("CREATE INDEX items_id_idx ON items (id);"));
+    m_await!(client.execute("CREATE TABLE items_archive (id INT);"));
+
+    // An invalid index outside the upgrader's schema does not block recording.
+    m_await!(client.execute("CREATE SCHEMA other;"));
+    m_await!(client.execute("CREATE TABLE other.t (id INT);"));
+    m_await!(client.execute("CREATE INDEX t_id_idx ON other.t (id);"));
+    m_await!(client.execute(
+        "UPDATE pg_index SET indisvalid = false WHERE indexrelid = 'other.t_id_idx'::regclass;"
+    ));
+
+    let err = m_upgrade!(folder, &container.connection_string, &options).unwrap_err();
+    match err {
+        UpgraderError::ExecutionError(message) => {
+            assert!(message.contains("0:2"), "{}", message);
+            assert!(
+                message.contains("drop the objects it created"),
+                "{}",
+                message
+            );
+        }
+        e => panic!("Expected ExecutionError, got {:?}", e),
+    }
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 2);
+
+    m_await!(client.execute("DROP TABLE items_archive;"));
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 3);
+    m_await!(client.ensure_table_exists("items_archive_log", None));
+    assert_eq!(m_await!(client.count_rows("\"$upgrader_in_progress$\"")), 0);
+});
+
+define_test_both_modes!(timeouts, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .statement_timeout(Duration::from_millis(200))
+        .build();
+
+    let err = m_upgrade!("tests/data/slow", &container.connection_string, &options).unwrap_err();
+    assert!(
+        matches!(&err, UpgraderError::TimeoutError(m) if m.contains("statement timeout")),
+        "{:?}",
+        err
+    );
+    let mut client = m_client!(&container.connection_string);
+    assert!(m_await!(client.get_upgraders(None)).is_empty());
+
+    // Another session holds the lock an upgrade takes for each upgrader.
+    let options = PostgresUpgraderOptions::builder()
+        .lock_timeout(Duration::from_millis(200))
+        .build();
+    m_await!(client.execute("BEGIN"));
+    m_await!(client.execute("SELECT pg_advisory_xact_lock(42004202)"));
+    let started = Instant::now();
+    let err = m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    assert!(
+        matches!(&err, UpgraderError::TimeoutError(m) if m.contains("lock timeout")),
+        "{:?}",
+        err
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+    m_await!(client.execute("ROLLBACK"));
+
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+});
+
+define_test_both_modes!(embedded_migrations, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+
+    let migrations = postgresql_schema_upgrader::embed_migrations!(
+        "tests/data/basic_flow_step2/000_init.sql",
+        "tests/data/basic_flow_step2/001_bar.sql",
+    );
+    m_upgrade_embedded!(&migrations, &container.connection_string, &options).unwrap();
+
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.ensure_table_exists("foo", None));
+    m_await!(client.ensure_table_exists("bar", None));
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 2);
+
+    // The embedded upgraders are recorded exactly as those of the folder.
+    m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+});
+
+define_test_both_modes!(lock_wait_timeout, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .advisory_lock_id(4242)
+        .lock_wait_timeout(Duration::from_millis(300))
+        .build();
+
+    let mut holder = m_client!(&container.connection_string);
+    m_await!(holder.execute("SELECT pg_advisory_lock(4242)"));
+    let started = Instant::now();
+    let err = m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    assert!(matches!(err, UpgraderError::LockTimeout(_)), "{:?}", err);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // Once the lock is released, the same options acquire it.
+    m_await!(holder.execute("SELECT pg_advisory_unlock(4242)"));
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert_eq!(m_await!(holder.get_upgraders(None)).len(), 1);
+});
+
+define_test_both_modes!(lock_wait_timeout_no_transaction, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .advisory_lock_id(4343)
+        .lock_wait_timeout(Duration::from_millis(300))
+        .build();
+
+    // The session lock taken around (no-transaction) upgraders is bounded as well.
+    let mut holder = m_client!(&container.connection_string);
+    m_await!(holder.execute("SELECT pg_advisory_lock(4344)"));
+    let started = Instant::now();
+    let err = m_upgrade!(
+        "tests/data/no_transaction",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    assert!(matches!(err, UpgraderError::LockTimeout(_)), "{:?}", err);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    m_await!(holder.execute("SELECT pg_advisory_unlock(4344)"));
+    m_upgrade!(
+        "tests/data/no_transaction",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+});
+
+define_test_both_modes!(connect_retries, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .connect_retries(2)
+        .connect_retry_delay(Duration::from_millis(200))
+        .build();
+
+    // Nothing listens on port 1: every attempt is made.
+    let started = Instant::now();
+    let err = m_upgrade!(
+        "tests/data/basic_flow_step1",
+        "host=localhost port=1 user=postgres dbname=postgres",
+        &options
+    )
+    .unwrap_err();
+    assert!(
+        matches!(err, UpgraderError::ConnectionError(_)),
+        "{:?}",
+        err
+    );
+    assert!(started.elapsed() >= Duration::from_millis(400));
+
+    // A wrong password is reported by the server and not retried.
+    let options = PostgresUpgraderOptions::builder()
+        .connect_retries(3)
+        .connect_retry_delay(Duration::from_secs(5))
+        .build();
+    let wrong_password = format!("{} password=wrong", container.connection_string);
+    let started = Instant::now();
+    let err = m_upgrade!("tests/data/basic_flow_step1", &wrong_password, &options).unwrap_err();
+    assert!(
+        matches!(err, UpgraderError::ConnectionError(_)),
+        "{:?}",
+        err
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+});
+
+define_test_both_modes!(try_upgrade_outcomes, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+
+    match m_try_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    ) {
+        UpgradeOutcome::Applied(report) => {
+            assert_eq!(report.applied, vec![(0, 0, "Create foo".to_string())]);
+            assert_eq!(report.already_applied, 0);
+        }
+        other => panic!("Expected Applied, got {:?}", other),
+    }
+
+    match m_try_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    ) {
+        UpgradeOutcome::Applied(report) => {
+            assert_eq!(report.applied, vec![(1, 0, "Create bar".to_string())]);
+            assert_eq!(report.already_applied, 1);
+        }
+        other => panic!("Expected Applied, got {:?}", other),
+    }
+
+    assert!(matches!(
+        m_try_upgrade!(
+            "tests/data/basic_flow_step2",
+            &container.connection_string,
+            &options
+        ),
+        UpgradeOutcome::UpToDate
+    ));
+
+    // An applied upgrader whose SQL changed blocks the upgrade without an error.
+    let drifted = tempfile::tempdir().unwrap();
+    std::fs::write(
+        drifted.path().join("000_init.sql"),
+        "--- 0: Create foo\nCREATE TABLE foo (id BIGINT);",
+    )
+    .unwrap();
+    match m_try_upgrade!(drifted.path(), &container.connection_string, &options) {
+        UpgradeOutcome::IntegrityBlocked(report) => {
+            assert_eq!(report.kind, "text_changed");
+            assert_eq!(report.ids, (0, 0));
+        }
+        other => panic!("Expected IntegrityBlocked, got {:?}", other),
+    }
+    // The wrapper reports the same violation as an error.
+    assert!(matches!(
+        m_upgrade!(drifted.path(), &container.connection_string, &options),
+        Err(UpgraderError::IntegrityError(_))
+    ));
+
+    assert!(matches!(
+        m_try_upgrade!(
+            "tests/data/does_not_exist",
+            &container.connection_string,
+            &options
+        ),
+        UpgradeOutcome::Failed(UpgraderError::LoaderError(_))
+    ));
+});
+
+define_test_both_modes!(table_comment, {
+    let container = PostgresContainer::start();
+    let comment_is = |comment: &str| {
+        format!(
+            "pg_class WHERE oid = '\"$upgraders$\"'::regclass AND obj_description(oid, 'pg_class') = '{}'",
+            comment.replace('\'', "''")
+        )
+    };
+
+    let options = PostgresUpgraderOptions::builder().build();
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    let mut client = m_client!(&container.connection_string);
+    let default_comment = format!(
+        "Managed by postgresql-schema-upgrader v{}; do not edit",
+        env!("CARGO_PKG_VERSION")
+    );
+    assert_eq!(
+        m_await!(client.count_rows(&comment_is(&default_comment))),
+        1
+    );
+
+    // A custom comment replaces the default on the next run.
+    let options = PostgresUpgraderOptions::builder()
+        .table_comment("Schema history of the billing service (don't edit)")
+        .build();
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert_eq!(
+        m_await!(client.count_rows(&comment_is(
+            "Schema history of the billing service (don't edit)"
+        ))),
+        1
+    );
+});
+
+define_test_both_modes!(dry_run, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let mut client = m_client!(&container.connection_string);
+    let upgraders_tables = "pg_tables WHERE tablename = '$upgraders$'";
+
+    // A fresh database is not even given an upgraders table.
+    let pending = m_dry_run!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert_eq!(
+        pending,
+        vec![
+            (0, 0, "Create foo".to_string()),
+            (1, 0, "Create bar".to_string())
+        ]
+    );
+    assert_eq!(m_await!(client.count_rows(upgraders_tables)), 0);
+
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    let pending = m_dry_run!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert_eq!(pending, vec![(1, 0, "Create bar".to_string())]);
+
+    // An upgrade with the option set applies nothing either.
+    let dry_options = PostgresUpgraderOptions::builder().dry_run(true).build();
+    m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &dry_options
+    )
+    .unwrap();
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 1);
+    assert_eq!(
+        m_await!(client.count_rows("pg_tables WHERE tablename = 'bar'")),
+        0
+    );
+
+    let err = m_dry_run!(
+        "tests/data/integrity_violation_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    assert!(matches!(err, UpgraderError::IntegrityError(_)));
+});
+
+define_test_both_modes!(status, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let mut client = m_client!(&container.connection_string);
+
+    // A fresh database has everything pending and is not given an upgraders table.
+    let status = m_status!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert!(status.applied.is_empty());
+    assert_eq!(status.pending.len(), 2);
+    assert_eq!(
+        m_await!(client.count_rows("pg_tables WHERE tablename = '$upgraders$'")),
+        0
+    );
+
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    let status = m_status!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert_eq!(status.applied.len(), 1);
+    assert_eq!(status.applied[0].description, "Create foo");
+    let pending: Vec<_> = status
+        .pending
+        .iter()
+        .map(|u| (u.file_id, u.upgrader_id, u.description.as_str()))
+        .collect();
+    assert_eq!(pending, vec![(1, 0, "Create bar")]);
+    assert!(status.pending[0].text.contains("bar"));
+    assert_eq!(status.drift, None);
+    assert!(!status.is_up_to_date());
+
+    // Drift is reported, not returned as an error.
+    let status = m_status!(
+        "tests/data/integrity_violation_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert!(status.drift.is_some());
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 1);
+});
+
+define_test_both_modes!(migration_set_multiple_databases, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let set = MigrationSet::load("tests/data/basic_flow_step2", &options).unwrap();
+    assert_eq!(set.len(), 2);
+
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.execute("CREATE DATABASE tenant_b"));
+    let tenant_b = container
+        .connection_string
+        .replace("dbname=postgres", "dbname=tenant_b");
+
+    for connection_string in [&container.connection_string, &tenant_b] {
+        m_apply!(set, connection_string).unwrap();
+        // Applying the same set again is a no-op.
+        m_apply!(set.clone(), connection_string).unwrap();
+
+        let mut client = m_client!(connection_string);
+        m_await!(client.ensure_table_exists("foo", None));
+        let rows = m_await!(client.get_upgraders(None));
+        assert_eq!(rows.len(), 2);
+    }
+});
+
+define_test_both_modes!(up_to_date_skips_lock, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    // Another session holds the upgrade lock for the rest of the test.
+    let mut holder = m_client!(&container.connection_string);
+    m_await!(holder.execute("SELECT pg_advisory_lock(42004202)"));
+
+    // The database is already up to date, so the upgrade must not wait for the lock.
+    let connection_string = format!(
+        "{} options='-c lock_timeout=2000'",
+        container.connection_string
+    );
+    m_upgrade!("tests/data/basic_flow_step1", &connection_string, &options).unwrap();
+
+    // With pending work the locked path is taken and times out.
+    let result = m_upgrade!("tests/data/basic_flow_step2", &connection_string, &options);
+    match result {
+        Err(UpgraderError::TimeoutError(msg)) => {
+            assert!(msg.contains("lock"), "Unexpected error: {}", msg)
+        }
+        other => panic!("Expected lock timeout, got {:?}", other),
+    }
+});
+
+define_test_both_modes!(schema_support, {
+    let container = PostgresContainer::start();
+
+    // Create schema manually first
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.execute("CREATE SCHEMA my_schema"));
+
+    let options = PostgresUpgraderOptions::builder()
+        .schema("my_schema")
+        .build();
+
+    m_upgrade!(
+        "tests/data/schema_support",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    m_await!(client.ensure_table_exists("foo", Some("my_schema")));
+    let rows = m_await!(client.get_upgraders(Some("my_schema")));
+    assert_eq!(rows.len(), 1);
+});
+
+define_test_both_modes!(tracking_schema, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .schema("app")
+        .tracking_schema("meta")
+        .create_schema(true)
+        .build();
+
+    m_upgrade!(
+        "tests/data/schema_support",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    // The upgraders run against the target schema, and are tracked in the other one.
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.ensure_table_exists("foo", Some("app")));
+    assert_eq!(m_await!(client.get_upgraders(Some("meta"))).len(), 1);
+    assert_eq!(
+        m_await!(
+            client.count_rows("pg_tables WHERE schemaname = 'app' AND tablename = '$upgraders$'")
+        ),
+        0
+    );
+
+    m_upgrade!(
+        "tests/data/schema_support",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert_eq!(m_await!(client.get_upgraders(Some("meta"))).len(), 1);
+});
+
+define_test_both_modes!(schema_auto_create, {
+    let container = PostgresContainer::start();
+    let schema_name = "auto_created_schema";
+
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.ensure_schema_does_not_exist(schema_name));
+
+    let options = PostgresUpgraderOptions::builder()
+        .schema(schema_name)
+        .create_schema(true)
+        .build();
+
+    m_upgrade!(
+        "tests/data/schema_auto_create",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    m_await!(client.ensure_schema_exists(schema_name));
+    m_await!(client.ensure_table_exists("test_table", Some(schema_name)));
+});
+
+define_test_both_modes!(schema_requiring_quotes, {
+    let container = PostgresContainer::start();
+    let mut client = m_client!(&container.connection_string);
+
+    // A reserved word and a mixed-case name are substituted quoted; a simple name is
+    // quoted only when asked to.
+    for (schema, always_quote) in [("Order", false), ("MixedCase", false), ("plain", true)] {
+        let options = PostgresUpgraderOptions::builder()
+            .schema(schema)
+            .create_schema(true)
+            .always_quote_schema(always_quote)
+            .build();
+
+        m_upgrade!(
+            "tests/data/schema_auto_create",
+            &container.connection_string,
+            &options
+        )
+        .unwrap();
+
+        m_await!(client.ensure_table_exists("test_table", Some(&format!("\"{}\"", schema))));
+        assert_eq!(m_await!(client.get_upgraders(Some(schema))).len(), 1);
+    }
+});
+
+define_test_both_modes!(variables, {
+    let container = PostgresContainer::start();
+    let mut client = m_client!(&container.connection_string);
+
+    // An unknown token is rejected before connecting in strict mode.
+    let strict = PostgresUpgraderOptions::builder()
+        .schema("audit")
+        .create_schema(true)
+        .variable("TABLE", "events")
+        .strict_variables(true)
+        .build();
+    let err = m_upgrade!(
+        "tests/data/variables",
+        &container.connection_string,
+        &strict
+    )
+    .unwrap_err();
+    assert!(matches!(err, UpgraderError::ConfigurationError(m) if m.contains("{{COMMENT}}")));
+
+    let options = PostgresUpgraderOptions::builder()
+        .schema("audit")
+        .create_schema(true)
+        .variable("TABLE", "events")
+        .variable("COMMENT", "Audit events")
+        .strict_variables(true)
+        .build();
+    m_upgrade!(
+        "tests/data/variables",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    m_await!(client.ensure_table_exists("events", Some("audit")));
+    assert_eq!(
+        m_await!(client.count_rows(
+            "pg_description WHERE objoid = 'audit.events'::regclass AND description = 'Audit events'"
+        )),
+        1
+    );
+    // The recorded text is the file text, before substitution.
+    assert_eq!(
+        m_await!(client.count_rows("audit.\"$upgraders$\" WHERE text LIKE '%{{TABLE}}%'")),
+        1
+    );
+});
+
+fn recording_hook(events: &Arc<Mutex<Vec<String>>>, stage: &'static str) -> ApplyHook {
+    let events = events.clone();
+    Arc::new(move |info| {
+        events.lock().unwrap().push(format!(
+            "{} {}:{} {}",
+            stage, info.file_id, info.upgrader_id, info.description
+        ))
+    })
+}
+
+define_test_both_modes!(apply_hooks, {
+    let container = PostgresContainer::start();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let options = PostgresUpgraderOptions::builder()
+        .on_before_apply(recording_hook(&events, "before"))
+        .on_after_apply(recording_hook(&events, "after"))
+        .build();
+
+    m_upgrade!(
+        "tests/data/rollback",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    // The failed upgrader was announced but never reported as applied.
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec!["before 0:0 init", "after 0:0 init", "before 1:0 fail_step"]
+    );
+
+    // Nothing is applied when already up to date, so no hook runs.
+    events.lock().unwrap().clear();
+    m_upgrade!(
+        "tests/data/rollback_fixed",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec!["before 1:0 fixed_step", "after 1:0 fixed_step"]
+    );
+    events.lock().unwrap().clear();
+    m_upgrade!(
+        "tests/data/rollback_fixed",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert!(events.lock().unwrap().is_empty());
+});
+
+define_test_both_modes!(on_progress, {
+    let container = PostgresContainer::start();
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let recorded = progress.clone();
+    let hook: ProgressHook = Arc::new(move |applied, total| {
+        recorded.lock().unwrap().push((applied, total));
+    });
+    let options = PostgresUpgraderOptions::builder()
+        .on_progress(hook.clone())
+        .build();
+
+    m_upgrade!(
+        "tests/data/rollback",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    assert_eq!(*progress.lock().unwrap(), vec![(1, 2)]);
+
+    // The total only counts what is still pending.
+    progress.lock().unwrap().clear();
+    m_upgrade!(
+        "tests/data/rollback_fixed",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert_eq!(*progress.lock().unwrap(), vec![(1, 1)]);
+
+    progress.lock().unwrap().clear();
+    m_upgrade!(
+        "tests/data/rollback_fixed",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert!(progress.lock().unwrap().is_empty());
+
+    // A single transaction is committed, and reported, once.
+    let single = PostgresUpgraderOptions::builder()
+        .upgraders_table("single_upgraders")
+        .single_transaction(true)
+        .on_progress(hook.clone())
+        .build();
+    m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &single
+    )
+    .unwrap();
+    assert_eq!(*progress.lock().unwrap(), vec![(2, 2)]);
+
+    // So is each batch of files applied in parallel.
+    progress.lock().unwrap().clear();
+    let parallel = PostgresUpgraderOptions::builder()
+        .upgraders_table("parallel_upgraders")
+        .max_parallel_files(3)
+        .on_progress(hook)
+        .build();
+    m_upgrade!(
+        "tests/data/parallel_files",
+        &container.connection_string,
+        &parallel
+    )
+    .unwrap();
+    assert_eq!(*progress.lock().unwrap(), vec![(6, 6)]);
+});
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_events() {
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .schema("traced")
+        .create_schema(true)
+        .build();
+    let capture = Capture::default();
+    let writer = capture.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+
+    tracing::subscriber::with_default(subscriber, || {
+        upgrade_blocking(
+            "tests/data/basic_flow_step2",
+            &container.connection_string,
+            &options,
+        )
+        .unwrap();
+    });
+
+    let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+    assert!(output.contains("upgrade{schema=\"traced\"}"), "{}", output);
+    assert!(output.contains("Applying upgrader 0:0"), "{}", output);
+    assert!(output.contains("Applying upgrader 1:0"), "{}", output);
+    assert!(output.contains("Acquired upgrade lock"), "{}", output);
+    assert!(output.contains("Acquired advisory lock"), "{}", output);
+}
+
+#[tokio::test]
+async fn async_apply_hooks() {
+    let container = PostgresContainer::start();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let async_hook = |stage: &'static str| -> AsyncApplyHook {
+        let events = events.clone();
+        Arc::new(move |info| {
+            let events = events.clone();
+            let entry = format!("{} {}:{}", stage, info.file_id, info.upgrader_id);
+            Box::pin(async move {
+                tokio::task::yield_now().await;
+                events.lock().unwrap().push(entry);
+            })
+        })
+    };
+    let options = PostgresUpgraderOptions::builder()
+        .on_before_apply_async(async_hook("before"))
+        .on_after_apply_async(async_hook("after"))
+        .build();
+
+    // The blocking API cannot await the hooks, so it refuses them.
+    let err = upgrade_blocking(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options,
+    )
+    .unwrap_err();
+    assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+
+    upgrade_async(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec!["before 0:0", "after 0:0", "before 1:0", "after 1:0"]
+    );
+}
+
+#[tokio::test]
+async fn upgrade_async_pooled_checkout() {
+    let container = PostgresContainer::start();
+    let (client, connection) =
+        tokio_postgres::connect(&container.connection_string, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+    let connection_task = tokio::spawn(connection);
+    // Stands in for a pool checkout, which derefs to the client.
+    let mut checkout = Box::new(client);
+    let options = PostgresUpgraderOptions::builder().build();
+
+    upgrade_async_pooled(&mut checkout, "tests/data/basic_flow_step2", &options)
+        .await
+        .unwrap();
+
+    // The connection is still owned by the caller, and usable outside a transaction.
+    assert!(!connection_task.is_finished());
+    checkout.batch_execute("BEGIN; COMMIT;").await.unwrap();
+    let client = AsyncTestClient::connect(&container.connection_string).await;
+    assert_eq!(client.get_upgraders(None).await.len(), 2);
+}
+
+// Concurrency tests need distinct implementations due to thread vs tokio::spawn differences.
+
+define_test_both_modes!(unquoted_identifiers, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .schema("tenant_a")
+        .create_schema(true)
+        .upgraders_table("schema_upgraders")
+        .unquoted_identifiers(true)
+        .build();
+
+    for _ in 0..2 {
+        m_upgrade!(
+            "tests/data/basic_flow_step1",
+            &container.connection_string,
+            &options
+        )
+        .unwrap();
+    }
+
+    // Unquoted names are folded to lowercase, so any casing refers to the same table.
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(m_await!(client.count_rows("tenant_a.schema_upgraders")), 1);
+    assert_eq!(m_await!(client.count_rows("TENANT_A.Schema_Upgraders")), 1);
+
+    // Names that need quoting are rejected before connecting.
+    let options = PostgresUpgraderOptions::builder()
+        .unquoted_identifiers(true)
+        .build();
+    let result = m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    );
+    assert!(matches!(result, Err(UpgraderError::ConfigurationError(_))));
+});
+
+define_test_both_modes!(quoted_custom_upgraders_table, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .upgraders_table("SchemaUpgraders")
+        .build();
+
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    // Quoted names keep their exact casing.
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(m_await!(client.count_rows("\"SchemaUpgraders\"")), 1);
+    m_await!(client.execute("CREATE TABLE schemaupgraders (id INT)"));
+    assert_eq!(m_await!(client.count_rows("schemaupgraders")), 0);
+});
+
+define_test_both_modes!(use_search_path, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .schema("tenant_a")
+        .create_schema(true)
+        .use_search_path(true)
+        .build();
+    m_upgrade!(
+        "tests/data/search_path",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    // Unqualified names resolve to the schema, in and outside transactions.
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(m_await!(client.count_rows("tenant_a.items")), 2);
+    assert_eq!(
+        m_await!(
+            client.count_rows(
+                "pg_indexes WHERE schemaname = 'tenant_a' AND indexname = 'items_id_idx'"
+            )
+        ),
+        1
+    );
+    assert_eq!(
+        m_await!(
+            client.count_rows("pg_tables WHERE tablename = 'items' AND schemaname = 'public'")
+        ),
+        0
+    );
+    assert_eq!(m_await!(client.get_upgraders(Some("tenant_a"))).len(), 3);
+
+    // A second run finds the schema up to date.
+    m_upgrade!(
+        "tests/data/search_path",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+});
+
+define_test_both_modes!(public_schema_vs_none, {
+    let container = PostgresContainer::start();
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.execute("CREATE SCHEMA tenant"));
+    let conn_str = format!(
+        "{} options='-c search_path=tenant'",
+        container.connection_string
+    );
+    let upgraders_in = |schema: &str| {
+        format!(
+            "pg_tables WHERE schemaname = '{}' AND tablename = '$upgraders$'",
+            schema
+        )
+    };
+
+    // Without a schema, names resolve through the connection's search_path.
+    let options = PostgresUpgraderOptions::builder().build();
+    m_upgrade!("tests/data/basic_flow_step1", &conn_str, &options).unwrap();
+    assert_eq!(m_await!(client.count_rows(&upgraders_in("tenant"))), 1);
+    assert_eq!(m_await!(client.count_rows(&upgraders_in("public"))), 0);
+    assert_eq!(m_await!(client.count_rows("tenant.foo")), 0);
+
+    // An explicit "public" qualifies the upgraders table and {{SCHEMA}} regardless of it.
+    let options = PostgresUpgraderOptions::builder().schema("public").build();
+    m_upgrade!("tests/data/schema_support", &conn_str, &options).unwrap();
+    assert_eq!(m_await!(client.count_rows("public.foo")), 0);
+    assert_eq!(m_await!(client.count_rows(&upgraders_in("public"))), 1);
+    assert_eq!(m_await!(client.get_upgraders(Some("public"))).len(), 1);
+    assert_eq!(m_await!(client.get_upgraders(Some("tenant"))).len(), 1);
+});
+
+define_test_both_modes!(role_per_schema, {
+    let container = PostgresContainer::start();
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.execute("CREATE ROLE tenant_a NOLOGIN"));
+    m_await!(client.execute("CREATE SCHEMA tenant_a AUTHORIZATION tenant_a"));
+    m_await!(client.execute("CREATE ROLE app_tenant_b NOLOGIN"));
+    m_await!(client.execute("CREATE SCHEMA tenant_b AUTHORIZATION app_tenant_b"));
+
+    let options = PostgresUpgraderOptions::builder()
+        .schema("tenant_a")
+        .role_per_schema(true)
+        .build();
+    m_upgrade!(
+        "tests/data/role_per_schema",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    let options = PostgresUpgraderOptions::builder()
+        .schema("tenant_b")
+        .role_per_schema(true)
+        .role_template("app_{schema}")
+        .build();
+    m_upgrade!(
+        "tests/data/role_per_schema",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    // Objects created by the upgraders are owned by the derived role...
+    assert_eq!(
+        m_await!(client.count_rows(
+            "pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace \
+             WHERE c.relname IN ('owned', 'owned_view') \
+             AND pg_get_userbyid(c.relowner) = CASE n.nspname \
+             WHEN 'tenant_a' THEN 'tenant_a' WHEN 'tenant_b' THEN 'app_tenant_b' END"
+        )),
+        4
+    );
+    // ...while the upgraders table stays owned by the connecting user.
+    assert_eq!(
+        m_await!(
+            client.count_rows(
+                "pg_tables WHERE tablename = '$upgraders$' AND tableowner = 'postgres'"
+            )
+        ),
+        2
+    );
+    assert_eq!(m_await!(client.get_upgraders(Some("tenant_a"))).len(), 2);
+    assert_eq!(m_await!(client.get_upgraders(Some("tenant_b"))).len(), 2);
+});
+
+define_test_both_modes!(plan_is_read_only, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .schema("planned")
+        .create_schema(true)
+        .build();
+
+    // Fresh database: everything is planned, nothing is created.
+    let set = MigrationSet::load("tests/data/basic_flow_step1", &options).unwrap();
+    let plan = m_plan!(set, &container.connection_string).unwrap();
+    assert!(matches!(plan.steps[0], PlanStep::CreateSchema { .. }));
+    assert!(matches!(
+        plan.steps[1],
+        PlanStep::CreateUpgradersTable { .. }
+    ));
+    assert!(matches!(plan.steps[2], PlanStep::AcquireUpgradeLock { .. }));
+    assert_eq!(plan.pending_upgraders(), 1);
+
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.ensure_schema_does_not_exist("planned"));
+
+    // After the first step only the new upgrader is planned.
+    m_apply!(set, &container.connection_string).unwrap();
+    let set = MigrationSet::load("tests/data/basic_flow_step2", &options).unwrap();
+    let plan = m_plan!(set, &container.connection_string).unwrap();
+    assert_eq!(plan.steps.len(), 2);
+    match &plan.steps[1] {
+        PlanStep::ApplyUpgrader {
+            file_id,
+            upgrader_id,
+            ..
+        } => assert_eq!((*file_id, *upgrader_id), (1, 0)),
+        other => panic!("Unexpected step: {:?}", other),
+    }
+    assert_eq!(plan.applied.len(), 1);
+    assert_eq!(
+        (plan.applied[0].file_id, plan.applied[0].upgrader_id),
+        (0, 0)
+    );
+    assert!(plan.applied[0].applied_host.is_some());
+    assert_eq!(m_await!(client.get_upgraders(Some("planned"))).len(), 1);
+
+    m_apply!(set, &container.connection_string).unwrap();
+    assert!(
+        m_plan!(set, &container.connection_string)
+            .unwrap()
+            .is_empty()
+    );
+});
+
+define_test_both_modes!(deploy_key_recorded_once, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .deploy_key("release-1")
+        .build();
+
+    let set = MigrationSet::load("tests/data/basic_flow_step1", &options).unwrap();
+    assert!(matches!(
+        m_plan!(set, &container.connection_string)
+            .unwrap()
+            .steps
+            .last(),
+        Some(PlanStep::RecordDeployKey { .. })
+    ));
+    m_apply!(set, &container.connection_string).unwrap();
+
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(m_await!(client.count_rows("\"$upgrader_deploys$\"")), 1);
+
+    // Repeating the same deploy has nothing left to do and is not counted again.
+    assert!(
+        m_plan!(set, &container.connection_string)
+            .unwrap()
+            .is_empty()
+    );
+    m_apply!(set, &container.connection_string).unwrap();
+    assert_eq!(m_await!(client.count_rows("\"$upgrader_deploys$\"")), 1);
+
+    // A new deploy is recorded next to the first one, also when nothing is pending.
+    let options = PostgresUpgraderOptions::builder()
+        .deploy_key("release-2")
+        .build();
+    let set = MigrationSet::load("tests/data/basic_flow_step1", &options).unwrap();
+    assert!(
+        !m_plan!(set, &container.connection_string)
+            .unwrap()
+            .is_empty()
+    );
+    m_apply!(set, &container.connection_string).unwrap();
+    assert_eq!(m_await!(client.count_rows("\"$upgrader_deploys$\"")), 2);
+
+    let options = PostgresUpgraderOptions::builder()
+        .deploy_key("release-3")
+        .build();
+    let set = MigrationSet::load("tests/data/basic_flow_step2", &options).unwrap();
+    m_apply!(set, &container.connection_string).unwrap();
+    assert_eq!(m_await!(client.count_rows("\"$upgrader_deploys$\"")), 3);
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 2);
+});
+
+define_test_both_modes!(single_transaction, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .single_transaction(true)
+        .build();
+
+    // The last upgrader fails, so the earlier ones are rolled back with it.
+    let err = m_upgrade!(
+        "tests/data/single_transaction",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        UpgraderError::ExecutionFailed {
+            file_id: 1,
+            upgrader_id: 0,
+            ..
+        }
+    ));
+    let mut client = m_client!(&container.connection_string);
+    assert!(m_await!(client.get_upgraders(None)).is_empty());
+    assert_eq!(
+        m_await!(client.count_rows("pg_tables WHERE tablename IN ('accounts', 'ledger')")),
+        0
+    );
+
+    // Committed once when every upgrader succeeds.
+    m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert_eq!(m_await!(client.get_sequences(None)), vec![Some(1), Some(2)]);
+
+    // Upgraders outside a transaction cannot take part.
+    let err = m_upgrade!(
+        "tests/data/no_transaction",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+});
+
+define_test_both_modes!(parallel_cold_start, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .parallel_cold_start(true)
+        .build();
+
+    // Five files that each sleep for a second: sequentially this takes five seconds.
+    let set = MigrationSet::load("tests/data/parallel_cold_start", &options).unwrap();
+    let started = std::time::Instant::now();
+    m_apply!(set, &container.connection_string).unwrap();
+    assert!(started.elapsed() < std::time::Duration::from_millis(4500));
+
+    // Recorded as if applied sequentially, so the history passes the integrity check.
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(
+        m_await!(client.get_sequences(None)),
+        (1..=5).map(Some).collect::<Vec<_>>()
+    );
+    m_apply!(set, &container.connection_string).unwrap();
+    assert_eq!(m_await!(client.count_rows("\"$upgraders$\"")), 5);
+});
+
+define_test_both_modes!(max_parallel_files, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .max_parallel_files(3)
+        .build();
+
+    // Three files whose first step sleeps for a second: sequentially this takes three
+    // seconds. The second step of each file uses the table of the first.
+    let set = MigrationSet::load("tests/data/parallel_files", &options).unwrap();
+    let started = std::time::Instant::now();
+    m_apply!(set, &container.connection_string).unwrap();
+    assert!(started.elapsed() < std::time::Duration::from_millis(2500));
+
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(
+        m_await!(client.get_sequences(None)),
+        (1..=6).map(Some).collect::<Vec<_>>()
+    );
+    assert_eq!(m_await!(client.count_rows("warehouses")), 2);
+    m_apply!(set, &container.connection_string).unwrap();
+    assert_eq!(m_await!(client.count_rows("\"$upgraders$\"")), 6);
+});
+
+define_test_both_modes!(parallel_cold_start_falls_back, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .schema("library")
+        .create_schema(true)
+        .parallel_cold_start(true)
+        .build();
+
+    // The second file needs the table of the first one, so every file is applied
+    // sequentially.
+    let set = MigrationSet::load("tests/data/parallel_dependent", &options).unwrap();
+    m_apply!(set, &container.connection_string).unwrap();
+
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(
+        m_await!(client.get_sequences(Some("library"))),
+        vec![Some(1), Some(2), Some(3)]
+    );
+    m_await!(client.ensure_table_exists("books", Some("library")));
+    m_await!(client.ensure_table_exists("shelves", Some("library")));
+
+    // Upgraders already applied: the regular sequential upgrade runs.
+    let set = MigrationSet::load("tests/data/basic_flow_step1", &options).unwrap();
+    let err = m_apply!(set, &container.connection_string).unwrap_err();
+    assert!(matches!(err, UpgraderError::IntegrityError(_)));
+});
+
+define_test_both_modes!(track_verification, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .track_verification(true)
+        .build();
+
+    // Untracked upgrades leave the column empty.
+    let untracked = PostgresUpgraderOptions::builder().build();
+    let set = MigrationSet::load("tests/data/basic_flow_step1", &untracked).unwrap();
+    m_apply!(set, &container.connection_string).unwrap();
+    let applied = m_plan!(set, &container.connection_string).unwrap().applied;
+    assert_eq!(applied[0].last_verified_on, None);
+    let applied_on = applied[0].applied_on;
+
+    // Every tracked run confirms the history, even with nothing to apply.
+    let set = MigrationSet::load("tests/data/basic_flow_step1", &options).unwrap();
+    m_apply!(set, &container.connection_string).unwrap();
+    let applied = m_plan!(set, &container.connection_string).unwrap().applied;
+    let first_verified = applied[0].last_verified_on.unwrap();
+    assert!(first_verified > applied_on);
+
+    thread::sleep(Duration::from_millis(10));
+    let set = MigrationSet::load("tests/data/basic_flow_step2", &options).unwrap();
+    m_apply!(set, &container.connection_string).unwrap();
+    let applied = m_plan!(set, &container.connection_string).unwrap().applied;
+    assert_eq!(applied.len(), 2);
+    assert_eq!(applied[0].applied_on, applied_on);
+    assert!(applied[0].last_verified_on.unwrap() > first_verified);
+    assert!(applied[1].last_verified_on.is_some());
+});
+
+define_test_both_modes!(maintenance_window, {
+    let container = PostgresContainer::start();
+    let closed = MaintenanceWindow::Callback(Arc::new(|_| false));
+    let options = PostgresUpgraderOptions::builder()
+        .maintenance_window(closed.clone())
+        .build();
+
+    // The CREATE TABLE runs anytime, the ALTER TABLE waits for the window.
+    let err = m_upgrade!(
+        "tests/data/maintenance_window",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    match err {
+        UpgraderError::MaintenanceWindowError(msg) => assert!(msg.contains("0:1 (Add name)")),
+        other => panic!("Unexpected error: {:?}", other),
+    }
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 1);
+
+    // Forced outside the window.
+    let options = PostgresUpgraderOptions::builder()
+        .maintenance_window(closed)
+        .ignore_maintenance_window(true)
+        .build();
+    m_upgrade!(
+        "tests/data/maintenance_window",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 2);
+});
+
+define_test_both_modes!(maintenance_window_open, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .maintenance_window(MaintenanceWindow::Callback(Arc::new(|_| true)))
+        .build();
+    m_upgrade!(
+        "tests/data/maintenance_window",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 2);
+});
+
+define_test_both_modes!(export_as_files_round_trip, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().schema("legacy").build();
+    let out = tempfile::tempdir().unwrap();
+
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.execute("CREATE SCHEMA legacy"));
+    let err = m_export_as_files!(&container.connection_string, &options, out.path()).unwrap_err();
+    assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+
+    // A history recorded by another tool, with SQL the loader would have trimmed.
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    m_await!(client.execute("CREATE TABLE legacy.orders (id INT)"));
+    m_await!(client.execute(
+        "INSERT INTO legacy.\"$upgraders$\" (file_id, upgrader_id, description, text, applied_on, sequence) VALUES \
+         (0, 1, 'Add orders', E'\\nCREATE TABLE legacy.orders (id INT);\\n', now(), 2), \
+         (1, 0, 'Index orders', 'CREATE INDEX ON legacy.orders (id);', now(), 3)"
+    ));
+
+    let paths = m_export_as_files!(&container.connection_string, &options, out.path()).unwrap();
+    let names: Vec<_> = paths
+        .iter()
+        .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(names, vec!["000_create_foo.sql", "001_index_orders.sql"]);
+
+    // The exported folder upgrades the database without changing anything.
+    let set = MigrationSet::load(out.path(), &options).unwrap();
+    assert_eq!(set.len(), 3);
+    assert!(
+        m_plan!(set, &container.connection_string)
+            .unwrap()
+            .is_empty()
+    );
+    m_apply!(set, &container.connection_string).unwrap();
+    assert_eq!(m_await!(client.get_upgraders(Some("legacy"))).len(), 3);
+});
+
+define_test_both_modes!(downgrade, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let folder = "tests/data/downgrade";
+
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 3);
+
+    let err = m_downgrade!(folder, &container.connection_string, &options, (0, 5)).unwrap_err();
+    assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+
+    // 1:0 and 0:1 are undone, latest first; the target stays applied.
+    m_downgrade!(folder, &container.connection_string, &options, (0, 0)).unwrap();
+    let rows = m_await!(client.get_upgraders(None));
+    assert_eq!(rows.len(), 1);
+    assert_eq!((rows[0].file_id, rows[0].upgrader_id), (0, 0));
+    assert_eq!(
+        m_await!(client.count_rows("pg_tables WHERE tablename = 'orders'")),
+        0
+    );
+    assert_eq!(
+        m_await!(client.count_rows(
+            "information_schema.columns WHERE table_name = 'users' AND column_name = 'email'"
+        )),
+        0
+    );
+
+    // Downgrading to the current state is a no-op, and upgrading again reapplies.
+    m_downgrade!(folder, &container.connection_string, &options, (0, 0)).unwrap();
+    m_upgrade!(folder, &container.connection_string, &options).unwrap();
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 3);
+
+    // Without down blocks nothing is undone.
+    m_await!(client.execute("CREATE DATABASE no_down"));
+    let no_down = container
+        .connection_string
+        .replace("dbname=postgres", "dbname=no_down");
+    m_upgrade!("tests/data/basic_flow_step2", &no_down, &options).unwrap();
+    let err = m_downgrade!("tests/data/basic_flow_step2", &no_down, &options, (0, 0)).unwrap_err();
+    assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+    let mut client = m_client!(&no_down);
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 2);
+});
+
+#[test]
+fn advisory_lock_id_blocking() {
+    let container = PostgresContainer::start();
+    let mut holder = BlockingTestClient::connect(&container.connection_string);
+    holder.execute("SELECT pg_advisory_lock(12345)");
+    holder.execute("SELECT pg_advisory_lock(42004200)");
+
+    // Another lock id is not held up, and neither is the default one: with a schema, the id is
+    // derived from its name.
+    let options = PostgresUpgraderOptions::builder()
+        .schema("other_app")
+        .create_schema(true)
+        .build();
+    upgrade_blocking(
+        "tests/data/schema_auto_create",
+        &container.connection_string,
+        &options,
+    )
+    .unwrap();
+
+    let connection_string = container.connection_string.clone();
+    let upgrade = thread::spawn(move || {
+        let options = PostgresUpgraderOptions::builder()
+            .advisory_lock_id(12345)
+            .build();
+        upgrade_blocking("tests/data/basic_flow_step1", &connection_string, &options)
+    });
+
+    // The upgrade waits in pg_advisory_xact_lock for the id that is held.
+    let mut observer = BlockingTestClient::connect(&container.connection_string);
+    let waiting = "pg_locks WHERE locktype = 'advisory' AND objid = 12345 AND NOT granted";
+    let started = Instant::now();
+    while observer.count_rows(waiting) == 0 {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "upgrade never waited for the lock"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!upgrade.is_finished());
+
+    holder.execute("SELECT pg_advisory_unlock(12345)");
+    upgrade.join().unwrap().unwrap();
+    assert_eq!(observer.get_upgraders(None).len(), 1);
+}
+
+#[test]
+fn concurrency_blocking() {
+    let container = PostgresContainer::start();
+    let folder = "tests/data/concurrency";
+    let connection_string = Arc::new(container.connection_string.clone());
+    let n_threads = 10;
+    let mut handles = vec![];
+    let barrier = Arc::new(Barrier::new(n_threads));
+
+    for _ in 0..n_threads {
+        let conn_str = connection_string.clone();
+        let b = barrier.clone();
+        handles.push(thread::spawn(move || {
+            b.wait();
+            let options = PostgresUpgraderOptions::builder().build();
+            upgrade_blocking(folder, &conn_str, &options)
+        }));
+    }
+
+    for handle in handles {
+        handle.join().unwrap().unwrap();
+    }
+
+    let mut client = BlockingTestClient::connect(&container.connection_string);
     let rows = client.get_upgraders(None);
     assert_eq!(rows.len(), 1);
 }
 
+#[test]
+fn no_transaction_concurrency_blocking() {
+    let container = PostgresContainer::start();
+    let connection_string = Arc::new(container.connection_string.clone());
+    let n_threads = 5;
+    let barrier = Arc::new(Barrier::new(n_threads));
+
+    let handles: Vec<_> = (0..n_threads)
+        .map(|_| {
+            let conn_str = connection_string.clone();
+            let b = barrier.clone();
+            thread::spawn(move || {
+                b.wait();
+                let options = PostgresUpgraderOptions::builder().build();
+                upgrade_blocking("tests/data/no_transaction", &conn_str, &options)
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap().unwrap();
+    }
+
+    let mut client = BlockingTestClient::connect(&container.connection_string);
+    assert_eq!(client.get_upgraders(None).len(), 4);
+}
+
+#[tokio::test]
+async fn concurrency_async() {
+    let container = PostgresContainer::start();
+    let folder = "tests/data/concurrency";
+    let connection_string = Arc::new(container.connection_string.clone());
+    let n_tasks = 10;
+    let mut handles = vec![];
+    let barrier = Arc::new(tokio::sync::Barrier::new(n_tasks));
+
+    for _ in 0..n_tasks {
+        let conn_str = connection_string.clone();
+        let b = barrier.clone();
+        handles.push(tokio::spawn(async move {
+            b.wait().await;
+            let options = PostgresUpgraderOptions::builder().build();
+            upgrade_async(folder, &conn_str, &options).await
+        }));
+    }
+
+    for handle in handles {
+        handle.await.unwrap().unwrap();
+    }
+
+    let client = AsyncTestClient::connect(&container.connection_string).await;
+    let rows = client.get_upgraders(None).await;
+    assert_eq!(rows.len(), 1);
+}
+
+#[tokio::test]
+async fn running_upgrader_does_not_lock_upgraders_table() {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let connection_string = container.connection_string.clone();
+    let upgrade = tokio::spawn(async move {
+        upgrade_async("tests/data/slow", &connection_string, &options).await
+    });
+
+    let client = AsyncTestClient::connect(&container.connection_string).await;
+    let running = "pg_stat_activity WHERE query LIKE 'SELECT pg_sleep%'";
+    while client.count_rows(running).await == 0 {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    // Writers of the upgraders table are not blocked while the upgrader runs.
+    client.execute("BEGIN").await;
+    client.execute("SET LOCAL lock_timeout = '1s'").await;
+    client
+        .execute(r#"LOCK TABLE "$upgraders$" IN ROW EXCLUSIVE MODE"#)
+        .await;
+    client.execute("COMMIT").await;
+    assert!(client.get_upgraders(None).await.is_empty());
+
+    upgrade.await.unwrap().unwrap();
+    assert_eq!(client.get_upgraders(None).await.len(), 1);
+}
+
+define_test_both_modes!(transaction_rollback, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+
+    // The rollback folder contains:
+    // 000_init.sql (Valid)
+    // 001_fail.sql (Valid creation + Invalid Select)
+
+    let result = m_upgrade!(
+        "tests/data/rollback",
+        &container.connection_string,
+        &options
+    );
+
+    // It should fail
+    assert!(result.is_err(), "Upgrade should fail due to bad SQL");
+
+    let mut client = m_client!(&container.connection_string);
+
+    // 1. Verify 000_init was applied (transaction committed before file 001 started?
+    // Wait, the library commits PER UPGRADER STEP or PER FILE?
+    // Let's check logic: "Loop { Transaction -> Lock -> Check -> Apply -> Commit }"
+    // It commits per *Applied Upgrader* (per step inside the file).
+    // Let's check 000_init content. It has one step.
+    // 001_fail content. It has one step.
+    // So 000_init should be committed.
+    // 001_fail step should be rolled back.
+
+    // Check 000_init's table
+    m_await!(client.ensure_table_exists("base_table", None));
+
+    // Check 001_fail's SIDE EFFECT table. It should NOT exist.
+    // "CREATE TABLE side_effect_table" happened before the error in the SAME step.
+    // So it should be rolled back.
+    let _sql = "SELECT 1 FROM information_schema.tables WHERE table_name = 'side_effect_table'";
+
+    // Abstracting this check slightly since ensure_table_does_not_exist isn't on client yet,
+    // but we can just use execute expectation failure or simple query check.
+    // Let's use raw query check available on client wrapper? No, wrapper hides it.
+    // We'll trust that ensure_table_exists fails if missing.
+    // Wait, we want to ensure it is MISSING.
+    // Let's rely on the Upgraders table first.
+    let rows = m_await!(client.get_upgraders(None));
+    // Should have 0:0. Should NOT have 0:1 (fail step) or 1:0 (file id 1).
+    // File 000 is 0:0. File 001 is 1:0 (fail step).
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].file_id, 0);
+    assert_eq!(rows[0].upgrader_id, 0);
+});
+
+define_test_both_modes!(rerun_after_failure, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+
+    // Every run reads and records through both the plain client (unlocked fast path) and the
+    // per-upgrader transaction, and must leave the same state behind each time.
+    for _ in 0..2 {
+        let result = m_upgrade!(
+            "tests/data/rollback",
+            &container.connection_string,
+            &options
+        );
+        match result {
+            Err(UpgraderError::ExecutionFailed {
+                file_id,
+                upgrader_id,
+                statement,
+                source,
+            }) => {
+                assert_eq!((file_id, upgrader_id, statement), (1, 0, None));
+                assert!(
+                    source.starts_with("db error: "),
+                    "Unexpected error: {}",
+                    source
+                )
+            }
+            other => panic!("Expected execution error, got {:?}", other),
+        }
+
+        let mut client = m_client!(&container.connection_string);
+        let rows = m_await!(client.get_upgraders(None));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(
+            m_current_sequence!(&container.connection_string, &options).unwrap(),
+            Some(1)
+        );
+    }
+});
+
+define_test_both_modes!(long_error_truncated, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .max_error_chars(200)
+        .build();
+
+    let result = m_upgrade!(
+        "tests/data/long_error",
+        &container.connection_string,
+        &options
+    );
+    match result {
+        Err(UpgraderError::ExecutionFailed { source: msg, .. }) => {
+            assert!(
+                msg.starts_with("db error: ERROR: conflicting rows: row, "),
+                "Unexpected error: {}",
+                msg
+            );
+            assert!(
+                msg.ends_with("...(truncated) (SQLSTATE P0001)"),
+                "Unexpected error: {}",
+                msg
+            );
+            assert!(
+                msg.len() < 300,
+                "Error was not truncated: {} chars",
+                msg.len()
+            );
+        }
+        other => panic!("Expected execution error, got {:?}", other),
+    }
+});
+
+define_test_both_modes!(integrity_violation, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+
+    // Step 1: Apply initial valid schema
+    m_upgrade!(
+        "tests/data/integrity_violation_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    // Verify it worked
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.ensure_table_exists("integrity_table", None));
+
+    // Step 2: Apply corrupted schema (File 0 modified, File 1 added)
+    let result = m_upgrade!(
+        "tests/data/integrity_violation_step2",
+        &container.connection_string,
+        &options
+    );
+
+    assert!(result.is_err());
+    let err_msg = result.err().unwrap().to_string();
+    assert!(
+        err_msg.contains("Integrity error") || err_msg.contains("SQL content has changed"),
+        "Unexpected error: {}",
+        err_msg
+    );
+
+    // Verify File 1 (next_table) was NOT applied
+    // We don't have a "ensure_table_does_not_exist" helper, but we can check the upgraders table.
+    let rows = m_await!(client.get_upgraders(None));
+    assert_eq!(
+        rows.len(),
+        1,
+        "Should stop immediately after integrity check failure"
+    );
+    assert_eq!(rows[0].file_id, 0);
+});
+
+define_test_both_modes!(applied_by_recorded, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .applied_by("ci-bot")
+        .build();
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    // Another applier does not make the recorded upgraders look changed.
+    let options = PostgresUpgraderOptions::builder().build();
+    m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    let status = m_status!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert!(status.is_up_to_date());
+    let applied_by: Vec<_> = status
+        .applied
+        .iter()
+        .map(|u| u.applied_by.as_deref())
+        .collect();
+    assert_eq!(applied_by, vec![Some("ci-bot"), None]);
+    assert!(status.applied.iter().all(|u| u.applied_host.is_some()));
+});
+
+define_test_both_modes!(clock, {
+    use chrono::{TimeZone, Utc};
+
+    let container = PostgresContainer::start();
+    let conn = &container.connection_string;
+    let at = Utc.with_ymd_and_hms(2024, 7, 1, 12, 30, 0).unwrap();
+    let options = PostgresUpgraderOptions::builder()
+        .clock(Arc::new(move || at))
+        .single_transaction(true)
+        .build();
+    m_upgrade!("tests/data/basic_flow_step2", conn, &options).unwrap();
+
+    let status = m_status!("tests/data/basic_flow_step2", conn, &options).unwrap();
+    assert!(status.is_up_to_date(), "{:?}", status.drift);
+    let applied_on: Vec<_> = status.applied.iter().map(|u| u.applied_on).collect();
+    assert_eq!(applied_on, vec![at, at]);
+});
+
+define_test_both_modes!(integrity_check_disabled, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    m_upgrade!(
+        "tests/data/integrity_violation_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    // The operator knowingly applies the pending upgrader despite the changed file 0.
+    let options = PostgresUpgraderOptions::builder()
+        .verify_integrity(false)
+        .build();
+    m_upgrade!(
+        "tests/data/integrity_violation_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.ensure_table_exists("next_table", None));
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 2);
+
+    // Status still reports the mismatch.
+    let status = m_status!(
+        "tests/data/integrity_violation_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    assert_eq!(status.drift.unwrap().kind, "text_changed");
+});
+
+define_test_both_modes!(integrity_violation_audit, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .audit_integrity_failures(true)
+        .build();
+
+    m_upgrade!(
+        "tests/data/integrity_violation_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(m_await!(client.count_rows("\"$upgrader_audit$\"")), 0);
+
+    let result = m_upgrade!(
+        "tests/data/integrity_violation_step2",
+        &container.connection_string,
+        &options
+    );
+    assert!(matches!(result, Err(UpgraderError::IntegrityError(_))));
+
+    // The blocked deploy left a trail, and nothing else was applied.
+    assert_eq!(
+        m_await!(client.count_rows(
+            "\"$upgrader_audit$\" WHERE violation_kind = 'text_changed' \
+             AND file_id = 0 AND upgrader_id = 0 AND host <> '' AND recorded_on IS NOT NULL"
+        )),
+        1
+    );
+    let rows = m_await!(client.get_upgraders(None));
+    assert_eq!(rows.len(), 1);
+});
+
+define_test_both_modes!(allow_out_of_order, {
+    let container = PostgresContainer::start();
+
+    // Files 0 and 1 are deployed, then a step appended to file 0 on another branch arrives.
+    m_upgrade!(
+        "tests/data/out_of_order_step1",
+        &container.connection_string,
+        &PostgresUpgraderOptions::builder().build()
+    )
+    .unwrap();
+
+    let result = m_upgrade!(
+        "tests/data/out_of_order_step2",
+        &container.connection_string,
+        &PostgresUpgraderOptions::builder().build()
+    );
+    assert!(matches!(result, Err(UpgraderError::IntegrityError(_))));
+
+    let options = PostgresUpgraderOptions::builder()
+        .allow_out_of_order(true)
+        .build();
+    m_upgrade!(
+        "tests/data/out_of_order_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    let mut client = m_client!(&container.connection_string);
+    let rows = m_await!(client.get_upgraders(None));
+    let ids: Vec<_> = rows.iter().map(|r| (r.file_id, r.upgrader_id)).collect();
+    // 0:1 was applied last, after the later file.
+    assert_eq!(ids, vec![(0, 0), (1, 0), (0, 1)]);
+    assert_eq!(
+        m_await!(client.get_sequences(None)),
+        vec![Some(1), Some(3), Some(2)]
+    );
+
+    // The interleaved history is accepted on later runs, in both modes of checking it.
+    m_upgrade!(
+        "tests/data/out_of_order_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    let plan = m_plan!(
+        MigrationSet::load("tests/data/out_of_order_step2", &options).unwrap(),
+        &container.connection_string
+    )
+    .unwrap();
+    assert!(plan.is_empty());
+});
+
+define_test_both_modes!(last_applier, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    assert_eq!(
+        m_last_applier!(&container.connection_string, &options).unwrap(),
+        None
+    );
+
+    m_upgrade!(
+        "tests/data/basic_flow_step1",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+    let host = gethostname::gethostname().to_string_lossy().into_owned();
+    assert_eq!(
+        m_last_applier!(&container.connection_string, &options).unwrap(),
+        Some(host)
+    );
+
+    // A row recorded by a version that did not track hosts.
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.execute(
+        "INSERT INTO \"$upgraders$\" (file_id, upgrader_id, description, text, applied_on, sequence) \
+         VALUES (0, 1, 'Legacy', 'SELECT 1;', now(), 2)"
+    ));
+    assert_eq!(
+        m_last_applier!(&container.connection_string, &options).unwrap(),
+        Some("unknown".to_string())
+    );
+});
+
+define_test_both_modes!(sequence_backfill, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    assert_eq!(
+        m_current_sequence!(&container.connection_string, &options).unwrap(),
+        None
+    );
+
+    // An upgraders table created before the sequence column existed.
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.execute(
+        "CREATE TABLE \"$upgraders$\" (file_id INT, upgrader_id INT, description VARCHAR(500), \
+         text TEXT, applied_on TIMESTAMPTZ, PRIMARY KEY (file_id, upgrader_id))"
+    ));
+    m_await!(client.execute(
+        "INSERT INTO \"$upgraders$\" VALUES (0, 0, 'Create foo', 'CREATE TABLE foo (id INT);', now())"
+    ));
+    m_await!(client.execute("CREATE TABLE foo (id INT)"));
+
+    m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    assert_eq!(m_await!(client.get_sequences(None)), vec![Some(1), Some(2)]);
+    assert_eq!(
+        m_current_sequence!(&container.connection_string, &options).unwrap(),
+        Some(2)
+    );
+});
+
+#[test]
+fn sequence_concurrency_blocking() {
+    let container = PostgresContainer::start();
+    let connection_string = Arc::new(container.connection_string.clone());
+    let n_threads = 8;
+    let mut handles = vec![];
+    let barrier = Arc::new(Barrier::new(n_threads));
+
+    for _ in 0..n_threads {
+        let conn_str = connection_string.clone();
+        let b = barrier.clone();
+        handles.push(thread::spawn(move || {
+            b.wait();
+            let options = PostgresUpgraderOptions::builder().build();
+            upgrade_blocking("tests/data/sequence", &conn_str, &options)
+        }));
+    }
+
+    for handle in handles {
+        handle.join().unwrap().unwrap();
+    }
+
+    let mut client = BlockingTestClient::connect(&container.connection_string);
+    let expected: Vec<_> = (1..=6).map(Some).collect();
+    assert_eq!(client.get_sequences(None), expected);
+}
+
 #[tokio::test]
-async fn concurrency_async() {
+async fn sequence_concurrency_async() {
     let container = PostgresContainer::start();
-    let folder = "tests/data/concurrency";
-    let connection_string = Arc::new(container.connection_string.clone());
-    let n_tasks = 10;
+    let n_tasks = 8;
     let mut handles = vec![];
     let barrier = Arc::new(tokio::sync::Barrier::new(n_tasks));
 
     for _ in 0..n_tasks {
-        let conn_str = connection_string.clone();
+        let conn_str = container.connection_string.clone();
         let b = barrier.clone();
         handles.push(tokio::spawn(async move {
             b.wait().await;
             let options = PostgresUpgraderOptions::builder().build();
-            upgrade_async(folder, &conn_str, &options).await
+            upgrade_async("tests/data/sequence", &conn_str, &options).await
         }));
     }
 
@@ -219,101 +3117,16 @@ async fn concurrency_async() {
     }
 
     let client = AsyncTestClient::connect(&container.connection_string).await;
-    let rows = client.get_upgraders(None).await;
-    assert_eq!(rows.len(), 1);
-}
-
-define_test_both_modes!(transaction_rollback, {
-    let container = PostgresContainer::start();
-    let options = PostgresUpgraderOptions::builder().build();
-
-    // The rollback folder contains:
-    // 000_init.sql (Valid)
-    // 001_fail.sql (Valid creation + Invalid Select)
-
-    let result = m_upgrade!(
-        "tests/data/rollback",
-        &container.connection_string,
-        &options
-    );
-
-    // It should fail
-    assert!(result.is_err(), "Upgrade should fail due to bad SQL");
-
-    let mut client = m_client!(&container.connection_string);
-
-    // 1. Verify 000_init was applied (transaction committed before file 001 started?
-    // Wait, the library commits PER UPGRADER STEP or PER FILE?
-    // Let's check logic: "Loop { Transaction -> Lock -> Check -> Apply -> Commit }"
-    // It commits per *Applied Upgrader* (per step inside the file).
-    // Let's check 000_init content. It has one step.
-    // 001_fail content. It has one step.
-    // So 000_init should be committed.
-    // 001_fail step should be rolled back.
-
-    // Check 000_init's table
-    m_await!(client.ensure_table_exists("base_table", None));
-
-    // Check 001_fail's SIDE EFFECT table. It should NOT exist.
-    // "CREATE TABLE side_effect_table" happened before the error in the SAME step.
-    // So it should be rolled back.
-    let _sql = "SELECT 1 FROM information_schema.tables WHERE table_name = 'side_effect_table'";
-
-    // Abstracting this check slightly since ensure_table_does_not_exist isn't on client yet,
-    // but we can just use execute expectation failure or simple query check.
-    // Let's use raw query check available on client wrapper? No, wrapper hides it.
-    // We'll trust that ensure_table_exists fails if missing.
-    // Wait, we want to ensure it is MISSING.
-    // Let's rely on the Upgraders table first.
-    let rows = m_await!(client.get_upgraders(None));
-    // Should have 0:0. Should NOT have 0:1 (fail step) or 1:0 (file id 1).
-    // File 000 is 0:0. File 001 is 1:0 (fail step).
-    assert_eq!(rows.len(), 1);
-    assert_eq!(rows[0].file_id, 0);
-    assert_eq!(rows[0].upgrader_id, 0);
-});
-
-define_test_both_modes!(integrity_violation, {
-    let container = PostgresContainer::start();
+    let expected: Vec<_> = (1..=6).map(Some).collect();
+    assert_eq!(client.get_sequences(None).await, expected);
     let options = PostgresUpgraderOptions::builder().build();
-
-    // Step 1: Apply initial valid schema
-    m_upgrade!(
-        "tests/data/integrity_violation_step1",
-        &container.connection_string,
-        &options
-    )
-    .unwrap();
-
-    // Verify it worked
-    let mut client = m_client!(&container.connection_string);
-    m_await!(client.ensure_table_exists("integrity_table", None));
-
-    // Step 2: Apply corrupted schema (File 0 modified, File 1 added)
-    let result = m_upgrade!(
-        "tests/data/integrity_violation_step2",
-        &container.connection_string,
-        &options
-    );
-
-    assert!(result.is_err());
-    let err_msg = result.err().unwrap().to_string();
-    assert!(
-        err_msg.contains("Integrity error") || err_msg.contains("SQL content has changed"),
-        "Unexpected error: {}",
-        err_msg
-    );
-
-    // Verify File 1 (next_table) was NOT applied
-    // We don't have a "ensure_table_does_not_exist" helper, but we can check the upgraders table.
-    let rows = m_await!(client.get_upgraders(None));
     assert_eq!(
-        rows.len(),
-        1,
-        "Should stop immediately after integrity check failure"
+        current_sequence_async(&container.connection_string, &options)
+            .await
+            .unwrap(),
+        Some(6)
     );
-    assert_eq!(rows[0].file_id, 0);
-});
+}
 
 // Mixed Version Concurrency Tests
 // Scenario:
@@ -404,3 +3217,420 @@ async fn mixed_concurrency_async() {
     client.ensure_table_exists("mixed_table", None).await;
     client.ensure_table_exists("feature_table", None).await;
 }
+
+#[tokio::test]
+async fn shutdown_async() {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+
+    // 000_init applies quickly, 001_slow sleeps for a minute and gets interrupted.
+    let started = Instant::now();
+    let result = upgrade_async_with_shutdown(
+        "tests/data/shutdown",
+        &container.connection_string,
+        &options,
+        tokio::time::sleep(Duration::from_secs(2)),
+    )
+    .await;
+
+    match result {
+        Err(UpgraderError::Cancelled { last_committed }) => {
+            assert_eq!(last_committed, Some((0, 0)))
+        }
+        other => panic!("Expected Cancelled, got {:?}", other),
+    }
+    assert!(
+        started.elapsed() < Duration::from_secs(30),
+        "The running statement should have been cancelled"
+    );
+
+    // The committed step stays applied, the interrupted one is rolled back.
+    let client = AsyncTestClient::connect(&container.connection_string).await;
+    let rows = client.get_upgraders(None).await;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].file_id, 0);
+    client.ensure_table_exists("shutdown_table", None).await;
+}
+
+const SCRATCH_SCHEMAS: &str =
+    "information_schema.schemata WHERE schema_name LIKE 'upgrader\\_scratch\\_%'";
+
+#[tokio::test]
+async fn verify_from_scratch_async_success() {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let client = AsyncTestClient::connect(&container.connection_string).await;
+
+    // Unqualified and {{SCHEMA}}-qualified objects both land in the scratch schema.
+    let checked = std::sync::Mutex::new(None);
+    let check = |schema: &str| {
+        *checked.lock().unwrap() = Some(schema.to_string());
+        Ok(())
+    };
+    for folder in ["tests/data/basic_flow_step2", "tests/data/schema_support"] {
+        verify_from_scratch_async(folder, &container.connection_string, &options, Some(&check))
+            .await
+            .unwrap();
+    }
+
+    let schema = checked.lock().unwrap().clone().unwrap();
+    assert!(schema.starts_with("upgrader_scratch_"));
+    assert_eq!(client.count_rows(SCRATCH_SCHEMAS).await, 0);
+    assert_eq!(
+        client
+            .count_rows("information_schema.tables WHERE table_name IN ('foo', '$upgraders$')")
+            .await,
+        0
+    );
+}
+
+#[tokio::test]
+async fn verify_from_scratch_async_failures_clean_up() {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    let client = AsyncTestClient::connect(&container.connection_string).await;
+
+    let check = |_: &str| Err("schema differs from the previous release".to_string());
+    let result = verify_from_scratch_async(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options,
+        Some(&check),
+    )
+    .await;
+    match result {
+        Err(UpgraderError::VerificationError(msg)) => {
+            assert_eq!(msg, "schema differs from the previous release")
+        }
+        other => panic!("Expected verification error, got {:?}", other),
+    }
+    assert_eq!(client.count_rows(SCRATCH_SCHEMAS).await, 0);
+
+    let result = verify_from_scratch_async(
+        "tests/data/rollback",
+        &container.connection_string,
+        &options,
+        None,
+    )
+    .await;
+    assert!(matches!(result, Err(UpgraderError::ExecutionFailed { .. })));
+    assert_eq!(client.count_rows(SCRATCH_SCHEMAS).await, 0);
+
+    // Dropping the future midway interrupts the upgrader and still removes the schema, in the
+    // background.
+    let result = tokio::time::timeout(
+        Duration::from_secs(2),
+        verify_from_scratch_async(
+            "tests/data/shutdown",
+            &container.connection_string,
+            &options,
+            None,
+        ),
+    )
+    .await;
+    assert!(result.is_err(), "The slow upgrader should still be running");
+    let mut remaining = 1;
+    for _ in 0..20 {
+        remaining = client.count_rows(SCRATCH_SCHEMAS).await;
+        if remaining == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    assert_eq!(remaining, 0);
+}
+
+#[cfg(feature = "sqlx")]
+#[tokio::test]
+async fn sqlx_upgrade() {
+    use postgresql_schema_upgrader::upgrade_sqlx;
+
+    let container = PostgresContainer::start();
+    // sqlx takes a URL rather than the key=value form of the other backends.
+    let field = |key: &str| {
+        container
+            .connection_string
+            .split_whitespace()
+            .find_map(|pair| pair.strip_prefix(&format!("{}=", key)))
+            .unwrap()
+            .to_string()
+    };
+    let url = format!(
+        "postgres://{}:{}@{}:{}/{}",
+        field("user"),
+        field("password"),
+        field("host"),
+        field("port"),
+        field("dbname")
+    );
+    let pool = sqlx::PgPool::connect(&url).await.unwrap();
+    let client = AsyncTestClient::connect(&container.connection_string).await;
+    let options = PostgresUpgraderOptions::builder()
+        .schema("sqlx")
+        .create_schema(true)
+        .build();
+
+    let report = upgrade_sqlx(&pool, "tests/data/schema_support", &options)
+        .await
+        .unwrap();
+    assert_eq!(report.applied.len(), 1);
+    client.ensure_table_exists("foo", Some("sqlx")).await;
+    assert_eq!(client.get_upgraders(Some("sqlx")).await.len(), 1);
+    assert_eq!(client.get_sequences(Some("sqlx")).await, vec![Some(1)]);
+
+    // The other backends accept what sqlx recorded, and the other way around.
+    upgrade_async(
+        "tests/data/schema_support",
+        &container.connection_string,
+        &options,
+    )
+    .await
+    .unwrap();
+    let report = upgrade_sqlx(&pool, "tests/data/schema_support", &options)
+        .await
+        .unwrap();
+    assert!(report.applied.is_empty());
+    assert_eq!(report.already_applied, 1);
+    assert_eq!(client.get_upgraders(Some("sqlx")).await.len(), 1);
+    let compare_checksums = PostgresUpgraderOptions::builder()
+        .schema("sqlx")
+        .compare_checksums(true)
+        .build();
+    upgrade_sqlx(&pool, "tests/data/schema_support", &compare_checksums)
+        .await
+        .unwrap();
+
+    // A changed upgrader is reported the same way.
+    let err = upgrade_sqlx(&pool, "tests/data/basic_flow_step1", &options)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, UpgraderError::IntegrityError(_)));
+
+    // A dry run reports what would be applied, and creates nothing.
+    let dry_run = PostgresUpgraderOptions::builder()
+        .schema("sqlx_dry_run")
+        .create_schema(true)
+        .dry_run(true)
+        .build();
+    let report = upgrade_sqlx(&pool, "tests/data/schema_support", &dry_run)
+        .await
+        .unwrap();
+    assert!(report.applied.is_empty());
+    assert_eq!(report.pending.len(), 1);
+    assert_eq!(report.pending[0].0, 0);
+    let dry_run = PostgresUpgraderOptions::builder()
+        .schema("sqlx")
+        .dry_run(true)
+        .build();
+    let report = upgrade_sqlx(&pool, "tests/data/schema_support", &dry_run)
+        .await
+        .unwrap();
+    assert!(report.pending.is_empty());
+    assert_eq!(report.already_applied, 1);
+    client.ensure_schema_does_not_exist("sqlx_dry_run").await;
+
+    let err = upgrade_sqlx(&pool, "tests/data/no_transaction", &options)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+
+    for parallel in [
+        PostgresUpgraderOptions::builder()
+            .parallel_cold_start(true)
+            .build(),
+        PostgresUpgraderOptions::builder()
+            .max_parallel_files(2)
+            .build(),
+    ] {
+        let err = upgrade_sqlx(&pool, "tests/data/parallel_files", &parallel)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, UpgraderError::ConfigurationError(_)));
+    }
+
+    let debug_statements = PostgresUpgraderOptions::builder()
+        .debug_statements(true)
+        .build();
+    let err = upgrade_sqlx(&pool, "tests/data/debug_statements", &debug_statements)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        UpgraderError::ExecutionFailed {
+            file_id: 0,
+            upgrader_id: 1,
+            statement: Some(3),
+            ..
+        }
+    ));
+}
+
+define_test_both_modes!(debug_statements, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .debug_statements(true)
+        .build();
+
+    let err = m_upgrade!(
+        "tests/data/debug_statements",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    match &err {
+        UpgraderError::ExecutionFailed {
+            file_id: 0,
+            upgrader_id: 1,
+            statement: Some(3),
+            source,
+        } => assert!(source.contains("division by zero"), "{}", source),
+        other => panic!("Expected execution error, got {:?}", other),
+    }
+    assert!(
+        err.to_string()
+            .starts_with("Execution error: statement 3 of upgrader 0:1 failed: "),
+        "{}",
+        err
+    );
+
+    // The failing upgrader was rolled back as a whole.
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 1);
+    assert_eq!(
+        m_await!(client.count_rows("ledger WHERE note = 'first; not the end'")),
+        1
+    );
+    assert_eq!(m_await!(client.count_rows("ledger")), 1);
+    assert_eq!(
+        m_await!(client.count_rows("pg_proc WHERE proname = 'ledger_count'")),
+        0
+    );
+
+    // Without the option the same upgrader fails as a whole.
+    let err = m_upgrade!(
+        "tests/data/debug_statements",
+        &container.connection_string,
+        &PostgresUpgraderOptions::builder().build()
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        UpgraderError::ExecutionFailed {
+            upgrader_id: 1,
+            statement: None,
+            ..
+        }
+    ));
+});
+
+define_test_both_modes!(schema_with_quote_rejected, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .schema("tenant\"; DROP TABLE victim; --")
+        .create_schema(true)
+        .build();
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.execute("CREATE TABLE victim (id INT)"));
+
+    let err = m_upgrade!(
+        "tests/data/schema_support",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    assert!(
+        matches!(err, UpgraderError::ConfigurationError(_)),
+        "{:?}",
+        err
+    );
+    let err = m_init_only!(&container.connection_string, &options).unwrap_err();
+    assert!(
+        matches!(err, UpgraderError::ConfigurationError(_)),
+        "{:?}",
+        err
+    );
+
+    m_await!(client.ensure_table_exists("victim", None));
+    assert_eq!(
+        m_await!(client.count_rows("pg_namespace WHERE nspname LIKE 'tenant%'")),
+        0
+    );
+});
+
+define_test_both_modes!(frozen_upgraders, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder().build();
+    m_upgrade!(
+        "tests/data/basic_flow_step2",
+        &container.connection_string,
+        &options
+    )
+    .unwrap();
+
+    // The squashed files no longer match what was applied.
+    let err = m_upgrade!(
+        "tests/data/squashed",
+        &container.connection_string,
+        &options
+    )
+    .unwrap_err();
+    assert!(matches!(err, UpgraderError::IntegrityError(_)), "{:?}", err);
+
+    let frozen = PostgresUpgraderOptions::builder()
+        .frozen_upgraders((0, 0)..=(1, 0))
+        .build();
+    m_upgrade!("tests/data/squashed", &container.connection_string, &frozen).unwrap();
+    let mut client = m_client!(&container.connection_string);
+    m_await!(client.ensure_table_exists("baz", None));
+    let rows = m_await!(client.get_upgraders(None));
+    assert_eq!(rows.len(), 3);
+    // The recorded history is left as it was applied.
+    assert_eq!(
+        m_await!(client.count_rows("\"$upgraders$\" WHERE description = 'Create foo'")),
+        1
+    );
+
+    // A fresh database is built from the squashed files.
+    m_await!(client.execute("DROP TABLE foo, bar, baz, \"$upgraders$\""));
+    m_upgrade!("tests/data/squashed", &container.connection_string, &frozen).unwrap();
+    m_await!(client.ensure_table_exists("bar", None));
+    assert_eq!(m_await!(client.get_upgraders(None)).len(), 3);
+});
+
+define_test_both_modes!(run_analyze_after, {
+    let container = PostgresContainer::start();
+    let options = PostgresUpgraderOptions::builder()
+        .schema("stats")
+        .create_schema(true)
+        .run_analyze_after(true)
+        .build();
+    // reltuples is -1 until the table is first analyzed.
+    let analyzed_rows = "pg_class WHERE oid = 'stats.items'::regclass AND reltuples = ";
+
+    m_upgrade!("tests/data/analyze", &container.connection_string, &options).unwrap();
+    let mut client = m_client!(&container.connection_string);
+    assert_eq!(
+        m_await!(client.count_rows(&format!("{}3", analyzed_rows))),
+        1
+    );
+
+    // Nothing is analyzed when nothing was applied.
+    m_await!(client.execute("INSERT INTO stats.items VALUES (4), (5)"));
+    m_upgrade!("tests/data/analyze", &container.connection_string, &options).unwrap();
+    assert_eq!(
+        m_await!(client.count_rows(&format!("{}3", analyzed_rows))),
+        1
+    );
+
+    let without = PostgresUpgraderOptions::builder()
+        .schema("no_stats")
+        .create_schema(true)
+        .build();
+    m_upgrade!("tests/data/analyze", &container.connection_string, &without).unwrap();
+    assert_eq!(
+        m_await!(
+            client.count_rows("pg_class WHERE oid = 'no_stats.items'::regclass AND reltuples = -1")
+        ),
+        1
+    );
+});